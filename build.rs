@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/ht.proto");
+
+    // protox compiles the .proto in Rust, so protoc isn't needed for building
+    let descriptors = protox::compile(["proto/ht.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+
+    Ok(())
+}
@@ -1,6 +1,18 @@
+pub mod fields;
+pub mod filter;
+pub mod grpc;
 pub mod http;
+pub mod hub;
+pub mod jsonrpc;
+pub mod publish;
+pub mod queue;
+pub mod schema;
+pub mod sink;
 pub mod stdio;
+use crate::command::{Command, Reply};
+use anyhow::Result;
 use std::str::FromStr;
+use tokio::sync::mpsc;
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Subscription {
@@ -8,6 +20,26 @@ pub struct Subscription {
     snapshot: bool,
     resize: bool,
     output: bool,
+    heartbeat: bool,
+    timeout: bool,
+    truncated: bool,
+    stderr: bool,
+    annotation: bool,
+    invalid_utf8: bool,
+    warning: bool,
+    process_state: bool,
+    foreground_changed: bool,
+    cwd_changed: bool,
+    cursor_style: bool,
+    mode_changed: bool,
+    prompt_start: bool,
+    command_start: bool,
+    command_end: bool,
+    command_executed: bool,
+    screen_hash: bool,
+    paste_progress: bool,
+    recording_paused: bool,
+    dropped: bool,
 }
 
 impl FromStr for Subscription {
@@ -22,6 +54,26 @@ impl FromStr for Subscription {
                 "output" => sub.output = true,
                 "resize" => sub.resize = true,
                 "snapshot" => sub.snapshot = true,
+                "heartbeat" => sub.heartbeat = true,
+                "timeout" => sub.timeout = true,
+                "truncated" => sub.truncated = true,
+                "stderr" => sub.stderr = true,
+                "annotation" => sub.annotation = true,
+                "invalidUtf8" => sub.invalid_utf8 = true,
+                "warning" => sub.warning = true,
+                "processState" => sub.process_state = true,
+                "foregroundChanged" => sub.foreground_changed = true,
+                "cwdChanged" => sub.cwd_changed = true,
+                "cursorStyle" => sub.cursor_style = true,
+                "modeChanged" => sub.mode_changed = true,
+                "promptStart" => sub.prompt_start = true,
+                "commandStart" => sub.command_start = true,
+                "commandEnd" => sub.command_end = true,
+                "commandExecuted" => sub.command_executed = true,
+                "screenHash" => sub.screen_hash = true,
+                "pasteProgress" => sub.paste_progress = true,
+                "recordingPaused" => sub.recording_paused = true,
+                "dropped" => sub.dropped = true,
                 _ => return Err(format!("invalid event name: {event}")),
             }
         }
@@ -29,3 +81,18 @@ impl FromStr for Subscription {
         Ok(sub)
     }
 }
+
+/// Sends the command to the session. The `sleep` pseudo-command is performed
+/// here instead, delaying the commands that follow it.
+pub async fn submit(
+    command_tx: &mpsc::Sender<(Command, Reply)>,
+    command: Command,
+    reply: Reply,
+) -> Result<()> {
+    match command {
+        Command::Sleep(period) => tokio::time::sleep(period).await,
+        command => command_tx.send((command, reply)).await?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Selection of event data fields to deliver to a client.
+///
+/// Given as a comma-separated list of `<event>.<field>` entries, e.g.
+/// `snapshot.text,snapshot.cursor`, which keeps only the listed fields of the
+/// event's `data`. Entries prefixed with `-` remove fields instead, e.g.
+/// `-snapshot.seq`. Events of types not mentioned are left intact.
+#[derive(Debug, Clone, Default)]
+pub struct Fields {
+    events: HashMap<String, Selection>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Selection {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Fields {
+    /// Trims an event, as serialized by `Event::to_json`.
+    pub fn apply(&self, event: &mut Value) {
+        let Some(selection) = event["type"].as_str().and_then(|t| self.events.get(t)) else {
+            return;
+        };
+
+        let Some(data) = event["data"].as_object_mut() else {
+            return;
+        };
+
+        if !selection.include.is_empty() {
+            data.retain(|key, _| selection.include.contains(key));
+        }
+
+        for key in &selection.exclude {
+            data.remove(key);
+        }
+    }
+}
+
+impl FromStr for Fields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields::default();
+
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (exclude, name) = match entry.strip_prefix('-') {
+                Some(name) => (true, name),
+                None => (false, entry),
+            };
+
+            let Some((event, field)) = name
+                .split_once('.')
+                .filter(|(e, f)| !e.is_empty() && !f.is_empty())
+            else {
+                return Err(format!("invalid field: {entry}"));
+            };
+
+            let selection = fields.events.entry(event.to_owned()).or_default();
+
+            if exclude {
+                selection.exclude.push(field.to_owned());
+            } else {
+                selection.include.push(field.to_owned());
+            }
+        }
+
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Fields;
+    use serde_json::json;
+
+    #[test]
+    fn select_fields() {
+        let fields: Fields = "snapshot.text,snapshot.cursor,-heartbeat.time"
+            .parse()
+            .unwrap();
+
+        let mut snapshot = json!({
+            "type": "snapshot",
+            "data": { "cols": 80, "rows": 24, "seq": "\u{1b}[1mhi", "text": "hi", "cursor": {} }
+        });
+
+        fields.apply(&mut snapshot);
+
+        assert_eq!(
+            snapshot,
+            json!({ "type": "snapshot", "data": { "text": "hi", "cursor": {} } })
+        );
+
+        let mut heartbeat = json!({
+            "type": "heartbeat",
+            "data": { "time": 1.5, "count": 3, "alive": true }
+        });
+
+        fields.apply(&mut heartbeat);
+
+        assert_eq!(
+            heartbeat,
+            json!({ "type": "heartbeat", "data": { "count": 3, "alive": true } })
+        );
+
+        let mut output = json!({ "type": "output", "data": { "seq": "x" } });
+        fields.apply(&mut output);
+        assert_eq!(output["data"]["seq"], "x");
+
+        assert!("snapshot".parse::<Fields>().is_err());
+        assert!("-.text".parse::<Fields>().is_err());
+    }
+}
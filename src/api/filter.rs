@@ -0,0 +1,277 @@
+use crate::tracker;
+use regex::Regex;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Names of all event types, as used in subscriptions.
+pub const EVENTS: [&str; 24] = [
+    "init",
+    "output",
+    "resize",
+    "snapshot",
+    "heartbeat",
+    "timeout",
+    "truncated",
+    "stderr",
+    "annotation",
+    "invalidUtf8",
+    "warning",
+    "processState",
+    "foregroundChanged",
+    "cwdChanged",
+    "cursorStyle",
+    "modeChanged",
+    "promptStart",
+    "commandStart",
+    "commandEnd",
+    "commandExecuted",
+    "screenHash",
+    "pasteProgress",
+    "recordingPaused",
+    "dropped",
+];
+
+/// Conditions that events must meet to be delivered to a client.
+///
+/// Each condition applies to events of a single type, referring to a field of
+/// the event's `data` object, e.g. `output.seq ~ "error"` or
+/// `resize.cols > 100`. Conditions are separated with `;` and all of them must
+/// hold. Events of types without conditions are not filtered.
+///
+/// `output.text` and `stderr.text` refer to the `seq` field with control
+/// sequences removed, so that e.g. colored text can be matched.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    event: String,
+    path: Vec<String>,
+    op: Op,
+    operand: Operand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Number(f64),
+    String(String),
+    Regex(Regex),
+}
+
+impl Filter {
+    /// Checks an event, as serialized by `Event::to_json`.
+    pub fn matches(&self, event: &Value) -> bool {
+        self.conditions
+            .iter()
+            .filter(|c| event["type"] == c.event.as_str())
+            .all(|c| c.matches(&event["data"]))
+    }
+}
+
+impl Condition {
+    fn matches(&self, data: &Value) -> bool {
+        let text;
+
+        let value = if self.is_plain_text() {
+            text = Value::String(tracker::plain_text(
+                data["seq"].as_str().unwrap_or_default(),
+            ));
+            &text
+        } else {
+            self.path.iter().fold(data, |value, key| &value[key])
+        };
+
+        match (&self.operand, value) {
+            (Operand::Regex(regex), Value::String(s)) => regex.is_match(s),
+
+            (Operand::Number(n), Value::Number(value)) => {
+                let Some(value) = value.as_f64() else {
+                    return false;
+                };
+
+                match self.op {
+                    Op::Eq => value == *n,
+                    Op::Ne => value != *n,
+                    Op::Lt => value < *n,
+                    Op::Le => value <= *n,
+                    Op::Gt => value > *n,
+                    Op::Ge => value >= *n,
+                    Op::Match => false,
+                }
+            }
+
+            (Operand::String(s), value) => {
+                let equal = match value {
+                    Value::String(value) => value == s,
+                    Value::Bool(value) => value.to_string() == *s,
+                    _ => false,
+                };
+
+                (self.op == Op::Eq) == equal
+            }
+
+            _ => false,
+        }
+    }
+
+    fn is_plain_text(&self) -> bool {
+        self.path == ["text"] && (self.event == "output" || self.event == "stderr")
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let conditions = split_outside_quotes(s, ';')
+            .into_iter()
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| c.parse())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Filter { conditions })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    /// Parses `<event>.<field> <op> <value>`, where the value is a number, a
+    /// string (optionally in double quotes), or a regular expression for `~`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+            ("~", Op::Match),
+        ];
+
+        let (field, op, value) = ops
+            .iter()
+            .filter_map(|(token, op)| s.find(token).map(|i| (i, token, op)))
+            .min_by_key(|(i, _, _)| *i)
+            .map(|(i, token, op)| (s[..i].trim(), *op, s[i + token.len()..].trim()))
+            .ok_or_else(|| format!("invalid filter condition: {s}"))?;
+
+        let mut path = field.split('.').map(str::to_owned);
+        let event = path.next().unwrap_or_default();
+        let path: Vec<String> = path.collect();
+
+        if !EVENTS.contains(&event.as_str()) {
+            return Err(format!("invalid event name in filter: {event}"));
+        }
+
+        if path.is_empty() || path.iter().any(String::is_empty) {
+            return Err(format!("invalid field in filter: {field}"));
+        }
+
+        let text = if value.starts_with('"') {
+            serde_json::from_str::<String>(value).map_err(|e| e.to_string())?
+        } else {
+            value.to_owned()
+        };
+
+        let operand = match op {
+            Op::Match => Operand::Regex(Regex::new(&text).map_err(|e| e.to_string())?),
+            Op::Eq | Op::Ne => match value.parse() {
+                Ok(n) if !value.starts_with('"') => Operand::Number(n),
+                _ => Operand::String(text),
+            },
+            _ => Operand::Number(
+                value
+                    .parse()
+                    .map_err(|_| format!("expected a number in filter: {s}"))?,
+            ),
+        };
+
+        Ok(Condition {
+            event,
+            path,
+            op,
+            operand,
+        })
+    }
+}
+
+fn split_outside_quotes(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+
+    parts.push(&s[start..]);
+
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::Filter;
+    use serde_json::json;
+
+    #[test]
+    fn filter_events() {
+        let filter: Filter =
+            r#"output.seq ~ "err(or)?;"; resize.cols > 100; snapshot.cursor.visible == true"#
+                .parse()
+                .unwrap();
+
+        let output = |seq| json!({ "type": "output", "data": { "seq": seq } });
+        let resize = |cols| json!({ "type": "resize", "data": { "cols": cols, "rows": 24 } });
+
+        assert!(filter.matches(&output("an error; oops")));
+        assert!(!filter.matches(&output("all good")));
+        assert!(filter.matches(&resize(120)));
+        assert!(!filter.matches(&resize(80)));
+        assert!(filter.matches(&json!({ "type": "init", "data": {} })));
+        assert!(!filter
+            .matches(&json!({ "type": "snapshot", "data": { "cursor": { "visible": false } } })));
+
+        let filter: Filter = r#"output.text ~ "(?m)^ERROR""#.parse().unwrap();
+        assert!(filter.matches(&output("ok\r\n\x1b[1;31mERR\x1b[0mOR: disk full")));
+        assert!(!filter.matches(&output("no ERROR here")));
+
+        let filter: Filter = "stderr.seq != done".parse().unwrap();
+        assert!(filter.matches(&json!({ "type": "stderr", "data": { "seq": "x" } })));
+        assert!(!filter.matches(&json!({ "type": "stderr", "data": { "seq": "done" } })));
+    }
+
+    #[test]
+    fn parse_invalid_filters() {
+        assert!("output.seq".parse::<Filter>().is_err());
+        assert!("bogus.seq == 1".parse::<Filter>().is_err());
+        assert!("output == 1".parse::<Filter>().is_err());
+        assert!("resize.cols > many".parse::<Filter>().is_err());
+        assert!("output.seq ~ (".parse::<Filter>().is_err());
+    }
+}
@@ -0,0 +1,419 @@
+// tonic's API mandates tonic::Status, a large error type
+#![allow(clippy::result_large_err)]
+
+use super::queue::{self, EventQueue};
+use super::{fields::Fields, filter::Filter, stdio, Subscription};
+use crate::audit;
+use crate::command::{Command, Reply};
+use crate::session;
+use anyhow::Result;
+use base64::prelude::*;
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
+use std::future::Future;
+use std::net::TcpListener;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("ht.v1");
+}
+
+use proto::event::Payload;
+use proto::terminal_server::{Terminal, TerminalServer};
+
+struct Service {
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+}
+
+/// Serves the `ht.v1.Terminal` service defined in `proto/ht.proto`.
+pub fn start(
+    listener: TcpListener,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<impl Future<Output = Result<(), tonic::transport::Error>>> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+
+    let service = Service {
+        command_tx,
+        clients_tx,
+    };
+
+    Ok(Server::builder()
+        .add_service(TerminalServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener)))
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<serde_json::Value, Status>> + Send>>;
+
+impl Service {
+    /// Sends a command in the STDIO API format, and returns its reply once the
+    /// session handled it.
+    async fn execute(
+        &self,
+        source: &str,
+        command: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>, Status> {
+        audit::record(source, &command);
+        let command = stdio::build_command(command).map_err(Status::invalid_argument)?;
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+
+        super::submit(&self.command_tx, command, reply_tx)
+            .await
+            .map_err(|_| Status::unavailable("session ended"))?;
+
+        // the session drops the reply channel when done with the command
+        match reply_rx.recv().await {
+            Some(reply) if reply["type"] == "error" => Err(Status::failed_precondition(
+                reply["data"]["message"].as_str().unwrap_or_default(),
+            )),
+
+            reply => Ok(reply),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        sub: Subscription,
+        filter: Filter,
+        fields: Fields,
+    ) -> Result<EventStream, Status> {
+        let queue = (queue::DEFAULT_SIZE, queue::Policy::default());
+
+        let events = EventQueue::subscribe(&self.clients_tx, sub, filter, fields, queue)
+            .await
+            .map_err(|_| Status::unavailable("session ended"))?;
+
+        let events = stream::unfold(events, |mut events| async move {
+            events.next().await.map(|event| (Ok(event), events))
+        });
+
+        Ok(Box::pin(events))
+    }
+}
+
+#[tonic::async_trait]
+impl Terminal for Service {
+    async fn input(
+        &self,
+        request: Request<proto::InputRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let source = source(&request);
+        let proto::InputRequest { payload, secret } = request.into_inner();
+        self.execute(
+            &source,
+            json!({ "type": "input", "payload": payload, "secret": secret }),
+        )
+        .await?;
+
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn send_keys(
+        &self,
+        request: Request<proto::SendKeysRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let source = source(&request);
+        let proto::SendKeysRequest { keys, secret } = request.into_inner();
+        self.execute(
+            &source,
+            json!({ "type": "sendKeys", "keys": keys, "secret": secret }),
+        )
+        .await?;
+
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn resize(
+        &self,
+        request: Request<proto::ResizeRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let source = source(&request);
+        let proto::ResizeRequest {
+            cols,
+            rows,
+            xpixel,
+            ypixel,
+        } = request.into_inner();
+
+        self.execute(
+            &source,
+            json!({ "type": "resize", "cols": cols, "rows": rows, "xpixel": xpixel, "ypixel": ypixel }),
+        )
+        .await?;
+
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn take_snapshot(
+        &self,
+        request: Request<proto::SnapshotRequest>,
+    ) -> Result<Response<proto::Snapshot>, Status> {
+        let source = source(&request);
+        let sub = "snapshot".parse().unwrap();
+        let mut events = self
+            .subscribe(sub, Filter::default(), Fields::default())
+            .await?;
+        self.execute(&source, json!({ "type": "takeSnapshot" }))
+            .await?;
+
+        match events.next().await {
+            Some(event) => Ok(Response::new(snapshot(&event?["data"]))),
+            None => Err(Status::unavailable("session ended")),
+        }
+    }
+
+    async fn exec(
+        &self,
+        request: Request<proto::ExecRequest>,
+    ) -> Result<Response<proto::ExecResult>, Status> {
+        let source = source(&request);
+        let proto::ExecRequest { command, timeout } = request.into_inner();
+
+        let reply = self
+            .execute(
+                &source,
+                json!({ "type": "exec", "command": command, "timeout": timeout }),
+            )
+            .await?
+            .ok_or_else(|| Status::unavailable("session ended"))?;
+
+        let data = &reply["data"];
+
+        Ok(Response::new(proto::ExecResult {
+            command: string(&data["command"]),
+            output: string(&data["output"]),
+            exit_code: data["exitCode"].as_i64().map(|c| c as i32),
+            duration: data["duration"].as_f64().unwrap_or_default(),
+        }))
+    }
+
+    async fn command(
+        &self,
+        request: Request<proto::CommandRequest>,
+    ) -> Result<Response<proto::CommandReply>, Status> {
+        let source = source(&request);
+        let command = serde_json::from_str(&request.into_inner().json)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let json = match self.execute(&source, command).await? {
+            Some(reply) => reply.to_string(),
+            None => String::new(),
+        };
+
+        Ok(Response::new(proto::CommandReply { json }))
+    }
+
+    type EventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn events(
+        &self,
+        request: Request<proto::EventsRequest>,
+    ) -> Result<Response<Self::EventsStream>, Status> {
+        let proto::EventsRequest {
+            sub,
+            filter,
+            fields,
+        } = request.into_inner();
+
+        let sub = sub.parse().map_err(Status::invalid_argument)?;
+        let filter = filter.parse().map_err(Status::invalid_argument)?;
+        let fields = fields.parse().map_err(Status::invalid_argument)?;
+        let events = self.subscribe(sub, filter, fields).await?;
+
+        Ok(Response::new(Box::pin(events.map(|e| e.map(event)))))
+    }
+}
+
+fn event(json: serde_json::Value) -> proto::Event {
+    let data = &json["data"];
+
+    let payload = match json["type"].as_str() {
+        Some("init") => Some(Payload::Init(proto::Init {
+            cols: uint(&data["cols"]),
+            rows: uint(&data["rows"]),
+            pid: data["pid"].as_i64().unwrap_or_default() as i32,
+            seq: string(&data["seq"]),
+            text: string(&data["text"]),
+        })),
+
+        Some("output") => Some(Payload::Output(proto::Output {
+            seq: string(&data["seq"]),
+            raw: data["raw"]
+                .as_str()
+                .and_then(|r| BASE64_STANDARD.decode(r).ok()),
+            id: data["id"].as_u64().unwrap_or_default(),
+        })),
+
+        Some("resize") => Some(Payload::Resize(proto::Resize {
+            cols: uint(&data["cols"]),
+            rows: uint(&data["rows"]),
+        })),
+
+        Some("snapshot") => Some(Payload::Snapshot(snapshot(data))),
+
+        _ => None,
+    };
+
+    proto::Event {
+        r#type: string(&json["type"]),
+        data: data.to_string(),
+        session: json
+            .get("session")
+            .map(|s| s.to_string())
+            .unwrap_or_default(),
+        payload,
+    }
+}
+
+fn snapshot(data: &serde_json::Value) -> proto::Snapshot {
+    let cursor = &data["cursor"];
+
+    proto::Snapshot {
+        cols: uint(&data["cols"]),
+        rows: uint(&data["rows"]),
+        text: string(&data["text"]),
+        seq: string(&data["seq"]),
+        cursor: Some(proto::Cursor {
+            col: uint(&cursor["col"]),
+            row: uint(&cursor["row"]),
+            visible: cursor["visible"].as_bool().unwrap_or_default(),
+            blink: cursor["blink"].as_bool().unwrap_or_default(),
+            shape: string(&cursor["shape"]),
+        }),
+        cwd: data["cwd"].as_str().map(str::to_owned),
+        output_id: data["outputId"].as_u64().unwrap_or_default(),
+    }
+}
+
+fn source<T>(request: &Request<T>) -> String {
+    match request.remote_addr() {
+        Some(addr) => format!("grpc {addr}"),
+        None => "grpc".to_owned(),
+    }
+}
+
+fn uint(value: &serde_json::Value) -> u32 {
+    value.as_u64().unwrap_or_default() as u32
+}
+
+fn string(value: &serde_json::Value) -> String {
+    value.as_str().unwrap_or_default().to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::proto::{self, event::Payload, terminal_client::TerminalClient};
+    use crate::command::Command;
+    use crate::session::{self, Session};
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use std::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn serve_commands_and_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (command_tx, mut command_rx) = mpsc::channel(1);
+        let (clients_tx, mut clients_rx) = mpsc::channel::<session::Client>(1);
+        tokio::spawn(super::start(listener, command_tx, clients_tx).unwrap());
+
+        tokio::spawn(async move {
+            let session = Session::new(20, 2, 0);
+
+            loop {
+                tokio::select! {
+                    Some(client) = clients_rx.recv() => client.accept(session.subscribe()),
+
+                    Some((command, reply)) = command_rx.recv() => match command {
+                        Command::GetLine(0) => {
+                            let _ = reply.send(json!({ "type": "line", "data": { "text": "$" } }));
+                        }
+
+                        Command::GetLine(_) => {
+                            let _ = reply.send(json!({ "type": "error", "data": { "message": "no such line" } }));
+                        }
+
+                        Command::Snapshot(format, full, trim) => {
+                            session.snapshot(format, full, trim)
+                        }
+
+                        _ => (),
+                    },
+                }
+            }
+        });
+
+        let mut client = TerminalClient::connect(url).await.unwrap();
+
+        let reply = client
+            .command(proto::CommandRequest {
+                json: r#"{ "type": "getLine", "row": 0 }"#.to_owned(),
+            })
+            .await
+            .unwrap();
+
+        let reply: serde_json::Value = serde_json::from_str(&reply.into_inner().json).unwrap();
+        assert_eq!(reply["data"]["text"], "$");
+
+        let status = client
+            .command(proto::CommandRequest {
+                json: r#"{ "type": "getLine", "row": 5 }"#.to_owned(),
+            })
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let status = client
+            .command(proto::CommandRequest {
+                json: r#"{ "type": "nope" }"#.to_owned(),
+            })
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        client
+            .input(proto::InputRequest {
+                payload: "ls\r".to_owned(),
+                secret: false,
+            })
+            .await
+            .unwrap();
+
+        let snapshot = client
+            .take_snapshot(proto::SnapshotRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!((snapshot.cols, snapshot.rows), (20, 2));
+
+        let mut events = client
+            .events(proto::EventsRequest {
+                sub: "init".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.r#type, "init");
+        assert!(matches!(
+            event.payload,
+            Some(Payload::Init(proto::Init {
+                cols: 20,
+                rows: 2,
+                ..
+            }))
+        ));
+    }
+}
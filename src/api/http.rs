@@ -1,4 +1,7 @@
-use super::Subscription;
+use super::queue::{Backlog, Item, Policy, Queue};
+use super::{fields::Fields, filter::Filter, stdio, Subscription};
+use crate::command::{Command, Reply, ViewRequest};
+use crate::identity;
 use crate::session;
 use anyhow::Result;
 use axum::{
@@ -8,35 +11,64 @@ use axum::{
     routing::get,
     Router,
 };
-use futures_util::{sink, stream, StreamExt};
+use futures_util::stream::SplitStream;
+use futures_util::{stream, Sink, SinkExt, StreamExt};
 use rust_embed::RustEmbed;
 use serde::Deserialize;
 use serde_json::json;
 use std::borrow::Cow;
-use std::future::{self, Future, IntoFuture};
+use std::future::{Future, IntoFuture};
 use std::io;
 use std::net::{SocketAddr, TcpListener};
-use tokio::sync::mpsc;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 
 #[derive(RustEmbed)]
 #[folder = "assets/"]
 struct Assets;
 
+#[derive(Clone)]
+struct AppState {
+    clients_tx: mpsc::Sender<session::Client>,
+    command_tx: Option<mpsc::Sender<(Command, Reply)>>,
+    views_tx: mpsc::Sender<(ViewRequest, Reply)>,
+    recording: Option<PathBuf>,
+}
+
+/// Starts the HTTP server.
+///
+/// When `command_tx` is given, `/ws/events` clients can also send commands,
+/// using the same JSON format as the STDIO API. `/ws/alis` clients move their
+/// viewport with `views_tx`. When `recording` is given, the recording file is
+/// served at `/recording.cast`.
 pub async fn start(
     listener: TcpListener,
     clients_tx: mpsc::Sender<session::Client>,
+    command_tx: Option<mpsc::Sender<(Command, Reply)>>,
+    views_tx: mpsc::Sender<(ViewRequest, Reply)>,
+    recording: Option<PathBuf>,
 ) -> Result<impl Future<Output = io::Result<()>>> {
     listener.set_nonblocking(true)?;
     let listener = tokio::net::TcpListener::from_std(listener)?;
-    let addr = listener.local_addr().unwrap();
-    eprintln!("HTTP server listening on {addr}");
-    eprintln!("live preview available at http://{addr}");
+
+    let state = AppState {
+        clients_tx,
+        command_tx,
+        views_tx,
+        recording,
+    };
 
     let app: Router<()> = Router::new()
         .route("/ws/alis", get(alis_handler))
         .route("/ws/events", get(event_stream_handler))
-        .with_state(clients_tx)
+        .route("/schema", get(schema_handler))
+        .route("/recording.cast", get(recording_handler))
+        .with_state(state)
         .fallback(static_handler);
 
     Ok(axum::serve(
@@ -50,36 +82,357 @@ pub async fn start(
 ///
 /// This endpoint implements ALiS (asciinema live stream) protocol (https://docs.asciinema.org/manual/alis/).
 /// It allows pointing asciinema player directly to ht to get a real-time terminal preview.
+/// The client can scroll its viewport over the scrollback by sending `scrollUp`, `scrollDown`,
+/// `scrollTo` and `follow` commands.
 async fn alis_handler(
     ws: ws::WebSocketUpgrade,
+    Query(params): Query<AlisParams>,
     ConnectInfo(_addr): ConnectInfo<SocketAddr>,
-    State(clients_tx): State<mpsc::Sender<session::Client>>,
+    State(state): State<AppState>,
 ) -> impl IntoResponse {
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_alis_socket(socket, clients_tx).await;
+        let _ = handle_alis_socket(socket, state, params.backpressure).await;
     })
 }
 
+#[derive(Debug, Deserialize)]
+struct AlisParams {
+    #[serde(default)]
+    backpressure: Backpressure,
+}
+
 async fn handle_alis_socket(
     socket: ws::WebSocket,
-    clients_tx: mpsc::Sender<session::Client>,
+    state: AppState,
+    backpressure: Backpressure,
 ) -> Result<()> {
-    let (sink, stream) = socket.split();
-    let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
+    let (mut sink, stream) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    let views_tx = state.views_tx;
+    let reader = tokio::spawn(read_view_requests(
+        stream,
+        views_tx.clone(),
+        reply_tx.clone(),
+    ));
+    let mut events = ClientQueue::new(state.clients_tx, backpressure).await?;
+    // time of the latest event, for showing the viewport
+    let mut time = 0.0;
+    // whether the viewport is scrolled back, holding the live screen
+    let mut scrolled = false;
+    // whether the player's screen is stale, waiting for a fresh init event
+    let mut resyncing = false;
+
+    let result = loop {
+        let message = tokio::select! {
+            item = events.take() => {
+                let event = match item {
+                    Ok(Some(Item::Item(Delivery::Event(event)))) => event,
+
+                    Ok(Some(Item::Item(Delivery::Resync(_, init)))) => {
+                        resyncing = false;
+                        init
+                    }
+
+                    Ok(Some(Item::Dropped(_))) => {
+                        resyncing = true;
+                        events.resync();
+                        continue;
+                    }
+
+                    Ok(None) => break send(&mut sink, close_message(), backpressure).await,
+                    Err(e) => break Err(e),
+                };
+
+                if let Some(t) = alis_time(&event) {
+                    time = t;
+                }
 
-    let result = session::stream(&clients_tx)
-        .await?
-        .filter_map(alis_message)
-        .chain(stream::once(future::ready(Ok(close_message()))))
-        .forward(sink)
-        .await;
+                if scrolled || resyncing {
+                    continue;
+                }
 
-    drainer.abort();
+                match alis_message(Ok(event)).await {
+                    Some(message) => message,
+                    None => continue,
+                }
+            }
+
+            Some(reply) = reply_rx.recv() => {
+                match reply["type"].as_str() {
+                    // the viewport moved, get its lines
+                    Some("viewport") => {
+                        let _ = views_tx.send((ViewRequest::GetView, reply_tx.clone())).await;
+                        continue;
+                    }
+
+                    Some("view") if reply["data"]["follow"] == true => {
+                        if !scrolled {
+                            continue;
+                        }
+
+                        // a fresh init event resets the player to the live screen
+                        scrolled = false;
+                        resyncing = true;
+                        events.resync();
+                        continue;
+                    }
+
+                    Some("view") => {
+                        scrolled = true;
+                        Ok(view_message(time, &reply["data"]))
+                    }
+
+                    _ => continue,
+                }
+            }
+        };
+
+        if let Err(e) = send(&mut sink, message?, backpressure).await {
+            break Err(e);
+        }
+    };
+
+    reader.abort();
     result?;
 
     Ok(())
 }
 
+/// Reads the preview's requests for moving its viewport, given as the JSON
+/// of `scrollUp`, `scrollDown`, `scrollTo` and `follow` commands. Other
+/// messages are ignored.
+async fn read_view_requests(
+    mut stream: SplitStream<ws::WebSocket>,
+    views_tx: mpsc::Sender<(ViewRequest, Reply)>,
+    reply_tx: Reply,
+) -> Result<()> {
+    while let Some(message) = stream.next().await {
+        let ws::Message::Text(line) = message? else {
+            continue;
+        };
+
+        let request = match stdio::parse_line(&line) {
+            Ok(Command::Scroll(scroll)) => ViewRequest::Scroll(scroll),
+            Ok(Command::Follow(enabled)) => ViewRequest::Follow(enabled),
+            _ => continue,
+        };
+
+        views_tx.send((request, reply_tx.clone())).await?;
+    }
+
+    Ok(())
+}
+
+/// How long a message may take to send to a client with `disconnect`
+/// backpressure.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of events queued for a WebSocket client.
+const QUEUE_SIZE: usize = 1024;
+
+/// How a WebSocket connection deals with a client which doesn't keep up with
+/// the events. Events are queued for every client (up to `QUEUE_SIZE`), so a
+/// slow client never holds up the session or other clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backpressure {
+    /// Wait for the client, disconnecting it only when it falls behind by
+    /// more than the buffer
+    #[default]
+    Block,
+    /// Skip the events the client missed, resyncing its screen
+    Drop,
+    /// Disconnect the client as soon as it falls behind, or a message takes
+    /// longer than `SEND_TIMEOUT` to send
+    Disconnect,
+}
+
+/// What's delivered to a WebSocket client.
+enum Delivery {
+    Event(session::Event),
+    /// Number of missed events, and the `init` event with the current screen
+    Resync(u64, session::Event),
+}
+
+impl Backpressure {
+    fn policy(self) -> Policy {
+        match self {
+            Backpressure::Drop => Policy::DropOldest,
+            Backpressure::Block | Backpressure::Disconnect => Policy::Disconnect,
+        }
+    }
+}
+
+/// Session events for a WebSocket client. With `drop` backpressure, a client
+/// which fell behind is resubscribed, which gets it a fresh `init` event (a
+/// keyframe) to resync from.
+struct ClientEvents {
+    clients_tx: mpsc::Sender<session::Client>,
+    backpressure: Backpressure,
+    stream: stream::BoxStream<'static, Result<session::Event, BroadcastStreamRecvError>>,
+    // whether the next event is the init event of a fresh subscription
+    resync: bool,
+    backlog: Backlog,
+}
+
+impl ClientEvents {
+    async fn new(
+        clients_tx: mpsc::Sender<session::Client>,
+        backpressure: Backpressure,
+    ) -> Result<Self> {
+        let (stream, backlog) = session::queued_stream(&clients_tx).await?;
+
+        Ok(Self {
+            clients_tx,
+            backpressure,
+            stream: stream.boxed(),
+            resync: false,
+            backlog,
+        })
+    }
+
+    async fn next(&mut self) -> Result<Option<Delivery>, axum::Error> {
+        match self.stream.next().await {
+            Some(Ok(init)) if std::mem::take(&mut self.resync) => {
+                Ok(Some(Delivery::Resync(0, init)))
+            }
+
+            Some(Ok(event)) => Ok(Some(Delivery::Event(event))),
+
+            Some(Err(BroadcastStreamRecvError::Lagged(count)))
+                if self.backpressure == Backpressure::Drop =>
+            {
+                self.resubscribe().await?;
+                self.resync = false;
+
+                match self.stream.next().await {
+                    Some(Ok(init)) => Ok(Some(Delivery::Resync(count, init))),
+                    _ => Ok(None),
+                }
+            }
+
+            Some(Err(e)) => Err(axum::Error::new(e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Subscribes again, so that the next event is a fresh `init` event,
+    /// delivered as a resync.
+    async fn resubscribe(&mut self) -> Result<(), axum::Error> {
+        self.stream = session::stream(&self.clients_tx)
+            .await
+            .map_err(axum::Error::new)?
+            .boxed();
+
+        self.resync = true;
+
+        Ok(())
+    }
+}
+
+/// Session events of a WebSocket client, queued up for it by a task of their
+/// own, shedding them according to the client's backpressure.
+struct ClientQueue {
+    queue: Arc<Queue<Delivery>>,
+    resync: Arc<Notify>,
+    producer: JoinHandle<bool>,
+}
+
+impl ClientQueue {
+    async fn new(
+        clients_tx: mpsc::Sender<session::Client>,
+        backpressure: Backpressure,
+    ) -> Result<Self> {
+        let events = ClientEvents::new(clients_tx, backpressure).await?;
+        let queue = Queue::new(QUEUE_SIZE, backpressure.policy());
+        let queue = Arc::new(queue.with_backlog(events.backlog.clone()));
+        let resync = Arc::new(Notify::new());
+        let producer = tokio::spawn(enqueue(events, queue.clone(), resync.clone()));
+
+        Ok(Self {
+            queue,
+            resync,
+            producer,
+        })
+    }
+
+    /// Takes the next delivery, or the number of events dropped from the
+    /// queue. Returns nothing once the session ended, or an error when the
+    /// client was disconnected for falling behind.
+    async fn take(&mut self) -> Result<Option<Item<Delivery>>, axum::Error> {
+        match self.queue.take().await {
+            Some(item) => Ok(Some(item)),
+            None if (&mut self.producer).await.unwrap_or(false) => Ok(None),
+            None => Err(axum::Error::new("client fell behind")),
+        }
+    }
+
+    /// Asks for a fresh `init` event, delivered as a resync.
+    fn resync(&self) {
+        self.resync.notify_one();
+    }
+}
+
+impl Drop for ClientQueue {
+    fn drop(&mut self) {
+        self.producer.abort();
+    }
+}
+
+/// Queues the client's events until the session ends, returning true, or
+/// until the client is disconnected.
+async fn enqueue(
+    mut events: ClientEvents,
+    queue: Arc<Queue<Delivery>>,
+    resync: Arc<Notify>,
+) -> bool {
+    loop {
+        let delivery = tokio::select! {
+            delivery = events.next() => delivery,
+
+            _ = resync.notified() => match events.resubscribe().await {
+                Ok(()) => continue,
+                Err(e) => Err(e),
+            },
+        };
+
+        match delivery {
+            Ok(Some(delivery)) => {
+                if !queue.push(delivery) {
+                    return false;
+                }
+            }
+
+            Ok(None) => {
+                queue.close();
+                return true;
+            }
+
+            Err(_) => {
+                queue.disconnect();
+                return false;
+            }
+        }
+    }
+}
+
+async fn send<S>(
+    sink: &mut S,
+    message: ws::Message,
+    backpressure: Backpressure,
+) -> Result<(), axum::Error>
+where
+    S: Sink<ws::Message, Error = axum::Error> + Unpin,
+{
+    match backpressure {
+        Backpressure::Disconnect => time::timeout(SEND_TIMEOUT, sink.send(message))
+            .await
+            .map_err(axum::Error::new)?,
+
+        Backpressure::Block | Backpressure::Drop => sink.send(message).await,
+    }
+}
+
 async fn alis_message(
     event: Result<session::Event, BroadcastStreamRecvError>,
 ) -> Option<Result<ws::Message, axum::Error>> {
@@ -93,7 +446,7 @@ async fn alis_message(
             "init": seq,
         })))),
 
-        Ok(Output(time, data)) => Some(Ok(json_message(json!([time, "o", data])))),
+        Ok(Output(time, _, data, _)) => Some(Ok(json_message(json!([time, "o", data.as_str()])))),
 
         Ok(Resize(time, cols, rows)) => Some(Ok(json_message(json!([
             time,
@@ -101,70 +454,329 @@ async fn alis_message(
             format!("{cols}x{rows}")
         ])))),
 
-        Ok(Snapshot(_, _, _, _)) => None,
+        Ok(Snapshot { .. }) => None,
+
+        Ok(Heartbeat(_, _, _)) => None,
+
+        Ok(Timeout(_, _)) => None,
+
+        Ok(Truncated(_, _, _)) => None,
+
+        Ok(Stderr(_, _, _)) => None,
+
+        Ok(Annotation(_, _)) => None,
+
+        Ok(RecordingPaused(_, _)) => None,
+
+        Ok(InvalidUtf8(_, _, _, _)) => None,
+
+        Ok(Warning(_, _)) => None,
+
+        Ok(ProcessState(_, _)) => None,
+
+        Ok(ForegroundChanged(_, _, _)) => None,
+
+        Ok(CwdChanged(_, _)) => None,
+
+        Ok(CursorStyle(_, _)) => None,
+
+        Ok(ModeChanged(_, _, _)) => None,
+
+        Ok(PromptStart(_)) => None,
+
+        Ok(CommandStart(_)) => None,
+
+        Ok(CommandEnd(_, _)) => None,
+
+        Ok(CommandExecuted(_, _, _, _)) => None,
+
+        Ok(ScreenHash(_, _)) => None,
+        Ok(PasteProgress(_, _, _)) => None,
 
         Err(e) => Some(Err(axum::Error::new(e))),
     }
 }
 
+/// Returns the time of the events forwarded to ALiS clients.
+fn alis_time(event: &session::Event) -> Option<f64> {
+    use session::Event::*;
+
+    match event {
+        Init(time, ..) | Output(time, ..) | Resize(time, ..) => Some(*time),
+        _ => None,
+    }
+}
+
+/// Shows the lines of a viewport scrolled back over the scrollback, as an
+/// `init` message resetting the player's terminal to them.
+fn view_message(time: f64, view: &serde_json::Value) -> ws::Message {
+    let lines: Vec<&str> = view["lines"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|line| line.as_str())
+        .collect();
+
+    json_message(json!({
+        "time": time,
+        "cols": view["cols"],
+        "rows": view["rows"],
+        "init": format!("\x1b[?25l{}", lines.join("\r\n")),
+    }))
+}
+
 #[derive(Debug, Deserialize)]
 struct EventsParams {
     sub: Option<String>,
+    filter: Option<String>,
+    fields: Option<String>,
+    #[serde(rename = "readOnly", default)]
+    read_only: bool,
+    #[serde(default)]
+    backpressure: Backpressure,
+}
+
+impl EventsParams {
+    /// Invalid values fall back to defaults.
+    fn parse(self) -> (Subscription, Filter, Fields) {
+        let sub = self.sub.unwrap_or_default().parse().unwrap_or_default();
+        let filter = self.filter.unwrap_or_default().parse().unwrap_or_default();
+        let fields = self.fields.unwrap_or_default().parse().unwrap_or_default();
+
+        (sub, filter, fields)
+    }
+}
+
+/// Parses the event selection from `sub`, `filter` and `fields` query params
+/// of the URI, as accepted by `/ws/events`.
+pub fn events_params(uri: &Uri) -> (Subscription, Filter, Fields) {
+    match Query::<EventsParams>::try_from_uri(uri) {
+        Ok(Query(params)) => params.parse(),
+        Err(_) => Default::default(),
+    }
+}
+
+/// Describes the protocol with JSON Schema.
+async fn schema_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        super::schema::schema().to_string(),
+    )
 }
 
 /// Event stream handler
 ///
 /// This endpoint allows the client to subscribe to selected events and have them delivered as they occur.
 /// Query param `sub` should be set to a comma-separated list desired of events.
+/// Optional query param `filter` holds conditions that the events must meet.
+/// Optional query param `fields` selects the fields of event data to deliver.
+/// Query param `readOnly=true` makes the server ignore commands sent by the client.
+/// Query param `backpressure` sets how a client which doesn't keep up is dealt with.
 /// See above for a list of supported events.
 async fn event_stream_handler(
     ws: ws::WebSocketUpgrade,
     Query(params): Query<EventsParams>,
-    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
-    State(clients_tx): State<mpsc::Sender<session::Client>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(mut state): State<AppState>,
 ) -> impl IntoResponse {
-    let sub: Subscription = params.sub.unwrap_or_default().parse().unwrap_or_default();
+    if params.read_only {
+        state.command_tx = None;
+    }
+
+    let backpressure = params.backpressure;
+    let (sub, filter, fields) = params.parse();
 
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_event_stream_socket(socket, clients_tx, sub).await;
+        let _ = handle_event_stream_socket(socket, state, addr, sub, filter, fields, backpressure)
+            .await;
     })
 }
 
 async fn handle_event_stream_socket(
     socket: ws::WebSocket,
-    clients_tx: mpsc::Sender<session::Client>,
+    state: AppState,
+    addr: SocketAddr,
     sub: Subscription,
+    filter: Filter,
+    fields: Fields,
+    backpressure: Backpressure,
 ) -> Result<()> {
-    let (sink, stream) = socket.split();
-    let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
+    let (mut sink, stream) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    let source = format!("ws {addr}");
+    let mut events = ClientQueue::new(state.clients_tx, backpressure).await?;
+    let reader = tokio::spawn(read_commands(stream, state.command_tx, reply_tx, source));
+    // events missed since the client's screen was last in sync
+    let mut missed = 0;
+
+    let result = loop {
+        tokio::select! {
+            item = events.take() => {
+                let messages = match item {
+                    // the client's screen is stale until the resync
+                    Ok(Some(Item::Item(Delivery::Event(_)))) if missed > 0 => continue,
+
+                    Ok(Some(Item::Item(Delivery::Event(event)))) => {
+                        match event_stream_message(Ok(event), sub, &filter, &fields) {
+                            Some(Ok(message)) => vec![message],
+                            Some(Err(e)) => break Err(e),
+                            None => continue,
+                        }
+                    }
+
+                    Ok(Some(Item::Item(Delivery::Resync(count, init)))) => {
+                        let count = std::mem::take(&mut missed) + count;
+                        // the screen is sent even if init events aren't subscribed to
+                        let resync = Subscription { init: true, ..sub };
+                        let mut messages = Vec::new();
+
+                        if count > 0 {
+                            messages.extend(dropped_json(count, sub, &filter, &fields).map(json_message));
+                        }
+
+                        messages.extend(event_stream_message(Ok(init), resync, &filter, &fields).and_then(Result::ok));
+                        messages
+                    }
+
+                    Ok(Some(Item::Dropped(count))) => {
+                        missed += count;
+                        events.resync();
+                        continue;
+                    }
 
-    let result = session::stream(&clients_tx)
-        .await?
-        .filter_map(move |e| event_stream_message(e, sub))
-        .chain(stream::once(future::ready(Ok(close_message()))))
-        .forward(sink)
-        .await;
+                    Ok(None) => break send(&mut sink, close_message(), backpressure).await,
+                    Err(e) => break Err(e),
+                };
 
-    drainer.abort();
+                let mut result = Ok(());
+
+                for message in messages {
+                    result = send(&mut sink, message, backpressure).await;
+
+                    if result.is_err() {
+                        break;
+                    }
+                }
+
+                if let Err(e) = result {
+                    break Err(e);
+                }
+            }
+
+            Some(reply) = reply_rx.recv() => {
+                if let Err(e) = send(&mut sink, json_message(reply), backpressure).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    reader.abort();
     result?;
 
     Ok(())
 }
 
-async fn event_stream_message(
+async fn read_commands(
+    mut stream: SplitStream<ws::WebSocket>,
+    command_tx: Option<mpsc::Sender<(Command, Reply)>>,
+    reply_tx: Reply,
+    source: String,
+) -> Result<()> {
+    while let Some(message) = stream.next().await {
+        let (ws::Message::Text(line), Some(command_tx)) = (message?, &command_tx) else {
+            continue;
+        };
+
+        match stdio::parse_audited_line(&line, &source) {
+            Ok(command) => super::submit(command_tx, command, reply_tx.clone()).await?,
+            Err(e) => eprintln!("command parse error: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn event_stream_message(
     event: Result<session::Event, BroadcastStreamRecvError>,
     sub: Subscription,
+    filter: &Filter,
+    fields: &Fields,
 ) -> Option<Result<ws::Message, axum::Error>> {
+    event_json(event, sub, filter, fields).map(|e| e.map(json_message).map_err(axum::Error::new))
+}
+
+/// Returns the JSON of the event if it's subscribed to and meets the filter,
+/// with only the selected fields.
+pub fn event_json(
+    event: Result<session::Event, BroadcastStreamRecvError>,
+    sub: Subscription,
+    filter: &Filter,
+    fields: &Fields,
+) -> Option<Result<serde_json::Value, BroadcastStreamRecvError>> {
     use session::Event::*;
 
-    match event {
-        Ok(e @ Init(_, _, _, _, _, _)) if sub.init => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Output(_, _)) if sub.output => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Resize(_, _, _)) if sub.resize => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Snapshot(_, _, _, _)) if sub.snapshot => Some(Ok(json_message(e.to_json()))),
-        Ok(_) => None,
-        Err(e) => Some(Err(axum::Error::new(e))),
+    let event = match event {
+        Ok(e @ Init(_, _, _, _, _, _)) if sub.init => e,
+        Ok(e @ Output(_, _, _, _)) if sub.output => e,
+        Ok(e @ Resize(_, _, _)) if sub.resize => e,
+        Ok(e @ Snapshot { .. }) if sub.snapshot => e,
+        Ok(e @ Heartbeat(_, _, _)) if sub.heartbeat => e,
+        Ok(e @ Timeout(_, _)) if sub.timeout => e,
+        Ok(e @ Truncated(_, _, _)) if sub.truncated => e,
+        Ok(e @ Stderr(_, _, _)) if sub.stderr => e,
+        Ok(e @ Annotation(_, _)) if sub.annotation => e,
+        Ok(e @ InvalidUtf8(_, _, _, _)) if sub.invalid_utf8 => e,
+        Ok(e @ Warning(_, _)) if sub.warning => e,
+        Ok(e @ ProcessState(_, _)) if sub.process_state => e,
+        Ok(e @ ForegroundChanged(_, _, _)) if sub.foreground_changed => e,
+        Ok(e @ CwdChanged(_, _)) if sub.cwd_changed => e,
+        Ok(e @ CursorStyle(_, _)) if sub.cursor_style => e,
+        Ok(e @ ModeChanged(_, _, _)) if sub.mode_changed => e,
+        Ok(e @ PromptStart(_)) if sub.prompt_start => e,
+        Ok(e @ CommandStart(_)) if sub.command_start => e,
+        Ok(e @ CommandEnd(_, _)) if sub.command_end => e,
+        Ok(e @ CommandExecuted(_, _, _, _)) if sub.command_executed => e,
+        Ok(e @ ScreenHash(_, _)) if sub.screen_hash => e,
+        Ok(e @ PasteProgress(_, _, _)) if sub.paste_progress => e,
+        Ok(e @ RecordingPaused(_, _)) if sub.recording_paused => e,
+        Ok(_) => return None,
+        Err(e) => return Some(Err(e)),
+    };
+
+    select(event.to_json(), filter, fields).map(Ok)
+}
+
+/// Returns the `dropped` event, telling the client how many events it missed,
+/// if it's subscribed to and meets the filter, with only the selected fields.
+pub fn dropped_json(
+    count: u64,
+    sub: Subscription,
+    filter: &Filter,
+    fields: &Fields,
+) -> Option<serde_json::Value> {
+    if !sub.dropped {
+        return None;
+    }
+
+    select(
+        json!({ "type": "dropped", "data": { "count": count } }),
+        filter,
+        fields,
+    )
+}
+
+fn select(json: serde_json::Value, filter: &Filter, fields: &Fields) -> Option<serde_json::Value> {
+    let mut json = json;
+    identity::stamp(&mut json);
+
+    if !filter.matches(&json) {
+        return None;
     }
+
+    fields.apply(&mut json);
+
+    Some(json)
 }
 
 fn json_message(value: serde_json::Value) -> ws::Message {
@@ -178,6 +790,28 @@ fn close_message() -> ws::Message {
     }))
 }
 
+/// Serves the recording made with `--record`, as far as it's been written:
+/// the last line is left out when its write is still in progress.
+async fn recording_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let Some(path) = &state.recording else {
+        return (StatusCode::NOT_FOUND, "not recording").into_response();
+    };
+
+    match tokio::fs::read(path).await {
+        Ok(mut content) => {
+            let len = content
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            content.truncate(len);
+
+            ([(header::CONTENT_TYPE, "application/x-asciicast")], content).into_response()
+        }
+
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
 async fn static_handler(uri: Uri) -> impl IntoResponse {
     let mut path = uri.path().trim_start_matches('/');
 
@@ -195,3 +829,183 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
         None => (StatusCode::NOT_FOUND, "404").into_response(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        event_json, send, view_message, Backpressure, ClientEvents, ClientQueue, Delivery,
+        SEND_TIMEOUT,
+    };
+    use crate::api::queue::Item;
+    use crate::api::Subscription;
+    use crate::session::{self, Session};
+    use axum::extract::ws;
+    use futures_util::{future, sink, SinkExt};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    /// Session accepting clients in the background.
+    fn session() -> (Arc<Mutex<Session>>, mpsc::Sender<session::Client>) {
+        let session = Arc::new(Mutex::new(Session::new(20, 2, 0)));
+        let (clients_tx, mut clients_rx) = mpsc::channel::<session::Client>(1);
+        let s = session.clone();
+
+        tokio::spawn(async move {
+            while let Some(client) = clients_rx.recv().await {
+                client.accept(s.lock().unwrap().subscribe());
+            }
+        });
+
+        (session, clients_tx)
+    }
+
+    /// Subscribes a client, then makes it fall 100 events behind.
+    async fn lagging_client(backpressure: Backpressure) -> (Arc<Mutex<Session>>, ClientEvents) {
+        let (session, clients_tx) = session();
+        let mut events = ClientEvents::new(clients_tx, backpressure).await.unwrap();
+
+        assert!(matches!(
+            events.next().await,
+            Ok(Some(Delivery::Event(session::Event::Init(..))))
+        ));
+
+        for _ in 0..1124 {
+            session.lock().unwrap().output("x");
+        }
+
+        (session, events)
+    }
+
+    #[tokio::test]
+    async fn lagging_client_blocked() {
+        let (_session, mut events) = lagging_client(Backpressure::Block).await;
+
+        assert!(events.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lagging_client_dropped() {
+        let (session, mut events) = lagging_client(Backpressure::Drop).await;
+
+        match events.next().await {
+            Ok(Some(Delivery::Resync(count, session::Event::Init(.., text)))) => {
+                assert_eq!(count, 100);
+                assert!(text.starts_with(&"x".repeat(20)));
+            }
+
+            _ => panic!("expected a resync"),
+        }
+
+        // the client keeps up from there
+        session.lock().unwrap().output("y");
+
+        assert!(matches!(
+            events.next().await,
+            Ok(Some(Delivery::Event(session::Event::Output(..))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lagging_client_disconnected() {
+        let (_session, mut events) = lagging_client(Backpressure::Disconnect).await;
+
+        assert!(events.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_client_resync() {
+        let (session, clients_tx) = session();
+        let mut events = ClientQueue::new(clients_tx, Backpressure::Drop)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            events.take().await,
+            Ok(Some(Item::Item(Delivery::Event(session::Event::Init(..)))))
+        ));
+
+        session.lock().unwrap().output("x");
+
+        assert!(matches!(
+            events.take().await,
+            Ok(Some(Item::Item(Delivery::Event(session::Event::Output(
+                ..
+            )))))
+        ));
+
+        events.resync();
+
+        match events.take().await {
+            Ok(Some(Item::Item(Delivery::Resync(0, session::Event::Init(.., text))))) => {
+                assert!(text.starts_with('x'));
+            }
+
+            _ => panic!("expected a resync"),
+        }
+    }
+
+    #[test]
+    fn recording_paused() {
+        let mut session = Session::new(20, 2, 0);
+        let mut events = session.subscribe().into_receiver();
+        session.pause_recording(true);
+        session.pause_recording(false);
+
+        let sub: Subscription = "recordingPaused".parse().unwrap();
+        let (filter, fields) = Default::default();
+
+        let paused: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|e| event_json(Ok(e), sub, &filter, &fields))
+            .map(|json| json.unwrap()["data"]["paused"].clone())
+            .collect();
+
+        assert_eq!(paused, vec![json!(true), json!(false)]);
+
+        // only delivered when subscribed to
+        session.pause_recording(true);
+        let event = events.try_recv().unwrap();
+        let sub = "output".parse().unwrap();
+        assert!(event_json(Ok(event), sub, &filter, &fields).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_send() {
+        let message = || ws::Message::Text("{}".to_owned());
+
+        let mut stuck = sink::unfold((), |_, _: ws::Message| {
+            future::pending::<Result<(), axum::Error>>()
+        });
+
+        let block = send(&mut stuck, message(), Backpressure::Block);
+        assert!(time::timeout(SEND_TIMEOUT * 2, block).await.is_err());
+
+        let disconnect = send(&mut stuck, message(), Backpressure::Disconnect);
+        assert!(time::timeout(SEND_TIMEOUT * 2, disconnect)
+            .await
+            .unwrap()
+            .is_err());
+
+        let mut drain = sink::drain().sink_map_err(axum::Error::new);
+        assert!(send(&mut drain, message(), Backpressure::Disconnect)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn view() {
+        let view = json!({ "cols": 3, "rows": 2, "lines": ["ab ", "c  "], "follow": false });
+
+        let ws::Message::Text(message) = view_message(1.5, &view) else {
+            panic!("expected a text message");
+        };
+
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+
+        assert_eq!(
+            message,
+            json!({ "time": 1.5, "cols": 3, "rows": 2, "init": "\x1b[?25lab \r\nc  " })
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use super::queue::{self, EventQueue};
+use super::{http, stdio};
+use crate::command::{Command, Reply};
+use crate::identity;
+use crate::session;
+use anyhow::Result;
+use futures_util::stream::SplitStream;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::{http::Uri, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub(super) const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub(super) const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Dials out to a hub, delivering events to it and accepting commands from it.
+///
+/// The events are selected with `sub`, `filter` and `fields` query params of
+/// the URL, same as for `/ws/events` endpoint. The connection is re-established
+/// (with a growing delay) when it drops, until the session ends.
+pub async fn start(
+    url: String,
+    id: String,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let uri: Uri = url.parse()?;
+    let (sub, filter, fields) = http::events_params(&uri);
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        match connect(&url, &id, sub, &filter, &fields, &command_tx, &clients_tx).await {
+            Ok(true) => return Ok(()),
+
+            Ok(false) => {
+                eprintln!("hub connection closed");
+                delay = MIN_RECONNECT_DELAY;
+            }
+
+            Err(e) => eprintln!("hub connection failed: {e}"),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Runs a single connection. Returns true when the session ended.
+async fn connect(
+    url: &str,
+    id: &str,
+    sub: super::Subscription,
+    filter: &super::filter::Filter,
+    fields: &super::fields::Fields,
+    command_tx: &mpsc::Sender<(Command, Reply)>,
+    clients_tx: &mpsc::Sender<session::Client>,
+) -> Result<bool> {
+    let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+    eprintln!("connected to hub {url}");
+    let (mut sink, stream) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<serde_json::Value>();
+
+    let mut register = json!({
+        "type": "register",
+        "data": { "id": id, "version": env!("CARGO_PKG_VERSION") }
+    });
+
+    if let Some(identity) = identity::get() {
+        register["data"]["name"] = identity["name"].clone();
+        register["data"]["labels"] = identity["labels"].clone();
+    }
+
+    sink.send(Message::Text(register.to_string())).await?;
+
+    // the reader is aborted when the set is dropped, i.e. on return
+    let mut reader = JoinSet::new();
+    reader.spawn(read_commands(stream, command_tx.clone(), reply_tx));
+
+    let queue = (queue::DEFAULT_SIZE, queue::Policy::default());
+    let mut events =
+        EventQueue::subscribe(clients_tx, sub, filter.clone(), fields.clone(), queue).await?;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(event) => {
+                        sink.send(Message::Text(event.to_string())).await?;
+                    }
+
+                    None => {
+                        sink.send(Message::Close(None)).await?;
+                        return Ok(true);
+                    }
+                }
+            }
+
+            Some(reply) = reply_rx.recv() => {
+                sink.send(Message::Text(reply.to_string())).await?;
+            }
+
+            Some(result) = reader.join_next() => {
+                result??;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Sends the commands received from the hub, in order. Returns when the hub
+/// closes the connection.
+async fn read_commands(
+    mut stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    reply_tx: Reply,
+) -> Result<()> {
+    while let Some(message) = stream.next().await {
+        match message? {
+            Message::Text(line) => match stdio::parse_audited_line(&line, "hub") {
+                Ok(command) => super::submit(&command_tx, command, reply_tx.clone()).await?,
+                Err(e) => eprintln!("command parse error: {e}"),
+            },
+
+            Message::Close(_) => break,
+
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::Command;
+    use crate::session::{self, Session};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    async fn register_and_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/?sub=init", listener.local_addr().unwrap());
+        let (command_tx, mut command_rx) = mpsc::channel(1);
+        let (clients_tx, mut clients_rx) = mpsc::channel::<session::Client>(1);
+
+        tokio::spawn(async move {
+            let session = Session::new(20, 2, 0);
+
+            while let Some(client) = clients_rx.recv().await {
+                client.accept(session.subscribe());
+            }
+        });
+
+        tokio::spawn(super::start(url, "abc".to_owned(), command_tx, clients_tx));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut hub = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+        let mut messages = (&mut hub).map(|m| {
+            let text = m.unwrap().into_text().unwrap();
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        });
+
+        let register = messages.next().await.unwrap();
+        assert_eq!(register["type"], "register");
+        assert_eq!(register["data"]["id"], "abc");
+        assert_eq!(messages.next().await.unwrap()["type"], "init");
+
+        hub.send(Message::Text(r#"{ "type": "dumpState" }"#.to_owned()))
+            .await
+            .unwrap();
+
+        let (command, _) = command_rx.recv().await.unwrap();
+        assert!(matches!(command, Command::DumpState));
+    }
+}
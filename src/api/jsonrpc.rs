@@ -0,0 +1,341 @@
+use super::queue::{EventQueue, Policy};
+use super::{fields::Fields, filter::Filter, stdio, Subscription};
+use crate::audit;
+use crate::command::{Command, Reply};
+use crate::session;
+use anyhow::Result;
+use serde_json::json;
+use std::thread;
+use tokio::sync::mpsc;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Command rejected by the session (first of the implementation-defined codes)
+const COMMAND_FAILED: i64 = -32000;
+
+/// Outcome of submitting a single request.
+enum Call {
+    /// Response known up front (an error)
+    Done(serde_json::Value),
+    /// Command submitted, its reply (if any) will come over the channel
+    Pending(
+        serde_json::Value,
+        mpsc::UnboundedReceiver<serde_json::Value>,
+    ),
+    /// Notification, i.e. request without an id, which gets no response
+    Notification,
+}
+
+/// JSON-RPC 2.0 flavor of the STDIO API.
+///
+/// Requests use command types as methods and command args as (named) params.
+/// Events are delivered as notifications, with event type as method and event
+/// data as params, queued like in the plain protocol.
+pub async fn start(
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+    sub: Subscription,
+    filter: Filter,
+    fields: Fields,
+    queue: (usize, Policy),
+) -> Result<()> {
+    let (input_tx, mut input_rx) = mpsc::unbounded_channel::<String>();
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+    thread::spawn(|| stdio::read_stdin(input_tx));
+
+    // requests are handled in order, and `sleep` delays the ones that follow.
+    // The session keeps running while command_tx is held here, even after all
+    // requests are sent.
+    let requests_tx = command_tx.clone();
+
+    let mut requests = tokio::spawn(async move {
+        while let Some(line) = input_rx.recv().await {
+            handle_line(&line, &requests_tx, &response_tx).await?;
+        }
+
+        anyhow::Ok(())
+    });
+
+    let mut events = EventQueue::subscribe(&clients_tx, sub, filter, fields, queue).await?;
+    let mut subscribed = true;
+
+    loop {
+        tokio::select! {
+            result = &mut requests => {
+                result??;
+                eprintln!("stdin closed, shutting down...");
+                break;
+            }
+
+            Some(response) = response_rx.recv() => {
+                println!("{response}");
+            }
+
+            event = events.next(), if subscribed => {
+                match event {
+                    Some(event) => {
+                        println!("{}", notification(event));
+                    }
+
+                    None => {
+                        if !events.disconnected().await {
+                            break;
+                        }
+
+                        // requests are still accepted
+                        eprintln!("events disconnected: STDOUT didn't keep up with them");
+                        subscribed = false;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Submits the request (or batch of requests) in the line, and spawns a task
+/// delivering the response(s) once the session replies.
+async fn handle_line(
+    line: &str,
+    command_tx: &mpsc::Sender<(Command, Reply)>,
+    response_tx: &mpsc::UnboundedSender<serde_json::Value>,
+) -> Result<()> {
+    let requests = match serde_json::from_str(line) {
+        Ok(serde_json::Value::Array(requests)) if !requests.is_empty() => requests,
+
+        Ok(serde_json::Value::Array(_)) => {
+            audit::record("stdio", &json!([]));
+            let _ = response_tx.send(error(json!(null), INVALID_REQUEST, "empty batch"));
+            return Ok(());
+        }
+
+        Ok(request) => {
+            let call = submit(request, command_tx).await?;
+            let response_tx = response_tx.clone();
+
+            tokio::spawn(async move {
+                if let Some(response) = response(call).await {
+                    let _ = response_tx.send(response);
+                }
+            });
+
+            return Ok(());
+        }
+
+        Err(e) => {
+            audit::record_invalid("stdio", line, &e);
+            let _ = response_tx.send(error(json!(null), PARSE_ERROR, &e.to_string()));
+            return Ok(());
+        }
+    };
+
+    let mut calls = Vec::new();
+
+    for request in requests {
+        calls.push(submit(request, command_tx).await?);
+    }
+
+    let response_tx = response_tx.clone();
+
+    tokio::spawn(async move {
+        let mut responses = Vec::new();
+
+        for call in calls {
+            responses.extend(response(call).await);
+        }
+
+        // a batch of notifications gets no response at all
+        if !responses.is_empty() {
+            let _ = response_tx.send(json!(responses));
+        }
+    });
+
+    Ok(())
+}
+
+/// Validates the request and sends its command to the session.
+async fn submit(
+    request: serde_json::Value,
+    command_tx: &mpsc::Sender<(Command, Reply)>,
+) -> Result<Call> {
+    audit::record("stdio", &request);
+
+    let id = match request.get("id") {
+        None => None,
+        Some(id @ (serde_json::Value::String(_) | serde_json::Value::Number(_))) => Some(id),
+        Some(serde_json::Value::Null) => Some(&serde_json::Value::Null),
+        Some(_) => {
+            let response = error(json!(null), INVALID_REQUEST, "invalid id");
+            return Ok(Call::Done(response));
+        }
+    };
+
+    let method = match (&request["jsonrpc"], &request["method"]) {
+        (serde_json::Value::String(v), serde_json::Value::String(m)) if v == "2.0" => m,
+        _ => {
+            let id = id.cloned().unwrap_or_default();
+            return Ok(Call::Done(error(id, INVALID_REQUEST, "invalid request")));
+        }
+    };
+
+    let mut args = match request.get("params") {
+        None => json!({}),
+        Some(params @ serde_json::Value::Object(_)) => params.clone(),
+        Some(_) => json!(null),
+    };
+
+    let command = if args.is_object() {
+        args["type"] = json!(method);
+
+        stdio::build_command(args).map_err(|e| {
+            let code = if e.starts_with("invalid command type") {
+                METHOD_NOT_FOUND
+            } else {
+                INVALID_PARAMS
+            };
+
+            (code, e)
+        })
+    } else {
+        Err((INVALID_PARAMS, "params must be an object".to_owned()))
+    };
+
+    match (command, id) {
+        (Ok(command), Some(id)) => {
+            let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+            super::submit(command_tx, command, reply_tx).await?;
+
+            Ok(Call::Pending(id.clone(), reply_rx))
+        }
+
+        (Ok(command), None) => {
+            let (reply_tx, _) = mpsc::unbounded_channel();
+            super::submit(command_tx, command, reply_tx).await?;
+
+            Ok(Call::Notification)
+        }
+
+        (Err((code, message)), Some(id)) => Ok(Call::Done(error(id.clone(), code, &message))),
+
+        (Err((_, message)), None) => {
+            eprintln!("command parse error: {message}");
+
+            Ok(Call::Notification)
+        }
+    }
+}
+
+async fn response(call: Call) -> Option<serde_json::Value> {
+    match call {
+        Call::Done(response) => Some(response),
+
+        // the session drops the reply channel when done with the command
+        Call::Pending(id, mut reply_rx) => match reply_rx.recv().await {
+            Some(reply) if reply["type"] == "error" => {
+                let message = reply["data"]["message"].as_str().unwrap_or_default();
+
+                Some(error(id, COMMAND_FAILED, message))
+            }
+
+            Some(reply) => Some(result(id, reply["data"].clone())),
+
+            None => Some(result(id, json!(null))),
+        },
+
+        Call::Notification => None,
+    }
+}
+
+fn result(id: serde_json::Value, result: serde_json::Value) -> serde_json::Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error(id: serde_json::Value, code: i64, message: &str) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message }
+    })
+}
+
+fn notification(event: serde_json::Value) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": event["type"],
+        "params": event["data"]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_line, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR};
+    use crate::command::Command;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn handle_requests() {
+        let (command_tx, mut command_rx) = mpsc::channel(8);
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+
+        let line = r#"{ "jsonrpc": "2.0", "id": 1, "method": "getLine", "params": { "row": 2 } }"#;
+        handle_line(line, &command_tx, &response_tx).await.unwrap();
+        let (command, reply) = command_rx.recv().await.unwrap();
+        assert!(matches!(command, Command::GetLine(2)));
+        reply
+            .send(json!({ "type": "line", "data": { "text": "$" } }))
+            .unwrap();
+
+        assert_eq!(
+            response_rx.recv().await.unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": { "text": "$" } })
+        );
+
+        let line =
+            r#"{ "jsonrpc": "2.0", "id": "a", "method": "signal", "params": { "signal": "INT" } }"#;
+        handle_line(line, &command_tx, &response_tx).await.unwrap();
+        let (_, reply) = command_rx.recv().await.unwrap();
+        reply
+            .send(json!({ "type": "error", "data": { "message": "no child" } }))
+            .unwrap();
+        let response = response_rx.recv().await.unwrap();
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["error"]["code"], -32000);
+        assert_eq!(response["error"]["message"], "no child");
+
+        let line = r#"[
+            { "jsonrpc": "2.0", "method": "input", "params": { "payload": "ls" } },
+            { "jsonrpc": "2.0", "id": 2, "method": "resize", "params": { "cols": 80, "rows": 24 } },
+            { "jsonrpc": "2.0", "id": 3, "method": "nope" },
+            { "jsonrpc": "2.0", "id": 4, "method": "getLine" },
+            { "jsonrpc": "1.0", "id": 5, "method": "dumpState" }
+        ]"#;
+
+        handle_line(&line.replace('\n', ""), &command_tx, &response_tx)
+            .await
+            .unwrap();
+
+        let (command, _) = command_rx.recv().await.unwrap();
+        assert!(matches!(command, Command::Input(..)));
+        let (command, _) = command_rx.recv().await.unwrap();
+        assert!(matches!(command, Command::Resize(80, 24, 0, 0)));
+        let responses = response_rx.recv().await.unwrap();
+
+        assert_eq!(
+            responses[0],
+            json!({ "jsonrpc": "2.0", "id": 2, "result": null })
+        );
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[2]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses[3]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(responses.as_array().unwrap().len(), 4);
+
+        handle_line("{", &command_tx, &response_tx).await.unwrap();
+        let response = response_rx.recv().await.unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        assert_eq!(response["id"], json!(null));
+    }
+}
@@ -0,0 +1,332 @@
+use super::hub::{MAX_RECONNECT_DELAY, MIN_RECONNECT_DELAY};
+use super::queue::{self, EventQueue};
+use super::{fields::Fields, filter::Filter, http, Subscription};
+use crate::session;
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+const DEFAULT_PREFIX: &str = "ht";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Broker {
+    Nats,
+    Redis,
+}
+
+/// Where (and as whom) the events get published.
+#[derive(Debug, PartialEq)]
+struct Target {
+    broker: Broker,
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    /// NATS subject or Redis stream key
+    key: String,
+}
+
+/// Publishes the event stream to a NATS subject (`nats://`) or a Redis stream
+/// (`redis://`), keyed by the session ID.
+///
+/// The events are selected with `sub`, `filter` and `fields` query params of
+/// the URL, same as for `/ws/events` endpoint. The connection is re-established
+/// (with a growing delay) when it drops, until the session ends.
+pub async fn start(
+    url: String,
+    id: String,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let uri: Uri = url.parse()?;
+    let target = Target::parse(&uri, &id)?;
+    let (sub, filter, fields) = http::events_params(&uri);
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        match publish(&target, sub, &filter, &fields, &clients_tx).await {
+            Ok(true) => return Ok(()),
+
+            Ok(false) => {
+                eprintln!("{} connection closed", target.broker.name());
+                delay = MIN_RECONNECT_DELAY;
+            }
+
+            Err(e) => eprintln!("publishing to {} failed: {e}", target.broker.name()),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Runs a single connection. Returns true when the session ended.
+async fn publish(
+    target: &Target,
+    sub: Subscription,
+    filter: &Filter,
+    fields: &Fields,
+    clients_tx: &mpsc::Sender<session::Client>,
+) -> Result<bool> {
+    let (reader, mut writer) = TcpStream::connect((target.host.as_str(), target.port))
+        .await?
+        .into_split();
+
+    let mut lines = BufReader::new(reader).lines();
+    target.handshake(&mut lines, &mut writer).await?;
+    eprintln!("publishing events to {}", target.key);
+
+    let queue = (queue::DEFAULT_SIZE, queue::Policy::default());
+    let mut events =
+        EventQueue::subscribe(clients_tx, sub, filter.clone(), fields.clone(), queue).await?;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(event) => {
+                        writer.write_all(&target.message(&event.to_string())).await?;
+                    }
+
+                    None => {
+                        writer.shutdown().await?;
+                        return Ok(true);
+                    }
+                }
+            }
+
+            line = lines.next_line() => {
+                match line? {
+                    Some(line) if line.starts_with('-') => {
+                        eprintln!("{} error: {}", target.broker.name(), &line[1..]);
+                    }
+
+                    Some(line) if target.broker == Broker::Nats && line == "PING" => {
+                        writer.write_all(b"PONG\r\n").await?;
+                    }
+
+                    Some(_) => (),
+
+                    None => return Ok(false),
+                }
+            }
+        }
+    }
+}
+
+impl Broker {
+    fn name(&self) -> &'static str {
+        match self {
+            Broker::Nats => "NATS",
+            Broker::Redis => "Redis",
+        }
+    }
+}
+
+impl Target {
+    /// Parses `nats://[user:password@]host[:port][/prefix]` or
+    /// `redis://[user:password@]host[:port][/prefix]`.
+    fn parse(uri: &Uri, id: &str) -> Result<Self> {
+        let (broker, port) = match uri.scheme_str() {
+            Some("nats") => (Broker::Nats, 4222),
+            Some("redis") => (Broker::Redis, 6379),
+            _ => bail!("expected nats:// or redis:// URL"),
+        };
+
+        let authority = uri.authority().context("missing host")?;
+
+        let (user, password) = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => match userinfo.split_once(':') {
+                Some((user, password)) => (user, Some(password.to_owned())),
+                None => (userinfo, None),
+            },
+
+            None => ("", None),
+        };
+
+        let prefix = uri.path().trim_matches('/');
+        let prefix = if prefix.is_empty() {
+            DEFAULT_PREFIX
+        } else {
+            prefix
+        };
+
+        let key = match broker {
+            Broker::Nats => format!("{}.{id}", prefix.replace('/', ".")),
+            Broker::Redis => format!("{prefix}:{id}"),
+        };
+
+        Ok(Self {
+            broker,
+            host: authority.host().to_owned(),
+            port: authority.port_u16().unwrap_or(port),
+            user: Some(user.to_owned()).filter(|u| !u.is_empty()),
+            password,
+            key,
+        })
+    }
+
+    async fn handshake(
+        &self,
+        lines: &mut tokio::io::Lines<BufReader<OwnedReadHalf>>,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        match self.broker {
+            Broker::Nats => {
+                let info = lines.next_line().await?.unwrap_or_default();
+
+                if !info.starts_with("INFO ") {
+                    bail!("unexpected NATS greeting: {info}");
+                }
+
+                let connect = json!({
+                    "verbose": false,
+                    "pedantic": false,
+                    "name": "ht",
+                    "lang": "rust",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "user": self.user,
+                    "pass": self.password,
+                });
+
+                // the server answers PING only when it accepted CONNECT
+                writer
+                    .write_all(format!("CONNECT {connect}\r\nPING\r\n").as_bytes())
+                    .await?;
+
+                expect_reply(lines, "PONG").await
+            }
+
+            Broker::Redis => {
+                let Some(password) = &self.password else {
+                    return Ok(());
+                };
+
+                let auth = match &self.user {
+                    Some(user) => resp(&["AUTH", user, password]),
+                    None => resp(&["AUTH", password]),
+                };
+
+                writer.write_all(&auth).await?;
+
+                expect_reply(lines, "+OK").await
+            }
+        }
+    }
+
+    /// Encodes the event as a NATS `PUB` or a Redis `XADD` command.
+    fn message(&self, event: &str) -> Vec<u8> {
+        match self.broker {
+            Broker::Nats => format!("PUB {} {}\r\n{event}\r\n", self.key, event.len()).into_bytes(),
+
+            Broker::Redis => resp(&["XADD", &self.key, "*", "event", event]),
+        }
+    }
+}
+
+async fn expect_reply(
+    lines: &mut tokio::io::Lines<BufReader<OwnedReadHalf>>,
+    expected: &str,
+) -> Result<()> {
+    match lines.next_line().await? {
+        Some(line) if line == expected => Ok(()),
+        Some(line) => bail!("{}", line.trim_start_matches('-')),
+        None => bail!("connection closed"),
+    }
+}
+
+/// Encodes a Redis command in RESP.
+fn resp(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len());
+
+    for arg in args {
+        command.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+
+    command.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resp, Broker, Target};
+    use crate::session::{self, Session};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parse_target() {
+        let target = Target::parse(&"redis://:secret@cache:7000/obs".parse().unwrap(), "abc");
+
+        assert_eq!(
+            target.unwrap(),
+            Target {
+                broker: Broker::Redis,
+                host: "cache".to_owned(),
+                port: 7000,
+                user: None,
+                password: Some("secret".to_owned()),
+                key: "obs:abc".to_owned(),
+            }
+        );
+
+        let target = Target::parse(&"nats://bus?sub=output".parse().unwrap(), "abc").unwrap();
+        assert_eq!((target.port, target.key.as_str()), (4222, "ht.abc"));
+
+        assert!(Target::parse(&"http://bus".parse().unwrap(), "abc").is_err());
+    }
+
+    #[test]
+    fn encode_redis_command() {
+        assert_eq!(
+            resp(&["XADD", "ht:abc", "*", "event", "{}"]),
+            b"*5\r\n$4\r\nXADD\r\n$6\r\nht:abc\r\n$1\r\n*\r\n$5\r\nevent\r\n$2\r\n{}\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_to_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}/term?sub=init", listener.local_addr().unwrap());
+        let (clients_tx, mut clients_rx) = mpsc::channel::<session::Client>(1);
+
+        tokio::spawn(async move {
+            let session = Session::new(20, 2, 0);
+
+            while let Some(client) = clients_rx.recv().await {
+                client.accept(session.subscribe());
+            }
+        });
+
+        tokio::spawn(super::start(url, "abc".to_owned(), clients_tx));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        writer.write_all(b"INFO {}\r\n").await.unwrap();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("CONNECT {"));
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PING\r\n");
+        writer.write_all(b"PONG\r\n").await.unwrap();
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        let (subject, len) = line
+            .trim_end()
+            .strip_prefix("PUB ")
+            .unwrap()
+            .split_once(' ')
+            .unwrap();
+        assert_eq!(subject, "term.abc");
+
+        let mut payload = vec![0; len.parse::<usize>().unwrap() + 2];
+        reader.read_exact(&mut payload).await.unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(event["type"], "init");
+    }
+}
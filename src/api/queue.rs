@@ -0,0 +1,414 @@
+use super::{fields::Fields, filter::Filter, http, Subscription};
+use crate::session;
+use anyhow::Result;
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+/// Number of events queued for a client by default.
+pub const DEFAULT_SIZE: usize = 1024;
+
+/// What a full queue does with a new item.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Policy {
+    /// Drop the oldest queued item to make room
+    #[default]
+    DropOldest,
+    /// Drop the new item
+    DropNewest,
+    /// Close the queue, disconnecting the consumer
+    Disconnect,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Policy::DropOldest),
+            "drop-newest" => Ok(Policy::DropNewest),
+            "disconnect" => Ok(Policy::Disconnect),
+            _ => Err(format!("invalid queue policy: {s}")),
+        }
+    }
+}
+
+/// Count of the items waiting in queues sharing it, including the item each
+/// consumer took last and is still handling, so the producer can tell when
+/// they all got through (see `Session::drain`).
+#[derive(Debug, Clone, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    pub fn is_empty(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+
+    fn add(&self, count: usize) {
+        self.0.fetch_add(count, Ordering::SeqCst);
+    }
+
+    fn remove(&self, count: usize) {
+        self.0.fetch_sub(count, Ordering::SeqCst);
+    }
+}
+
+/// What a consumer takes from the queue.
+#[derive(Debug, PartialEq)]
+pub enum Item<T> {
+    Item(T),
+    /// Number of items dropped since the previous take
+    Dropped(u64),
+}
+
+/// Bounded queue between a producer and a slow consumer, e.g. an event sink,
+/// shedding items according to the policy when full, so the consumer's lag
+/// neither grows memory nor holds up the producer.
+pub struct Queue<T> {
+    capacity: usize,
+    policy: Policy,
+    state: Mutex<State<T>>,
+    notify: Notify,
+    backlog: Backlog,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    dropped: u64,
+    closed: bool,
+    disconnected: bool,
+    // whether the consumer is still handling the item it took last
+    handling: bool,
+}
+
+impl<T> Queue<T> {
+    pub fn new(capacity: usize, policy: Policy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+                disconnected: false,
+                handling: false,
+            }),
+            notify: Notify::new(),
+            backlog: Backlog::default(),
+        }
+    }
+
+    /// Counts the queued items in the backlog.
+    pub fn with_backlog(mut self, backlog: Backlog) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Adds the item, returning false when the queue is closed.
+    pub fn push(&self, item: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed || state.disconnected {
+            return false;
+        }
+
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                Policy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                    self.backlog.remove(1);
+                }
+
+                Policy::DropNewest => {
+                    state.dropped += 1;
+                    return true;
+                }
+
+                Policy::Disconnect => {
+                    self.clear(&mut state);
+                    self.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        self.backlog.add(1);
+        self.notify.notify_one();
+
+        true
+    }
+
+    /// Records items lost before reaching the queue, e.g. when the producer
+    /// itself lagged. Closes the queue with the disconnect policy.
+    pub fn lost(&self, count: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if self.policy == Policy::Disconnect {
+            self.clear(&mut state);
+        } else {
+            state.dropped += count;
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Closes the queue. The consumer still gets the queued items.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Closes the queue, dropping the queued items, as the disconnect policy
+    /// does.
+    pub fn disconnect(&self) {
+        self.clear(&mut self.state.lock().unwrap());
+        self.notify.notify_one();
+    }
+
+    fn clear(&self, state: &mut State<T>) {
+        state.disconnected = true;
+        self.backlog.remove(state.items.len());
+        state.items.clear();
+    }
+
+    /// Takes the next item, waiting for one. The number of items dropped
+    /// since the previous take, if any, is taken first. Returns nothing once
+    /// the queue is closed and drained, or right away when it was
+    /// disconnected.
+    pub async fn take(&self) -> Option<Item<T>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if std::mem::take(&mut state.handling) {
+                    self.backlog.remove(1);
+                }
+
+                if state.disconnected {
+                    return None;
+                }
+
+                if state.dropped > 0 {
+                    return Some(Item::Dropped(std::mem::take(&mut state.dropped)));
+                }
+
+                if let Some(item) = state.items.pop_front() {
+                    state.handling = true;
+                    return Some(Item::Item(item));
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        self.backlog
+            .remove(state.items.len() + usize::from(state.handling));
+    }
+}
+
+/// Subscribed events of a client, as JSON, waiting for delivery in the
+/// client's own queue.
+pub struct EventQueue {
+    queue: Arc<Queue<serde_json::Value>>,
+    producer: JoinHandle<bool>,
+    sub: Subscription,
+    filter: Filter,
+    fields: Fields,
+}
+
+impl EventQueue {
+    /// Subscribes to the session's events, queuing up to `size` of them, and
+    /// shedding them with the policy when the client doesn't keep up.
+    pub async fn subscribe(
+        clients_tx: &mpsc::Sender<session::Client>,
+        sub: Subscription,
+        filter: Filter,
+        fields: Fields,
+        (size, policy): (usize, Policy),
+    ) -> Result<Self> {
+        let (events, backlog) = session::queued_stream(clients_tx).await?;
+        let queue = Arc::new(Queue::new(size, policy).with_backlog(backlog));
+        let producer = tokio::spawn(enqueue(
+            events,
+            sub,
+            filter.clone(),
+            fields.clone(),
+            queue.clone(),
+        ));
+
+        Ok(Self {
+            queue,
+            producer,
+            sub,
+            filter,
+            fields,
+        })
+    }
+
+    /// Takes the next event, or a `dropped` event telling how many were shed,
+    /// if it's subscribed to. Returns nothing once the session ended, or the
+    /// queue was closed by the disconnect policy (see `disconnected`).
+    pub async fn next(&mut self) -> Option<serde_json::Value> {
+        loop {
+            match self.queue.take().await? {
+                Item::Item(json) => return Some(json),
+
+                Item::Dropped(count) => {
+                    let dropped = http::dropped_json(count, self.sub, &self.filter, &self.fields);
+
+                    if dropped.is_some() {
+                        return dropped;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells whether the client was disconnected for not keeping up, once
+    /// `next` returned nothing.
+    pub async fn disconnected(&mut self) -> bool {
+        matches!((&mut self.producer).await, Ok(false))
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        self.producer.abort();
+    }
+}
+
+/// Queues the subscribed events until the session ends, or until the queue
+/// is closed, returning false in the latter case.
+async fn enqueue(
+    mut events: impl Stream<Item = Result<session::Event, BroadcastStreamRecvError>> + Unpin,
+    sub: Subscription,
+    filter: Filter,
+    fields: Fields,
+    queue: Arc<Queue<serde_json::Value>>,
+) -> bool {
+    while let Some(event) = events.next().await {
+        let json = match http::event_json(event, sub, &filter, &fields) {
+            Some(Ok(json)) => json,
+
+            Some(Err(BroadcastStreamRecvError::Lagged(count))) => {
+                queue.lost(count);
+                continue;
+            }
+
+            None => continue,
+        };
+
+        if !queue.push(json) {
+            return false;
+        }
+    }
+
+    queue.close();
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventQueue, Item, Policy, Queue, DEFAULT_SIZE};
+    use crate::session::Session;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    async fn drain(queue: &Queue<u32>) -> Vec<Item<u32>> {
+        queue.close();
+        let mut items = Vec::new();
+
+        while let Some(item) = queue.take().await {
+            items.push(item);
+        }
+
+        items
+    }
+
+    #[tokio::test]
+    async fn shed_items() {
+        let queue = Queue::new(2, Policy::DropOldest);
+        (1..=4).for_each(|i| assert!(queue.push(i)));
+
+        assert_eq!(
+            drain(&queue).await,
+            vec![Item::Dropped(2), Item::Item(3), Item::Item(4)]
+        );
+
+        let queue = Queue::new(2, Policy::DropNewest);
+        (1..=4).for_each(|i| assert!(queue.push(i)));
+        queue.lost(1);
+
+        assert_eq!(
+            drain(&queue).await,
+            vec![Item::Dropped(3), Item::Item(1), Item::Item(2)]
+        );
+
+        let queue = Queue::new(2, Policy::Disconnect);
+        assert!(queue.push(1) && queue.push(2));
+        assert_eq!(drain(&queue).await, vec![Item::Item(1), Item::Item(2)]);
+
+        let queue = Queue::new(2, Policy::Disconnect);
+        assert!(queue.push(1) && queue.push(2));
+        assert!(!queue.push(3));
+        assert_eq!(queue.take().await, None);
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_queued_events() {
+        let mut session = Session::new(20, 2, 0);
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+
+        let subscribing = tokio::spawn(async move {
+            let sub = "timeout".parse().unwrap();
+            let queue = (DEFAULT_SIZE, Policy::default());
+            EventQueue::subscribe(
+                &clients_tx,
+                sub,
+                Default::default(),
+                Default::default(),
+                queue,
+            )
+            .await
+            .unwrap()
+        });
+
+        clients_rx.recv().await.unwrap().accept(session.subscribe());
+        let mut events = subscribing.await.unwrap();
+        session.timeout(Duration::from_secs(3));
+
+        // the event leaves the broadcast channel for the client's queue right
+        // away, but the client takes a while to handle it
+        let (timeout_tx, mut timeout_rx) = oneshot::channel();
+
+        let client = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = timeout_tx.send(events.next().await);
+            events.next().await
+        });
+
+        session.drain(Duration::from_secs(5)).await;
+
+        let timeout = timeout_rx.try_recv().unwrap().unwrap();
+        assert_eq!(timeout["type"], "timeout");
+
+        client.abort();
+    }
+}
@@ -0,0 +1,371 @@
+use serde_json::{json, Map, Value};
+
+/// Returns a JSON Schema describing all commands and events of the protocol,
+/// as implemented by this version of ht.
+///
+/// `$defs` has a schema for each command (`<type>Command`) and each event
+/// (`<type>Event`), and `command` and `event` match any of them.
+pub fn schema() -> Value {
+    let mut defs = Map::new();
+
+    for (name, properties, required) in commands() {
+        defs.insert(
+            format!("{name}Command"),
+            command(name, properties, required),
+        );
+    }
+
+    for (name, data) in events() {
+        defs.insert(format!("{name}Event"), event(name, data));
+    }
+
+    let refs = |suffix: &str| -> Vec<Value> {
+        defs.keys()
+            .filter(|k| k.ends_with(suffix))
+            .map(|k| json!({ "$ref": format!("#/$defs/{k}") }))
+            .collect()
+    };
+
+    let command = json!({ "oneOf": refs("Command") });
+    let event = json!({ "oneOf": refs("Event") });
+    defs.insert("command".to_owned(), command);
+    defs.insert("event".to_owned(), event);
+    defs.insert("cursor".to_owned(), cursor());
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "ht protocol",
+        "version": env!("CARGO_PKG_VERSION"),
+        "$defs": defs,
+    })
+}
+
+type CommandSchema = (&'static str, Value, &'static [&'static str]);
+
+fn commands() -> Vec<CommandSchema> {
+    let boolean = json!({ "type": "boolean" });
+    let string = json!({ "type": "string" });
+    let index = json!({ "type": "integer", "minimum": 0 });
+    let pixels = json!({ "type": "integer", "minimum": 0, "maximum": 65535 });
+
+    let position = json!({
+        "type": "object",
+        "properties": { "row": index, "col": index },
+        "required": ["row", "col"],
+    });
+
+    vec![
+        (
+            "input",
+            json!({ "payload": string, "secret": boolean }),
+            &["payload"],
+        ),
+        ("inputRaw", json!({ "base64": string, "hex": string }), &[]),
+        ("paste", json!({ "payload": string }), &["payload"]),
+        ("flush", json!({}), &[]),
+        ("sync", json!({}), &[]),
+        (
+            "sendKeys",
+            json!({ "keys": { "type": "array", "items": string }, "secret": boolean }),
+            &["keys"],
+        ),
+        (
+            "mouse",
+            json!({
+                "event": { "enum": ["press", "release", "drag", "click"] },
+                "button": { "enum": ["left", "middle", "right", "wheel_up", "wheel_down"] },
+                "row": { "type": "integer", "minimum": 1 },
+                "col": { "type": "integer", "minimum": 1 },
+                "shift": boolean,
+                "alt": boolean,
+                "control": boolean,
+            }),
+            &["event", "button", "row", "col"],
+        ),
+        (
+            "focus",
+            json!({ "event": { "enum": ["gained", "lost"] } }),
+            &["event"],
+        ),
+        (
+            "resize",
+            json!({ "cols": index, "rows": index, "xpixel": pixels, "ypixel": pixels }),
+            &["cols", "rows"],
+        ),
+        (
+            "takeSnapshot",
+            json!({
+                "format": { "enum": ["ansi", "markdown"] },
+                "full": boolean,
+                "cursor": boolean,
+                "trim": boolean,
+                "collapseBlank": boolean,
+            }),
+            &[],
+        ),
+        (
+            "snapshotAt",
+            json!({ "time": { "type": "number" }, "outputId": index }),
+            &[],
+        ),
+        ("getOutput", json!({ "from": index, "to": index }), &[]),
+        ("getLine", json!({ "row": index }), &["row"]),
+        ("getColumns", json!({ "row": index }), &["row"]),
+        (
+            "getView",
+            json!({ "trim": boolean, "collapseBlank": boolean, "offset": index }),
+            &[],
+        ),
+        ("scrollUp", json!({ "lines": index }), &[]),
+        ("scrollDown", json!({ "lines": index }), &[]),
+        ("scrollTo", json!({ "offset": index }), &["offset"]),
+        ("follow", json!({ "enabled": boolean }), &[]),
+        (
+            "getText",
+            json!({ "x": index, "y": index, "w": index, "h": index }),
+            &[],
+        ),
+        (
+            "getWord",
+            json!({ "row": index, "col": index, "separators": string }),
+            &["row", "col"],
+        ),
+        (
+            "setSelection",
+            json!({
+                "anchor": position,
+                "extent": position,
+                "mode": { "enum": ["normal", "line", "block"] },
+            }),
+            &[],
+        ),
+        ("getSelection", json!({}), &[]),
+        ("setClipboard", json!({ "payload": string }), &["payload"]),
+        ("dumpState", json!({}), &[]),
+        ("getProcesses", json!({}), &[]),
+        ("capabilities", json!({}), &[]),
+        ("schema", json!({}), &[]),
+        ("close", json!({}), &[]),
+        ("reset", json!({ "redraw": boolean }), &[]),
+        ("annotate", json!({ "payload": {} }), &["payload"]),
+        ("recordPause", json!({}), &[]),
+        ("recordResume", json!({}), &[]),
+        ("macroRecord", json!({ "name": string }), &["name"]),
+        ("macroStop", json!({}), &[]),
+        (
+            "macroPlay",
+            json!({ "name": string, "timing": boolean }),
+            &["name"],
+        ),
+        ("suspend", json!({}), &[]),
+        ("resume", json!({}), &[]),
+        (
+            "exec",
+            json!({
+                "command": string,
+                "timeout": { "type": "number", "exclusiveMinimum": 0 },
+            }),
+            &["command"],
+        ),
+        (
+            "waitForStable",
+            json!({
+                "quietMs": { "type": "integer", "minimum": 0, "default": 500 },
+                "timeoutMs": { "type": "integer", "minimum": 0, "default": 5000 },
+            }),
+            &[],
+        ),
+        (
+            "signal",
+            json!({
+                "signal": { "type": ["string", "integer"] },
+                "target": { "enum": ["group", "child"], "default": "group" },
+            }),
+            &["signal"],
+        ),
+        ("clearScrollback", json!({ "clearScreen": boolean }), &[]),
+        ("sleep", json!({ "ms": index }), &["ms"]),
+    ]
+}
+
+fn events() -> Vec<(&'static str, Value)> {
+    let time = json!({ "type": "number" });
+    let string = json!({ "type": "string" });
+    let nullable_string = json!({ "type": ["string", "null"] });
+    let count = json!({ "type": "integer", "minimum": 0 });
+    let exit_code = json!({ "type": ["integer", "null"] });
+    let base64 = json!({ "type": "string", "contentEncoding": "base64" });
+    let modes = json!({ "type": "object", "additionalProperties": { "type": "boolean" } });
+
+    vec![
+        (
+            "init",
+            json!({
+                "cols": count,
+                "rows": count,
+                "pid": { "type": "integer" },
+                "seq": string,
+                "text": string,
+            }),
+        ),
+        (
+            "output",
+            json!({ "seq": string, "raw": base64, "id": count }),
+        ),
+        ("resize", json!({ "cols": count, "rows": count })),
+        (
+            "snapshot",
+            json!({
+                "cols": count,
+                "rows": count,
+                "seq": string,
+                "text": string,
+                "cursor": { "$ref": "#/$defs/cursor" },
+                "cwd": nullable_string,
+                "outputId": count,
+                "ansi": string,
+                "markdown": string,
+                "title": nullable_string,
+                "screen": { "enum": ["primary", "alternate"] },
+                "decModes": modes,
+                "ansiModes": modes,
+                "tabStops": { "type": "array", "items": count },
+            }),
+        ),
+        (
+            "heartbeat",
+            json!({ "time": time, "count": count, "alive": { "type": "boolean" } }),
+        ),
+        ("timeout", json!({ "time": time, "idle": time })),
+        (
+            "truncated",
+            json!({ "time": time, "limit": count, "total": count }),
+        ),
+        ("stderr", json!({ "seq": string, "raw": base64 })),
+        ("annotation", json!({ "time": time, "payload": {} })),
+        (
+            "invalidUtf8",
+            json!({
+                "time": time,
+                "stream": { "enum": ["stdout", "stderr"] },
+                "offset": count,
+                "bytes": base64,
+            }),
+        ),
+        ("warning", json!({ "time": time, "message": string })),
+        (
+            "processState",
+            json!({ "time": time, "state": { "enum": ["running", "suspended"] } }),
+        ),
+        (
+            "foregroundChanged",
+            json!({ "time": time, "pid": { "type": "integer" }, "name": nullable_string }),
+        ),
+        ("cwdChanged", json!({ "time": time, "cwd": string })),
+        (
+            "cursorStyle",
+            json!({
+                "time": time,
+                "shape": { "enum": ["block", "underline", "bar"] },
+                "blink": { "type": "boolean" },
+            }),
+        ),
+        (
+            "modeChanged",
+            json!({
+                "time": time,
+                "mode": count,
+                "name": nullable_string,
+                "enabled": { "type": "boolean" },
+            }),
+        ),
+        ("promptStart", json!({ "time": time })),
+        ("commandStart", json!({ "time": time })),
+        ("commandEnd", json!({ "time": time, "exitCode": exit_code })),
+        (
+            "commandExecuted",
+            json!({
+                "time": time,
+                "command": nullable_string,
+                "duration": time,
+                "exitCode": exit_code,
+            }),
+        ),
+        ("screenHash", json!({ "time": time, "hash": string })),
+        (
+            "pasteProgress",
+            json!({ "time": time, "written": count, "total": count }),
+        ),
+        (
+            "recordingPaused",
+            json!({ "time": time, "paused": { "type": "boolean" } }),
+        ),
+        ("dropped", json!({ "count": count })),
+    ]
+}
+
+fn command(name: &str, properties: Value, required: &[&str]) -> Value {
+    let mut properties = properties;
+    properties["type"] = json!({ "const": name });
+    let required: Vec<&str> = ["type"].iter().chain(required).copied().collect();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn event(name: &str, data: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "type": { "const": name },
+            "data": { "type": "object", "properties": data },
+        },
+        "required": ["type", "data"],
+    })
+}
+
+fn cursor() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "col": { "type": "integer", "minimum": 0 },
+            "row": { "type": "integer", "minimum": 0 },
+            "visible": { "type": "boolean" },
+            "blink": { "type": "boolean" },
+            "shape": { "enum": ["block", "underline", "bar"] },
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::schema;
+    use crate::api::{filter::EVENTS, stdio::COMMANDS};
+
+    #[test]
+    fn covers_protocol() {
+        let schema = schema();
+        let defs = schema["$defs"].as_object().unwrap();
+
+        for name in COMMANDS {
+            let def = &defs[&format!("{name}Command")];
+            assert_eq!(def["properties"]["type"]["const"], name);
+        }
+
+        for name in EVENTS {
+            let def = &defs[&format!("{name}Event")];
+            assert_eq!(def["properties"]["type"]["const"], name);
+        }
+
+        let commands = defs["command"]["oneOf"].as_array().unwrap();
+        assert_eq!(commands.len(), COMMANDS.len());
+        assert_eq!(
+            defs["event"]["oneOf"].as_array().unwrap().len(),
+            EVENTS.len()
+        );
+        assert_eq!(defs["sendKeysCommand"]["required"][1], "keys");
+    }
+}
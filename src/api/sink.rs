@@ -0,0 +1,252 @@
+use super::queue::{self, EventQueue, Policy};
+use super::{fields::Fields, filter::Filter, http, Subscription};
+use crate::session;
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+/// Where the events go.
+#[derive(Debug, PartialEq)]
+enum Target {
+    /// File the events are appended to, one JSON object per line
+    File(PathBuf),
+    /// URL each event is POSTed to
+    Webhook {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+/// Delivers the events to a file (`file:///PATH`) or a webhook
+/// (`http://HOST[:PORT]/PATH`), until the session ends.
+///
+/// Like other consumers, each sink has its own subscription, given with `sub`,
+/// `filter` and `fields` query params of the URL, same as for `/ws/events`
+/// endpoint. The events wait for delivery in the sink's bounded queue, whose
+/// size and what happens when it's full are set with `queue` and `lag` query
+/// params.
+pub async fn start(url: String, clients_tx: mpsc::Sender<session::Client>) -> Result<()> {
+    let (target, query) = Target::parse(&url)?;
+    let (sub, filter, fields, queue) = params(query)?;
+    let mut events = EventQueue::subscribe(&clients_tx, sub, filter, fields, queue).await?;
+
+    let result = match &target {
+        Target::File(path) => write_file(path, &mut events).await,
+        Target::Webhook { host, port, path } => post(host, *port, path, &mut events).await,
+    };
+
+    match &result {
+        Ok(()) => {
+            if events.disconnected().await {
+                eprintln!("event sink {url} disconnected: it didn't keep up with the events");
+            }
+        }
+
+        Err(e) => {
+            eprintln!("event sink {url} failed: {e}");
+        }
+    }
+
+    result
+}
+
+async fn write_file(path: &Path, events: &mut EventQueue) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("cannot open {}", path.display()))?;
+
+    while let Some(json) = events.next().await {
+        file.write_all(format!("{json}\n").as_bytes()).await?;
+    }
+
+    file.flush().await?;
+
+    Ok(())
+}
+
+async fn post(host: &str, port: u16, path: &str, events: &mut EventQueue) -> Result<()> {
+    while let Some(json) = events.next().await {
+        if let Err(e) = post_event(host, port, path, &json.to_string()).await {
+            eprintln!("webhook {host}:{port}{path} failed: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a single event, expecting a 2xx response.
+async fn post_event(host: &str, port: u16, path: &str, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(request.as_bytes()).await?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("unexpected response: {}", status.trim_end()),
+    }
+}
+
+fn params(query: &str) -> Result<(Subscription, Filter, Fields, (usize, Policy))> {
+    let uri: Uri = format!("/?{query}").parse()?;
+    let (sub, filter, fields) = http::events_params(&uri);
+    let param = |name: &str| query.split('&').find_map(|p| p.strip_prefix(name));
+
+    let policy = match param("lag=") {
+        None | Some("drop") => Policy::DropOldest,
+        Some("close") => Policy::Disconnect,
+        Some(lag) => lag.parse().map_err(anyhow::Error::msg)?,
+    };
+
+    let size = match param("queue=") {
+        Some(size) => size.parse().context("invalid queue size")?,
+        None => queue::DEFAULT_SIZE,
+    };
+
+    Ok((sub, filter, fields, (size, policy)))
+}
+
+impl Target {
+    /// Parses the sink URL, returning the target and the query string.
+    fn parse(url: &str) -> Result<(Self, &str)> {
+        let (url, query) = url.split_once('?').unwrap_or((url, ""));
+
+        if let Some(path) = url.strip_prefix("file://") {
+            if !path.starts_with('/') {
+                bail!("expected absolute path in file:// URL");
+            }
+
+            return Ok((Target::File(PathBuf::from(path)), query));
+        }
+
+        let uri: Uri = url.parse()?;
+
+        if uri.scheme_str() != Some("http") {
+            bail!("expected file:// or http:// URL");
+        }
+
+        let authority = uri.authority().context("missing host")?;
+
+        let target = Target::Webhook {
+            host: authority.host().to_owned(),
+            port: authority.port_u16().unwrap_or(80),
+            path: uri.path().to_owned(),
+        };
+
+        Ok((target, query))
+    }
+}
+
+/// Validates the sink URL given on the command line.
+pub fn parse_url(url: &str) -> Result<String> {
+    let (_, query) = Target::parse(url)?;
+    params(query)?;
+
+    Ok(url.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{params, Target};
+    use crate::api::queue::Policy;
+    use crate::session::{self, Session};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn serve_session() -> mpsc::Sender<session::Client> {
+        let (clients_tx, mut clients_rx) = mpsc::channel::<session::Client>(1);
+
+        tokio::spawn(async move {
+            let session = Session::new(20, 2, 0);
+
+            while let Some(client) = clients_rx.recv().await {
+                client.accept(session.subscribe());
+            }
+        });
+
+        clients_tx
+    }
+
+    #[test]
+    fn parse_target() {
+        assert_eq!(
+            Target::parse("file:///tmp/events.jsonl?sub=output").unwrap(),
+            (
+                Target::File(PathBuf::from("/tmp/events.jsonl")),
+                "sub=output"
+            )
+        );
+
+        assert_eq!(
+            Target::parse("http://alerts:8080/hook").unwrap(),
+            (
+                Target::Webhook {
+                    host: "alerts".to_owned(),
+                    port: 8080,
+                    path: "/hook".to_owned(),
+                },
+                ""
+            )
+        );
+
+        assert!(Target::parse("file://events.jsonl").is_err());
+        assert!(Target::parse("https://alerts/hook").is_err());
+    }
+
+    #[test]
+    fn parse_lag_policy() {
+        assert_eq!(params("sub=output").unwrap().3, (1024, Policy::DropOldest));
+        assert_eq!(params("lag=close").unwrap().3, (1024, Policy::Disconnect));
+
+        assert_eq!(
+            params("sub=output&lag=drop-newest&queue=10").unwrap().3,
+            (10, Policy::DropNewest)
+        );
+
+        assert!(params("lag=block").is_err());
+        assert!(params("queue=many").is_err());
+    }
+
+    #[tokio::test]
+    async fn post_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook?sub=init", listener.local_addr().unwrap());
+        tokio::spawn(super::start(url, serve_session()));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+
+        while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        let request = String::from_utf8(request).unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["type"], "init");
+    }
+}
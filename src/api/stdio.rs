@@ -1,27 +1,182 @@
-use super::Subscription;
-use crate::command::{self, Command, InputSeq};
+use super::queue::{EventQueue, Policy};
+use super::{fields::Fields, filter::Filter, Subscription};
+use crate::audit;
+use crate::command::{
+    self, Command, InputSeq, Region, Reply, Scroll, Selection, SelectionMode, Trim,
+};
+use crate::history::Moment;
+use crate::keymap::{self, Binding};
+use crate::render;
 use crate::session;
 use anyhow::Result;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use nix::sys::signal::Signal;
 use serde::{de::DeserializeOwned, Deserialize};
 use std::io;
 use std::thread;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio_stream::StreamExt;
 
 #[derive(Debug, Deserialize)]
 struct InputArgs {
     payload: String,
+    #[serde(default)]
+    secret: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct InputRawArgs {
+    base64: Option<String>,
+    hex: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PasteArgs {
+    payload: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct SendKeysArgs {
     keys: Vec<String>,
+    #[serde(default)]
+    secret: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct ResizeArgs {
     cols: usize,
     rows: usize,
+    #[serde(default)]
+    xpixel: u16,
+    #[serde(default)]
+    ypixel: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotArgs {
+    format: Option<String>,
+    #[serde(default)]
+    full: bool,
+    #[serde(default)]
+    cursor: bool,
+    #[serde(flatten)]
+    trim: TrimArgs,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotAtArgs {
+    time: Option<f64>,
+    output_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetOutputArgs {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetViewArgs {
+    #[serde(flatten)]
+    trim: TrimArgs,
+    offset: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrollArgs {
+    lines: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrollToArgs {
+    offset: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct FollowArgs {
+    #[serde(default = "default_true")]
+    enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrimArgs {
+    #[serde(default)]
+    trim: bool,
+    #[serde(default)]
+    collapse_blank: bool,
+}
+
+impl From<TrimArgs> for Trim {
+    fn from(args: TrimArgs) -> Self {
+        Trim {
+            trailing: args.trim,
+            collapse_blank: args.collapse_blank,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetLineArgs {
+    row: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetTextArgs {
+    #[serde(default)]
+    x: usize,
+    #[serde(default)]
+    y: usize,
+    w: Option<usize>,
+    h: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetWordArgs {
+    row: usize,
+    col: usize,
+    separators: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetSelectionArgs {
+    anchor: Option<PositionArgs>,
+    extent: Option<PositionArgs>,
+    mode: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct PositionArgs {
+    row: usize,
+    col: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetClipboardArgs {
+    payload: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetArgs {
+    #[serde(default)]
+    redraw: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClearScrollbackArgs {
+    #[serde(default)]
+    clear_screen: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnnotateArgs {
+    payload: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,53 +193,104 @@ struct MouseArgs {
     control: bool,
 }
 
+#[derive(Debug, Deserialize)]
+struct FocusArgs {
+    event: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MacroArgs {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MacroPlayArgs {
+    name: String,
+    #[serde(default)]
+    timing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecArgs {
+    command: String,
+    timeout: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WaitForStableArgs {
+    #[serde(default = "default_quiet_ms")]
+    quiet_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_quiet_ms() -> u64 {
+    500
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Debug, Deserialize)]
+struct SleepArgs {
+    ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignalArgs {
+    signal: serde_json::Value,
+    target: Option<String>,
+}
+
+/// Runs the STDIO API. The subscribed events are printed from the queue of
+/// the given size and policy, so a slow reader of STDOUT doesn't hold up the
+/// session.
 pub async fn start(
-    command_tx: mpsc::Sender<Command>,
+    command_tx: mpsc::Sender<(Command, Reply)>,
     clients_tx: mpsc::Sender<session::Client>,
     sub: Subscription,
+    filter: Filter,
+    fields: Fields,
+    queue: (usize, Policy),
 ) -> Result<()> {
-    let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
     thread::spawn(|| read_stdin(input_tx));
-    let mut events = session::stream(&clients_tx).await?;
+    // the session keeps running while command_tx is held here, even after
+    // all commands are sent
+    let mut commands = tokio::spawn(send_commands(input_rx, command_tx.clone(), reply_tx));
+    let mut events = EventQueue::subscribe(&clients_tx, sub, filter, fields, queue).await?;
+    let mut subscribed = true;
 
     loop {
         tokio::select! {
-            line = input_rx.recv() => {
-                match line {
-                    Some(line) => {
-                        match parse_line(&line) {
-                            Ok(command) => command_tx.send(command).await?,
-                            Err(e) => eprintln!("command parse error: {e}"),
-                        }
-                    }
-
-                    None => break
-                }
+            result = &mut commands => {
+                result??;
+                eprintln!("stdin closed, shutting down...");
+                break;
             }
 
-            event = events.next() => {
-                use session::Event::*;
+            Some(reply) = reply_rx.recv() => {
+                println!("{reply}");
+            }
 
+            event = events.next(), if subscribed => {
                 match event {
-                    Some(Ok(e @ Init(_, _, _, _, _, _))) if sub.init => {
-                        println!("{}", e.to_json());
+                    Some(event) => {
+                        println!("{event}");
                     }
 
-                    Some(Ok(e @ Output(_, _))) if sub.output => {
-                        println!("{}", e.to_json());
-                    }
-
-                    Some(Ok(e @ Resize(_, _, _))) if sub.resize => {
-                        println!("{}", e.to_json());
-                    }
+                    None => {
+                        if !events.disconnected().await {
+                            break;
+                        }
 
-                    Some(Ok(e @ Snapshot(_, _, _, _))) if sub.snapshot => {
-                        println!("{}", e.to_json());
+                        // commands are still accepted
+                        eprintln!("events disconnected: STDOUT didn't keep up with them");
+                        subscribed = false;
                     }
-
-                    Some(_) => (),
-
-                    None => break
                 }
             }
         }
@@ -93,7 +299,24 @@ pub async fn start(
     Ok(())
 }
 
-fn read_stdin(input_tx: mpsc::UnboundedSender<String>) -> Result<()> {
+/// Sends the commands read from stdin, in order. Returns once stdin is closed
+/// and all the commands have been sent.
+async fn send_commands(
+    mut input_rx: mpsc::UnboundedReceiver<String>,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    reply_tx: Reply,
+) -> Result<()> {
+    while let Some(line) = input_rx.recv().await {
+        match parse_audited_line(&line, "stdio") {
+            Ok(command) => super::submit(&command_tx, command, reply_tx.clone()).await?,
+            Err(e) => eprintln!("command parse error: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) fn read_stdin(input_tx: mpsc::UnboundedSender<String>) -> Result<()> {
     for line in io::stdin().lines() {
         input_tx.send(line?)?;
     }
@@ -101,23 +324,112 @@ fn read_stdin(input_tx: mpsc::UnboundedSender<String>) -> Result<()> {
     Ok(())
 }
 
-fn parse_line(line: &str) -> Result<command::Command, String> {
+pub fn parse_line(line: &str) -> Result<command::Command, String> {
     serde_json::from_str::<serde_json::Value>(line)
         .map_err(|e| e.to_string())
         .and_then(build_command)
 }
 
-fn build_command(value: serde_json::Value) -> Result<Command, String> {
+/// Parses the line, recording it in the audit log as received from the
+/// source.
+pub fn parse_audited_line(line: &str, source: &str) -> Result<command::Command, String> {
+    match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(value) => {
+            audit::record(source, &value);
+            build_command(value)
+        }
+
+        Err(e) => {
+            audit::record_invalid(source, line, &e);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Types of all supported commands.
+pub const COMMANDS: [&str; 43] = [
+    "input",
+    "inputRaw",
+    "paste",
+    "flush",
+    "sync",
+    "sendKeys",
+    "mouse",
+    "focus",
+    "resize",
+    "takeSnapshot",
+    "snapshotAt",
+    "getOutput",
+    "getLine",
+    "getColumns",
+    "getView",
+    "scrollUp",
+    "scrollDown",
+    "scrollTo",
+    "follow",
+    "getText",
+    "getWord",
+    "setSelection",
+    "getSelection",
+    "setClipboard",
+    "dumpState",
+    "getProcesses",
+    "capabilities",
+    "schema",
+    "close",
+    "reset",
+    "annotate",
+    "recordPause",
+    "recordResume",
+    "macroRecord",
+    "macroStop",
+    "macroPlay",
+    "suspend",
+    "resume",
+    "exec",
+    "waitForStable",
+    "signal",
+    "clearScrollback",
+    "sleep",
+];
+
+pub fn build_command(value: serde_json::Value) -> Result<Command, String> {
     match value["type"].as_str() {
         Some("input") => {
             let args: InputArgs = args_from_json_value(value)?;
-            Ok(Command::Input(vec![standard_key(args.payload)]))
+            Ok(Command::Input(
+                vec![standard_key(args.payload)],
+                args.secret,
+            ))
+        }
+
+        Some("flush") => Ok(Command::Flush),
+
+        Some("sync") => Ok(Command::Sync),
+
+        Some("inputRaw") => {
+            let args: InputRawArgs = args_from_json_value(value)?;
+
+            let bytes = match (args.base64, args.hex) {
+                (Some(data), None) => BASE64_STANDARD
+                    .decode(data)
+                    .map_err(|e| format!("invalid base64: {e}"))?,
+
+                (None, Some(data)) => parse_hex(&data)?,
+                _ => return Err("expected either base64 or hex field".to_owned()),
+            };
+
+            Ok(Command::InputRaw(bytes))
+        }
+
+        Some("paste") => {
+            let args: PasteArgs = args_from_json_value(value)?;
+            Ok(Command::Paste(args.payload))
         }
 
         Some("sendKeys") => {
             let args: SendKeysArgs = args_from_json_value(value)?;
-            let seqs = args.keys.into_iter().map(parse_key).collect();
-            Ok(Command::Input(seqs))
+            Ok(Command::Input(parse_keys(args.keys), args.secret))
         }
 
         Some("mouse") => {
@@ -143,9 +455,7 @@ fn build_command(value: serde_json::Value) -> Result<Command, String> {
 
             // Validate coordinates (1-indexed)
             if args.row == 0 || args.col == 0 {
-                return Err(
-                    "mouse coordinates must be 1-indexed (row >= 1, col >= 1)".to_string()
-                );
+                return Err("mouse coordinates must be 1-indexed (row >= 1, col >= 1)".to_string());
             }
 
             let modifiers = command::MouseModifiers {
@@ -171,10 +481,222 @@ fn build_command(value: serde_json::Value) -> Result<Command, String> {
 
         Some("resize") => {
             let args: ResizeArgs = args_from_json_value(value)?;
-            Ok(Command::Resize(args.cols, args.rows))
+            Ok(Command::Resize(
+                args.cols,
+                args.rows,
+                args.xpixel,
+                args.ypixel,
+            ))
+        }
+
+        Some("takeSnapshot") => {
+            let args: SnapshotArgs = args_from_json_value(value)?;
+
+            let format = match args.format.as_deref() {
+                None => None,
+                Some("ansi") => Some(render::Format::Ansi),
+                Some("markdown") => Some(render::Format::Markdown {
+                    cursor: args.cursor,
+                }),
+                Some(f) => return Err(format!("invalid snapshot format: {}", f)),
+            };
+
+            Ok(Command::Snapshot(format, args.full, args.trim.into()))
+        }
+
+        Some("snapshotAt") => {
+            let args: SnapshotAtArgs = args_from_json_value(value)?;
+
+            match (args.time, args.output_id) {
+                (Some(time), None) => Ok(Command::SnapshotAt(Moment::Time(time))),
+                (None, Some(id)) => Ok(Command::SnapshotAt(Moment::OutputId(id))),
+                _ => Err("expected either time or outputId field".to_owned()),
+            }
+        }
+
+        Some("getOutput") => {
+            let args: GetOutputArgs = args_from_json_value(value)?;
+            Ok(Command::GetOutput(args.from, args.to))
+        }
+
+        Some("getLine") => {
+            let args: GetLineArgs = args_from_json_value(value)?;
+            Ok(Command::GetLine(args.row))
+        }
+
+        Some("getColumns") => {
+            let args: GetLineArgs = args_from_json_value(value)?;
+            Ok(Command::GetColumns(args.row))
+        }
+
+        Some("getView") => {
+            let args: GetViewArgs = args_from_json_value(value)?;
+            Ok(Command::GetView(args.trim.into(), args.offset))
+        }
+
+        Some("scrollUp") => {
+            let args: ScrollArgs = args_from_json_value(value)?;
+            Ok(Command::Scroll(Scroll::Up(args.lines)))
+        }
+
+        Some("scrollDown") => {
+            let args: ScrollArgs = args_from_json_value(value)?;
+            Ok(Command::Scroll(Scroll::Down(args.lines)))
+        }
+
+        Some("scrollTo") => {
+            let args: ScrollToArgs = args_from_json_value(value)?;
+            Ok(Command::Scroll(Scroll::To(args.offset)))
+        }
+
+        Some("follow") => {
+            let args: FollowArgs = args_from_json_value(value)?;
+            Ok(Command::Follow(args.enabled))
+        }
+
+        Some("getText") => {
+            let args: GetTextArgs = args_from_json_value(value)?;
+
+            Ok(Command::GetText(Region {
+                x: args.x,
+                y: args.y,
+                w: args.w,
+                h: args.h,
+            }))
+        }
+
+        Some("getWord") => {
+            let args: GetWordArgs = args_from_json_value(value)?;
+            Ok(Command::GetWord(args.row, args.col, args.separators))
+        }
+
+        Some("setSelection") => {
+            let args: SetSelectionArgs = args_from_json_value(value)?;
+
+            let mode = match args.mode.as_deref() {
+                None | Some("normal") => SelectionMode::Normal,
+                Some("line") => SelectionMode::Line,
+                Some("block") => SelectionMode::Block,
+                Some(m) => return Err(format!("invalid selection mode: {m}")),
+            };
+
+            let selection = match (args.anchor, args.extent) {
+                (None, None) => None,
+                (None, Some(_)) => return Err("extent given without anchor".to_owned()),
+
+                (Some(anchor), extent) => {
+                    let extent = extent.unwrap_or(anchor);
+
+                    Some(Selection {
+                        anchor: (anchor.col, anchor.row),
+                        extent: (extent.col, extent.row),
+                        mode,
+                    })
+                }
+            };
+
+            Ok(Command::SetSelection(selection))
+        }
+
+        Some("getSelection") => Ok(Command::GetSelection),
+
+        Some("setClipboard") => {
+            let args: SetClipboardArgs = args_from_json_value(value)?;
+            Ok(Command::SetClipboard(args.payload))
+        }
+
+        Some("dumpState") => Ok(Command::DumpState),
+
+        Some("getProcesses") => Ok(Command::GetProcesses),
+
+        Some("capabilities") => Ok(Command::Capabilities),
+
+        Some("schema") => Ok(Command::Schema),
+
+        Some("close") => Ok(Command::Close),
+
+        Some("reset") => {
+            let args: ResetArgs = args_from_json_value(value)?;
+            Ok(Command::Reset(args.redraw))
+        }
+
+        Some("annotate") => {
+            let args: AnnotateArgs = args_from_json_value(value)?;
+            Ok(Command::Annotate(args.payload))
+        }
+
+        Some("recordPause") => Ok(Command::PauseRecording(true)),
+
+        Some("recordResume") => Ok(Command::PauseRecording(false)),
+
+        Some("macroRecord") => {
+            let args: MacroArgs = args_from_json_value(value)?;
+            Ok(Command::RecordMacro(args.name))
+        }
+
+        Some("macroStop") => Ok(Command::StopMacro),
+
+        Some("macroPlay") => {
+            let args: MacroPlayArgs = args_from_json_value(value)?;
+            Ok(Command::PlayMacro(args.name, args.timing))
+        }
+
+        Some("suspend") => Ok(Command::Suspend(true)),
+
+        Some("resume") => Ok(Command::Suspend(false)),
+
+        Some("exec") => {
+            let args: ExecArgs = args_from_json_value(value)?;
+
+            let timeout = match args.timeout {
+                Some(t) if t.is_finite() && t > 0.0 => Some(Duration::from_secs_f64(t)),
+                Some(t) => return Err(format!("invalid exec timeout: {t}")),
+                None => None,
+            };
+
+            Ok(Command::Exec(args.command, timeout))
+        }
+
+        Some("waitForStable") => {
+            let args: WaitForStableArgs = args_from_json_value(value)?;
+
+            Ok(Command::WaitForStable(
+                Duration::from_millis(args.quiet_ms),
+                Duration::from_millis(args.timeout_ms),
+            ))
+        }
+
+        Some("focus") => {
+            let args: FocusArgs = args_from_json_value(value)?;
+
+            match args.event.as_str() {
+                "gained" => Ok(Command::Focus(true)),
+                "lost" => Ok(Command::Focus(false)),
+                e => Err(format!("invalid focus event: {e}")),
+            }
         }
 
-        Some("takeSnapshot") => Ok(Command::Snapshot),
+        Some("signal") => {
+            let args: SignalArgs = args_from_json_value(value)?;
+
+            let target = match args.target.as_deref() {
+                None | Some("group") => command::SignalTarget::Group,
+                Some("child") => command::SignalTarget::Child,
+                Some(t) => return Err(format!("invalid signal target: {}", t)),
+            };
+
+            Ok(Command::Signal(parse_signal(&args.signal)?, target))
+        }
+
+        Some("clearScrollback") => {
+            let args: ClearScrollbackArgs = args_from_json_value(value)?;
+            Ok(Command::ClearScrollback(args.clear_screen))
+        }
+
+        Some("sleep") => {
+            let args: SleepArgs = args_from_json_value(value)?;
+            Ok(Command::Sleep(Duration::from_millis(args.ms)))
+        }
 
         other => Err(format!("invalid command type: {other:?}")),
     }
@@ -187,6 +709,50 @@ where
     serde_json::from_value(value).map_err(|e| e.to_string())
 }
 
+/// Parses hex-encoded bytes, e.g. `1b5b41` or `1b 5b 41`.
+fn parse_hex(data: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = data.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+
+    if digits.len() % 2 != 0 {
+        return Err("invalid hex: odd number of digits".to_owned());
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex: {}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+/// Parses a signal given by name (`SIGINT`, `INT`, case-insensitive) or number.
+fn parse_signal(value: &serde_json::Value) -> Result<Signal, String> {
+    let signal = match value {
+        serde_json::Value::String(name) => {
+            let name = name.to_uppercase();
+
+            if name.starts_with("SIG") {
+                name.parse()
+            } else {
+                format!("SIG{name}").parse()
+            }
+        }
+
+        serde_json::Value::Number(number) => number
+            .as_i64()
+            .and_then(|n| i32::try_from(n).ok())
+            .ok_or(nix::errno::Errno::EINVAL)
+            .and_then(Signal::try_from),
+
+        _ => Err(nix::errno::Errno::EINVAL),
+    };
+
+    signal.map_err(|_| format!("invalid signal: {value}"))
+}
+
 fn standard_key<S: ToString>(seq: S) -> InputSeq {
     InputSeq::Standard(seq.to_string())
 }
@@ -195,6 +761,21 @@ fn cursor_key<S: ToString>(seq1: S, seq2: S) -> InputSeq {
     InputSeq::Cursor(seq1.to_string(), seq2.to_string())
 }
 
+/// Parses key names, expanding the custom ones defined in the keymap.
+fn parse_keys(keys: Vec<String>) -> Vec<InputSeq> {
+    let mut seqs = Vec::new();
+
+    for key in keys {
+        match keymap::get(&key) {
+            Some(Binding::Seq(seq)) => seqs.push(standard_key(seq)),
+            Some(Binding::Keys(keys)) => seqs.extend(keys.iter().cloned().map(parse_key)),
+            None => seqs.push(parse_key(key)),
+        }
+    }
+
+    seqs
+}
+
 fn parse_key(key: String) -> InputSeq {
     let seq = match key.as_str() {
         "C-@" | "C-Space" | "^@" => "\x00",
@@ -343,13 +924,55 @@ fn parse_key(key: String) -> InputSeq {
 
 #[cfg(test)]
 mod test {
-    use super::{cursor_key, parse_line, standard_key, Command};
-    use crate::command::{InputSeq, MouseButton, MouseEventType};
+    use super::{cursor_key, parse_line, standard_key, Command, COMMANDS};
+    use crate::command::{
+        InputSeq, MouseButton, MouseEventType, Region, Scroll, Selection, SelectionMode, Trim,
+    };
+    use crate::history::Moment;
+    use crate::keymap::{self, Binding};
+    use crate::render::Format;
+    use nix::sys::signal::Signal;
 
     #[test]
     fn parse_input() {
         let command = parse_line(r#"{ "type": "input", "payload": "hello" }"#).unwrap();
-        assert!(matches!(command, Command::Input(input) if input == vec![standard_key("hello")]));
+        assert!(
+            matches!(command, Command::Input(input, false) if input == vec![standard_key("hello")])
+        );
+    }
+
+    #[test]
+    fn parse_secret_input() {
+        let command =
+            parse_line(r#"{ "type": "input", "payload": "hunter2\r", "secret": true }"#).unwrap();
+        assert!(matches!(command, Command::Input(_, true)));
+
+        let command =
+            parse_line(r#"{ "type": "sendKeys", "keys": ["hunter2", "Enter"], "secret": true }"#)
+                .unwrap();
+        assert!(matches!(command, Command::Input(_, true)));
+    }
+
+    #[test]
+    fn parse_paste() {
+        let command = parse_line(r#"{ "type": "paste", "payload": "a: 1\nb: 2\n" }"#).unwrap();
+        assert!(matches!(command, Command::Paste(payload) if payload == "a: 1\nb: 2\n"));
+    }
+
+    #[test]
+    fn parse_input_raw() {
+        let command = parse_line(r#"{ "type": "inputRaw", "hex": "00ff 1b5B41" }"#).unwrap();
+        assert!(matches!(command, Command::InputRaw(bytes) if bytes == b"\x00\xff\x1b[A"));
+
+        let command = parse_line(r#"{ "type": "inputRaw", "base64": "AP8=" }"#).unwrap();
+        assert!(matches!(command, Command::InputRaw(bytes) if bytes == b"\x00\xff"));
+
+        parse_line(r#"{ "type": "inputRaw", "hex": "1b5" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "inputRaw", "hex": "zz" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "inputRaw", "base64": "!" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "inputRaw" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "inputRaw", "hex": "00", "base64": "AA==" }"#)
+            .expect_err("should fail");
     }
 
     #[test]
@@ -480,7 +1103,9 @@ mod test {
             ))
             .unwrap();
 
-            assert!(matches!(command, Command::Input(input) if input == vec![standard_key(chars)]));
+            assert!(
+                matches!(command, Command::Input(input, false) if input == vec![standard_key(chars)])
+            );
         }
 
         let command = parse_line(
@@ -489,7 +1114,7 @@ mod test {
         .unwrap();
 
         assert!(
-            matches!(command, Command::Input(input) if input == vec![standard_key("hello"), standard_key("\x0d"), standard_key("\x03"), standard_key("\x1b^"), cursor_key("\x1b[D", "\x1bOD")])
+            matches!(command, Command::Input(input, false) if input == vec![standard_key("hello"), standard_key("\x0d"), standard_key("\x03"), standard_key("\x1b^"), cursor_key("\x1b[D", "\x1bOD")])
         );
     }
 
@@ -510,7 +1135,7 @@ mod test {
             ))
             .unwrap();
 
-            if let Command::Input(seqs) = command {
+            if let Command::Input(seqs, _) = command {
                 if let InputSeq::Cursor(seq3, seq4) = &seqs[0] {
                     if seq1 == seq3 && seq2 == seq4 {
                         continue;
@@ -524,6 +1149,37 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_send_keys_with_keymap() {
+        keymap::init(
+            [
+                ("accept".to_owned(), Binding::Seq("\r".to_owned())),
+                (
+                    "save".to_owned(),
+                    Binding::Keys(vec!["C-x".to_owned(), "C-s".to_owned()]),
+                ),
+            ]
+            .into(),
+        );
+
+        let command =
+            parse_line(r#"{ "type": "sendKeys", "keys": ["save", "accept", "Up"] }"#).unwrap();
+
+        let Command::Input(seqs, _) = command else {
+            panic!("expected input");
+        };
+
+        assert_eq!(
+            seqs,
+            [
+                InputSeq::Standard("\x18".to_owned()),
+                InputSeq::Standard("\x13".to_owned()),
+                InputSeq::Standard("\r".to_owned()),
+                InputSeq::Cursor("\x1b[A".to_owned(), "\x1bOA".to_owned()),
+            ]
+        );
+    }
+
     #[test]
     fn parse_send_keys_missing_args() {
         parse_line(r#"{ "type": "sendKeys" }"#).expect_err("should fail");
@@ -532,7 +1188,14 @@ mod test {
     #[test]
     fn parse_resize() {
         let command = parse_line(r#"{ "type": "resize", "cols": 80, "rows": 24 }"#).unwrap();
-        assert!(matches!(command, Command::Resize(80, 24)));
+        assert!(matches!(command, Command::Resize(80, 24, 0, 0)));
+
+        let command = parse_line(
+            r#"{ "type": "resize", "cols": 80, "rows": 24, "xpixel": 640, "ypixel": 480 }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(command, Command::Resize(80, 24, 640, 480)));
     }
 
     #[test]
@@ -543,7 +1206,394 @@ mod test {
     #[test]
     fn parse_take_snapshot() {
         let command = parse_line(r#"{ "type": "takeSnapshot" }"#).unwrap();
-        assert!(matches!(command, Command::Snapshot));
+        assert!(matches!(command, Command::Snapshot(None, false, _)));
+    }
+
+    #[test]
+    fn parse_take_snapshot_with_format() {
+        let command = parse_line(r#"{ "type": "takeSnapshot", "format": "ansi" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Snapshot(Some(Format::Ansi), false, _)
+        ));
+
+        let command = parse_line(r#"{ "type": "takeSnapshot", "full": true }"#).unwrap();
+        assert!(matches!(command, Command::Snapshot(None, true, _)));
+
+        let command = parse_line(r#"{ "type": "takeSnapshot", "trim": true }"#).unwrap();
+
+        assert!(matches!(
+            command,
+            Command::Snapshot(
+                None,
+                false,
+                Trim {
+                    trailing: true,
+                    collapse_blank: false
+                }
+            )
+        ));
+
+        let command =
+            parse_line(r#"{ "type": "takeSnapshot", "format": "markdown", "cursor": true }"#)
+                .unwrap();
+
+        assert!(matches!(
+            command,
+            Command::Snapshot(Some(Format::Markdown { cursor: true }), false, _)
+        ));
+
+        parse_line(r#"{ "type": "takeSnapshot", "format": "png" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_line() {
+        let command = parse_line(r#"{ "type": "getLine", "row": 3 }"#).unwrap();
+        assert!(matches!(command, Command::GetLine(3)));
+
+        parse_line(r#"{ "type": "getLine" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_columns() {
+        let command = parse_line(r#"{ "type": "getColumns", "row": 1 }"#).unwrap();
+        assert!(matches!(command, Command::GetColumns(1)));
+
+        parse_line(r#"{ "type": "getColumns" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_view() {
+        let command = parse_line(r#"{ "type": "getView" }"#).unwrap();
+        assert!(matches!(command, Command::GetView(trim, None) if trim == Trim::default()));
+
+        let command =
+            parse_line(r#"{ "type": "getView", "trim": true, "collapseBlank": true }"#).unwrap();
+
+        assert!(matches!(
+            command,
+            Command::GetView(
+                Trim {
+                    trailing: true,
+                    collapse_blank: true
+                },
+                None
+            )
+        ));
+
+        let command = parse_line(r#"{ "type": "getView", "offset": 10 }"#).unwrap();
+        assert!(matches!(command, Command::GetView(_, Some(10))));
+    }
+
+    #[test]
+    fn parse_scroll() {
+        let command = parse_line(r#"{ "type": "scrollUp" }"#).unwrap();
+        assert!(matches!(command, Command::Scroll(Scroll::Up(None))));
+
+        let command = parse_line(r#"{ "type": "scrollDown", "lines": 3 }"#).unwrap();
+        assert!(matches!(command, Command::Scroll(Scroll::Down(Some(3)))));
+
+        let command = parse_line(r#"{ "type": "scrollTo", "offset": 0 }"#).unwrap();
+        assert!(matches!(command, Command::Scroll(Scroll::To(0))));
+
+        parse_line(r#"{ "type": "scrollTo" }"#).expect_err("should fail");
+
+        let command = parse_line(r#"{ "type": "follow" }"#).unwrap();
+        assert!(matches!(command, Command::Follow(true)));
+
+        let command = parse_line(r#"{ "type": "follow", "enabled": false }"#).unwrap();
+        assert!(matches!(command, Command::Follow(false)));
+    }
+
+    #[test]
+    fn parse_get_text() {
+        let command = parse_line(r#"{ "type": "getText" }"#).unwrap();
+        assert!(matches!(command, Command::GetText(region) if region == Region::default()));
+
+        let command = parse_line(r#"{ "type": "getText", "x": 2, "y": 1, "w": 10 }"#).unwrap();
+
+        let expected = Region {
+            x: 2,
+            y: 1,
+            w: Some(10),
+            h: None,
+        };
+
+        assert!(matches!(command, Command::GetText(region) if region == expected));
+    }
+
+    #[test]
+    fn parse_get_word() {
+        let command = parse_line(r#"{ "type": "getWord", "row": 1, "col": 4 }"#).unwrap();
+        assert!(matches!(command, Command::GetWord(1, 4, None)));
+
+        let command =
+            parse_line(r#"{ "type": "getWord", "row": 0, "col": 0, "separators": ":/" }"#).unwrap();
+        assert!(matches!(command, Command::GetWord(0, 0, Some(s)) if s == ":/"));
+
+        parse_line(r#"{ "type": "getWord", "row": 1 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_set_selection() {
+        let command = parse_line(
+            r#"{ "type": "setSelection", "anchor": { "row": 1, "col": 2 }, "extent": { "row": 3, "col": 4 }, "mode": "block" }"#,
+        )
+        .unwrap();
+
+        let expected = Selection {
+            anchor: (2, 1),
+            extent: (4, 3),
+            mode: SelectionMode::Block,
+        };
+
+        assert!(matches!(command, Command::SetSelection(Some(s)) if s == expected));
+
+        let command =
+            parse_line(r#"{ "type": "setSelection", "anchor": { "row": 1, "col": 2 } }"#).unwrap();
+
+        let expected = Selection {
+            anchor: (2, 1),
+            extent: (2, 1),
+            mode: SelectionMode::Normal,
+        };
+
+        assert!(matches!(command, Command::SetSelection(Some(s)) if s == expected));
+
+        let command = parse_line(r#"{ "type": "setSelection" }"#).unwrap();
+        assert!(matches!(command, Command::SetSelection(None)));
+
+        parse_line(r#"{ "type": "setSelection", "extent": { "row": 1, "col": 2 } }"#)
+            .expect_err("should fail");
+
+        parse_line(
+            r#"{ "type": "setSelection", "anchor": { "row": 1, "col": 2 }, "mode": "word" }"#,
+        )
+        .expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_focus() {
+        let command = parse_line(r#"{ "type": "focus", "event": "gained" }"#).unwrap();
+        assert!(matches!(command, Command::Focus(true)));
+
+        let command = parse_line(r#"{ "type": "focus", "event": "lost" }"#).unwrap();
+        assert!(matches!(command, Command::Focus(false)));
+
+        parse_line(r#"{ "type": "focus", "event": "blur" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_snapshot_at() {
+        let command = parse_line(r#"{ "type": "snapshotAt", "time": 12.5 }"#).unwrap();
+        assert!(matches!(command, Command::SnapshotAt(Moment::Time(t)) if t == 12.5));
+
+        let command = parse_line(r#"{ "type": "snapshotAt", "outputId": 42 }"#).unwrap();
+        assert!(matches!(command, Command::SnapshotAt(Moment::OutputId(42))));
+
+        parse_line(r#"{ "type": "snapshotAt" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "snapshotAt", "time": 1, "outputId": 1 }"#)
+            .expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_output() {
+        let command = parse_line(r#"{ "type": "getOutput", "from": 100 }"#).unwrap();
+        assert!(matches!(command, Command::GetOutput(Some(100), None)));
+
+        let command = parse_line(r#"{ "type": "getOutput", "from": 0, "to": 10 }"#).unwrap();
+        assert!(matches!(command, Command::GetOutput(Some(0), Some(10))));
+    }
+
+    #[test]
+    fn parse_macros() {
+        let command = parse_line(r#"{ "type": "macroRecord", "name": "login" }"#).unwrap();
+        assert!(matches!(command, Command::RecordMacro(name) if name == "login"));
+
+        let command = parse_line(r#"{ "type": "macroStop" }"#).unwrap();
+        assert!(matches!(command, Command::StopMacro));
+
+        let command = parse_line(r#"{ "type": "macroPlay", "name": "login" }"#).unwrap();
+        assert!(matches!(command, Command::PlayMacro(name, false) if name == "login"));
+
+        let command =
+            parse_line(r#"{ "type": "macroPlay", "name": "login", "timing": true }"#).unwrap();
+        assert!(matches!(command, Command::PlayMacro(name, true) if name == "login"));
+
+        parse_line(r#"{ "type": "macroRecord" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_set_clipboard() {
+        let command = parse_line(r#"{ "type": "setClipboard", "payload": "hello" }"#).unwrap();
+        assert!(matches!(command, Command::SetClipboard(content) if content == "hello"));
+
+        parse_line(r#"{ "type": "setClipboard" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_dump_state() {
+        let command = parse_line(r#"{ "type": "dumpState" }"#).unwrap();
+        assert!(matches!(command, Command::DumpState));
+    }
+
+    #[test]
+    fn parse_reset() {
+        let command = parse_line(r#"{ "type": "reset" }"#).unwrap();
+        assert!(matches!(command, Command::Reset(false)));
+
+        let command = parse_line(r#"{ "type": "reset", "redraw": true }"#).unwrap();
+        assert!(matches!(command, Command::Reset(true)));
+    }
+
+    #[test]
+    fn parse_clear_scrollback() {
+        let command = parse_line(r#"{ "type": "clearScrollback" }"#).unwrap();
+        assert!(matches!(command, Command::ClearScrollback(false)));
+
+        let command = parse_line(r#"{ "type": "clearScrollback", "clearScreen": true }"#).unwrap();
+        assert!(matches!(command, Command::ClearScrollback(true)));
+    }
+
+    #[test]
+    fn parse_annotate() {
+        let command = parse_line(r#"{ "type": "annotate", "payload": "step 3" }"#).unwrap();
+        assert!(matches!(command, Command::Annotate(payload) if payload == "step 3"));
+
+        let command = parse_line(r#"{ "type": "annotate", "payload": { "step": 3 } }"#).unwrap();
+        assert!(matches!(command, Command::Annotate(payload) if payload["step"] == 3));
+
+        parse_line(r#"{ "type": "annotate" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_record_pause_resume() {
+        let command = parse_line(r#"{ "type": "recordPause" }"#).unwrap();
+        assert!(matches!(command, Command::PauseRecording(true)));
+
+        let command = parse_line(r#"{ "type": "recordResume" }"#).unwrap();
+        assert!(matches!(command, Command::PauseRecording(false)));
+    }
+
+    #[test]
+    fn parse_suspend_resume() {
+        let command = parse_line(r#"{ "type": "suspend" }"#).unwrap();
+        assert!(matches!(command, Command::Suspend(true)));
+
+        let command = parse_line(r#"{ "type": "resume" }"#).unwrap();
+        assert!(matches!(command, Command::Suspend(false)));
+    }
+
+    #[test]
+    fn parse_signal_command() {
+        use crate::command::SignalTarget;
+
+        let command = parse_line(r#"{ "type": "signal", "signal": "SIGINT" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Signal(Signal::SIGINT, SignalTarget::Group)
+        ));
+
+        let command =
+            parse_line(r#"{ "type": "signal", "signal": "term", "target": "child" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Signal(Signal::SIGTERM, SignalTarget::Child)
+        ));
+
+        let command = parse_line(r#"{ "type": "signal", "signal": 9 }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Signal(Signal::SIGKILL, SignalTarget::Group)
+        ));
+
+        parse_line(r#"{ "type": "signal", "signal": "NOPE" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "signal", "signal": 999 }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "signal", "signal": "INT", "target": "all" }"#)
+            .expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_exec() {
+        let command =
+            parse_line(r#"{ "type": "exec", "command": "make", "timeout": 1.5 }"#).unwrap();
+        assert!(
+            matches!(command, Command::Exec(c, Some(t)) if c == "make" && t.as_millis() == 1500)
+        );
+
+        let command = parse_line(r#"{ "type": "exec", "command": "ls" }"#).unwrap();
+        assert!(matches!(command, Command::Exec(_, None)));
+
+        parse_line(r#"{ "type": "exec", "command": "ls", "timeout": -1 }"#)
+            .expect_err("should fail");
+        parse_line(r#"{ "type": "exec" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_processes() {
+        let command = parse_line(r#"{ "type": "getProcesses" }"#).unwrap();
+        assert!(matches!(command, Command::GetProcesses));
+    }
+
+    #[test]
+    fn parse_capabilities() {
+        let command = parse_line(r#"{ "type": "capabilities" }"#).unwrap();
+        assert!(matches!(command, Command::Capabilities));
+
+        let command = parse_line(r#"{ "type": "schema" }"#).unwrap();
+        assert!(matches!(command, Command::Schema));
+
+        for name in COMMANDS {
+            if let Err(e) = parse_line(&format!(r#"{{ "type": "{name}" }}"#)) {
+                assert!(!e.starts_with("invalid command type"), "{name}: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_sleep() {
+        let command = parse_line(r#"{ "type": "sleep", "ms": 250 }"#).unwrap();
+        assert!(matches!(command, Command::Sleep(d) if d.as_millis() == 250));
+
+        parse_line(r#"{ "type": "sleep" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "sleep", "ms": -1 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_wait_for_stable() {
+        let command =
+            parse_line(r#"{ "type": "waitForStable", "quietMs": 200, "timeoutMs": 3000 }"#)
+                .unwrap();
+
+        assert!(matches!(
+            command,
+            Command::WaitForStable(q, t) if q.as_millis() == 200 && t.as_millis() == 3000
+        ));
+
+        let command = parse_line(r#"{ "type": "waitForStable" }"#).unwrap();
+
+        assert!(matches!(
+            command,
+            Command::WaitForStable(q, t) if q.as_millis() == 500 && t.as_millis() == 5000
+        ));
+    }
+
+    #[test]
+    fn parse_flush() {
+        let command = parse_line(r#"{ "type": "flush" }"#).unwrap();
+        assert!(matches!(command, Command::Flush));
+    }
+
+    #[test]
+    fn parse_sync() {
+        let command = parse_line(r#"{ "type": "sync" }"#).unwrap();
+        assert!(matches!(command, Command::Sync));
+    }
+
+    #[test]
+    fn parse_close() {
+        let command = parse_line(r#"{ "type": "close" }"#).unwrap();
+        assert!(matches!(command, Command::Close));
     }
 
     #[test]
@@ -0,0 +1,172 @@
+use anyhow::{bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use nix::libc;
+use nix::sys::termios::{self, SetArg, Termios};
+use serde_json::json;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Key detaching from the session (Ctrl-]).
+const DETACH_KEY: u8 = 0x1d;
+
+/// Connects to the session listening on `addr` and bridges it with the local
+/// terminal, until the detach key is pressed or the session ends. With
+/// `read_only` the session is only shown, and the server is asked to ignore
+/// any commands from the connection.
+pub fn run(addr: SocketAddr, read_only: bool) -> Result<()> {
+    let stdin = io::stdin();
+
+    let Ok(attrs) = termios::tcgetattr(stdin.as_fd()) else {
+        bail!("stdin is not a terminal");
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let (socket, _) = runtime.block_on(tokio_tungstenite::connect_async(format!(
+        "ws://{addr}/ws/events?sub=init,output&readOnly={read_only}"
+    )))?;
+
+    let raw = RawMode::enable(attrs)?;
+    let result = runtime.block_on(bridge(socket, read_only));
+    drop(raw);
+
+    // don't wait for the pending read of stdin
+    runtime.shutdown_background();
+
+    match result? {
+        true => eprintln!("\r\n[session ended]"),
+        false => eprintln!("\r\n[detached]"),
+    }
+
+    Ok(())
+}
+
+/// Local terminal switched to raw mode, restored on drop.
+struct RawMode(Termios);
+
+impl RawMode {
+    fn enable(attrs: Termios) -> Result<Self> {
+        let mut raw = attrs.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, &raw)?;
+
+        Ok(Self(attrs))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, &self.0);
+    }
+}
+
+/// Relays the keys (unless `read_only`) and the output. Returns true when the
+/// session ended, and false when detached.
+async fn bridge(
+    socket: tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+    read_only: bool,
+) -> Result<bool> {
+    let (mut sink, mut stream) = socket.split();
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = io::stdout();
+    let mut sigwinch = signal(SignalKind::window_change())?;
+    let mut buf = [0u8; 4096];
+
+    if !read_only {
+        sink.send(resize_message()).await?;
+    }
+
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(true),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                };
+
+                let event: serde_json::Value = serde_json::from_str(&text)?;
+
+                let seq = match event["type"].as_str() {
+                    Some("init") => format!("\x1b[H\x1b[2J{}", event["data"]["seq"].as_str().unwrap_or_default()),
+                    Some("output") => event["data"]["seq"].as_str().unwrap_or_default().to_owned(),
+                    _ => continue,
+                };
+
+                stdout.write_all(seq.as_bytes())?;
+                stdout.flush()?;
+            }
+
+            n = stdin.read(&mut buf) => {
+                let n = n?;
+
+                if n == 0 {
+                    return Ok(false);
+                }
+
+                let (keys, detach) = split_keys(&buf[..n]);
+
+                if !keys.is_empty() && !read_only {
+                    let payload = String::from_utf8_lossy(keys);
+                    let command = json!({ "type": "input", "payload": payload });
+                    sink.send(Message::Text(command.to_string())).await?;
+                }
+
+                if detach {
+                    let _ = sink.close().await;
+                    return Ok(false);
+                }
+            }
+
+            _ = sigwinch.recv(), if !read_only => {
+                sink.send(resize_message()).await?;
+            }
+        }
+    }
+}
+
+/// Returns the keys preceding the detach key, and whether it was pressed.
+fn split_keys(keys: &[u8]) -> (&[u8], bool) {
+    match keys.iter().position(|b| *b == DETACH_KEY) {
+        Some(i) => (&keys[..i], true),
+        None => (keys, false),
+    }
+}
+
+/// Builds the command resizing the session to the size of the local terminal.
+fn resize_message() -> Message {
+    let mut winsize = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut winsize) };
+
+    let command = json!({
+        "type": "resize",
+        "cols": winsize.ws_col.max(1),
+        "rows": winsize.ws_row.max(1),
+    });
+
+    Message::Text(command.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_keys;
+
+    #[test]
+    fn detach_key() {
+        assert_eq!(split_keys(b"ls\r"), (&b"ls\r"[..], false));
+        assert_eq!(split_keys(b"ls\x1dpwd"), (&b"ls"[..], true));
+        assert_eq!(split_keys(b"\x1d"), (&b""[..], true));
+    }
+}
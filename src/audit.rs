@@ -0,0 +1,175 @@
+use anyhow::Result;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MASK: &str = "********";
+
+static LOG: OnceLock<Mutex<Log>> = OnceLock::new();
+
+/// Append-only log of received commands.
+///
+/// Each entry is a line of JSON carrying the SHA-256 of the previous line
+/// (`prev`), so removing or altering an entry breaks the chain at the entry
+/// that follows it.
+struct Log {
+    file: File,
+    prev: String,
+}
+
+/// Opens the audit log, continuing the hash chain of the entries already in
+/// the file.
+pub fn open(path: &Path) -> Result<()> {
+    let prev = match fs::read(path) {
+        Ok(content) => match content.trim_ascii_end().rsplit(|b| *b == b'\n').next() {
+            Some(line) if !line.is_empty() => hash(line),
+            _ => genesis(),
+        },
+
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => genesis(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+
+    let _ = LOG.set(Mutex::new(Log { file, prev }));
+
+    Ok(())
+}
+
+/// Appends the command received from the source to the audit log, if it's
+/// enabled. Payloads of input marked as secret are masked.
+pub fn record(source: &str, command: &serde_json::Value) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+
+    let mut log = log.lock().unwrap();
+    let line = entry(time, source, command, &log.prev).to_string();
+
+    if let Err(e) = writeln!(log.file, "{line}") {
+        eprintln!("writing to the audit log failed: {e}");
+        return;
+    }
+
+    log.prev = hash(line.as_bytes());
+}
+
+/// Appends a line which isn't valid JSON to the audit log, if it's enabled.
+/// Only its length and the parse error are recorded, as secret input in it
+/// can't be told apart to be masked.
+pub fn record_invalid(source: &str, line: &str, error: &serde_json::Error) {
+    record(source, &invalid(line, error));
+}
+
+fn invalid(line: &str, error: &serde_json::Error) -> serde_json::Value {
+    json!({ "invalid": { "length": line.len(), "error": error.to_string() } })
+}
+
+fn entry(time: f64, source: &str, command: &serde_json::Value, prev: &str) -> serde_json::Value {
+    let mut command = command.clone();
+    mask_secret(&mut command);
+
+    // JSON-RPC requests carry the command args in params
+    if let Some(params) = command.get_mut("params") {
+        mask_secret(params);
+    }
+
+    json!({
+        "time": time,
+        "source": source,
+        "command": command,
+        "prev": prev,
+    })
+}
+
+fn mask_secret(command: &mut serde_json::Value) {
+    if command["secret"] != true {
+        return;
+    }
+
+    if command.get("payload").is_some() {
+        command["payload"] = json!(MASK);
+    }
+
+    if let Some(keys) = command.get_mut("keys").and_then(|k| k.as_array_mut()) {
+        keys.fill(json!(MASK));
+    }
+}
+
+fn hash(line: &[u8]) -> String {
+    Sha256::digest(line)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn genesis() -> String {
+    "0".repeat(64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{entry, hash, invalid};
+    use serde_json::json;
+
+    #[test]
+    fn mask_secrets() {
+        let command = json!({ "type": "sendKeys", "keys": ["hunter2", "Enter"], "secret": true });
+
+        assert_eq!(
+            entry(1.5, "stdio", &command, "abc"),
+            json!({
+                "time": 1.5,
+                "source": "stdio",
+                "command": { "type": "sendKeys", "keys": ["********", "********"], "secret": true },
+                "prev": "abc",
+            })
+        );
+
+        let command = json!({
+            "jsonrpc": "2.0",
+            "method": "input",
+            "params": { "payload": "hunter2", "secret": true }
+        });
+
+        let command = &entry(1.5, "stdio", &command, "abc")["command"];
+        assert_eq!(command["params"]["payload"], "********");
+
+        let command = json!({ "type": "input", "payload": "ls\r" });
+        assert_eq!(entry(1.5, "stdio", &command, "abc")["command"], command);
+    }
+
+    #[test]
+    fn invalid_line() {
+        let line = r#"{"type":"input","payload":"hunter2","secret":true"#;
+        let error = serde_json::from_str::<serde_json::Value>(line).unwrap_err();
+        let command = invalid(line, &error);
+
+        assert_eq!(command["invalid"]["length"], line.len());
+        assert_eq!(command["invalid"]["error"], error.to_string());
+        assert!(!command.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn hash_line() {
+        assert_eq!(
+            hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
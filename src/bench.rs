@@ -0,0 +1,114 @@
+use crate::session::Session;
+use anyhow::Result;
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Workload run by `--bench` when no command is given: 64 MiB of log lines
+/// with colors, like a busy service would print.
+pub const WORKLOAD: &str = "yes \"$(printf '\\033[1;32mINFO\\033[0m \
+    request handled in 12ms: GET /api/items?page=3 status=200 \\033[2muser=42\\033[0m')\" \
+    | head -c 67108864";
+
+/// Throughput of each stage of the output path.
+struct Report {
+    bytes: usize,
+    events: usize,
+    pty: Duration,
+    emulator: Duration,
+    serialization: Duration,
+}
+
+/// Measures the throughput of the output path, stage by stage: reading the
+/// child's output from the PTY until EOF, feeding it to the emulator (with
+/// everything else the session does with output), and serializing the
+/// resulting events to JSON. The stages are timed separately, so the output
+/// is first read in full, then replayed through the session.
+pub async fn run(mut output_rx: mpsc::Receiver<Bytes>, mut session: Session) -> Result<()> {
+    let start = Instant::now();
+    let mut chunks = Vec::new();
+
+    while let Some(chunk) = output_rx.recv().await {
+        chunks.push(chunk);
+    }
+
+    let pty = start.elapsed();
+    let mut events_rx = session.subscribe().into_receiver();
+    let mut events = Vec::new();
+    let mut emulator = Duration::ZERO;
+
+    for chunk in &chunks {
+        let start = Instant::now();
+        session.output(chunk.clone());
+        emulator += start.elapsed();
+
+        while let Ok(event) = events_rx.try_recv() {
+            events.push(event);
+        }
+    }
+
+    let start = Instant::now();
+
+    for event in &events {
+        std::hint::black_box(event.to_json().to_string());
+    }
+
+    let report = Report {
+        bytes: chunks.iter().map(Bytes::len).sum(),
+        events: events.len(),
+        pty,
+        emulator,
+        serialization: start.elapsed(),
+    };
+
+    print!("{}", report.format());
+
+    Ok(())
+}
+
+impl Report {
+    fn format(&self) -> String {
+        let stage = |name: &str, time: Duration| {
+            let rate = self.bytes as f64 / time.as_secs_f64().max(f64::EPSILON) / 1024.0 / 1024.0;
+
+            format!(
+                "{name:<15}{rate:>10.1} MiB/s  ({:.3}s)\n",
+                time.as_secs_f64()
+            )
+        };
+
+        format!(
+            "{} bytes of output, {} events\n{}{}{}",
+            self.bytes,
+            self.events,
+            stage("pty", self.pty),
+            stage("emulator", self.emulator),
+            stage("serialization", self.serialization),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Report;
+    use std::time::Duration;
+
+    #[test]
+    fn format_report() {
+        let report = Report {
+            bytes: 4 * 1024 * 1024,
+            events: 10,
+            pty: Duration::from_secs(2),
+            emulator: Duration::from_millis(500),
+            serialization: Duration::from_secs(4),
+        };
+
+        assert_eq!(
+            report.format(),
+            "4194304 bytes of output, 10 events\n\
+             pty                   2.0 MiB/s  (2.000s)\n\
+             emulator              8.0 MiB/s  (0.500s)\n\
+             serialization         1.0 MiB/s  (4.000s)\n"
+        );
+    }
+}
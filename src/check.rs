@@ -0,0 +1,204 @@
+use crate::api::{self, stdio};
+use crate::cli::CheckArgs;
+use crate::command::{Command, Region, Reply};
+use crate::diff;
+use crate::session::{self, Event};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::Stream;
+use std::fs;
+use std::path::Path;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::StreamExt;
+
+/// Step of a check script.
+#[derive(Debug)]
+enum Step {
+    Command(Command),
+    Checkpoint(String),
+}
+
+/// Runs a check script, comparing the screen at each checkpoint with a golden
+/// file.
+///
+/// The script consists of STDIO API commands, one per line, and checkpoints:
+/// `{ "type": "check", "name": "..." }`. Fails with a diff when any of the
+/// screens doesn't match.
+pub async fn run(
+    args: CheckArgs,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let script = fs::read_to_string(&args.script)
+        .with_context(|| format!("cannot read script {}", args.script.display()))?;
+
+    let steps = parse_script(&script)?;
+    let mut events = pin!(session::stream(&clients_tx).await?);
+    let mut checkpoints = 0;
+    let mut failures = 0;
+
+    if args.update {
+        fs::create_dir_all(&args.golden)?;
+    }
+
+    for step in steps {
+        match step {
+            Step::Command(command) => {
+                let (reply_tx, _) = mpsc::unbounded_channel();
+                api::submit(&command_tx, command, reply_tx).await?;
+            }
+
+            Step::Checkpoint(name) => {
+                checkpoints += 1;
+                settle(&mut events, args.settle, args.settle_timeout).await;
+                let screen = screen_text(&command_tx).await?;
+                let path = args.golden.join(format!("{name}.txt"));
+
+                if args.update {
+                    fs::write(&path, &screen)?;
+                    println!("updated {name}");
+                } else if let Some(diff) = compare(&path, &screen)? {
+                    failures += 1;
+                    println!("FAIL {name}\n{diff}");
+                } else {
+                    println!("ok {name}");
+                }
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{failures} of {checkpoints} checkpoints failed");
+    }
+
+    Ok(())
+}
+
+fn parse_script(script: &str) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    let mut checkpoints = 0;
+
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let value: serde_json::Value =
+            serde_json::from_str(line).with_context(|| format!("script line {}", i + 1))?;
+
+        if value["type"] == "check" {
+            checkpoints += 1;
+
+            let name = match value["name"].as_str() {
+                Some(name) => name.to_owned(),
+                None => format!("{checkpoints:02}"),
+            };
+
+            steps.push(Step::Checkpoint(name));
+        } else {
+            let command =
+                stdio::parse_line(line).map_err(|e| anyhow!("script line {}: {e}", i + 1))?;
+            steps.push(Step::Command(command));
+        }
+    }
+
+    Ok(steps)
+}
+
+/// Waits until there's no output for `period`, but no longer than `timeout`.
+pub async fn settle<S>(events: &mut S, period: Duration, timeout: Duration)
+where
+    S: Stream<Item = Result<Event, BroadcastStreamRecvError>> + Unpin,
+{
+    let give_up = Instant::now() + timeout;
+    let mut deadline = Instant::now() + period;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(Ok(Event::Output(_, _, _, _))) => deadline = Instant::now() + period,
+                    Some(_) => (),
+                    None => return,
+                }
+            }
+
+            _ = time::sleep_until(deadline.min(give_up)) => return,
+        }
+    }
+}
+
+pub async fn screen_text(command_tx: &mpsc::Sender<(Command, Reply)>) -> Result<String> {
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+
+    command_tx
+        .send((Command::GetText(Region::default()), reply_tx))
+        .await?;
+
+    let reply = reply_rx
+        .recv()
+        .await
+        .ok_or_else(|| anyhow!("session ended before the checkpoint"))?;
+
+    Ok(normalize(
+        reply["data"]["text"].as_str().unwrap_or_default(),
+    ))
+}
+
+/// Trims trailing whitespace from lines and trailing empty lines.
+fn normalize(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    let len = lines.len() - lines.iter().rev().take_while(|l| l.is_empty()).count();
+
+    lines[..len].iter().map(|l| format!("{l}\n")).collect()
+}
+
+/// Compares the screen with the golden file, returning a diff on mismatch.
+fn compare(path: &Path, screen: &str) -> Result<Option<String>> {
+    let golden = match fs::read_to_string(path) {
+        Ok(golden) => golden,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some(format!(
+                "  golden file {} doesn't exist (run with --update to create it)",
+                path.display()
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let diffs = diff::rows(&normalize(&golden), screen);
+
+    Ok((!diffs.is_empty()).then(|| diff::report(&diffs)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize, parse_script, Step};
+
+    #[test]
+    fn parse() {
+        let script = r#"
+            # log in
+            { "type": "sendKeys", "keys": ["login", "Enter"] }
+            { "type": "check" }
+            { "type": "check", "name": "prompt" }
+        "#;
+
+        let steps = parse_script(script).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert!(matches!(&steps[1], Step::Checkpoint(name) if name == "01"));
+        assert!(matches!(&steps[2], Step::Checkpoint(name) if name == "prompt"));
+
+        parse_script(r#"{ "type": "bogus" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn compare_screens() {
+        assert_eq!(normalize("a  \nb\n  \n\n"), "a\nb\n");
+    }
+}
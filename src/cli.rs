@@ -1,15 +1,24 @@
-use crate::api::Subscription;
+use crate::api::{fields::Fields, filter::Filter, queue, Subscription};
+use crate::keymap::{self, Keymap};
+use crate::namespace::Namespace;
+use crate::recorder::Theme;
+use crate::scenario::{self, Scenario};
+use crate::seccomp::{self, Profile};
+use crate::{bench, locale, redact, serial, utf8};
 use anyhow::bail;
-use clap::Parser;
+use clap::builder::RangedU64ValueParser;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use nix::pty;
-use std::{fmt::Display, net::SocketAddr, ops::Deref, str::FromStr};
+use regex::Regex;
+use std::{fmt::Display, net::SocketAddr, ops::Deref, path::PathBuf, str::FromStr, time::Duration};
 
 #[derive(Debug, Parser)]
 #[clap(version, about)]
-#[command(name = "ht")]
+#[command(name = "ht", disable_help_subcommand = true)]
 pub struct Cli {
-    /// Terminal size
-    #[arg(long, value_name = "COLSxROWS", default_value = Some("120x40"))]
+    /// Terminal size, optionally with the size of the window in pixels
+    #[arg(long, value_name = "COLSxROWS[:WIDTHxHEIGHT]", default_value = Some("120x40"))]
     pub size: Size,
 
     /// Command to run inside the terminal
@@ -20,14 +29,418 @@ pub struct Cli {
     #[arg(short, long, value_name = "LISTEN_ADDR", default_missing_value = "127.0.0.1:0", num_args = 0..=1)]
     pub listen: Option<SocketAddr>,
 
+    /// Enable gRPC server
+    #[arg(long, value_name = "LISTEN_ADDR", default_missing_value = "127.0.0.1:0", num_args = 0..=1)]
+    pub grpc: Option<SocketAddr>,
+
+    /// Connect to a hub at the WebSocket URL, streaming events to it and accepting commands
+    #[arg(long, value_name = "URL", value_parser = parse_ws_url)]
+    pub connect: Option<String>,
+
+    /// Publish events to a NATS subject or Redis stream (nats://HOST/PREFIX or redis://HOST/PREFIX)
+    #[arg(long, value_name = "URL", value_parser = parse_publish_url)]
+    pub publish: Option<String>,
+
+    /// Deliver events to a file or a webhook (file:///PATH or http://HOST/PATH), can be repeated
+    #[arg(long, value_name = "URL", value_parser = crate::api::sink::parse_url)]
+    pub sink: Vec<String>,
+
+    /// Session ID reported to the hub and used for publishing (random by default)
+    #[arg(long, value_name = "ID")]
+    pub session_id: Option<String>,
+
+    /// Name of the session, included in every event
+    #[arg(long, value_name = "NAME")]
+    pub name: Option<String>,
+
+    /// Label of the session included in every event, can be repeated (e.g. team=infra)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+    pub label: Vec<(String, String)>,
+
+    /// File with custom key names for sendKeys (YAML or JSON mapping of names to sequences or key lists)
+    #[arg(long, value_name = "PATH", value_parser = keymap::load)]
+    pub keymap: Option<Keymap>,
+
+    /// Message format of the STDIO API
+    #[arg(long, value_enum, default_value_t = Protocol::Json)]
+    pub protocol: Protocol,
+
     /// Subscribe to events
     #[arg(long, value_name = "EVENTS")]
     pub subscribe: Option<Subscription>,
+
+    /// Deliver only events meeting the conditions (e.g. 'output.seq ~ "error"; resize.cols > 100')
+    #[arg(long, value_name = "CONDITIONS")]
+    pub filter: Option<Filter>,
+
+    /// Deliver only selected fields of event data (e.g. snapshot.text,-snapshot.seq)
+    #[arg(long, value_name = "FIELDS")]
+    pub fields: Option<Fields>,
+
+    /// Number of events queued for delivery to STDOUT
+    #[arg(long, value_name = "SIZE", default_value_t = queue::DEFAULT_SIZE, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub queue: usize,
+
+    /// What happens when the event queue is full: drop-oldest, drop-newest or disconnect
+    #[arg(long, value_name = "POLICY", default_value = "drop-oldest")]
+    pub lag: queue::Policy,
+
+    /// Emit heartbeat events at the given interval (e.g. 30s, 500ms)
+    #[arg(long, value_name = "INTERVAL", value_parser = parse_duration)]
+    pub heartbeat: Option<Duration>,
+
+    /// Terminate the child process when it produces no output for the given period
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub watchdog: Option<Duration>,
+
+    /// Stop emitting output events after the child produced this many bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_output_bytes: Option<usize>,
+
+    /// What to do once --max-output-bytes is exceeded
+    #[arg(long, value_enum, default_value_t = OutputLimitAction::Stop, requires = "max_output_bytes")]
+    pub on_output_limit: OutputLimitAction,
+
+    /// Keep the screen history of the given period (e.g. 10m) for snapshotAt
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub history: Option<Duration>,
+
+    /// Keep this many bytes of the most recent output for getOutput
+    #[arg(long, value_name = "BYTES")]
+    pub output_buffer: Option<usize>,
+
+    /// Let the child resize the terminal with XTWINOPS (CSI 8 ; rows ; cols t)
+    #[arg(long)]
+    pub allow_resize: bool,
+
+    /// Answer the app's clipboard read requests (OSC 52) with the content set with setClipboard
+    #[arg(long)]
+    pub allow_clipboard_read: bool,
+
+    /// Reject mouse commands with an error while the app hasn't enabled mouse tracking
+    #[arg(long)]
+    pub strict_mouse: bool,
+
+    /// Write input to the child at no more than this many bytes per second, queuing the excess
+    #[arg(long, value_name = "BYTES", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_input_rate: Option<usize>,
+
+    /// Start the child with an empty environment (see --env-pass)
+    #[arg(long)]
+    pub env_clear: bool,
+
+    /// Keep environment variables matching a glob pattern when using --env-clear
+    #[arg(
+        long,
+        value_name = "GLOB",
+        value_delimiter = ',',
+        requires = "env_clear"
+    )]
+    pub env_pass: Vec<String>,
+
+    /// Set an environment variable for the child (repeatable)
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_label)]
+    pub env: Vec<(String, String)>,
+
+    /// File mode creation mask of the child, in octal (e.g. 077)
+    #[arg(long, value_name = "MASK", value_parser = parse_umask)]
+    pub umask: Option<u32>,
+
+    /// Don't let the child inherit ht's file descriptors other than stdin, stdout and stderr
+    #[arg(long)]
+    pub close_fds: bool,
+
+    /// Let the child inherit a file descriptor of ht (repeatable; overrides --close-fds)
+    #[arg(long, value_name = "FD")]
+    pub inherit_fd: Vec<i32>,
+
+    /// Start the child in a new session, without a controlling terminal
+    #[arg(long, requires = "no_pty")]
+    pub setsid: bool,
+
+    /// Put the child in an existing process group
+    #[arg(
+        long,
+        value_name = "PGID",
+        requires = "no_pty",
+        conflicts_with = "setsid"
+    )]
+    pub process_group: Option<i32>,
+
+    /// Isolate the child in new Linux namespaces (comma-separated)
+    #[arg(long, value_enum, value_name = "NAMESPACES", value_delimiter = ',')]
+    pub unshare: Vec<Namespace>,
+
+    /// Map ht's user to this user ID in a new user namespace (0 for root)
+    #[arg(long, value_name = "UID")]
+    pub map_user: Option<u32>,
+
+    /// Attach a seccomp filter to the child: the built-in "default" profile, or a profile file
+    #[arg(long, value_name = "PROFILE", value_parser = seccomp::load)]
+    pub seccomp: Option<Profile>,
+
+    /// Value of COLORTERM advertised to the child (empty to leave it unset)
+    #[arg(long, value_name = "VALUE", default_value = "truecolor")]
+    pub colorterm: String,
+
+    /// Run the command with plain pipes instead of a PTY
+    #[arg(long)]
+    pub no_pty: bool,
+
+    /// Attach to a pane of an existing tmux session instead of running a command
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["no_pty", "separate_stderr"])]
+    pub tmux: Option<String>,
+
+    /// Run the command (or a login shell by default) on a remote host over SSH
+    #[arg(long, value_name = "DESTINATION", conflicts_with_all = ["no_pty", "tmux"])]
+    pub ssh: Option<String>,
+
+    /// Attach to a serial device instead of running a command
+    #[arg(long, value_name = "DEVICE", conflicts_with_all = ["no_pty", "tmux", "ssh", "separate_stderr"])]
+    pub serial: Option<String>,
+
+    /// Baud rate of the serial device
+    #[arg(long, value_name = "RATE", default_value_t = 115200, value_parser = parse_baud, requires = "serial")]
+    pub baud: u32,
+
+    /// Give the child a separate pipe for stderr and emit its output as stderr events
+    #[arg(long)]
+    pub separate_stderr: bool,
+
+    /// How to handle output bytes that aren't valid UTF-8
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = utf8::Policy::Lossy)]
+    pub invalid_utf8: utf8::Policy,
+
+    /// Mask text matching the regex in the output (repeatable; with capture groups only the groups are masked)
+    #[arg(long, value_name = "REGEX")]
+    pub redact: Vec<Regex>,
+
+    /// Text replacing the redacted parts of the output
+    #[arg(long, value_name = "TEXT", default_value = redact::DEFAULT_MASK, requires = "redact")]
+    pub redact_mask: String,
+
+    /// Set LANG for the child
+    #[arg(long, value_name = "LOCALE", value_parser = parse_locale)]
+    pub lang: Option<String>,
+
+    /// Set an LC_* variable for the child (e.g. LC_TIME=C.UTF-8)
+    #[arg(long, value_name = "VAR=LOCALE", value_delimiter = ',', value_parser = parse_locale_var)]
+    pub lc: Vec<(String, String)>,
+
+    /// Don't pass LANG, LANGUAGE and LC_* variables from ht's environment to the child
+    #[arg(long)]
+    pub locale_clear: bool,
+
+    /// Fall back to a UTF-8 locale for the child when the environment's locale isn't UTF-8
+    #[arg(long)]
+    pub force_utf8: bool,
+
+    /// UTF-8 locale set for the child by --force-utf8
+    #[arg(skip)]
+    pub fallback_locale: Option<String>,
+
+    /// Directory ht was started in, where the child starts when ht runs in the background
+    #[arg(skip)]
+    pub start_dir: Option<PathBuf>,
+
+    /// Write the commands executed at the shell prompt to a shell script when the session ends
+    #[arg(long, value_name = "PATH")]
+    pub export_script: Option<PathBuf>,
+
+    /// Measure the throughput of the output path with a synthetic workload, or the given command
+    #[arg(long)]
+    pub bench: bool,
+
+    /// Record the session to a file in asciicast v2 format
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Continue the recording file if it exists, instead of overwriting it
+    #[arg(long, requires = "record")]
+    pub record_append: bool,
+
+    /// Limit idle time between events in the recording to the given period
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "record")]
+    pub idle_time_limit: Option<Duration>,
+
+    /// Title of the recording
+    #[arg(long, value_name = "TITLE", requires = "record")]
+    pub record_title: Option<String>,
+
+    /// Names of the child's environment variables to include in the recording
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        default_value = "SHELL,TERM",
+        requires = "record"
+    )]
+    pub record_env: Vec<String>,
+
+    /// Terminal theme of the recording
+    #[arg(long, value_name = "FG,BG,PALETTE", requires = "record")]
+    pub record_theme: Option<Theme>,
+
+    /// Append every received command to a file, along with its source and time
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Run in the background, controlled via the HTTP API only
+    #[arg(long, requires = "listen")]
+    pub daemon: bool,
+
+    /// Write the PID of the daemonized process to a file
+    #[arg(long, value_name = "PATH", requires = "daemon")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Refuse commands from all WebSocket clients, leaving the session to be watched only
+    #[arg(long, requires = "daemon")]
+    pub read_only_ws: bool,
+
+    #[command(subcommand)]
+    pub mode: Option<Mode>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Mode {
+    /// Run a script against the command and compare screens with golden files
+    Check(CheckArgs),
+
+    /// Run a scenario file and report its assertions
+    Test(TestArgs),
+
+    /// Attach the local terminal to a session running with --daemon
+    Attach(AttachArgs),
+
+    /// Compare two snapshots saved as JSON and report the differences
+    Diff(DiffArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DiffArgs {
+    /// Expected snapshot
+    #[arg(value_name = "A")]
+    pub a: PathBuf,
+
+    /// Actual snapshot
+    #[arg(value_name = "B")]
+    pub b: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct AttachArgs {
+    /// HTTP listen address of the session
+    #[arg(value_name = "ADDR")]
+    pub addr: SocketAddr,
+
+    /// Only show the session, without passing keys to it or resizing it
+    #[arg(long)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CheckArgs {
+    /// Script with commands (JSON lines) and checkpoints
+    #[arg(long, value_name = "PATH")]
+    pub script: PathBuf,
+
+    /// Directory with golden files
+    #[arg(long, value_name = "DIR")]
+    pub golden: PathBuf,
+
+    /// Write the screens to the golden files instead of comparing them
+    #[arg(long)]
+    pub update: bool,
+
+    /// How long the output must stay quiet before a checkpoint's screen is taken
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "200ms")]
+    pub settle: Duration,
+
+    /// Maximum time to wait for the output to settle at a checkpoint
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10s")]
+    pub settle_timeout: Duration,
+
+    /// Command to check
+    #[arg(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TestArgs {
+    /// Scenario file (YAML)
+    #[arg(value_name = "SCENARIO", value_parser = scenario::load)]
+    pub scenario: Scenario,
+
+    /// Report format
+    #[arg(long, value_enum, default_value_t = ReportFormat::Tap)]
+    pub format: ReportFormat,
+
+    /// Resolve sleeps against a virtual clock, waiting only for the output to settle
+    #[arg(long)]
+    pub virtual_time: bool,
+
+    /// How long the output must stay quiet to end a sleep with --virtual-time
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "100ms", requires = "virtual_time")]
+    pub settle: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    /// Test Anything Protocol
+    Tap,
+    /// JUnit XML
+    Junit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Protocol {
+    /// Plain JSON commands, replies and events, one per line
+    Json,
+    /// JSON-RPC 2.0 requests and responses, events as notifications
+    Jsonrpc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputLimitAction {
+    /// Keep the child running, but stop emitting output events
+    Stop,
+    /// Terminate the child process
+    Kill,
 }
 
 impl Cli {
     pub fn new() -> Self {
-        Cli::parse()
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        let default_command = matches.value_source("command") == Some(ValueSource::DefaultValue);
+
+        // the remote login shell is used instead of the default command
+        if cli.ssh.is_some() && default_command {
+            cli.command.clear();
+        }
+
+        if cli.bench && default_command {
+            cli.command = vec![bench::WORKLOAD.to_owned()];
+        }
+
+        match &cli.mode {
+            Some(Mode::Check(args)) => {
+                cli.command = args.command.clone();
+            }
+
+            Some(Mode::Test(args)) => {
+                cli.command = vec![args.scenario.command.clone()];
+
+                if let Some(size) = &args.scenario.size {
+                    cli.size = size.clone();
+                }
+            }
+
+            Some(Mode::Attach(_)) | Some(Mode::Diff(_)) | None => (),
+        }
+
+        cli
     }
 }
 
@@ -48,28 +461,38 @@ impl FromStr for Size {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
-        match s.split_once('x') {
-            Some((cols, rows)) => {
-                let cols: u16 = cols.parse()?;
-                let rows: u16 = rows.parse()?;
-
-                let winsize = pty::Winsize {
-                    ws_col: cols,
-                    ws_row: rows,
-                    ws_xpixel: 0,
-                    ws_ypixel: 0,
-                };
-
-                Ok(Size(winsize))
-            }
+        let (cells, pixels) = match s.split_once(':') {
+            Some((cells, pixels)) => (cells, Some(pixels)),
+            None => (s, None),
+        };
 
-            None => {
-                bail!("invalid size format: {s}");
-            }
-        }
+        let Some((cols, rows)) = parse_dimensions(cells) else {
+            bail!("invalid size format: {s}");
+        };
+
+        let (xpixel, ypixel) = match pixels.map(parse_dimensions) {
+            Some(Some(pixels)) => pixels,
+            Some(None) => bail!("invalid size format: {s}"),
+            None => (0, 0),
+        };
+
+        let winsize = pty::Winsize {
+            ws_col: cols,
+            ws_row: rows,
+            ws_xpixel: xpixel,
+            ws_ypixel: ypixel,
+        };
+
+        Ok(Size(winsize))
     }
 }
 
+fn parse_dimensions(s: &str) -> Option<(u16, u16)> {
+    let (width, height) = s.split_once('x')?;
+
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
 impl Deref for Size {
     type Target = pty::Winsize;
 
@@ -80,6 +503,186 @@ impl Deref for Size {
 
 impl Display for Size {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}x{}", self.0.ws_col, self.0.ws_row)
+        write!(f, "{}x{}", self.0.ws_col, self.0.ws_row)?;
+
+        if self.0.ws_xpixel != 0 || self.0.ws_ypixel != 0 {
+            write!(f, ":{}x{}", self.0.ws_xpixel, self.0.ws_ypixel)?;
+        }
+
+        Ok(())
+    }
+}
+
+const LOCALE_VARS: [&str; 13] = [
+    "LC_ALL",
+    "LC_ADDRESS",
+    "LC_COLLATE",
+    "LC_CTYPE",
+    "LC_IDENTIFICATION",
+    "LC_MEASUREMENT",
+    "LC_MESSAGES",
+    "LC_MONETARY",
+    "LC_NAME",
+    "LC_NUMERIC",
+    "LC_PAPER",
+    "LC_TELEPHONE",
+    "LC_TIME",
+];
+
+fn parse_ws_url(s: &str) -> anyhow::Result<String> {
+    if !s.starts_with("ws://") && !s.starts_with("wss://") {
+        bail!("expected ws:// or wss:// URL");
+    }
+
+    Ok(s.to_owned())
+}
+
+fn parse_publish_url(s: &str) -> anyhow::Result<String> {
+    if !s.starts_with("nats://") && !s.starts_with("redis://") {
+        bail!("expected nats:// or redis:// URL");
+    }
+
+    Ok(s.to_owned())
+}
+
+fn parse_baud(s: &str) -> anyhow::Result<u32> {
+    let baud = s.parse()?;
+
+    if serial::baud_rate(baud).is_none() {
+        bail!("unsupported baud rate: {baud}");
+    }
+
+    Ok(baud)
+}
+
+fn parse_locale(s: &str) -> anyhow::Result<String> {
+    if !locale::is_available(s) {
+        bail!("locale {s} is not available on this system (see `locale -a`)");
+    }
+
+    Ok(s.to_owned())
+}
+
+/// Parses `LC_<CATEGORY>=<LOCALE>`.
+fn parse_locale_var(s: &str) -> anyhow::Result<(String, String)> {
+    let Some((name, value)) = s.split_once('=') else {
+        bail!("expected VAR=LOCALE: {s}");
+    };
+
+    if !LOCALE_VARS.contains(&name) {
+        bail!("invalid locale variable: {name}");
+    }
+
+    Ok((name.to_owned(), parse_locale(value)?))
+}
+
+/// Parses an octal file mode creation mask.
+fn parse_umask(s: &str) -> anyhow::Result<u32> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => bail!("invalid umask: {s}"),
+    }
+}
+
+/// Parses `KEY=VALUE`.
+fn parse_label(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => bail!("expected KEY=VALUE: {s}"),
+    }
+}
+
+/// Parses a duration such as `500ms`, `30s`, `5m` or `1h`. A bare number means seconds.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (value, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+
+    let value: f64 = value.parse()?;
+
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => bail!("invalid duration unit: {unit}"),
+    };
+
+    Ok(Duration::try_from_secs_f64(secs)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_duration, parse_label, parse_locale_var, parse_umask, Size};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_sizes() {
+        let size: Size = "80x24".parse().unwrap();
+        assert_eq!((size.cols(), size.rows()), (80, 24));
+        assert_eq!((size.ws_xpixel, size.ws_ypixel), (0, 0));
+
+        let size: Size = "80x24:640x480".parse().unwrap();
+        assert_eq!((size.ws_xpixel, size.ws_ypixel), (640, 480));
+        assert_eq!(size.to_string(), "80x24:640x480");
+
+        "80".parse::<Size>().expect_err("should fail");
+        "80x24:640".parse::<Size>().expect_err("should fail");
+        "80x24:".parse::<Size>().expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        parse_duration("").expect_err("should fail");
+        parse_duration("5x").expect_err("should fail");
+        parse_duration("-1s").expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_locale_vars() {
+        assert_eq!(
+            parse_locale_var("LC_TIME=C").unwrap(),
+            ("LC_TIME".to_owned(), "C".to_owned())
+        );
+
+        parse_locale_var("LC_TIME").expect_err("should fail");
+        parse_locale_var("PATH=C").expect_err("should fail");
+        parse_locale_var("LC_TIME=xx_XX.NOPE").expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_labels() {
+        assert_eq!(
+            parse_label("team=infra").unwrap(),
+            ("team".to_owned(), "infra".to_owned())
+        );
+
+        assert_eq!(
+            parse_label("url=a=b").unwrap(),
+            ("url".to_owned(), "a=b".to_owned())
+        );
+
+        parse_label("team").expect_err("should fail");
+        parse_label("=infra").expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_umasks() {
+        assert_eq!(parse_umask("077").unwrap(), 0o77);
+        assert_eq!(parse_umask("0022").unwrap(), 0o22);
+
+        parse_umask("8").expect_err("should fail");
+        parse_umask("1000").expect_err("should fail");
+        parse_umask("").expect_err("should fail");
     }
 }
@@ -1,10 +1,165 @@
+use crate::history::Moment;
+use crate::render;
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
 #[derive(Debug)]
 pub enum Command {
-    Input(Vec<InputSeq>),
+    /// Input, and whether it's a secret masked in the output
+    Input(Vec<InputSeq>, bool),
+    Paste(String),
+    /// Bytes written to the child as they are
+    InputRaw(Vec<u8>),
+    Flush,
+    Sync,
     Mouse(MouseEvent),
     MouseClick(MouseEvent), // Convenience: sends press then release
-    Snapshot,
-    Resize(usize, usize),
+    /// Whether the terminal gained (or lost) focus
+    Focus(bool),
+    Snapshot(Option<render::Format>, bool, Trim),
+    SnapshotAt(Moment),
+    /// Byte range of the buffered output, missing bounds meaning all of it
+    GetOutput(Option<u64>, Option<u64>),
+    Resize(usize, usize, u16, u16),
+    GetLine(usize),
+    GetColumns(usize),
+    /// Trim, and the viewport offset when given instead of the client's one
+    GetView(Trim, Option<usize>),
+    Scroll(Scroll),
+    /// Whether the client's viewport follows the live screen
+    Follow(bool),
+    GetText(Region),
+    GetWord(usize, usize, Option<String>),
+    /// New selection, or none to clear it
+    SetSelection(Option<Selection>),
+    GetSelection,
+    /// Content answering the application's clipboard read requests
+    SetClipboard(String),
+    DumpState,
+    GetProcesses,
+    Capabilities,
+    Schema,
+    Reset(bool),
+    ClearScrollback(bool),
+    Annotate(serde_json::Value),
+    PauseRecording(bool),
+    /// Name of the input macro to record
+    RecordMacro(String),
+    StopMacro,
+    /// Name of the macro, and whether to keep the delays between its steps
+    PlayMacro(String, bool),
+    Suspend(bool),
+    Signal(Signal, SignalTarget),
+    Exec(String, Option<Duration>),
+    WaitForStable(Duration, Duration),
+    Close,
+    /// Pause before the commands that follow, performed by the API layer
+    Sleep(Duration),
+}
+
+/// Channel delivering replies to query commands back to the API client that
+/// sent them.
+pub type Reply = mpsc::UnboundedSender<serde_json::Value>;
+
+/// Rectangular area of the screen, 0-indexed. Missing width and height extend
+/// the region to the right and bottom edges of the screen.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub w: Option<usize>,
+    pub h: Option<usize>,
+}
+
+/// How the screen text is trimmed in `takeSnapshot` and `getView`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Trim {
+    /// Strip trailing whitespace of each line, and trailing blank lines
+    pub trailing: bool,
+    /// Replace runs of blank lines with a single one
+    pub collapse_blank: bool,
+}
+
+impl Trim {
+    pub fn apply(&self, lines: Vec<String>) -> Vec<String> {
+        let mut lines = lines;
+
+        if self.trailing {
+            for line in &mut lines {
+                line.truncate(line.trim_end().len());
+            }
+
+            let len = lines.len() - lines.iter().rev().take_while(|l| l.is_empty()).count();
+            lines.truncate(len);
+        }
+
+        if self.collapse_blank {
+            lines.dedup_by(|l, prev| l.trim().is_empty() && prev.trim().is_empty());
+        }
+
+        lines
+    }
+}
+
+/// Movement of the client's viewport over the scrollback, in lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scroll {
+    /// Towards older lines, by a screen height when not given
+    Up(Option<usize>),
+    /// Towards newer lines, by a screen height when not given
+    Down(Option<usize>),
+    /// To this many lines above the live screen
+    To(usize),
+}
+
+/// Request of the live preview about its viewport, answered like the
+/// commands of the same name. Unlike commands, these are accepted from every
+/// preview, as they don't change the session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewRequest {
+    GetView,
+    Scroll(Scroll),
+    /// Whether the viewport follows the live screen
+    Follow(bool),
+}
+
+/// Part of the screen selected with `setSelection`, between the `anchor` and
+/// `extent` positions, given as (col, row), 0-indexed and inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    pub anchor: (usize, usize),
+    pub extent: (usize, usize),
+    pub mode: SelectionMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionMode {
+    /// Text flowing from one position to the other, in reading order
+    Normal,
+    /// Whole lines
+    Line,
+    /// Rectangle with the positions at its corners
+    Block,
+}
+
+impl SelectionMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionMode::Normal => "normal",
+            SelectionMode::Line => "line",
+            SelectionMode::Block => "block",
+        }
+    }
+}
+
+/// Recipient of a signal sent with the `signal` command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalTarget {
+    /// The child process only
+    Child,
+    /// The child's whole process group
+    Group,
 }
 
 #[derive(Debug, PartialEq)]
@@ -23,6 +178,33 @@ pub fn seqs_to_bytes(seqs: &[InputSeq], app_mode: bool) -> Vec<u8> {
     bytes
 }
 
+/// Returns the printable parts of the input, i.e. the text the terminal would
+/// echo back. The text of consecutive keys is joined, as they're typed one
+/// after another, up to a control character or a cursor key.
+pub fn seqs_text(seqs: &[InputSeq]) -> Vec<String> {
+    let mut parts = vec![String::new()];
+
+    for seq in seqs {
+        match seq {
+            InputSeq::Standard(seq) => {
+                for c in seq.chars() {
+                    if c.is_control() {
+                        parts.push(String::new());
+                    } else {
+                        parts.last_mut().unwrap().push(c);
+                    }
+                }
+            }
+
+            InputSeq::Cursor(..) => parts.push(String::new()),
+        }
+    }
+
+    parts.retain(|part| !part.is_empty());
+
+    parts
+}
+
 fn seq_as_bytes(seq: &InputSeq, app_mode: bool) -> &[u8] {
     match (seq, app_mode) {
         (InputSeq::Standard(seq), _) => seq.as_bytes(),
@@ -97,3 +279,28 @@ pub fn mouse_to_bytes(event: &MouseEvent) -> Vec<u8> {
 
     format!("\x1b[<{};{};{}{}", btn, event.col, event.row, suffix).into_bytes()
 }
+
+#[cfg(test)]
+mod test {
+    use super::{seqs_text, InputSeq};
+
+    #[test]
+    fn input_text() {
+        let key = |s: &str| InputSeq::Standard(s.to_owned());
+        let cursor = InputSeq::Cursor("\x1b[A".to_owned(), "\x1bOA".to_owned());
+
+        let seqs = [
+            key("p"),
+            key("4ss"),
+            key(" "),
+            key("w0rd"),
+            key("\r"),
+            key("ab\x03cd"),
+            cursor,
+            key("ef"),
+        ];
+
+        assert_eq!(seqs_text(&seqs), ["p4ss w0rd", "ab", "cd", "ef"]);
+        assert!(seqs_text(&[key("\r")]).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{self, FcntlArg, FdFlag, OFlag};
+use nix::sys::stat::{self, Mode};
+use nix::unistd::{self, ForkResult, Pid};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+
+/// What the background process sends its parent once the session has started.
+const READY: &str = "ready";
+
+/// Write end of the pipe to the parent, until readiness (or failure) is reported.
+static STATUS_PIPE: Mutex<Option<OwnedFd>> = Mutex::new(None);
+
+/// Forks into the background.
+///
+/// Must be called before the async runtime is started, as forking a process
+/// with multiple threads leaves the child with only the calling thread.
+/// The child leaves the current directory for `/` and resets umask. The
+/// parent waits for the child to report with `ready` (or `failed`), then
+/// writes the child's PID to `pid_file` (if given) and exits, with a non-zero
+/// status when the child failed to start.
+pub fn daemonize(pid_file: Option<&Path>) -> Result<()> {
+    let (read_fd, write_fd) = unistd::pipe()?;
+    // keep the write end from leaking into the session's child
+    fcntl::fcntl(write_fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+    match unsafe { unistd::fork() }? {
+        ForkResult::Parent { child } => {
+            drop(write_fd);
+
+            if let Err(e) = wait_ready(File::from(read_fd)) {
+                eprintln!("ht failed to start in the background: {e}");
+                process::exit(1);
+            }
+
+            if let Some(path) = pid_file {
+                write_pid_file(path, child)?;
+            }
+
+            eprintln!("running in background with PID {child}");
+            process::exit(0);
+        }
+
+        ForkResult::Child => {
+            drop(read_fd);
+            unistd::setsid()?;
+            unistd::chdir("/")?;
+            stat::umask(Mode::from_bits_truncate(0o022));
+            redirect_stdio()?;
+            *STATUS_PIPE.lock().unwrap() = Some(write_fd);
+
+            Ok(())
+        }
+    }
+}
+
+/// Tells the parent the session has started, letting it exit.
+pub fn ready() {
+    report(READY);
+}
+
+/// Tells the parent the session failed to start, and why.
+pub fn failed(error: &anyhow::Error) {
+    report(&format!("{error:#}"));
+}
+
+fn report(status: &str) {
+    if let Some(fd) = STATUS_PIPE.lock().unwrap().take() {
+        let _ = writeln!(File::from(fd), "{}", status.replace('\n', " "));
+    }
+}
+
+fn wait_ready(pipe: impl Read) -> Result<()> {
+    let mut status = String::new();
+    BufReader::new(pipe).read_line(&mut status)?;
+
+    match status.trim_end() {
+        READY => Ok(()),
+        "" => bail!("the background process exited"),
+        error => bail!("{error}"),
+    }
+}
+
+pub fn remove_pid_file(pid_file: &Path) {
+    let _ = fs::remove_file(pid_file);
+}
+
+fn write_pid_file(path: &Path, pid: Pid) -> Result<()> {
+    fs::write(path, format!("{pid}\n"))
+        .with_context(|| format!("cannot write PID file {}", path.display()))
+}
+
+fn redirect_stdio() -> Result<()> {
+    let null = fcntl::open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+
+    for fd in 0..=2 {
+        unistd::dup2(null, fd)?;
+    }
+
+    if null > 2 {
+        unistd::close(null)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::wait_ready;
+
+    #[test]
+    fn readiness() {
+        assert!(wait_ready("ready\n".as_bytes()).is_ok());
+
+        let error = wait_ready("cannot bind 127.0.0.1:9999\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "cannot bind 127.0.0.1:9999");
+
+        let error = wait_ready("".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "the background process exited");
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Row of the screen which differs between two snapshots.
+#[derive(Debug, PartialEq)]
+pub struct RowDiff {
+    pub row: usize,
+    /// First and last differing column, 0-indexed
+    pub columns: (usize, usize),
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Compares screens row by row, locating the differing columns in each row.
+pub fn rows(expected: &str, actual: &str) -> Vec<RowDiff> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut diffs = Vec::new();
+
+    for row in 0..expected.len().max(actual.len()) {
+        let e = expected.get(row).copied().unwrap_or_default();
+        let a = actual.get(row).copied().unwrap_or_default();
+
+        if e == a {
+            continue;
+        }
+
+        let e_chars: Vec<char> = e.chars().collect();
+        let a_chars: Vec<char> = a.chars().collect();
+        let len = e_chars.len().max(a_chars.len());
+        let differs = |col: &usize| e_chars.get(*col) != a_chars.get(*col);
+        let first = (0..len).find(differs).unwrap_or_default();
+        let last = (0..len).rev().find(differs).unwrap_or_default();
+
+        diffs.push(RowDiff {
+            row,
+            columns: (first, last),
+            expected: e.to_owned(),
+            actual: a.to_owned(),
+        });
+    }
+
+    diffs
+}
+
+/// Formats the differences, marking the differing columns under each row.
+pub fn report(diffs: &[RowDiff]) -> String {
+    let mut out = String::new();
+
+    for diff in diffs {
+        let (first, last) = diff.columns;
+
+        let columns = if first == last {
+            format!("col {first}")
+        } else {
+            format!("cols {first}-{last}")
+        };
+
+        out.push_str(&format!(
+            "  row {}, {columns}:\n    - {}\n    + {}\n      {}{}\n",
+            diff.row,
+            diff.expected,
+            diff.actual,
+            " ".repeat(first),
+            "^".repeat(last - first + 1),
+        ));
+    }
+
+    out
+}
+
+/// Compares two snapshots saved as JSON (`snapshot` events, or their data),
+/// printing the differences in size, cursor position and screen text. Fails
+/// when the snapshots differ.
+pub fn run(a: &Path, b: &Path) -> Result<()> {
+    let a = load(a)?;
+    let b = load(b)?;
+    let report = compare(&a, &b);
+
+    if !report.is_empty() {
+        print!("{report}");
+        bail!("snapshots differ");
+    }
+
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Value> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("cannot read snapshot {}", path.display()))?;
+
+    let value: Value = serde_json::from_str(&json)
+        .with_context(|| format!("invalid snapshot {}", path.display()))?;
+
+    let snapshot = match value.get("data") {
+        Some(data) => data.clone(),
+        None => value,
+    };
+
+    if !snapshot["text"].is_string() {
+        bail!("snapshot {} has no text", path.display());
+    }
+
+    Ok(snapshot)
+}
+
+fn compare(a: &Value, b: &Value) -> String {
+    let mut out = String::new();
+    let size = |s: &Value| format!("{}x{}", s["cols"], s["rows"]);
+    let cursor = |s: &Value| format!("({}, {})", s["cursor"]["col"], s["cursor"]["row"]);
+
+    if size(a) != size(b) {
+        out.push_str(&format!("size: {} -> {}\n", size(a), size(b)));
+    }
+
+    if cursor(a) != cursor(b) {
+        out.push_str(&format!("cursor: {} -> {}\n", cursor(a), cursor(b)));
+    }
+
+    let diffs = rows(
+        a["text"].as_str().unwrap_or_default(),
+        b["text"].as_str().unwrap_or_default(),
+    );
+
+    if !diffs.is_empty() {
+        out.push_str("text:\n");
+        out.push_str(&report(&diffs));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, report, rows, RowDiff};
+    use serde_json::json;
+
+    #[test]
+    fn row_diffs() {
+        assert_eq!(rows("a\nb\n", "a\nb\n"), vec![]);
+
+        assert_eq!(
+            rows("hello world\n", "hello there\n"),
+            vec![RowDiff {
+                row: 0,
+                columns: (6, 10),
+                expected: "hello world".to_owned(),
+                actual: "hello there".to_owned(),
+            }]
+        );
+
+        assert_eq!(
+            report(&rows("ab\nc\n", "ax\nc\nd\n")),
+            "  row 0, col 1:\n    - ab\n    + ax\n       ^\n\
+             \x20 row 2, col 0:\n    - \n    + d\n      ^\n"
+        );
+    }
+
+    #[test]
+    fn compare_snapshots() {
+        let a = json!({ "cols": 4, "rows": 1, "text": "ab  ", "cursor": { "col": 2, "row": 0 } });
+        let b = json!({ "cols": 4, "rows": 1, "text": "abc ", "cursor": { "col": 3, "row": 0 } });
+
+        assert_eq!(compare(&a, &a), "");
+
+        assert_eq!(
+            compare(&a, &b),
+            "cursor: (2, 0) -> (3, 0)\ntext:\n  row 0, col 2:\n    - ab  \n    + abc \n        ^\n"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::env;
+use std::ffi::{CString, NulError, OsStr, OsString};
+use std::os::unix::ffi::OsStringExt;
+use tokio::process::Command;
+
+/// Environment of the child process.
+///
+/// By default the child inherits ht's environment. When cleared, only the
+/// variables matching one of the `pass` glob patterns are kept. Variables
+/// matching one of the `remove` patterns are never inherited.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    clear: bool,
+    pass: Vec<String>,
+    remove: Vec<String>,
+    vars: Vec<(String, String)>,
+}
+
+impl Environment {
+    pub fn new(clear: bool, pass: Vec<String>) -> Self {
+        Self {
+            clear,
+            pass,
+            remove: Vec::new(),
+            vars: Vec::new(),
+        }
+    }
+
+    /// Stops the child from inheriting variables matching a glob pattern.
+    pub fn remove<S: ToString>(&mut self, pattern: S) {
+        self.remove.push(pattern.to_string());
+    }
+
+    /// Sets a variable for the child, regardless of the filtering.
+    pub fn set<S: ToString>(&mut self, name: S, value: S) {
+        self.vars.push((name.to_string(), value.to_string()));
+    }
+
+    /// Returns the value of a variable as seen by the child.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self.vars.iter().rev().find(|(n, _)| n == name) {
+            Some((_, value)) => Some(value.clone()),
+            None if self.passes(name) => env::var(name).ok(),
+            None => None,
+        }
+    }
+
+    /// Returns the environment as `NAME=value` strings, for exec. Meant to be
+    /// built before forking, as the forked child can't allocate, and leaves
+    /// the environment of the current process alone.
+    pub fn envp(&self) -> Result<Vec<CString>, NulError> {
+        let mut vars: Vec<(OsString, OsString)> = env::vars_os()
+            .filter(|(name, _)| self.passes(&name.to_string_lossy()))
+            .collect();
+
+        for (name, value) in &self.vars {
+            vars.retain(|(n, _)| n != OsStr::new(name));
+            vars.push((name.into(), value.into()));
+        }
+
+        vars.into_iter()
+            .map(|(name, value)| {
+                let mut var = name.into_vec();
+                var.push(b'=');
+                var.extend(value.into_vec());
+                CString::new(var)
+            })
+            .collect()
+    }
+
+    pub fn apply_to(&self, command: &mut Command) {
+        if self.clear || !self.remove.is_empty() {
+            command.env_clear();
+            command.envs(env::vars_os().filter(|(name, _)| self.passes(&name.to_string_lossy())));
+        }
+
+        command.envs(self.vars.iter().cloned());
+    }
+
+    fn passes(&self, name: &str) -> bool {
+        (!self.clear || self.pass.iter().any(|pattern| glob_match(pattern, name)))
+            && !self.remove.iter().any(|pattern| glob_match(pattern, name))
+    }
+}
+
+/// Matches a name against a glob pattern supporting `*` and `?` wildcards.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp + 1;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob_match, Environment};
+
+    #[test]
+    fn get_filtered() {
+        let mut env = Environment::new(true, vec!["PA*".to_owned()]);
+        env.set("TERM", "dumb");
+
+        assert_eq!(env.get("TERM").as_deref(), Some("dumb"));
+        assert!(env.get("PATH").is_some());
+        assert_eq!(env.get("HOME"), None);
+    }
+
+    #[test]
+    fn get_removed() {
+        let mut env = Environment::new(false, Vec::new());
+        env.remove("LC_*");
+        env.set("LC_TIME", "C");
+
+        assert!(env.get("PATH").is_some());
+        assert_eq!(env.get("LC_TIME").as_deref(), Some("C"));
+        assert!(!env.passes("LC_ALL"));
+    }
+
+    #[test]
+    fn envp() {
+        let mut env = Environment::new(true, vec!["PATH".to_owned()]);
+        env.set("TERM", "dumb");
+        env.set("TERM", "xterm");
+        let envp = env.envp().unwrap();
+
+        assert!(envp.iter().any(|var| var.to_bytes().starts_with(b"PATH=")));
+        assert!(envp.iter().any(|var| var.to_bytes() == b"TERM=xterm"));
+        assert!(!envp.iter().any(|var| var.to_bytes() == b"TERM=dumb"));
+        assert!(!envp.iter().any(|var| var.to_bytes().starts_with(b"HOME=")));
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("PATH", "PATH"));
+        assert!(!glob_match("PATH", "PATHS"));
+        assert!(glob_match("LC_*", "LC_ALL"));
+        assert!(glob_match("LC_*", "LC_"));
+        assert!(!glob_match("LC_*", "LANG"));
+        assert!(glob_match("*", "ANYTHING"));
+        assert!(glob_match("*_PROXY", "HTTPS_PROXY"));
+        assert!(glob_match("X?Z", "XYZ"));
+        assert!(!glob_match("X?Z", "XZ"));
+        assert!(glob_match("A*B*C", "AxxBxxBxC"));
+        assert!(!glob_match("A*B*C", "AxxBxx"));
+    }
+}
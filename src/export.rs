@@ -0,0 +1,147 @@
+use crate::session::{self, Event};
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+/// Sequences switching to the alternate screen, used by full-screen apps.
+const ALTERNATE_SCREEN_SEQS: [&str; 3] = ["\x1b[?1049h", "\x1b[?1047h", "\x1b[?47h"];
+
+/// DEC private modes enabling mouse tracking.
+const MOUSE_TRACKING_MODES: [u16; 4] = [9, 1000, 1002, 1003];
+
+/// Command executed at the shell prompt.
+#[derive(Debug, PartialEq)]
+struct Step {
+    command: Option<String>,
+    status: Option<i32>,
+    /// Whether the command took over the terminal, e.g. an editor
+    interactive: bool,
+}
+
+/// Exports the commands executed in the session (detected from the shell's
+/// prompt marks) as a shell script, written to `path` when the session ends.
+/// Commands which can't be replayed unattended are left in as comments.
+pub async fn start(
+    path: PathBuf,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<impl Future<Output = Result<()>>> {
+    // fail early, rather than after the session, when the file can't be written
+    tokio::fs::File::create(&path)
+        .await
+        .with_context(|| format!("cannot create script file {}", path.display()))?;
+
+    Ok(export(path, clients_tx))
+}
+
+async fn export(path: PathBuf, clients_tx: mpsc::Sender<session::Client>) -> Result<()> {
+    let mut events = session::stream(&clients_tx).await?;
+    let mut steps = Vec::new();
+    let mut interactive = false;
+
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(Event::CommandStart(_)) => interactive = false,
+
+            Ok(Event::Output(_, _, data, _)) => {
+                interactive |= ALTERNATE_SCREEN_SEQS.iter().any(|seq| data.contains(seq));
+            }
+
+            Ok(Event::ModeChanged(_, mode, enabled)) => {
+                interactive |= enabled && MOUSE_TRACKING_MODES.contains(&mode);
+            }
+
+            Ok(Event::CommandExecuted(_, command, _, status)) => {
+                if command.as_ref().is_some_and(|c| c.trim().is_empty()) {
+                    continue;
+                }
+
+                steps.push(Step {
+                    command,
+                    status,
+                    interactive,
+                });
+
+                interactive = false;
+            }
+
+            Ok(_) => {}
+
+            Err(e) => eprintln!("script export: {e}"),
+        }
+    }
+
+    tokio::fs::write(&path, script(&steps))
+        .await
+        .with_context(|| format!("cannot write script file {}", path.display()))?;
+
+    Ok(())
+}
+
+fn script(steps: &[Step]) -> String {
+    let mut script = "#!/bin/sh\n# Commands executed in an ht session, in order.\n".to_owned();
+
+    for step in steps {
+        script.push('\n');
+
+        let Some(command) = &step.command else {
+            script.push_str("# (a command whose text wasn't captured)\n");
+            continue;
+        };
+
+        if let Some(status) = step.status.filter(|s| *s != 0) {
+            script.push_str(&format!("# exited with status {status}\n"));
+        }
+
+        if step.interactive {
+            script.push_str("# interactive step, to be done by hand:\n");
+
+            for line in command.lines() {
+                script.push_str(&format!("# {line}\n"));
+            }
+        } else {
+            script.push_str(command);
+            script.push('\n');
+        }
+    }
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{script, Step};
+
+    #[test]
+    fn script_of_steps() {
+        let step = |command: Option<&str>, status, interactive| Step {
+            command: command.map(|c| c.to_owned()),
+            status,
+            interactive,
+        };
+
+        let steps = [
+            step(Some("cd /tmp"), Some(0), false),
+            step(Some("make test"), Some(2), false),
+            step(Some("vim notes.txt"), None, true),
+            step(None, Some(0), false),
+        ];
+
+        assert_eq!(
+            script(&steps),
+            "#!/bin/sh\n\
+             # Commands executed in an ht session, in order.\n\
+             \n\
+             cd /tmp\n\
+             \n\
+             # exited with status 2\n\
+             make test\n\
+             \n\
+             # interactive step, to be done by hand:\n\
+             # vim notes.txt\n\
+             \n\
+             # (a command whose text wasn't captured)\n"
+        );
+    }
+}
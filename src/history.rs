@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Output between checkpoints, bounding how much is replayed to reconstruct
+/// a screen.
+const CHECKPOINT_INTERVAL: usize = 64 * 1024;
+
+/// Moment in the session's past, by session time or by output event id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Moment {
+    Time(f64),
+    OutputId(u64),
+}
+
+/// Recent past of the terminal, for reconstructing the screen as it was at
+/// an earlier moment: emulator checkpoints, each followed by the output fed
+/// to the emulator after it.
+pub struct History {
+    period: f64,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+struct Checkpoint {
+    time: f64,
+    output_id: u64,
+    cols: usize,
+    rows: usize,
+    dump: String,
+    /// Output following the checkpoint: time, output id, data
+    output: Vec<(f64, u64, String)>,
+    output_len: usize,
+}
+
+/// Screen reconstructed from the history, with the time and id of the last
+/// output applied.
+pub struct Past {
+    pub time: f64,
+    pub output_id: u64,
+    pub vt: avt::Vt,
+}
+
+impl History {
+    /// Creates the history keeping (at least) the given period of time.
+    pub fn new(period: Duration, vt: &avt::Vt) -> Self {
+        let mut history = Self {
+            period: period.as_secs_f64(),
+            checkpoints: VecDeque::new(),
+        };
+
+        history.checkpoint(0.0, 0, vt);
+
+        history
+    }
+
+    /// Adds a checkpoint, e.g. after the emulator was changed other than by
+    /// output, like on resize.
+    pub fn checkpoint(&mut self, time: f64, output_id: u64, vt: &avt::Vt) {
+        let (cols, rows) = vt.size();
+
+        self.checkpoints.push_back(Checkpoint {
+            time,
+            output_id,
+            cols,
+            rows,
+            dump: vt.dump(),
+            output: Vec::new(),
+            output_len: 0,
+        });
+
+        // the oldest checkpoint is needed as long as the next one is in the period
+        while self
+            .checkpoints
+            .get(1)
+            .is_some_and(|c| c.time < time - self.period)
+        {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// Records the output, already fed to `vt`.
+    pub fn output(&mut self, time: f64, output_id: u64, data: &str, vt: &avt::Vt) {
+        let Some(checkpoint) = self.checkpoints.back_mut() else {
+            return;
+        };
+
+        checkpoint.output.push((time, output_id, data.to_owned()));
+        checkpoint.output_len += data.len();
+
+        if checkpoint.output_len >= CHECKPOINT_INTERVAL {
+            self.checkpoint(time, output_id, vt);
+        }
+    }
+
+    /// Reconstructs the screen as it was at the moment, i.e. after the output
+    /// up to it. Returns nothing when the moment is before the retained
+    /// history.
+    pub fn at(&self, moment: Moment) -> Option<Past> {
+        let is_before = |time: f64, output_id: u64| match moment {
+            Moment::Time(t) => time <= t,
+            Moment::OutputId(id) => output_id <= id,
+        };
+
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|c| is_before(c.time, c.output_id))?;
+
+        let mut vt = avt::Vt::builder()
+            .size(checkpoint.cols, checkpoint.rows)
+            .build();
+
+        vt.feed_str(&checkpoint.dump);
+        let mut past = (checkpoint.time, checkpoint.output_id);
+
+        for (time, output_id, data) in &checkpoint.output {
+            if !is_before(*time, *output_id) {
+                break;
+            }
+
+            vt.feed_str(data);
+            past = (*time, *output_id);
+        }
+
+        Some(Past {
+            time: past.0,
+            output_id: past.1,
+            vt,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{History, Moment};
+    use std::time::Duration;
+
+    fn text(history: &History, moment: Moment) -> Option<String> {
+        history
+            .at(moment)
+            .map(|past| past.vt.view()[0].text().trim_end().to_owned())
+    }
+
+    #[test]
+    fn screen_at_moment() {
+        let mut vt = avt::Vt::new(10, 2);
+        let mut history = History::new(Duration::from_secs(60), &vt);
+
+        for (i, data) in ["a", "b", "c"].iter().enumerate() {
+            vt.feed_str(data);
+            history.output(i as f64 + 1.0, i as u64 + 1, data, &vt);
+        }
+
+        assert_eq!(text(&history, Moment::Time(0.5)), Some("".to_owned()));
+        assert_eq!(text(&history, Moment::Time(2.5)), Some("ab".to_owned()));
+        assert_eq!(text(&history, Moment::OutputId(1)), Some("a".to_owned()));
+        assert_eq!(text(&history, Moment::OutputId(9)), Some("abc".to_owned()));
+
+        vt.resize(5, 2);
+        history.checkpoint(4.0, 3, &vt);
+        vt.feed_str("\r\nd");
+        history.output(5.0, 4, "\r\nd", &vt);
+
+        let past = history.at(Moment::Time(5.0)).unwrap();
+        assert_eq!(past.vt.size(), (5, 2));
+        assert_eq!(past.vt.view()[1].text().trim_end(), "d");
+        assert_eq!((past.time, past.output_id), (5.0, 4));
+    }
+
+    #[test]
+    fn prune_old_checkpoints() {
+        let vt = avt::Vt::new(10, 2);
+        let mut history = History::new(Duration::from_secs(10), &vt);
+        history.checkpoint(5.0, 1, &vt);
+        history.checkpoint(20.0, 2, &vt);
+
+        assert_eq!(history.checkpoints.len(), 2);
+        assert!(history.at(Moment::Time(4.0)).is_none());
+        assert!(history.at(Moment::Time(6.0)).is_some());
+    }
+}
@@ -0,0 +1,63 @@
+use serde_json::{json, Map, Value};
+use std::sync::OnceLock;
+
+static IDENTITY: OnceLock<Value> = OnceLock::new();
+
+/// Sets the name and labels attached to every emitted event. Does nothing
+/// when neither is given, leaving the events as they were.
+pub fn init(name: Option<&str>, labels: &[(String, String)]) {
+    if let Some(identity) = to_json(name, labels) {
+        let _ = IDENTITY.set(identity);
+    }
+}
+
+/// Returns the name and labels of the session, if any were given.
+pub fn get() -> Option<&'static Value> {
+    IDENTITY.get()
+}
+
+/// Adds the `session` field, with the name and labels, to the event.
+pub fn stamp(event: &mut Value) {
+    if let Some(identity) = get() {
+        event["session"] = identity.clone();
+    }
+}
+
+fn to_json(name: Option<&str>, labels: &[(String, String)]) -> Option<Value> {
+    if name.is_none() && labels.is_empty() {
+        return None;
+    }
+
+    let labels: Map<String, Value> = labels
+        .iter()
+        .map(|(key, value)| (key.clone(), json!(value)))
+        .collect();
+
+    Some(json!({ "name": name, "labels": labels }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_json;
+    use serde_json::json;
+
+    #[test]
+    fn identity_json() {
+        assert_eq!(to_json(None, &[]), None);
+
+        assert_eq!(
+            to_json(Some("build"), &[]),
+            Some(json!({ "name": "build", "labels": {} }))
+        );
+
+        let labels = [
+            ("team".to_owned(), "infra".to_owned()),
+            ("job".to_owned(), "42".to_owned()),
+        ];
+
+        assert_eq!(
+            to_json(None, &labels),
+            Some(json!({ "name": null, "labels": { "team": "infra", "job": "42" } }))
+        );
+    }
+}
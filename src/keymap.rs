@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+static KEYMAP: OnceLock<Keymap> = OnceLock::new();
+
+/// Custom key names usable in `sendKeys`, loaded with `--keymap`.
+pub type Keymap = HashMap<String, Binding>;
+
+/// What a custom key name stands for.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Binding {
+    /// Sequence sent as is
+    Seq(String),
+    /// Built-in key names (e.g. `C-x`), sent one after another
+    Keys(Vec<String>),
+}
+
+/// Loads a keymap file: a YAML (or JSON) mapping of names to sequences or
+/// lists of built-in key names.
+pub fn load(path: &str) -> Result<Keymap> {
+    let text = fs::read_to_string(path).with_context(|| format!("cannot read keymap {path}"))?;
+    let keymap = serde_yaml::from_str(&text).with_context(|| format!("invalid keymap {path}"))?;
+
+    Ok(keymap)
+}
+
+pub fn init(keymap: Keymap) {
+    let _ = KEYMAP.set(keymap);
+}
+
+/// Returns the binding of a custom key name, if there's one.
+pub fn get(name: &str) -> Option<&'static Binding> {
+    KEYMAP.get()?.get(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Binding, Keymap};
+
+    #[test]
+    fn parse_keymap() {
+        let keymap: Keymap = serde_yaml::from_str(
+            r#"
+            accept: "\r"
+            save: [C-x, C-s]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(keymap["accept"], Binding::Seq("\r".to_owned()));
+        assert_eq!(
+            keymap["save"],
+            Binding::Keys(vec!["C-x".to_owned(), "C-s".to_owned()])
+        );
+    }
+}
@@ -1,6 +1,6 @@
-use nix::libc::{self, CODESET, LC_ALL};
+use nix::libc::{self, CODESET, LC_ALL, LC_ALL_MASK};
 use std::env;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 
 pub fn check_utf8_locale() -> anyhow::Result<()> {
     initialize_from_env();
@@ -20,9 +20,45 @@ pub fn check_utf8_locale() -> anyhow::Result<()> {
     }
 }
 
+/// Locales tried, in order, when the environment's locale isn't usable.
+const FALLBACK_LOCALES: [&str; 2] = ["C.UTF-8", "en_US.UTF-8"];
+
+/// Switches to the first available UTF-8 locale, returning its name.
+pub fn force_utf8_locale() -> anyhow::Result<&'static str> {
+    for name in FALLBACK_LOCALES {
+        let locale = CString::new(name)?;
+        let result = unsafe { libc::setlocale(LC_ALL, locale.as_ptr()) };
+
+        if !result.is_null() && get_encoding() == "UTF-8" {
+            return Ok(name);
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "none of the fallback locales ({}) is available",
+        FALLBACK_LOCALES.join(", ")
+    ))
+}
+
+/// Checks whether the locale is installed on the system.
+pub fn is_available(name: &str) -> bool {
+    let Ok(name) = CString::new(name) else {
+        return false;
+    };
+
+    let locale = unsafe { libc::newlocale(LC_ALL_MASK, name.as_ptr(), std::ptr::null_mut()) };
+
+    if locale.is_null() {
+        false
+    } else {
+        unsafe { libc::freelocale(locale) };
+        true
+    }
+}
+
 pub fn initialize_from_env() {
     unsafe {
-        libc::setlocale(LC_ALL, b"\0".as_ptr() as *const libc::c_char);
+        libc::setlocale(LC_ALL, c"".as_ptr());
     };
 }
 
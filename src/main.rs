@@ -1,66 +1,595 @@
 mod api;
+mod attach;
+mod audit;
+mod bench;
+mod check;
 mod cli;
 mod command;
+mod daemon;
+mod diff;
+mod environ;
+mod export;
+mod history;
+mod identity;
+mod keymap;
 mod locale;
+mod namespace;
 mod nbio;
+mod pipe;
+mod prexec;
+mod procfs;
 mod pty;
+mod recorder;
+mod redact;
+mod render;
+mod ring;
+mod scenario;
+mod seccomp;
+mod serial;
 mod session;
-use anyhow::{Context, Result};
-use command::Command;
+mod ssh;
+mod throttle;
+mod tmux;
+mod tracker;
+mod utf8;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use cli::OutputLimitAction;
+use command::{Command, Reply, Trim, ViewRequest};
+use environ::Environment;
+use prexec::{Group, PreExec};
+use redact::Redactor;
 use session::Session;
+use std::env;
+use std::future;
 use std::net::{SocketAddr, TcpListener};
-use tokio::{sync::mpsc, task::JoinHandle};
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, Interval};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    locale::check_utf8_locale()?;
-    let cli = cli::Cli::new();
+/// Exit status used when the watchdog terminates the child, same as timeout(1).
+const WATCHDOG_EXIT_CODE: i32 = 124;
 
-    let (input_tx, input_rx) = mpsc::channel(1024);
+/// How long the clients get to receive the last events before ht exits.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Ctrl-L, which makes most interactive programs clear and redraw the screen.
+const REDRAW_KEY: &[u8] = b"\x0c";
+
+/// How often the terminal's foreground process group, and the screen hash,
+/// are checked.
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+fn main() -> Result<()> {
+    let mut cli = cli::Cli::new();
+
+    if let Some(cli::Mode::Attach(args)) = &cli.mode {
+        return attach::run(args.addr, args.read_only);
+    }
+
+    if let Some(cli::Mode::Diff(args)) = &cli.mode {
+        return diff::run(&args.a, &args.b);
+    }
+
+    match locale::check_utf8_locale() {
+        Ok(()) => (),
+
+        Err(e) if cli.force_utf8 => {
+            let locale = locale::force_utf8_locale().context(e)?;
+            eprintln!("warning: {}", locale_warning(locale));
+            cli.fallback_locale = Some(locale.to_owned());
+        }
+
+        Err(e) => return Err(e),
+    }
+    let listener = bind_http_listener(cli.listen)?;
+    let grpc_listener = bind_grpc_listener(cli.grpc)?;
+
+    identity::init(cli.name.as_deref(), &cli.label);
+
+    if let Some(keymap) = cli.keymap.take() {
+        keymap::init(keymap);
+    }
+
+    if let Some(path) = &cli.audit_log {
+        audit::open(path).context("cannot open audit log")?;
+    }
+
+    if cli.daemon {
+        // the background process leaves the current directory
+        let dir = env::current_dir()?;
+
+        for path in [&mut cli.record, &mut cli.export_script, &mut cli.pid_file]
+            .into_iter()
+            .flatten()
+        {
+            *path = dir.join(&path);
+        }
+
+        cli.start_dir = Some(dir);
+        daemon::daemonize(cli.pid_file.as_deref())?;
+    }
+
+    let pid_file = cli.pid_file.clone();
+    let result = tokio::runtime::Runtime::new()?.block_on(run(cli, listener, grpc_listener));
+
+    if let Err(e) = &result {
+        daemon::failed(e);
+    }
+
+    if let Some(path) = pid_file {
+        daemon::remove_pid_file(&path);
+    }
+
+    match result? {
+        Outcome::Exited => Ok(()),
+        Outcome::TimedOut => process::exit(WATCHDOG_EXIT_CODE),
+        Outcome::OutputLimitExceeded => bail!("output limit exceeded, child process terminated"),
+    }
+}
+
+async fn run(
+    cli: cli::Cli,
+    listener: Option<TcpListener>,
+    grpc_listener: Option<TcpListener>,
+) -> Result<Outcome> {
+    let (input_tx, mut input_rx) = mpsc::channel(1024);
     let (output_tx, output_rx) = mpsc::channel(1024);
+    let (stderr_tx, stderr_rx) = mpsc::channel(1024);
     let (command_tx, command_rx) = mpsc::channel(1024);
     let (clients_tx, clients_rx) = mpsc::channel(1);
+    let (views_tx, views_rx) = mpsc::channel(1024);
+
+    let recorder = match &cli.record {
+        Some(path) => Some(tokio::spawn(
+            recorder::start(
+                path.clone(),
+                recording_metadata(&cli),
+                cli.record_append,
+                clients_tx.clone(),
+            )
+            .await?,
+        )),
+
+        None => None,
+    };
+
+    let exporter = match &cli.export_script {
+        Some(path) => Some(tokio::spawn(
+            export::start(path.clone(), clients_tx.clone()).await?,
+        )),
+
+        None => None,
+    };
+
+    if let Some(listener) = grpc_listener {
+        tokio::spawn(api::grpc::start(
+            listener,
+            command_tx.clone(),
+            clients_tx.clone(),
+        )?);
+    }
+
+    let session_id = cli.session_id.clone().unwrap_or_else(session::generate_id);
+
+    if let Some(url) = &cli.connect {
+        tokio::spawn(api::hub::start(
+            url.clone(),
+            session_id.clone(),
+            command_tx.clone(),
+            clients_tx.clone(),
+        ));
+    }
+
+    if let Some(url) = &cli.publish {
+        tokio::spawn(api::publish::start(
+            url.clone(),
+            session_id.clone(),
+            clients_tx.clone(),
+        ));
+    }
+
+    for url in &cli.sink {
+        tokio::spawn(api::sink::start(url.clone(), clients_tx.clone()));
+    }
+
+    let api = match &cli.mode {
+        Some(cli::Mode::Check(args)) => {
+            start_http_api(
+                listener,
+                clients_tx.clone(),
+                None,
+                views_tx,
+                cli.record.clone(),
+            )
+            .await?;
+            tokio::spawn(check::run(args.clone(), command_tx, clients_tx))
+        }
+
+        Some(cli::Mode::Test(args)) => {
+            start_http_api(
+                listener,
+                clients_tx.clone(),
+                None,
+                views_tx,
+                cli.record.clone(),
+            )
+            .await?;
+            tokio::spawn(scenario::run(args.clone(), command_tx, clients_tx))
+        }
+
+        Some(cli::Mode::Attach(_)) => unreachable!("attaching doesn't run a session"),
+
+        Some(cli::Mode::Diff(_)) => unreachable!("diffing doesn't run a session"),
+
+        None if cli.daemon && cli.read_only_ws => {
+            start_http_api(listener, clients_tx, None, views_tx, cli.record.clone()).await?;
+
+            // nothing sends commands, but the session runs until ht is stopped
+            tokio::spawn(async move {
+                let _command_tx = command_tx;
+                future::pending().await
+            })
+        }
+
+        None if cli.daemon => {
+            start_http_api(
+                listener,
+                clients_tx,
+                Some(command_tx),
+                views_tx,
+                cli.record.clone(),
+            )
+            .await?;
+            tokio::spawn(future::pending())
+        }
+
+        None => {
+            start_http_api(
+                listener,
+                clients_tx.clone(),
+                None,
+                views_tx,
+                cli.record.clone(),
+            )
+            .await?;
+            start_stdio_api(
+                cli.protocol,
+                command_tx,
+                clients_tx,
+                cli.subscribe.unwrap_or_default(),
+                cli.filter.clone().unwrap_or_default(),
+                cli.fields.clone().unwrap_or_default(),
+                (cli.queue, cli.lag),
+            )
+        }
+    };
+
+    if let Some(rate) = cli.max_input_rate {
+        input_rx = throttle::start(rate, input_rx);
+    }
 
-    start_http_api(cli.listen, clients_tx.clone()).await?;
-    let api = start_stdio_api(command_tx, clients_tx, cli.subscribe.unwrap_or_default());
-    let (pid, pty) = start_pty(cli.command, &cli.size, input_rx, output_tx)?;
-    let session = build_session(&cli.size, pid);
-    run_event_loop(output_rx, input_tx, command_rx, clients_rx, session, api).await?;
-    pty.await?
+    let (written_tx, written_rx) = watch::channel(0);
+    let (pid, master, pty) = start_child(&cli, input_rx, written_tx, output_tx, stderr_tx)?;
+    let session = build_session(&cli, pid, master);
+    daemon::ready();
+
+    if cli.bench {
+        bench::run(output_rx, session).await?;
+        drop(input_tx);
+        pty.await??;
+
+        return Ok(Outcome::Exited);
+    }
+
+    let input = Input::new(input_tx, written_rx);
+    let outcome = run_event_loop(
+        output_rx, stderr_rx, input, command_rx, clients_rx, views_rx, session, api, &cli,
+    )
+    .await;
+    pty.await??;
+
+    if let Some(recorder) = recorder {
+        recorder.await??;
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.await??;
+    }
+
+    outcome
 }
 
-fn build_session(size: &cli::Size, pid: i32) -> Session {
-    Session::new(size.cols(), size.rows(), pid)
+enum Outcome {
+    Exited,
+    TimedOut,
+    OutputLimitExceeded,
+}
+
+fn build_session(cli: &cli::Cli, pid: i32, master: Option<OwnedFd>) -> Session {
+    let mut session = Session::new(cli.size.cols(), cli.size.rows(), pid);
+
+    if let Some(master) = master {
+        session.set_terminal(master);
+    }
+
+    session.set_output_limit(cli.max_output_bytes);
+    session.set_allow_resize(cli.allow_resize);
+    session.set_strict_mouse(cli.strict_mouse);
+    session.set_allow_clipboard_read(cli.allow_clipboard_read);
+    session.set_history(cli.history);
+    session.set_output_buffer(cli.output_buffer);
+    session.set_utf8_policy(cli.invalid_utf8);
+    session.set_redactor(Redactor::new(cli.redact.clone(), cli.redact_mask.clone()));
+
+    if let Some(locale) = &cli.fallback_locale {
+        session.warn(locale_warning(locale));
+    }
+
+    session
 }
 
 fn start_stdio_api(
-    command_tx: mpsc::Sender<Command>,
+    protocol: cli::Protocol,
+    command_tx: mpsc::Sender<(Command, Reply)>,
     clients_tx: mpsc::Sender<session::Client>,
     sub: api::Subscription,
+    filter: api::filter::Filter,
+    fields: api::fields::Fields,
+    queue: (usize, api::queue::Policy),
 ) -> JoinHandle<Result<()>> {
-    tokio::spawn(api::stdio::start(command_tx, clients_tx, sub))
+    match protocol {
+        cli::Protocol::Json => tokio::spawn(api::stdio::start(
+            command_tx, clients_tx, sub, filter, fields, queue,
+        )),
+
+        cli::Protocol::Jsonrpc => tokio::spawn(api::jsonrpc::start(
+            command_tx, clients_tx, sub, filter, fields, queue,
+        )),
+    }
+}
+
+fn start_child(
+    cli: &cli::Cli,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+    stderr_tx: mpsc::Sender<Bytes>,
+) -> Result<(i32, Option<OwnedFd>, JoinHandle<Result<()>>)> {
+    let command = cli.command.join(" ");
+    let stderr_tx = cli.separate_stderr.then_some(stderr_tx);
+    let pre_exec = Arc::new(child_pre_exec(cli)?);
+
+    if let Some(device) = &cli.serial {
+        start_serial(device, cli.baud, input_rx, written_tx, output_tx)
+    } else if let Some(destination) = &cli.ssh {
+        let command = ssh::command(destination, &cli.command);
+        start_pty(
+            command, &cli.size, input_rx, written_tx, output_tx, stderr_tx, &pre_exec,
+        )
+    } else if let Some(target) = &cli.tmux {
+        start_tmux(target.clone(), &cli.size, input_rx, written_tx, output_tx)
+    } else if cli.no_pty {
+        start_pipes(
+            command, input_rx, written_tx, output_tx, stderr_tx, pre_exec,
+        )
+    } else {
+        start_pty(
+            command, &cli.size, input_rx, written_tx, output_tx, stderr_tx, &pre_exec,
+        )
+    }
+}
+
+fn child_pre_exec(cli: &cli::Cli) -> Result<PreExec> {
+    let mut pre_exec = PreExec::new(child_env(cli));
+
+    if let Some(dir) = &cli.start_dir {
+        pre_exec.chdir(dir.clone());
+    }
+
+    if cli.setsid {
+        pre_exec.set_group(Group::Session);
+    } else if let Some(pgid) = cli.process_group {
+        pre_exec.set_group(Group::Join(pgid));
+    }
+
+    if let Some(mask) = cli.umask {
+        pre_exec.umask(mask);
+    }
+
+    if cli.close_fds {
+        pre_exec.close_fds();
+    }
+
+    for fd in &cli.inherit_fd {
+        pre_exec.inherit_fd(*fd);
+    }
+
+    if !cli.unshare.is_empty() || cli.map_user.is_some() {
+        namespace::isolate(&mut pre_exec, &cli.unshare, cli.map_user)?;
+    }
+
+    if let Some(profile) = &cli.seccomp {
+        seccomp::apply(&mut pre_exec, profile)?;
+    }
+
+    Ok(pre_exec)
+}
+
+fn child_env(cli: &cli::Cli) -> Environment {
+    let mut env = Environment::new(cli.env_clear, cli.env_pass.clone());
+
+    if cli.locale_clear {
+        for pattern in ["LANG", "LANGUAGE", "LC_*"] {
+            env.remove(pattern);
+        }
+    }
+
+    if let Some(lang) = &cli.lang {
+        env.set("LANG", lang);
+    }
+
+    for (name, value) in &cli.lc {
+        env.set(name, value);
+    }
+
+    if !cli.colorterm.is_empty() {
+        env.set("COLORTERM", &cli.colorterm);
+    }
+
+    if let Some(locale) = &cli.fallback_locale {
+        env.set("LC_ALL", locale);
+    }
+
+    for (name, value) in &cli.env {
+        env.set(name, value);
+    }
+
+    env
+}
+
+fn locale_warning(locale: &str) -> String {
+    format!("the environment's locale doesn't use UTF-8, falling back to {locale}")
+}
+
+fn recording_metadata(cli: &cli::Cli) -> recorder::Metadata {
+    let child_env = child_env(cli);
+    let term = if cli.no_pty { pipe::TERM } else { pty::TERM };
+
+    let env = cli
+        .record_env
+        .iter()
+        .filter_map(|name| {
+            let value = match name.as_str() {
+                "TERM" => Some(term.to_owned()),
+                name => child_env.get(name),
+            };
+
+            value.map(|value| (name.clone(), value))
+        })
+        .collect();
+
+    recorder::Metadata {
+        title: cli.record_title.clone(),
+        command: Some(cli.command.join(" ")),
+        env,
+        theme: cli.record_theme.clone(),
+        idle_time_limit: cli.idle_time_limit,
+    }
 }
 
 fn start_pty(
-    command: Vec<String>,
+    command: String,
     size: &cli::Size,
     input_rx: mpsc::Receiver<Vec<u8>>,
-    output_tx: mpsc::Sender<Vec<u8>>,
-) -> Result<(i32, JoinHandle<Result<()>>)> {
-    let command = command.join(" ");
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+    stderr_tx: Option<mpsc::Sender<Bytes>>,
+    pre_exec: &PreExec,
+) -> Result<(i32, Option<OwnedFd>, JoinHandle<Result<()>>)> {
     eprintln!("launching \"{}\" in terminal of size {}", command, size);
-    let (pid, fut) = pty::spawn(command, size, input_rx, output_tx)?;
+    let (pid, master, fut) = pty::spawn(
+        command, size, input_rx, written_tx, output_tx, stderr_tx, pre_exec,
+    )?;
+
+    Ok((pid, Some(master), tokio::spawn(fut)))
+}
+
+fn start_pipes(
+    command: String,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+    stderr_tx: Option<mpsc::Sender<Bytes>>,
+    pre_exec: Arc<PreExec>,
+) -> Result<(i32, Option<OwnedFd>, JoinHandle<Result<()>>)> {
+    eprintln!("launching \"{}\" without a terminal", command);
+    let (pid, fut) = pipe::spawn(
+        command, input_rx, written_tx, output_tx, stderr_tx, pre_exec,
+    )?;
+
+    Ok((pid, None, tokio::spawn(fut)))
+}
+
+fn start_serial(
+    device: &str,
+    baud: u32,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<(i32, Option<OwnedFd>, JoinHandle<Result<()>>)> {
+    eprintln!("attaching to serial device {} at {} baud", device, baud);
+    let fut = serial::open(device, baud, input_rx, written_tx, output_tx)?;
+
+    // there's no child process
+    Ok((0, None, tokio::spawn(fut)))
+}
+
+fn start_tmux(
+    target: String,
+    size: &cli::Size,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<(i32, Option<OwnedFd>, JoinHandle<Result<()>>)> {
+    eprintln!("attaching to tmux pane \"{}\"", target);
+    let (pid, fut) = tmux::spawn(
+        target,
+        size.cols(),
+        size.rows(),
+        input_rx,
+        written_tx,
+        output_tx,
+    )?;
 
-    Ok((pid, tokio::spawn(fut)))
+    Ok((pid, None, tokio::spawn(fut)))
+}
+
+fn bind_http_listener(listen_addr: Option<SocketAddr>) -> Result<Option<TcpListener>> {
+    match listen_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).context("cannot start HTTP listener")?;
+            let addr = listener.local_addr()?;
+            eprintln!("HTTP server listening on {addr}");
+            eprintln!("live preview available at http://{addr}");
+
+            Ok(Some(listener))
+        }
+
+        None => Ok(None),
+    }
+}
+
+fn bind_grpc_listener(listen_addr: Option<SocketAddr>) -> Result<Option<TcpListener>> {
+    match listen_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).context("cannot start gRPC listener")?;
+            eprintln!("gRPC server listening on {}", listener.local_addr()?);
+
+            Ok(Some(listener))
+        }
+
+        None => Ok(None),
+    }
 }
 
 async fn start_http_api(
-    listen_addr: Option<SocketAddr>,
+    listener: Option<TcpListener>,
     clients_tx: mpsc::Sender<session::Client>,
+    command_tx: Option<mpsc::Sender<(Command, Reply)>>,
+    views_tx: mpsc::Sender<(ViewRequest, Reply)>,
+    recording: Option<PathBuf>,
 ) -> Result<()> {
-    if let Some(addr) = listen_addr {
-        let listener = TcpListener::bind(addr).context("cannot start HTTP listener")?;
-        tokio::spawn(api::http::start(listener, clients_tx).await?);
+    if let Some(listener) = listener {
+        tokio::spawn(
+            api::http::start(listener, clients_tx, command_tx, views_tx, recording).await?,
+        );
     }
 
     Ok(())
@@ -76,22 +605,42 @@ fn validate_mouse_coordinates(mouse_event: &command::MouseEvent, session: &Sessi
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_event_loop(
-    mut output_rx: mpsc::Receiver<Vec<u8>>,
-    input_tx: mpsc::Sender<Vec<u8>>,
-    mut command_rx: mpsc::Receiver<Command>,
+    mut output_rx: mpsc::Receiver<Bytes>,
+    mut stderr_rx: mpsc::Receiver<Bytes>,
+    mut input: Input,
+    mut command_rx: mpsc::Receiver<(Command, Reply)>,
     mut clients_rx: mpsc::Receiver<session::Client>,
+    mut views_rx: mpsc::Receiver<(ViewRequest, Reply)>,
     mut session: Session,
     mut api_handle: JoinHandle<Result<()>>,
-) -> Result<()> {
+    cli: &cli::Cli,
+) -> Result<Outcome> {
     let mut serving = true;
+    let mut viewing = true;
+    let mut capturing_stderr = true;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut last_output = Instant::now();
+
+    let mut poll = time::interval(POLL_PERIOD);
+
+    let mut heartbeat = cli
+        .heartbeat
+        .map(|period| time::interval_at(Instant::now() + period, period));
 
     loop {
         tokio::select! {
             result = output_rx.recv() => {
                 match result {
                     Some(data) => {
-                        session.output(String::from_utf8_lossy(&data).to_string());
+                        process_output(&mut session, data, &mut input).await?;
+                        last_output = Instant::now();
+
+                        if session.is_truncated() && cli.on_output_limit == OutputLimitAction::Kill {
+                            eprintln!("output limit exceeded, shutting down...");
+                            return Ok(Outcome::OutputLimitExceeded);
+                        }
                     },
 
                     None => {
@@ -101,45 +650,259 @@ async fn run_event_loop(
                 }
             }
 
-            command = command_rx.recv() => {
+            result = stderr_rx.recv(), if capturing_stderr => {
+                match result {
+                    Some(data) => {
+                        session.stderr(data);
+                    }
+
+                    None => {
+                        capturing_stderr = false;
+                    }
+                }
+            }
+
+            // the commands following a paste wait until it's accepted
+            command = command_rx.recv(), if !session.is_pasting() => {
                 match command {
-                    Some(Command::Input(seqs)) => {
+                    Some((Command::Input(seqs, secret), _)) => {
+                        if secret {
+                            session.add_secrets(command::seqs_text(&seqs));
+                        }
+
                         let data = command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
-                        input_tx.send(data).await?;
+
+                        // macros are kept in the clear, so secrets stay out
+                        if !secret {
+                            session.record_macro_input(&data);
+                        }
+
+                        input.send(data).await?;
+                    }
+
+                    Some((Command::InputRaw(bytes), _)) => {
+                        session.record_macro_input(&bytes);
+                        input.send(bytes).await?;
+                    }
+
+                    Some((Command::Paste(text), reply)) => {
+                        session.start_paste(text, reply);
+                        input.continue_paste(&mut session).await?;
+                    }
+
+                    Some((Command::Sync, reply)) => {
+                        // output that arrived before the command goes first
+                        for _ in 0..output_rx.len() {
+                            if let Ok(data) = output_rx.try_recv() {
+                                process_output(&mut session, data, &mut input).await?;
+                                last_output = Instant::now();
+                            }
+                        }
+
+                        for _ in 0..stderr_rx.len() {
+                            if let Ok(data) = stderr_rx.try_recv() {
+                                session.stderr(data);
+                            }
+                        }
+
+                        let _ = reply.send(session.sync_reply());
+
+                        if session.is_truncated() && cli.on_output_limit == OutputLimitAction::Kill {
+                            eprintln!("output limit exceeded, shutting down...");
+                            return Ok(Outcome::OutputLimitExceeded);
+                        }
+                    }
+
+                    Some((Command::Flush, reply)) => {
+                        input.flush(reply);
+                    }
+
+                    Some((Command::Mouse(mouse_event), reply)) => {
+                        if let Some(error) = session.reject_mouse() {
+                            let _ = reply.send(error);
+                        } else {
+                            validate_mouse_coordinates(&mouse_event, &session);
+                            let data = command::mouse_to_bytes(&mouse_event);
+                            input.send(data).await?;
+                        }
+                    }
+
+                    Some((Command::MouseClick(mouse_event), reply)) => {
+                        if let Some(error) = session.reject_mouse() {
+                            let _ = reply.send(error);
+                        } else {
+                            validate_mouse_coordinates(&mouse_event, &session);
+
+                            // Send press event
+                            let mut press_event = mouse_event.clone();
+                            press_event.event_type = command::MouseEventType::Press;
+                            let press_data = command::mouse_to_bytes(&press_event);
+                            input.send(press_data).await?;
+
+                            // Send release event
+                            let mut release_event = mouse_event;
+                            release_event.event_type = command::MouseEventType::Release;
+                            let release_data = command::mouse_to_bytes(&release_event);
+                            input.send(release_data).await?;
+                        }
+                    }
+
+                    Some((Command::Focus(gained), _)) => {
+                        if let Some(report) = session.focus_report(gained) {
+                            input.send(report.to_vec()).await?;
+                        }
+                    }
+
+                    Some((Command::Snapshot(format, full, trim), _)) => {
+                        session.snapshot(format, full, trim);
+                    }
+
+                    Some((Command::Resize(cols, rows, xpixel, ypixel), _)) => {
+                        session.resize(cols, rows, xpixel, ypixel);
+                    }
+
+                    Some((Command::SnapshotAt(moment), reply)) => {
+                        let _ = reply.send(session.snapshot_at(moment));
+                    }
+
+                    Some((Command::GetOutput(from, to), reply)) => {
+                        let _ = reply.send(session.get_output(from, to));
+                    }
+
+                    Some((Command::GetLine(row), reply)) => {
+                        let _ = reply.send(session.get_line(row));
                     }
 
-                    Some(Command::Mouse(mouse_event)) => {
-                        validate_mouse_coordinates(&mouse_event, &session);
-                        let data = command::mouse_to_bytes(&mouse_event);
-                        input_tx.send(data).await?;
+                    Some((Command::GetColumns(row), reply)) => {
+                        let _ = reply.send(session.get_columns(row));
                     }
 
-                    Some(Command::MouseClick(mouse_event)) => {
-                        validate_mouse_coordinates(&mouse_event, &session);
+                    Some((Command::GetView(trim, offset), reply)) => {
+                        let _ = reply.send(session.get_view(trim, offset, &reply));
+                    }
+
+                    Some((Command::Scroll(scroll), reply)) => {
+                        let _ = reply.send(session.scroll(scroll, &reply));
+                    }
+
+                    Some((Command::Follow(enabled), reply)) => {
+                        let _ = reply.send(session.follow(enabled, &reply));
+                    }
+
+                    Some((Command::GetText(region), reply)) => {
+                        let _ = reply.send(session.get_text(&region));
+                    }
+
+                    Some((Command::GetWord(row, col, separators), reply)) => {
+                        let _ = reply.send(session.get_word(row, col, separators.as_deref()));
+                    }
+
+                    Some((Command::SetClipboard(content), _)) => {
+                        session.set_clipboard(content);
+                    }
+
+                    Some((Command::SetSelection(selection), reply)) => {
+                        let _ = reply.send(session.set_selection(selection));
+                    }
+
+                    Some((Command::GetSelection, reply)) => {
+                        let _ = reply.send(session.get_selection());
+                    }
 
-                        // Send press event
-                        let mut press_event = mouse_event.clone();
-                        press_event.event_type = command::MouseEventType::Press;
-                        let press_data = command::mouse_to_bytes(&press_event);
-                        input_tx.send(press_data).await?;
+                    Some((Command::DumpState, reply)) => {
+                        let _ = reply.send(session.dump_state());
+                    }
 
-                        // Send release event
-                        let mut release_event = mouse_event;
-                        release_event.event_type = command::MouseEventType::Release;
-                        let release_data = command::mouse_to_bytes(&release_event);
-                        input_tx.send(release_data).await?;
+                    Some((Command::GetProcesses, reply)) => {
+                        let _ = reply.send(session.get_processes());
                     }
 
-                    Some(Command::Snapshot) => {
-                        session.snapshot();
+                    Some((Command::Capabilities, reply)) => {
+                        let _ = reply.send(session::capabilities());
                     }
 
-                    Some(Command::Resize(cols, rows)) => {
-                        session.resize(cols, rows);
+                    Some((Command::Schema, reply)) => {
+                        let _ = reply.send(session::schema());
+                    }
+
+                    Some((Command::Reset(redraw), _)) => {
+                        session.reset();
+
+                        if redraw {
+                            input.send(REDRAW_KEY.to_vec()).await?;
+                        }
+                    }
+
+                    Some((Command::ClearScrollback(clear_screen), _)) => {
+                        session.clear_scrollback(clear_screen);
+                    }
+
+                    Some((Command::Annotate(payload), _)) => {
+                        session.annotate(payload);
+                    }
+
+                    Some((Command::Close, _)) => {
+                        eprintln!("close requested, shutting down...");
+                        break;
+                    }
+
+                    // performed by the API layer, before it gets here
+                    Some((Command::Sleep(_), _)) => (),
+
+                    Some((Command::PauseRecording(paused), _)) => {
+                        session.pause_recording(paused);
+                    }
+
+                    Some((Command::RecordMacro(name), reply)) => {
+                        if let Some(error) = session.record_macro(name) {
+                            let _ = reply.send(error);
+                        }
+                    }
+
+                    Some((Command::StopMacro, reply)) => {
+                        let _ = reply.send(session.stop_macro());
+                    }
+
+                    Some((Command::PlayMacro(name, timing), reply)) => {
+                        match session.play_macro(&name, timing) {
+                            Some(error) => {
+                                let _ = reply.send(error);
+                            }
+
+                            None => input.send(session.take_macro_input()).await?,
+                        }
+                    }
+
+                    Some((Command::Suspend(suspended), reply)) => {
+                        if let Some(error) = session.suspend(suspended) {
+                            let _ = reply.send(error);
+                        } else if !suspended {
+                            // the idle period starts over
+                            last_output = Instant::now();
+                        }
+                    }
+
+                    Some((Command::Exec(command, timeout), reply)) => {
+                        let data = format!("{command}\r").into_bytes();
+
+                        if session.start_exec(command, timeout, reply) {
+                            input.send(data).await?;
+                        }
+                    }
+
+                    Some((Command::WaitForStable(quiet, timeout), reply)) => {
+                        session.update_screen_hash();
+                        session.wait_for_stable(quiet, timeout, reply);
+                    }
+
+                    Some((Command::Signal(sig, target), reply)) => {
+                        if let Some(error) = session.signal(sig, target) {
+                            let _ = reply.send(error);
+                        }
                     }
 
                     None => {
-                        eprintln!("stdin closed, shutting down...");
+                        (&mut api_handle).await??;
                         break;
                     }
                 }
@@ -157,12 +920,173 @@ async fn run_event_loop(
                 }
             }
 
-            _ = &mut api_handle => {
-                eprintln!("stdin closed, shutting down...");
+            view = views_rx.recv(), if viewing => {
+                match view {
+                    Some((ViewRequest::GetView, reply)) => {
+                        let _ = reply.send(session.get_view(Trim::default(), None, &reply));
+                    }
+
+                    Some((ViewRequest::Scroll(scroll), reply)) => {
+                        let _ = reply.send(session.scroll(scroll, &reply));
+                    }
+
+                    Some((ViewRequest::Follow(enabled), reply)) => {
+                        let _ = reply.send(session.follow(enabled, &reply));
+                    }
+
+                    None => {
+                        viewing = false;
+                    }
+                }
+            }
+
+            Ok(()) = input.written_rx.changed(), if session.is_pasting() || input.is_flushing() => {
+                input.continue_paste(&mut session).await?;
+                input.complete_flushes();
+            }
+
+            result = &mut api_handle => {
+                result??;
+                break;
+            }
+
+            _ = deadline(session.exec_deadline()), if session.exec_deadline().is_some() => {
+                session.exec_timeout();
+            }
+
+            _ = deadline(session.stable_deadline()), if session.stable_deadline().is_some() => {
+                session.check_stable();
+            }
+
+            _ = deadline(session.macro_deadline()), if session.macro_deadline().is_some() => {
+                input.send(session.take_macro_input()).await?;
+            }
+
+            _ = poll.tick() => {
+                if session.has_terminal() {
+                    session.update_foreground();
+                }
+
+                session.update_screen_hash();
+            }
+
+            _ = tick(&mut heartbeat), if heartbeat.is_some() => {
+                session.heartbeat();
+            }
+
+            _ = watchdog(cli.watchdog, last_output), if cli.watchdog.is_some() && !session.is_suspended() => {
+                let idle = last_output.elapsed();
+                eprintln!("no output for {idle:?}, shutting down...");
+                session.timeout(idle);
+                // ht exits right away after a timeout, so the event goes out first
+                session.drain(DRAIN_TIMEOUT).await;
+
+                return Ok(Outcome::TimedOut);
+            }
+
+            _ = sigterm.recv() => {
+                eprintln!("received SIGTERM, shutting down...");
                 break;
             }
         }
     }
 
+    Ok(Outcome::Exited)
+}
+
+async fn process_output(session: &mut Session, data: Bytes, input: &mut Input) -> Result<()> {
+    session.output(data);
+    session.apply_resize_request();
+
+    let answers = session.take_clipboard_answers();
+
+    if !answers.is_empty() {
+        input.send(answers).await?;
+    }
+
     Ok(())
 }
+
+/// Input channel of the child, tracking how much of the input it accepted.
+struct Input {
+    tx: mpsc::Sender<Vec<u8>>,
+    written_rx: watch::Receiver<u64>,
+    sent: u64,
+    /// Replies to `flush` commands, sent once the input is written up to the
+    /// given byte
+    flushes: Vec<(u64, Reply)>,
+}
+
+impl Input {
+    fn new(tx: mpsc::Sender<Vec<u8>>, written_rx: watch::Receiver<u64>) -> Self {
+        Self {
+            tx,
+            written_rx,
+            sent: 0,
+            flushes: Vec::new(),
+        }
+    }
+
+    fn flush(&mut self, reply: Reply) {
+        self.flushes.push((self.sent, reply));
+        self.complete_flushes();
+    }
+
+    fn is_flushing(&self) -> bool {
+        !self.flushes.is_empty()
+    }
+
+    fn complete_flushes(&mut self) {
+        let written = *self.written_rx.borrow();
+
+        self.flushes.retain(|(end, reply)| {
+            if *end > written {
+                return true;
+            }
+
+            let _ = reply.send(session::flush_reply(written));
+
+            false
+        });
+    }
+
+    async fn send(&mut self, data: Vec<u8>) -> Result<()> {
+        self.sent += data.len() as u64;
+        self.tx.send(data).await?;
+
+        Ok(())
+    }
+
+    /// Sends the next chunk of the paste once the child accepted all the
+    /// input sent so far.
+    async fn continue_paste(&mut self, session: &mut Session) -> Result<()> {
+        if *self.written_rx.borrow_and_update() < self.sent {
+            return Ok(());
+        }
+
+        if let Some(chunk) = session.next_paste_chunk() {
+            self.send(chunk).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn deadline(at: Option<std::time::Instant>) {
+    match at {
+        Some(at) => time::sleep_until(Instant::from_std(at)).await,
+        None => future::pending().await,
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    if let Some(interval) = interval {
+        interval.tick().await;
+    }
+}
+
+async fn watchdog(timeout: Option<Duration>, last_output: Instant) {
+    if let Some(timeout) = timeout {
+        time::sleep_until(last_output + timeout).await;
+    }
+}
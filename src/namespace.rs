@@ -0,0 +1,339 @@
+use crate::prexec::PreExec;
+use anyhow::Result;
+
+/// Linux namespace the child can be isolated in.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Namespace {
+    /// User and group IDs
+    User,
+    /// Network interfaces, with only a loopback interface in the new one
+    Net,
+    /// Process IDs, with the command as the init process
+    Pid,
+    /// Mount points
+    Mount,
+    /// Hostname
+    Uts,
+    /// System V IPC and POSIX message queues
+    Ipc,
+}
+
+/// Isolates the child in new namespaces, created right before exec. Without
+/// root privileges a user namespace is always created, as the others require
+/// one. In a new user namespace, ht's user and group are mapped to
+/// `map_user` (or to themselves), and the group to root when mapped to root.
+#[cfg(target_os = "linux")]
+pub fn isolate(
+    pre_exec: &mut PreExec,
+    namespaces: &[Namespace],
+    map_user: Option<u32>,
+) -> Result<()> {
+    use nix::sched::CloneFlags;
+    use nix::unistd;
+
+    let mut flags = CloneFlags::empty();
+
+    for namespace in namespaces {
+        flags |= match namespace {
+            Namespace::User => CloneFlags::CLONE_NEWUSER,
+            Namespace::Net => CloneFlags::CLONE_NEWNET,
+            Namespace::Pid => CloneFlags::CLONE_NEWPID,
+            Namespace::Mount => CloneFlags::CLONE_NEWNS,
+            Namespace::Uts => CloneFlags::CLONE_NEWUTS,
+            Namespace::Ipc => CloneFlags::CLONE_NEWIPC,
+        };
+    }
+
+    if map_user.is_some() || (!flags.is_empty() && !unistd::geteuid().is_root()) {
+        flags |= CloneFlags::CLONE_NEWUSER;
+    }
+
+    let uid = unistd::getuid().as_raw();
+    let gid = unistd::getgid().as_raw();
+    let inner_uid = map_user.unwrap_or(uid);
+    let inner_gid = if inner_uid == 0 { 0 } else { gid };
+    let uid_map = format!("{inner_uid} {uid} 1");
+    let gid_map = format!("{inner_gid} {gid} 1");
+
+    pre_exec.hook(move || {
+        linux::unshare(flags)?;
+
+        if flags.contains(CloneFlags::CLONE_NEWUSER) {
+            linux::map_ids(&uid_map, &gid_map)?;
+        }
+
+        if flags.contains(CloneFlags::CLONE_NEWNS) {
+            linux::make_mounts_private()?;
+        }
+
+        if flags.contains(CloneFlags::CLONE_NEWNET) {
+            linux::loopback_up()?;
+        }
+
+        if flags.contains(CloneFlags::CLONE_NEWPID) {
+            linux::enter_pid_namespace(flags.contains(CloneFlags::CLONE_NEWNS))?;
+        }
+
+        Ok(())
+    });
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn isolate(
+    _pre_exec: &mut PreExec,
+    _namespaces: &[Namespace],
+    _map_user: Option<u32>,
+) -> Result<()> {
+    anyhow::bail!("namespaces are only available on Linux")
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use nix::libc;
+    use nix::mount::{self, MsFlags};
+    use nix::sched::{self, CloneFlags};
+    use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    use nix::sys::wait::{self, WaitStatus};
+    use nix::unistd::{self, ForkResult, Pid};
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+    pub fn unshare(flags: CloneFlags) -> io::Result<()> {
+        sched::unshare(flags)?;
+        Ok(())
+    }
+
+    /// Maps the user and group in the new user namespace, which is only
+    /// allowed once setgroups(2) is disabled.
+    pub fn map_ids(uid_map: &str, gid_map: &str) -> io::Result<()> {
+        fs::write("/proc/self/uid_map", uid_map)?;
+        fs::write("/proc/self/setgroups", "deny")?;
+        fs::write("/proc/self/gid_map", gid_map)?;
+
+        Ok(())
+    }
+
+    /// Keeps mounts done in the new mount namespace from propagating back.
+    pub fn make_mounts_private() -> io::Result<()> {
+        let flags = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+        mount::mount(None::<&str>, "/", None::<&str>, flags, None::<&str>)?;
+
+        Ok(())
+    }
+
+    /// Brings up the loopback interface, which is down in a new network
+    /// namespace.
+    pub fn loopback_up() -> io::Result<()> {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut req: libc::ifreq = unsafe { mem::zeroed() };
+        req.ifr_name[..2].copy_from_slice(&[b'l' as libc::c_char, b'o' as libc::c_char]);
+
+        let result = unsafe {
+            if libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut req) == 0 {
+                req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+                libc::ioctl(fd, libc::SIOCSIFFLAGS, &req)
+            } else {
+                -1
+            }
+        };
+
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+
+        if result < 0 {
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Forks, as only children of the process which created the PID namespace
+    /// are in it. The child returns, to carry on with the setup and exec the
+    /// command, as the namespace's init process. The parent stays behind as
+    /// the process ht knows, passing on the command's exit status.
+    pub fn enter_pid_namespace(mount_proc: bool) -> io::Result<()> {
+        match unsafe { unistd::fork() }? {
+            ForkResult::Child => {
+                // the namespace goes down with its init process
+                unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+
+                if mount_proc {
+                    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+                    mount::mount(Some("proc"), "/proc", Some("proc"), flags, None::<&str>)?;
+                }
+
+                Ok(())
+            }
+
+            ForkResult::Parent { child } => supervise(child),
+        }
+    }
+
+    /// Pid of the command, as seen by the supervisor.
+    static CHILD: AtomicI32 = AtomicI32::new(0);
+
+    /// Whether the supervisor was told to end the command.
+    static ENDING: AtomicBool = AtomicBool::new(false);
+
+    /// How long the command gets to exit after SIGHUP or SIGTERM before it's
+    /// killed, in seconds. As the namespace's init process, it doesn't get
+    /// the signals it has no handler for.
+    const KILL_DELAY: u32 = 5;
+
+    /// Passes a signal on to the command. Signals sent to the whole process
+    /// group (or by the terminal) reach the command on their own, so only
+    /// the ones queued for this process (see `Session::signal`) are passed
+    /// on, except for SIGHUP and SIGTERM, which end the command.
+    extern "C" fn forward(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        let child = CHILD.load(Ordering::SeqCst);
+
+        match sig {
+            libc::SIGHUP | libc::SIGTERM => {
+                ENDING.store(true, Ordering::SeqCst);
+                unsafe {
+                    libc::kill(child, sig);
+                    libc::alarm(KILL_DELAY);
+                }
+            }
+
+            libc::SIGALRM if ENDING.load(Ordering::SeqCst) => unsafe {
+                libc::kill(child, libc::SIGKILL);
+            },
+
+            _ if unsafe { (*info).si_code } == libc::SI_QUEUE => unsafe {
+                libc::kill(child, sig);
+            },
+
+            _ => {}
+        }
+    }
+
+    /// Passes the signals on to the command, and waits for it, exiting the
+    /// same way it did.
+    fn supervise(child: Pid) -> ! {
+        // let go of the pipes, so their other ends see EOF when the command exits
+        let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(3, 1 << 20);
+
+        for fd in 0..max as libc::c_int {
+            unsafe { libc::close(fd) };
+        }
+
+        CHILD.store(child.as_raw(), Ordering::SeqCst);
+        let flags = SaFlags::SA_SIGINFO | SaFlags::SA_RESTART;
+        let action = SigAction::new(SigHandler::SigAction(forward), flags, SigSet::empty());
+
+        for sig in Signal::iterator() {
+            // the faults are left alone, as they'd repeat right after the handler
+            if !matches!(
+                sig,
+                Signal::SIGKILL
+                    | Signal::SIGSTOP
+                    | Signal::SIGCHLD
+                    | Signal::SIGSEGV
+                    | Signal::SIGBUS
+                    | Signal::SIGILL
+                    | Signal::SIGFPE
+                    | Signal::SIGTRAP
+                    | Signal::SIGSYS
+            ) {
+                let _ = unsafe { signal::sigaction(sig, &action) };
+            }
+        }
+
+        loop {
+            match wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, status)) => unsafe { libc::_exit(status) },
+
+                Ok(WaitStatus::Signaled(_, sig, _)) => {
+                    let _ = unsafe { signal::signal(sig, SigHandler::SigDfl) };
+                    let _ = signal::raise(sig);
+                    unsafe { libc::_exit(128 + sig as i32) }
+                }
+
+                Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+
+                Err(_) => unsafe { libc::_exit(1) },
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::Namespace;
+    use crate::command::SignalTarget;
+    use crate::environ::Environment;
+    use crate::prexec::PreExec;
+    use crate::pty;
+    use crate::session::Session;
+    use nix::pty::Winsize;
+    use nix::sys::signal::Signal;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+
+    #[tokio::test]
+    async fn forward_signals() {
+        let mut pre_exec = PreExec::new(Environment::default());
+        super::isolate(&mut pre_exec, &[Namespace::Pid], None).unwrap();
+
+        let winsize = Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (written_tx, _) = watch::channel(0);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+
+        let (pid, _master, driver) = pty::spawn(
+            "trap 'echo got USR1; exit 3' USR1; echo ready; while :; do sleep 0.1; done".to_owned(),
+            &winsize,
+            input_rx,
+            written_tx,
+            output_tx,
+            None,
+            &pre_exec,
+        )
+        .unwrap();
+
+        let driver = tokio::spawn(driver);
+        let mut output = String::new();
+
+        while !output.contains("ready") {
+            let chunk = output_rx.recv().await.unwrap();
+            output.push_str(&String::from_utf8_lossy(&chunk));
+
+            // namespaces aren't available everywhere, e.g. in some containers
+            if output.contains("cannot start the command") {
+                return;
+            }
+        }
+
+        let mut session = Session::new(80, 24, pid);
+        assert_eq!(session.signal(Signal::SIGUSR1, SignalTarget::Child), None);
+
+        // the command, not only its supervisor, exits
+        tokio::time::timeout(Duration::from_secs(5), driver)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        while let Some(chunk) = output_rx.recv().await {
+            output.push_str(&String::from_utf8_lossy(&chunk));
+        }
+
+        assert!(output.contains("got USR1"));
+    }
+}
@@ -1,5 +1,13 @@
-use std::io::{self, ErrorKind, Read};
-use std::{io::Write, os::fd::RawFd};
+use bytes::{Bytes, BytesMut};
+use nix::libc;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 pub fn set_non_blocking(fd: &RawFd) -> Result<(), io::Error> {
     use nix::fcntl::{fcntl, FcntlArg::*, OFlag};
@@ -12,34 +20,231 @@ pub fn set_non_blocking(fd: &RawFd) -> Result<(), io::Error> {
     Ok(())
 }
 
-pub fn read<R: Read + ?Sized>(source: &mut R, buf: &mut [u8]) -> io::Result<Option<usize>> {
-    match source.read(buf) {
-        Ok(n) => Ok(Some(n)),
+/// Terminal or other character device, read and written asynchronously.
+///
+/// EIO, which reading the PTY master returns once the child closed the other
+/// side, is reported as EOF. Writes failing with EIO return 0.
+pub struct AsyncFile(AsyncFd<File>);
 
-        Err(e) => {
-            if e.kind() == ErrorKind::WouldBlock {
-                Ok(None)
-            } else if e.raw_os_error().is_some_and(|code| code == 5) {
-                Ok(Some(0))
-            } else {
-                return Err(e);
+impl AsyncFile {
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        set_non_blocking(&fd.as_raw_fd())?;
+
+        Ok(Self(AsyncFd::new(File::from(fd))?))
+    }
+
+    /// Reads into the spare capacity of the buffers, in order, with a single
+    /// readv(2), waiting until there's data. The spare capacity isn't zeroed
+    /// before reading. Returns 0 on EOF.
+    pub async fn read_vectored(&self, bufs: &mut [BytesMut]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.readable().await?;
+
+            let mut iovecs: Vec<libc::iovec> = bufs
+                .iter_mut()
+                .map(|buf| {
+                    let spare = buf.spare_capacity_mut();
+
+                    libc::iovec {
+                        iov_base: spare.as_mut_ptr().cast(),
+                        iov_len: spare.len(),
+                    }
+                })
+                .collect();
+
+            if let Ok(result) =
+                guard.try_io(|file| eio_as_eof(readv_uninit(file.as_raw_fd(), &mut iovecs)))
+            {
+                let total = result?;
+                let mut n = total;
+
+                for buf in bufs.iter_mut() {
+                    let len = n.min(buf.capacity() - buf.len());
+
+                    // the len bytes following the initialized part were just read
+                    unsafe { buf.set_len(buf.len() + len) };
+                    n -= len;
+                }
+
+                return Ok(total);
             }
         }
     }
 }
 
-pub fn write<W: Write + ?Sized>(sink: &mut W, buf: &[u8]) -> io::Result<Option<usize>> {
-    match sink.write(buf) {
-        Ok(n) => Ok(Some(n)),
+/// Buffers which output is read into, with vectored reads.
+///
+/// A burst of output fills several buffers with one syscall, and each buffer
+/// is reused once the chunks split off it have been dropped, so a chunk still
+/// held by a slow consumer pins only its own buffer rather than a whole read.
+pub struct BufferPool {
+    bufs: Vec<BytesMut>,
+    size: usize,
+}
+
+impl BufferPool {
+    /// Pool of `count` buffers, each with room for at least `size` bytes.
+    pub fn new(count: usize, size: usize) -> Self {
+        Self {
+            bufs: (0..count).map(|_| BytesMut::new()).collect(),
+            size,
+        }
+    }
+
+    /// Reads from the file into the buffers, waiting until there's data.
+    /// Returns the data as one chunk per buffer it went into, and no chunks on
+    /// EOF.
+    pub async fn read(&mut self, file: &AsyncFile) -> io::Result<Vec<Bytes>> {
+        for buf in &mut self.bufs {
+            // reclaims the buffer once the chunks split off it have been dropped
+            buf.reserve(self.size);
+        }
+
+        file.read_vectored(&mut self.bufs).await?;
 
-        Err(e) => {
-            if e.kind() == ErrorKind::WouldBlock {
-                Ok(None)
-            } else if e.raw_os_error().is_some_and(|code| code == 5) {
-                Ok(Some(0))
-            } else {
-                return Err(e);
+        let chunks = self
+            .bufs
+            .iter_mut()
+            .filter(|buf| !buf.is_empty())
+            .map(|buf| buf.split().freeze())
+            .collect();
+
+        Ok(chunks)
+    }
+}
+
+impl AsyncRead for AsyncFile {
+    /// Reads into the unfilled part of `buf`, without initializing it first.
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = unsafe { buf.unfilled_mut() };
+
+            if let Ok(result) =
+                guard.try_io(|file| eio_as_eof(read_uninit(file.as_raw_fd(), unfilled)))
+            {
+                let n = result?;
+
+                // the n bytes following the filled part were just read
+                unsafe { buf.assume_init(n) };
+                buf.advance(n);
+
+                return Poll::Ready(Ok(()));
             }
         }
     }
 }
+
+impl AsyncWrite for AsyncFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut file = &*self;
+
+        Pin::new(&mut file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writes through a shared reference, like `&File` does, so the file can be
+/// written while `read_vectored` waits for it.
+impl AsyncWrite for &AsyncFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+
+            if let Ok(result) = guard.try_io(|file| eio_as_eof(file.get_ref().write(buf))) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn read_uninit(fd: RawFd, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+    match unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+fn readv_uninit(fd: RawFd, iovecs: &mut [libc::iovec]) -> io::Result<usize> {
+    match unsafe { libc::readv(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+fn eio_as_eof(result: io::Result<usize>) -> io::Result<usize> {
+    match result {
+        Err(e) if e.kind() != ErrorKind::WouldBlock && e.raw_os_error() == Some(5) => Ok(0),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncFile, BufferPool};
+    use nix::unistd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn read_into_pool() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap();
+        let file = AsyncFile::new(read_fd).unwrap();
+        let mut pool = BufferPool::new(4, 2000);
+        unistd::write(&write_fd, &[b'x'; 5000]).unwrap();
+
+        // one read spread over the buffers
+        let chunks = pool.read(&file).await.unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), [b'x'; 5000]);
+
+        // the buffers are reused, whether or not the chunks are still held
+        unistd::write(&write_fd, b"hello").unwrap();
+        assert_eq!(pool.read(&file).await.unwrap(), [&b"hello"[..]]);
+        drop(chunks);
+        unistd::write(&write_fd, b"again").unwrap();
+        assert_eq!(pool.read(&file).await.unwrap(), [&b"again"[..]]);
+
+        drop(write_fd);
+        assert!(pool.read(&file).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_and_write_halves() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap();
+        let (mut reader, _) = tokio::io::split(AsyncFile::new(read_fd).unwrap());
+        let (_, mut writer) = tokio::io::split(AsyncFile::new(write_fd).unwrap());
+
+        assert_eq!(writer.write(b"hello").await.unwrap(), 5);
+
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+}
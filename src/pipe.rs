@@ -0,0 +1,266 @@
+use crate::nbio;
+use crate::prexec::PreExec;
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
+use std::future::Future;
+use std::mem;
+use std::os::fd::OwnedFd;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+/// Number and size of the buffers output is read into.
+const READ_BUF_COUNT: usize = 4;
+const READ_BUF_SIZE: usize = 32 * 1024;
+
+/// How long a partial line, e.g. a prompt, waits for the rest of it before
+/// it's delivered as it is.
+const PARTIAL_LINE_DELAY: Duration = Duration::from_millis(50);
+
+/// Value of TERM set for the child.
+pub const TERM: &str = "dumb";
+
+/// Runs the command with plain pipes instead of a PTY.
+///
+/// stdout (and stderr, unless `stderr_tx` is given) is delivered in whole
+/// lines, with LF translated to CRLF like a terminal's line discipline would.
+/// A partial line is delivered once no more output follows for
+/// `PARTIAL_LINE_DELAY`, or once it outgrows `READ_BUF_SIZE`.
+pub fn spawn(
+    command: String,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+    stderr_tx: Option<mpsc::Sender<Bytes>>,
+    pre_exec: Arc<PreExec>,
+) -> Result<(i32, impl Future<Output = Result<()>>)> {
+    let (read_fd, write_fd) = unistd::pipe()?;
+
+    let stderr = match stderr_tx {
+        Some(_) => Stdio::piped(),
+        None => Stdio::from(write_fd.try_clone()?),
+    };
+
+    let mut child = Command::new("/bin/sh");
+    pre_exec.env().apply_to(&mut child);
+
+    // own process group by default, so signals can be sent to the whole job
+    unsafe {
+        child.pre_exec(move || pre_exec.run());
+    }
+
+    let mut child = child
+        .arg("-c")
+        .arg(command)
+        .env("TERM", TERM)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(write_fd))
+        .stderr(stderr)
+        .spawn()?;
+
+    let pid = child.id().unwrap_or_default() as i32;
+
+    if let (Some(stderr), Some(stderr_tx)) = (child.stderr.take(), stderr_tx) {
+        tokio::spawn(forward(stderr.into_owned_fd()?, stderr_tx));
+    }
+
+    Ok((
+        pid,
+        drive_child(child, read_fd, input_rx, written_tx, output_tx),
+    ))
+}
+
+/// Forwards everything read from a pipe to the channel, until EOF.
+pub async fn forward(fd: OwnedFd, tx: mpsc::Sender<Bytes>) -> Result<()> {
+    let mut pipe = unix::pipe::Receiver::from_owned_fd(fd)?;
+    let mut buf = BytesMut::new();
+
+    loop {
+        buf.reserve(READ_BUF_SIZE);
+
+        match pipe.read_buf(&mut buf).await? {
+            0 => return Ok(()),
+            _ => tx.send(buf.split().freeze()).await?,
+        }
+    }
+}
+
+async fn drive_child(
+    mut child: Child,
+    stdout: OwnedFd,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<()> {
+    let stdin = child.stdin.take().unwrap();
+    let mut writer = tokio::spawn(write_input(stdin, input_rx, written_tx));
+    let result = do_drive_child(stdout, &mut writer, output_tx).await;
+
+    if let Some(pid) = child.id() {
+        eprintln!("sending HUP signal to the child process");
+        let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGHUP);
+        // in case it's been suspended
+        let _ = signal::killpg(Pid::from_raw(pid as i32), Signal::SIGCONT);
+    }
+
+    eprintln!("waiting for the child process to exit");
+    child.wait().await?;
+
+    result
+}
+
+async fn do_drive_child(
+    stdout: OwnedFd,
+    writer: &mut tokio::task::JoinHandle<()>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<()> {
+    let stdout = nbio::AsyncFile::new(stdout)?;
+    let mut pool = nbio::BufferPool::new(READ_BUF_COUNT, READ_BUF_SIZE);
+    let mut pending = Vec::new();
+
+    loop {
+        let (data, eof) = tokio::select! {
+            _ = &mut *writer => {
+                return Ok(());
+            }
+
+            result = pool.read(&stdout) => {
+                let chunks = result?;
+                let mut data = Vec::new();
+
+                for chunk in &chunks {
+                    data.extend(frame_lines(&mut pending, chunk).unwrap_or_default());
+                }
+
+                if chunks.is_empty() || pending.len() >= READ_BUF_SIZE {
+                    data.extend(translate_newlines(&mem::take(&mut pending)));
+                }
+
+                (data, chunks.is_empty())
+            }
+
+            _ = time::sleep(PARTIAL_LINE_DELAY), if !pending.is_empty() => {
+                (translate_newlines(&mem::take(&mut pending)), false)
+            }
+        };
+
+        if !data.is_empty() && output_tx.send(Bytes::from(data)).await.is_err() {
+            return Ok(());
+        }
+
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes input to the child's stdin until the input channel is closed.
+///
+/// Once the child stops reading its stdin the remaining input is discarded
+/// (and counted as written).
+async fn write_input(
+    mut stdin: ChildStdin,
+    mut input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+) {
+    let mut open = true;
+
+    while let Some(data) = input_rx.recv().await {
+        if open && stdin.write_all(&data).await.is_err() {
+            open = false;
+        }
+
+        written_tx.send_modify(|total| *total += data.len() as u64);
+    }
+}
+
+/// Appends data to the pending buffer and takes out all complete lines.
+fn frame_lines(pending: &mut Vec<u8>, data: &[u8]) -> Option<Vec<u8>> {
+    pending.extend_from_slice(data);
+    let end = pending.iter().rposition(|b| *b == b'\n')? + 1;
+    let rest = pending.split_off(end);
+    let lines = mem::replace(pending, rest);
+
+    Some(translate_newlines(&lines))
+}
+
+fn translate_newlines(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+
+    for b in data {
+        if *b == b'\n' {
+            result.push(b'\r');
+        }
+
+        result.push(*b);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::frame_lines;
+    use crate::prexec::PreExec;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time;
+
+    #[test]
+    fn frame_complete_lines() {
+        let mut pending = Vec::new();
+
+        assert_eq!(frame_lines(&mut pending, b"hel"), None);
+        assert_eq!(
+            frame_lines(&mut pending, b"lo\nwor"),
+            Some(b"hello\r\n".to_vec())
+        );
+        assert_eq!(
+            frame_lines(&mut pending, b"ld\n\n!"),
+            Some(b"world\r\n\r\n".to_vec())
+        );
+        assert_eq!(pending, b"!");
+    }
+
+    #[tokio::test]
+    async fn deliver_prompt() {
+        let (input_tx, input_rx) = mpsc::channel(1);
+        let (written_tx, _) = watch::channel(0);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+
+        let (_pid, driver) = super::spawn(
+            r#"printf 'Name: '; read name; echo "hi $name""#.to_owned(),
+            input_rx,
+            written_tx,
+            output_tx,
+            None,
+            Arc::new(PreExec::default()),
+        )
+        .unwrap();
+
+        let driver = tokio::spawn(driver);
+
+        // the prompt shows up without waiting for the end of its line
+        let prompt = time::timeout(Duration::from_secs(5), output_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&prompt[..], b"Name: ");
+
+        input_tx.send(b"ht\n".to_vec()).await.unwrap();
+        let greeting = output_rx.recv().await.unwrap();
+        assert_eq!(&greeting[..], b"hi ht\r\n");
+
+        drop(input_tx);
+        driver.await.unwrap().unwrap();
+    }
+}
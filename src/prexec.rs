@@ -0,0 +1,159 @@
+use crate::environ::Environment;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::sys::stat::{self, Mode};
+use nix::unistd::{self, Pid};
+use std::io;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+
+type Hook = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+/// Process group (and session) the child is put in. Only applies to children
+/// without a PTY: with a PTY the child always leads its own session, with the
+/// PTY as its controlling terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Group {
+    /// New process group, in ht's session
+    #[default]
+    Own,
+    /// Existing process group, in ht's session
+    Join(i32),
+    /// New session, without a controlling terminal
+    Session,
+}
+
+/// Setup of the child process, done in the forked child right before exec:
+/// its environment, process group, and a list of hooks, run in the order
+/// they were added. As hooks run after fork in a multi-threaded process,
+/// they should stick to plain syscalls: no locking, which includes
+/// allocating memory.
+#[derive(Default)]
+pub struct PreExec {
+    env: Environment,
+    group: Group,
+    hooks: Vec<Hook>,
+}
+
+impl PreExec {
+    pub fn new(env: Environment) -> Self {
+        Self {
+            env,
+            ..Self::default()
+        }
+    }
+
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    pub fn set_group(&mut self, group: Group) {
+        self.group = group;
+    }
+
+    /// Adds a hook. The child fails to start when a hook returns an error.
+    pub fn hook<F>(&mut self, f: F)
+    where
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(f));
+    }
+
+    /// Sets the working directory of the child.
+    pub fn chdir(&mut self, dir: PathBuf) {
+        self.hook(move || {
+            unistd::chdir(&dir)?;
+            Ok(())
+        });
+    }
+
+    /// Sets the file mode creation mask of the child.
+    pub fn umask(&mut self, mask: u32) {
+        self.hook(move || {
+            stat::umask(Mode::from_bits_truncate(mask as libc::mode_t));
+            Ok(())
+        });
+    }
+
+    /// Lets the child inherit a file descriptor of ht, which must be open.
+    pub fn inherit_fd(&mut self, fd: RawFd) {
+        self.hook(move || {
+            let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD)?);
+            fcntl(fd, FcntlArg::F_SETFD(flags - FdFlag::FD_CLOEXEC))?;
+            Ok(())
+        });
+    }
+
+    /// Stops the child from inheriting any file descriptors other than stdin,
+    /// stdout and stderr. Descriptors are marked close-on-exec rather than
+    /// closed, so later hooks, and the spawning code, can still use theirs,
+    /// and are kept with `inherit_fd` added after this.
+    pub fn close_fds(&mut self) {
+        self.hook(|| {
+            let max = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+                max if max > 0 => max.min(1 << 20) as RawFd,
+                _ => 1024,
+            };
+
+            for fd in 3..max {
+                // fails for descriptors which aren't open
+                let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+            }
+
+            Ok(())
+        });
+    }
+
+    /// Puts the current process in the process group, then runs the hooks.
+    /// The environment is left to the spawning code.
+    pub fn run(&self) -> io::Result<()> {
+        match self.group {
+            Group::Own => unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))?,
+            Group::Join(pgid) => unistd::setpgid(Pid::from_raw(0), Pid::from_raw(pgid))?,
+            Group::Session => {
+                unistd::setsid()?;
+            }
+        }
+
+        self.run_hooks()
+    }
+
+    /// Runs the hooks, leaving the process group alone.
+    pub fn run_hooks(&self) -> io::Result<()> {
+        for hook in &self.hooks {
+            hook()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PreExec;
+    use crate::environ::Environment;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn run_hooks_in_order() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut pre_exec = PreExec::new(Environment::default());
+
+        for i in 0..3 {
+            let calls = calls.clone();
+
+            pre_exec.hook(move || {
+                assert_eq!(calls.fetch_add(1, Ordering::SeqCst), i);
+                Ok(())
+            });
+        }
+
+        pre_exec.hook(|| Err(io::Error::from_raw_os_error(1)));
+        pre_exec.hook(|| panic!("run after a failed hook"));
+
+        assert!(pre_exec.run_hooks().is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
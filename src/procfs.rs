@@ -0,0 +1,134 @@
+use serde_json::json;
+use std::fs;
+use std::io;
+
+/// Process information, as read from `/proc/<pid>/stat`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    pub pid: i32,
+    pub ppid: i32,
+    pub pgrp: i32,
+    pub name: String,
+    pub state: char,
+}
+
+impl Process {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "pid": self.pid,
+            "ppid": self.ppid,
+            "pgid": self.pgrp,
+            "name": self.name,
+            "state": state_name(self.state),
+        })
+    }
+}
+
+/// Reads information about a single process.
+pub fn process(pid: i32) -> io::Result<Process> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))?;
+
+    parse_stat(&stat)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc stat"))
+}
+
+/// Returns the process `pid` followed by all of its descendants, parents
+/// before their children.
+pub fn tree(pid: i32) -> io::Result<Vec<Process>> {
+    let root = process(pid)?;
+    let mut all = Vec::new();
+
+    for entry in fs::read_dir("/proc")? {
+        let Some(pid) = entry?.file_name().to_str().and_then(|n| n.parse().ok()) else {
+            continue;
+        };
+
+        // the process may have exited in the meantime
+        if let Ok(process) = process(pid) {
+            all.push(process);
+        }
+    }
+
+    all.sort_by_key(|p| p.pid);
+    let mut tree = vec![root];
+    let mut i = 0;
+
+    while i < tree.len() {
+        let parent = tree[i].pid;
+        tree.extend(all.iter().filter(|p| p.ppid == parent).cloned());
+        i += 1;
+    }
+
+    Ok(tree)
+}
+
+fn parse_stat(stat: &str) -> Option<Process> {
+    // the name is in parens and may contain anything, including parens
+    let (pid, rest) = stat.split_once(" (")?;
+    let (name, rest) = rest.rsplit_once(") ")?;
+    let mut fields = rest.split(' ');
+
+    Some(Process {
+        pid: pid.parse().ok()?,
+        name: name.to_owned(),
+        state: fields.next()?.chars().next()?,
+        ppid: fields.next()?.parse().ok()?,
+        pgrp: fields.next()?.parse().ok()?,
+    })
+}
+
+fn state_name(state: char) -> &'static str {
+    match state {
+        'R' => "running",
+        'S' => "sleeping",
+        'D' => "waiting",
+        'Z' => "zombie",
+        'T' => "stopped",
+        't' => "tracing",
+        'X' | 'x' => "dead",
+        'I' => "idle",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_stat, tree, Process};
+
+    #[test]
+    fn parse_process_stat() {
+        let process = parse_stat("4242 (make (x) ) S 4200 4242 4200 34816 4242 4194304 ...");
+
+        assert_eq!(
+            process,
+            Some(Process {
+                pid: 4242,
+                ppid: 4200,
+                pgrp: 4242,
+                name: "make (x) ".to_owned(),
+                state: 'S',
+            })
+        );
+
+        assert_eq!(parse_stat("4242 make S 1 1"), None);
+    }
+
+    #[test]
+    fn process_tree() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let pid = std::process::id() as i32;
+        let processes = tree(pid).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(processes[0].pid, pid);
+
+        assert!(processes
+            .iter()
+            .any(|p| p.pid == child.id() as i32 && p.ppid == pid));
+    }
+}
@@ -1,50 +1,137 @@
 use crate::nbio;
+use crate::pipe;
+use crate::prexec::PreExec;
 use anyhow::Result;
+use bytes::Bytes;
 use nix::libc;
 use nix::pty;
 use nix::sys::signal::{self, SigHandler, Signal};
 use nix::sys::wait;
 use nix::unistd::{self, ForkResult, Pid};
-use std::env;
+use std::convert::Infallible;
 use std::ffi::{CString, NulError};
-use std::fs::File;
 use std::future::Future;
-use std::io;
-use std::os::fd::FromRawFd;
+use std::io::{self, Write};
 use std::os::fd::{AsRawFd, OwnedFd};
-use tokio::io::unix::AsyncFd;
-use tokio::sync::mpsc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
 
 pub fn spawn(
     command: String,
     winsize: &pty::Winsize,
     input_rx: mpsc::Receiver<Vec<u8>>,
-    output_tx: mpsc::Sender<Vec<u8>>,
-) -> Result<(i32, impl Future<Output = Result<()>>)> {
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+    stderr_tx: Option<mpsc::Sender<Bytes>>,
+    pre_exec: &PreExec,
+) -> Result<(i32, OwnedFd, impl Future<Output = Result<()>>)> {
+    // built before forking, as the child can't allocate
+    let argv = argv(command)?;
+    let mut env = pre_exec.env().clone();
+    env.set("TERM", TERM);
+    let envp = env.envp()?;
+    let stderr_pipe = stderr_tx.as_ref().map(|_| unistd::pipe()).transpose()?;
     let result = unsafe { pty::forkpty(Some(winsize), None) }?;
 
     match result.fork_result {
-        ForkResult::Parent { child } => Ok((
-            child.as_raw(),
-            drive_child(child, result.master, input_rx, output_tx),
-        )),
+        ForkResult::Parent { child } => {
+            if let (Some((read_fd, _write_fd)), Some(stderr_tx)) = (stderr_pipe, stderr_tx) {
+                tokio::spawn(pipe::forward(read_fd, stderr_tx));
+            }
+
+            Ok((
+                child.as_raw(),
+                result.master.try_clone()?,
+                drive_child(child, result.master, input_rx, written_tx, output_tx),
+            ))
+        }
 
         ForkResult::Child => {
-            exec(command)?;
-            unreachable!();
+            let Err(error) = start_child(&argv, &envp, stderr_pipe, pre_exec);
+            child_failed(&error)
         }
     }
 }
 
+/// Sets up the forked child, then execs the command, returning only on
+/// failure.
+fn start_child(
+    argv: &[CString],
+    envp: &[CString],
+    stderr_pipe: Option<(OwnedFd, OwnedFd)>,
+    pre_exec: &PreExec,
+) -> io::Result<Infallible> {
+    if let Some((read_fd, write_fd)) = stderr_pipe {
+        drop(read_fd);
+        unistd::dup2(write_fd.as_raw_fd(), libc::STDERR_FILENO)?;
+    }
+
+    // forkpty made the child a session leader, so the group is left alone
+    pre_exec.run_hooks()?;
+    unsafe { signal::signal(Signal::SIGPIPE, SigHandler::SigDfl) }?;
+    Ok(unistd::execve(&argv[0], argv, envp)?)
+}
+
+/// Reports the failure to start the command on the child's stderr (the
+/// terminal, unless captured), then exits. The forked child is a copy of ht
+/// without the runtime's threads, so it must never return to ht's code.
+fn child_failed(error: &io::Error) -> ! {
+    let mut buf = [0u8; 512];
+    let len = buf.len();
+    let mut cursor = &mut buf[..];
+    let _ = writeln!(cursor, "ht: cannot start the command: {error}");
+    let n = len - cursor.len();
+
+    unsafe {
+        libc::write(libc::STDERR_FILENO, buf.as_ptr().cast(), n);
+        libc::_exit(127)
+    }
+}
+
+/// Sets the size of the terminal, which sends SIGWINCH to its foreground
+/// process group. The size in pixels is 0 when unknown.
+pub fn resize(
+    master: &OwnedFd,
+    cols: usize,
+    rows: usize,
+    xpixel: u16,
+    ypixel: u16,
+) -> io::Result<()> {
+    let winsize = pty::Winsize {
+        ws_row: rows as u16,
+        ws_col: cols as u16,
+        ws_xpixel: xpixel,
+        ws_ypixel: ypixel,
+    };
+
+    match unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the size of the terminal, in cells and in pixels.
+pub fn size(master: &OwnedFd) -> io::Result<pty::Winsize> {
+    let mut winsize: pty::Winsize = unsafe { std::mem::zeroed() };
+
+    match unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(winsize),
+    }
+}
+
 async fn drive_child(
     child: Pid,
     master: OwnedFd,
     input_rx: mpsc::Receiver<Vec<u8>>,
-    output_tx: mpsc::Sender<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
 ) -> Result<()> {
-    let result = do_drive_child(master, input_rx, output_tx).await;
+    let result = do_drive_child(master, input_rx, written_tx, output_tx).await;
     eprintln!("sending HUP signal to the child process");
     unsafe { libc::kill(child.as_raw(), libc::SIGHUP) };
+    // in case it's been suspended
+    let _ = signal::killpg(child, Signal::SIGCONT);
     eprintln!("waiting for the child process to exit");
 
     tokio::task::spawn_blocking(move || {
@@ -56,18 +143,26 @@ async fn drive_child(
     result
 }
 
-const READ_BUF_SIZE: usize = 128 * 1024;
+/// Number and size of the buffers output is read into.
+const READ_BUF_COUNT: usize = 4;
+const READ_BUF_SIZE: usize = 32 * 1024;
+
+/// Value of TERM set for the child.
+pub const TERM: &str = "xterm-256color";
 
-async fn do_drive_child(
+/// Moves data between the terminal (or other character device) and the
+/// channels, until EOF or until the input channel is closed. The number of
+/// input bytes written so far is published with `written_tx`.
+pub async fn do_drive_child(
     master: OwnedFd,
     mut input_rx: mpsc::Receiver<Vec<u8>>,
-    output_tx: mpsc::Sender<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
 ) -> Result<()> {
-    let mut buf = [0u8; READ_BUF_SIZE];
+    let mut pool = nbio::BufferPool::new(READ_BUF_COUNT, READ_BUF_SIZE);
     let mut input: Vec<u8> = Vec::with_capacity(READ_BUF_SIZE);
-    nbio::set_non_blocking(&master.as_raw_fd())?;
-    let mut master_file = unsafe { File::from_raw_fd(master.as_raw_fd()) };
-    let master_fd = AsyncFd::new(master)?;
+    let file = nbio::AsyncFile::new(master)?;
+    let mut writer = &file;
 
     loop {
         tokio::select! {
@@ -83,72 +178,111 @@ async fn do_drive_child(
                 }
             }
 
-            result = master_fd.readable() => {
-                let mut guard = result?;
+            result = pool.read(&file) => {
+                let chunks = result?;
 
-                loop {
-                    match nbio::read(&mut master_file, &mut buf)? {
-                        Some(0) => {
-                            return Ok(());
-                        }
-
-                        Some(n) => {
-                            output_tx.send(buf[0..n].to_vec()).await?;
-                        }
+                if chunks.is_empty() {
+                    return Ok(());
+                }
 
-                        None => {
-                            guard.clear_ready();
-                            break;
-                        }
+                for chunk in chunks {
+                    if output_tx.send(chunk).await.is_err() {
+                        return Ok(());
                     }
                 }
             }
 
-            result = master_fd.writable(), if !input.is_empty() => {
-                let mut guard = result?;
-                let mut buf: &[u8] = input.as_ref();
+            result = writer.write(&input), if !input.is_empty() => {
+                let n = result?;
 
-                loop {
-                    match nbio::write(&mut master_file, buf)? {
-                        Some(0) => {
-                            return Ok(());
-                        }
-
-                        Some(n) => {
-                            buf = &buf[n..];
-
-                            if buf.is_empty() {
-                                break;
-                            }
-                        }
-
-                        None => {
-                            guard.clear_ready();
-                            break;
-                        }
-                    }
+                if n == 0 {
+                    return Ok(());
                 }
 
-                let left = buf.len();
-
-                if left == 0 {
-                    input.clear();
-                } else {
-                    input.drain(..input.len() - left);
-                }
+                input.drain(..n);
+                written_tx.send_modify(|total| *total += n as u64);
             }
         }
     }
 }
 
-fn exec(command: String) -> io::Result<()> {
-    let command = ["/bin/sh".to_owned(), "-c".to_owned(), command]
+/// Returns the arguments running the command with the shell.
+fn argv(command: String) -> Result<Vec<CString>, NulError> {
+    ["/bin/sh".to_owned(), "-c".to_owned(), command]
         .iter()
         .map(|s| CString::new(s.as_bytes()))
-        .collect::<Result<Vec<CString>, NulError>>()?;
+        .collect()
+}
 
-    env::set_var("TERM", "xterm-256color");
-    unsafe { signal::signal(Signal::SIGPIPE, SigHandler::SigDfl) }?;
-    unistd::execvp(&command[0], &command)?;
-    unsafe { libc::_exit(1) }
+#[cfg(test)]
+mod test {
+    use crate::environ::Environment;
+    use crate::prexec::PreExec;
+    use nix::libc;
+    use nix::pty;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+
+    #[tokio::test]
+    async fn failing_hook() {
+        let mut pre_exec = PreExec::new(Environment::default());
+        pre_exec.hook(|| Err(io::Error::from_raw_os_error(libc::EBADF)));
+
+        let winsize = pty::Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (written_tx, _) = watch::channel(0);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+
+        let (_pid, _master, driver) = super::spawn(
+            "echo started".to_owned(),
+            &winsize,
+            input_rx,
+            written_tx,
+            output_tx,
+            None,
+            &pre_exec,
+        )
+        .unwrap();
+
+        // the driver ends once the child exits
+        tokio::time::timeout(Duration::from_secs(5), driver)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut output = Vec::new();
+
+        while let Some(chunk) = output_rx.recv().await {
+            output.extend_from_slice(&chunk);
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("ht: cannot start the command: Bad file descriptor"));
+        assert!(!output.contains("started"));
+    }
+
+    #[test]
+    fn resize() {
+        let pty::OpenptyResult { master, slave } = pty::openpty(None, None).unwrap();
+        super::resize(&master, 100, 30, 800, 480).unwrap();
+
+        let mut winsize: pty::Winsize = unsafe { std::mem::zeroed() };
+        let result = unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) };
+
+        assert_eq!(result, 0);
+        assert_eq!((winsize.ws_col, winsize.ws_row), (100, 30));
+        assert_eq!((winsize.ws_xpixel, winsize.ws_ypixel), (800, 480));
+
+        let winsize = super::size(&master).unwrap();
+        assert_eq!((winsize.ws_col, winsize.ws_row), (100, 30));
+        assert_eq!((winsize.ws_xpixel, winsize.ws_ypixel), (800, 480));
+    }
 }
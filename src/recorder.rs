@@ -0,0 +1,447 @@
+use crate::session::{self, Event};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+/// Information about the recording, written to the asciicast header.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub command: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub theme: Option<Theme>,
+    pub idle_time_limit: Option<Duration>,
+}
+
+/// Terminal color theme, as defined by asciicast v2.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    fg: String,
+    bg: String,
+    palette: Vec<String>,
+}
+
+/// Label of the marker written where an appended recording continues.
+const RESUME_MARKER: &str = "resumed";
+
+/// Records the session to a file in asciicast v2 format.
+///
+/// Annotations are written as marker events, which players show as chapters.
+/// Nothing is written while the recording is paused. Gaps between events are
+/// capped at `idle_time_limit`, if given.
+/// With `append`, an existing recording is continued instead of overwritten.
+/// The returned future completes when the session ends.
+pub async fn start(
+    path: PathBuf,
+    metadata: Metadata,
+    append: bool,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<impl Future<Output = Result<()>>> {
+    let (file, resume) = open(&path, append).await?;
+
+    Ok(record(file, metadata, resume, clients_tx))
+}
+
+/// Opens the recording file. With `append`, an existing recording is
+/// continued after its last complete line, dropping a cut-off one, which
+/// would otherwise end up in the middle of the file.
+async fn open(path: &Path, append: bool) -> Result<(File, Option<Resume>)> {
+    let (resume, len) = if append {
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => {
+                let len = content.rfind('\n').map_or(0, |i| i + 1);
+
+                let resume = resume_point(&content[..len])
+                    .with_context(|| format!("cannot append to recording {}", path.display()))?;
+
+                (resume, len)
+            }
+
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, 0),
+
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("cannot read recording file {}", path.display()))
+            }
+        }
+    } else {
+        (None, 0)
+    };
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(resume.is_some())
+        .truncate(resume.is_none())
+        .open(path)
+        .await
+        .with_context(|| format!("cannot create recording file {}", path.display()))?;
+
+    if resume.is_some() {
+        file.set_len(len as u64).await?;
+    }
+
+    Ok((file, resume))
+}
+
+async fn record(
+    mut file: File,
+    metadata: Metadata,
+    resume: Option<Resume>,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let mut events = session::stream(&clients_tx).await?;
+    let mut clock = Clock::new(metadata.idle_time_limit.map(|d| d.as_secs_f64()));
+    let mut paused = false;
+
+    while let Some(event) = events.next().await {
+        let lines = match event {
+            Ok(Event::RecordingPaused(time, p)) => {
+                if paused && !p {
+                    // the pause leaves no gap
+                    clock.skip_to(time);
+                }
+
+                paused = p;
+                continue;
+            }
+
+            Ok(_) if paused => continue,
+
+            Ok(Event::Init(time, cols, rows, _pid, seq, _text)) => {
+                clock.skip_to(time);
+
+                let mut lines = match &resume {
+                    Some(resume) => {
+                        clock.elapsed = resume.time;
+                        let mut lines = vec![json!([resume.time, "m", RESUME_MARKER])];
+
+                        if resume.size != (cols, rows) {
+                            lines.push(json!([resume.time, "r", format!("{cols}x{rows}")]));
+                        }
+
+                        lines
+                    }
+
+                    None => vec![header(cols, rows, &metadata)],
+                };
+
+                if !seq.is_empty() {
+                    lines.push(json!([clock.elapsed, "o", seq]));
+                }
+
+                lines
+            }
+
+            Ok(Event::Output(time, _, data, _)) => {
+                vec![json!([clock.tick(time), "o", data.as_str()])]
+            }
+
+            Ok(Event::Resize(time, cols, rows)) => {
+                vec![json!([clock.tick(time), "r", format!("{cols}x{rows}")])]
+            }
+
+            Ok(Event::Annotation(time, payload)) => {
+                vec![json!([clock.tick(time), "m", marker_label(payload)])]
+            }
+
+            Ok(_) => continue,
+
+            Err(e) => {
+                eprintln!("recording: {e}");
+                continue;
+            }
+        };
+
+        for line in lines {
+            file.write_all(format!("{line}\n").as_bytes()).await?;
+        }
+    }
+
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Maps session time to recording time.
+struct Clock {
+    elapsed: f64,
+    last: f64,
+    idle_time_limit: Option<f64>,
+}
+
+impl Clock {
+    fn new(idle_time_limit: Option<f64>) -> Self {
+        Self {
+            elapsed: 0.0,
+            last: 0.0,
+            idle_time_limit,
+        }
+    }
+
+    /// Returns the recording time of an event, capping the gap since the
+    /// previous one.
+    fn tick(&mut self, time: f64) -> f64 {
+        let mut delta = (time - self.last).max(0.0);
+
+        if let Some(limit) = self.idle_time_limit {
+            delta = delta.min(limit);
+        }
+
+        self.elapsed += delta;
+        self.last = time;
+
+        self.elapsed
+    }
+
+    /// Moves to the given session time without advancing the recording time.
+    fn skip_to(&mut self, time: f64) {
+        self.last = time;
+    }
+}
+
+/// End of an existing recording, which an appended one continues from.
+#[derive(Debug, PartialEq)]
+struct Resume {
+    /// Time of the last event
+    time: f64,
+    /// Terminal size (cols, rows) at the end
+    size: (usize, usize),
+}
+
+/// Finds where an existing asciicast v2 recording ends. Returns `None` for
+/// an empty file, which is then recorded to from scratch.
+///
+/// Lines which aren't valid events, e.g. the last one of a recording which
+/// was cut off, are skipped.
+fn resume_point(content: &str) -> Result<Option<Resume>> {
+    let mut lines = content.lines();
+
+    let Some(header) = lines.next().filter(|l| !l.trim().is_empty()) else {
+        return Ok(None);
+    };
+
+    let header: serde_json::Value =
+        serde_json::from_str(header).context("invalid asciicast header")?;
+
+    if header["version"] != 2 {
+        bail!("not an asciicast v2 recording");
+    }
+
+    let mut resume = Resume {
+        time: 0.0,
+        size: (
+            header["width"].as_u64().unwrap_or_default() as usize,
+            header["height"].as_u64().unwrap_or_default() as usize,
+        ),
+    };
+
+    for line in lines {
+        let Ok(serde_json::Value::Array(event)) = serde_json::from_str(line) else {
+            continue;
+        };
+
+        let Some(time) = event.first().and_then(|t| t.as_f64()) else {
+            continue;
+        };
+
+        resume.time = resume.time.max(time);
+
+        if event.get(1).and_then(|c| c.as_str()) == Some("r") {
+            let size = event.get(2).and_then(|s| s.as_str()).and_then(|s| {
+                let (cols, rows) = s.split_once('x')?;
+                Some((cols.parse().ok()?, rows.parse().ok()?))
+            });
+
+            if let Some(size) = size {
+                resume.size = size;
+            }
+        }
+    }
+
+    Ok(Some(resume))
+}
+
+fn header(cols: usize, rows: usize, metadata: &Metadata) -> serde_json::Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut header = json!({
+        "version": 2,
+        "width": cols,
+        "height": rows,
+        "timestamp": timestamp,
+    });
+
+    if let Some(limit) = metadata.idle_time_limit {
+        header["idle_time_limit"] = json!(limit.as_secs_f64());
+    }
+
+    if let Some(command) = &metadata.command {
+        header["command"] = json!(command);
+    }
+
+    if let Some(title) = &metadata.title {
+        header["title"] = json!(title);
+    }
+
+    if !metadata.env.is_empty() {
+        let env: serde_json::Map<_, _> = metadata
+            .env
+            .iter()
+            .map(|(name, value)| (name.clone(), json!(value)))
+            .collect();
+
+        header["env"] = env.into();
+    }
+
+    if let Some(theme) = &metadata.theme {
+        header["theme"] = json!({
+            "fg": theme.fg,
+            "bg": theme.bg,
+            "palette": theme.palette.join(":"),
+        });
+    }
+
+    header
+}
+
+fn marker_label(payload: serde_json::Value) -> String {
+    match payload {
+        serde_json::Value::String(label) => label,
+        payload => payload.to_string(),
+    }
+}
+
+impl FromStr for Theme {
+    type Err = anyhow::Error;
+
+    /// Parses `FG,BG,PALETTE`, where PALETTE is a colon-separated list of 8
+    /// or 16 colors, all in `#rrggbb` format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((fg, bg, palette)) = s
+            .split_once(',')
+            .and_then(|(fg, rest)| rest.split_once(',').map(|(bg, p)| (fg, bg, p)))
+        else {
+            bail!("invalid theme format: {s}");
+        };
+
+        let palette: Vec<String> = palette.split(':').map(str::to_owned).collect();
+
+        if palette.len() != 8 && palette.len() != 16 {
+            bail!("theme palette must have 8 or 16 colors");
+        }
+
+        for color in [fg, bg]
+            .into_iter()
+            .chain(palette.iter().map(String::as_str))
+        {
+            if !is_hex_color(color) {
+                bail!("invalid color: {color}");
+            }
+        }
+
+        Ok(Theme {
+            fg: fg.to_owned(),
+            bg: bg.to_owned(),
+            palette,
+        })
+    }
+}
+
+fn is_hex_color(s: &str) -> bool {
+    s.len() == 7 && s.starts_with('#') && s[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resume_point, Clock, Resume, Theme};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn parse_theme() {
+        let palette = ["#000000"; 8].join(":");
+        let theme: Theme = format!("#d0d0d0,#212121,{palette}").parse().unwrap();
+        assert_eq!(theme.fg, "#d0d0d0");
+        assert_eq!(theme.palette.len(), 8);
+
+        assert!("#d0d0d0,#212121".parse::<Theme>().is_err());
+        assert!("#d0d0d0,#212121,#000000:#ffffff".parse::<Theme>().is_err());
+        assert!(format!("d0d0d0,#212121,{palette}")
+            .parse::<Theme>()
+            .is_err());
+    }
+
+    #[test]
+    fn clock_caps_idle_time() {
+        let mut clock = Clock::new(Some(2.0));
+        clock.skip_to(1.0);
+
+        assert_eq!(clock.tick(1.5), 0.5);
+        assert_eq!(clock.tick(10.0), 2.5);
+
+        clock.skip_to(20.0);
+        assert_eq!(clock.tick(21.0), 3.5);
+    }
+
+    #[test]
+    fn find_resume_point() {
+        let content = concat!(
+            "{\"version\": 2, \"width\": 80, \"height\": 24}\n",
+            "[0.5, \"o\", \"$ \"]\n",
+            "[1.25, \"r\", \"100x30\"]\n",
+            "[2.0, \"o\", \"ls\"]\n",
+            "[2.5, \"o\", \"cut",
+        );
+
+        assert_eq!(
+            resume_point(content).unwrap(),
+            Some(Resume {
+                time: 2.0,
+                size: (100, 30)
+            })
+        );
+
+        assert_eq!(resume_point("").unwrap(), None);
+        assert!(resume_point("{\"version\": 1}\n").is_err());
+        assert!(resume_point("garbage\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn append_after_cut_off_line() {
+        let path = std::env::temp_dir().join(format!("ht-test-{}.cast", std::process::id()));
+
+        let content = concat!(
+            "{\"version\": 2, \"width\": 80, \"height\": 24}\n",
+            "[0.5, \"o\", \"$ \"]\n",
+            "[1.0, \"o\", \"cut",
+        );
+
+        std::fs::write(&path, content).unwrap();
+        let (mut file, resume) = super::open(&path, true).await.unwrap();
+        file.write_all(b"[1.5, \"m\", \"resumed\"]\n")
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resume.unwrap().time, 0.5);
+        assert_eq!(content.lines().count(), 3);
+
+        for line in content.lines() {
+            assert!(serde_json::from_str::<serde_json::Value>(line).is_ok());
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use regex::Regex;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MASK: &str = "********";
+
+/// How long the echo of secret input is waited for.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Length, in characters, below which secret input isn't masked: a single
+/// key, e.g. `y` or a digit, would mask that character everywhere.
+const MIN_SECRET_LEN: usize = 4;
+
+/// Replaces matches of the redaction rules in the output with a mask.
+///
+/// When a rule has capture groups, only the text of the groups is masked, so
+/// `password=(\S+)` keeps the `password=` part. Rules only match the printable
+/// text between control characters and escape sequences, which are passed
+/// through as is. Matching is done on each chunk of output separately, so a
+/// secret split between two reads of the child's output isn't detected.
+///
+/// Secret input is masked once it's echoed back, i.e. when it occurs in the
+/// output within `ECHO_TIMEOUT`, outside of control sequences. From then on,
+/// every occurrence of it is masked, in the output and on the screen, so
+/// redraws of the echo don't reveal it. As with rules, an echo split between
+/// two reads isn't detected.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    rules: Vec<Regex>,
+    mask: String,
+    /// Secret input waiting to be echoed, until the given time
+    pending: Vec<(String, Instant)>,
+    /// Secret input which was echoed
+    echoed: Vec<String>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new(Vec::new(), DEFAULT_MASK.to_owned())
+    }
+}
+
+impl Redactor {
+    pub fn new(rules: Vec<Regex>, mask: String) -> Self {
+        Self {
+            rules,
+            mask,
+            pending: Vec::new(),
+            echoed: Vec::new(),
+        }
+    }
+
+    /// Masks the echo of the secret input in the output to come, unless it's
+    /// shorter than `MIN_SECRET_LEN`.
+    pub fn add_secret(&mut self, text: &str) {
+        if text.chars().count() >= MIN_SECRET_LEN {
+            self.pending
+                .push((text.to_owned(), Instant::now() + ECHO_TIMEOUT));
+        }
+    }
+
+    /// Redacts a chunk of output, masking the echo of secret input too.
+    pub fn redact_output<'a>(&mut self, text: &'a str) -> Cow<'a, str> {
+        let now = Instant::now();
+        self.pending.retain(|(_, until)| *until > now);
+        let mut i = 0;
+
+        while i < self.pending.len() {
+            if literal(text, &self.pending[i].0).is_empty() {
+                i += 1;
+                continue;
+            }
+
+            let (secret, _) = self.pending.remove(i);
+
+            if !self.echoed.contains(&secret) {
+                self.echoed.push(secret);
+            }
+        }
+
+        self.redact(text)
+    }
+
+    /// Redacts text, from the screen or the output, masking the secrets echoed
+    /// so far.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = self.redact_rules(text);
+
+        for secret in &self.echoed {
+            let ranges = literal(&text, secret);
+
+            if !ranges.is_empty() {
+                text = Cow::Owned(mask(&text, &ranges, &self.mask));
+            }
+        }
+
+        text
+    }
+
+    /// Byte ranges of the text which `redact` masks, each rule (and secret)
+    /// matched against the printable parts of the text as is.
+    pub fn ranges(&self, text: &str) -> Vec<Range<usize>> {
+        let rules = self.rules.iter().flat_map(|rule| masked(rule, text));
+        let secrets = self.echoed.iter().flat_map(|secret| literal(text, secret));
+
+        rules.chain(secrets).collect()
+    }
+
+    fn redact_rules<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+
+        for rule in &self.rules {
+            let ranges = masked(rule, &text);
+
+            if !ranges.is_empty() {
+                text = Cow::Owned(mask(&text, &ranges, &self.mask));
+            }
+        }
+
+        text
+    }
+}
+
+/// Ranges of the rule's matches to mask in the printable parts of the text,
+/// in order.
+fn masked(rule: &Regex, text: &str) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+
+    for part in printable(text) {
+        for captures in rule.captures_iter(&text[part.clone()]) {
+            let groups: Vec<_> = if captures.len() > 1 {
+                captures.iter().skip(1).flatten().collect()
+            } else {
+                captures.get(0).into_iter().collect()
+            };
+
+            for group in groups {
+                let range = part.start + group.start()..part.start + group.end();
+                let last = ranges.last().map_or(0, |r| r.end);
+
+                // nested or empty groups have nothing left to mask
+                if range.start < last || range.is_empty() {
+                    continue;
+                }
+
+                ranges.push(range);
+            }
+        }
+    }
+
+    ranges
+}
+
+/// Ranges of the occurrences of the literal in the printable parts of the
+/// text, in order.
+fn literal(text: &str, literal: &str) -> Vec<Range<usize>> {
+    printable(text)
+        .into_iter()
+        .flat_map(|part| {
+            text[part.clone()]
+                .match_indices(literal)
+                .map(move |(i, _)| part.start + i..part.start + i + literal.len())
+        })
+        .collect()
+}
+
+/// Splits the text into runs of printable characters, leaving out control
+/// characters and escape sequences (CSI, OSC and other strings, and the
+/// rest of ESC sequences).
+fn printable(text: &str) -> Vec<Range<usize>> {
+    let bytes = text.as_bytes();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let end = match bytes[i] {
+            0x1b => match bytes.get(i + 1) {
+                Some(b'[') => {
+                    let mut j = i + 2;
+
+                    while j < bytes.len() && !(0x40..=0x7e).contains(&bytes[j]) {
+                        j += 1;
+                    }
+
+                    j + 1
+                }
+
+                Some(b']' | b'P' | b'_' | b'^' | b'X') => {
+                    let mut j = i + 2;
+
+                    while j < bytes.len()
+                        && bytes[j] != 0x07
+                        && !(bytes[j] == 0x1b && bytes.get(j + 1) == Some(&b'\\'))
+                    {
+                        j += 1;
+                    }
+
+                    if bytes.get(j) == Some(&0x1b) {
+                        j + 2
+                    } else {
+                        j + 1
+                    }
+                }
+
+                _ => {
+                    let mut j = i + 1;
+
+                    while j < bytes.len() && (0x20..=0x2f).contains(&bytes[j]) {
+                        j += 1;
+                    }
+
+                    j + 1
+                }
+            },
+
+            b if b < 0x20 || b == 0x7f => i + 1,
+
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        if start < i {
+            parts.push(start..i);
+        }
+
+        i = end.min(bytes.len());
+        start = i;
+    }
+
+    if start < bytes.len() {
+        parts.push(start..bytes.len());
+    }
+
+    parts
+}
+
+/// Replaces the (ordered, non-overlapping) ranges of the text with the mask.
+fn mask(text: &str, ranges: &[Range<usize>], mask: &str) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut last = 0;
+
+    for range in ranges {
+        masked.push_str(&text[last..range.start]);
+        masked.push_str(mask);
+        last = range.end;
+    }
+
+    masked.push_str(&text[last..]);
+
+    masked
+}
+
+#[cfg(test)]
+mod test {
+    use super::Redactor;
+    use regex::Regex;
+
+    fn redactor(rules: &[&str]) -> Redactor {
+        let rules = rules.iter().map(|r| Regex::new(r).unwrap()).collect();
+
+        Redactor::new(rules, "***".to_owned())
+    }
+
+    #[test]
+    fn redact() {
+        let redactor = redactor(&[r"ghp_[A-Za-z0-9]+", r"(?i)password: (\S+)"]);
+
+        assert_eq!(
+            redactor.redact("token ghp_abc123 and ghp_XYZ\r\n"),
+            "token *** and ***\r\n"
+        );
+
+        assert_eq!(redactor.redact("Password: hunter2 ok"), "Password: *** ok");
+
+        assert_eq!(redactor.redact("nothing here"), "nothing here");
+    }
+
+    #[test]
+    fn redact_groups() {
+        let redactor = redactor(&[r"user=(\w+) pass=(\w+)", r"key(=\w+)?"]);
+
+        assert_eq!(redactor.redact("user=bob pass=secret"), "user=*** pass=***");
+
+        // the optional group didn't match, so nothing to mask
+        assert_eq!(redactor.redact("key key=v"), "key key***");
+    }
+
+    #[test]
+    fn redact_secret_echo() {
+        let mut redactor = redactor(&[]);
+        redactor.add_secret("p4ss");
+
+        // once echoed, every occurrence is masked, redraws included
+        assert_eq!(redactor.redact_output("p4ss p4ss\r\n"), "*** ***\r\n");
+        assert_eq!(redactor.redact_output("\x1b[1mp4ss"), "\x1b[1m***");
+
+        // the screen shows the echo, wherever it is now
+        assert_eq!(redactor.redact("$ p4ss"), "$ ***");
+    }
+
+    #[test]
+    fn redact_secret_outside_sequences() {
+        let mut redactor = redactor(&[]);
+        redactor.add_secret("1;31");
+        redactor.add_secret("xyzw");
+
+        assert_eq!(
+            redactor.redact_output("\x1b[1;31mok\x1b]0;xyzw\x07 1;31 xyzw"),
+            "\x1b[1;31mok\x1b]0;xyzw\x07 *** ***"
+        );
+
+        assert_eq!(redactor.redact("\x1b[1;31m1;31"), "\x1b[1;31m***");
+    }
+
+    #[test]
+    fn short_secrets_ignored() {
+        let mut redactor = redactor(&[]);
+        redactor.add_secret("y");
+        redactor.add_secret("123");
+
+        assert_eq!(redactor.redact_output("y 123"), "y 123");
+        assert_eq!(redactor.redact("y 123"), "y 123");
+    }
+
+    #[test]
+    fn ranges() {
+        let mut redactor = redactor(&[r"pass=(\w+)", "tok"]);
+        redactor.add_secret("abcd");
+        redactor.redact_output("abcd");
+
+        assert_eq!(
+            redactor.ranges("tok pass=x tok abcd"),
+            [9..10, 0..3, 11..14, 15..19]
+        );
+
+        assert!(redactor.ranges("nothing").is_empty());
+    }
+
+    #[test]
+    fn redact_outside_sequences() {
+        let redactor = redactor(&["31", r"pw=(\w+)"]);
+
+        assert_eq!(
+            redactor.redact("\x1b[31m31\x1b]0;pw=x\x07 pw=y"),
+            "\x1b[31m***\x1b]0;pw=x\x07 pw=***"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::tracker::Tracker;
+use avt::{Color, Line, Pen};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Ansi,
+    Markdown { cursor: bool },
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Ansi => "ansi",
+            Format::Markdown { .. } => "markdown",
+        }
+    }
+
+    pub fn render(&self, vt: &avt::Vt, tracker: &Tracker) -> String {
+        match self {
+            Format::Ansi => ansi(vt, tracker) + &restore_tab_stops(vt, &tracker.tab_stops),
+            Format::Markdown { cursor } => markdown(vt, *cursor),
+        }
+    }
+}
+
+/// Renders the screen as text with ANSI escape sequences.
+///
+/// The result clears the screen, draws all lines with their colors and
+/// attributes, sets the screen modes (reverse video, origin, autowrap), and
+/// restores the cursor position and visibility, so it can be `cat`-ed into
+/// another terminal.
+pub fn ansi(vt: &avt::Vt, tracker: &Tracker) -> String {
+    let mut out = String::from("\x1b[0m\x1b[H\x1b[2J");
+
+    for (i, line) in vt.view().iter().enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+
+        ansi_line(line, &mut out);
+    }
+
+    if tracker.reverse_video() {
+        out.push_str("\x1b[?5h");
+    }
+
+    if tracker.origin_mode() {
+        out.push_str("\x1b[?6h");
+    }
+
+    if !tracker.auto_wrap() {
+        out.push_str("\x1b[?7l");
+    }
+
+    let cursor = vt.cursor();
+    out.push_str(&format!("\x1b[{};{}H", cursor.row + 1, cursor.col + 1));
+
+    if !cursor.visible {
+        out.push_str("\x1b[?25l");
+    }
+
+    out
+}
+
+/// Renders the screen as a fenced Markdown code block, ready to be pasted into
+/// an issue or a chat.
+///
+/// Trailing whitespace and blank lines above and below the content are
+/// trimmed. With `cursor` the block is followed by a line telling where the
+/// cursor is (1-based, counting from the top of the screen).
+pub fn markdown(vt: &avt::Vt, cursor: bool) -> String {
+    let lines: Vec<String> = vt
+        .view()
+        .iter()
+        .map(|l| l.text().trim_end().to_owned())
+        .collect();
+
+    let start = lines.iter().position(|l| !l.is_empty()).unwrap_or(0);
+    let end = lines
+        .iter()
+        .rposition(|l| !l.is_empty())
+        .map_or(start, |i| i + 1);
+    let lines = &lines[start..end];
+
+    // the fence must be longer than any backtick run in the content
+    let longest_run = lines
+        .iter()
+        .flat_map(|l| l.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    let mut out = format!("{fence}\n");
+
+    for line in lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out.push_str(&fence);
+    out.push('\n');
+
+    if cursor {
+        let cursor = vt.cursor();
+
+        out.push_str(&format!(
+            "\nCursor at row {}, column {}{}\n",
+            cursor.row + 1,
+            cursor.col + 1,
+            if cursor.visible { "" } else { " (hidden)" }
+        ));
+    }
+
+    out
+}
+
+/// Sets the tab stops, when they differ from the default ones (every 8
+/// columns), so tabs printed after the rendered screen line up as in the
+/// original terminal. The cursor position is kept.
+fn restore_tab_stops(vt: &avt::Vt, tab_stops: &[usize]) -> String {
+    let (cols, _) = vt.size();
+
+    if tab_stops.iter().copied().eq((8..cols).step_by(8)) {
+        return String::new();
+    }
+
+    let mut out = String::from("\x1b[3g");
+
+    for t in tab_stops {
+        out.push_str(&format!("\x1b[{}G\x1bH", t + 1));
+    }
+
+    out.push_str(&format!("\x1b[{}G", vt.cursor().col + 1));
+
+    out
+}
+
+fn ansi_line(line: &Line, out: &mut String) {
+    let cells = line.cells();
+    let len = cells.len() - cells.iter().rev().take_while(|c| c.is_default()).count();
+    let mut col = 0;
+
+    for chunk in line.chunks(|c1, c2| c1.pen() != c2.pen()) {
+        if col >= len {
+            break;
+        }
+
+        out.push_str(&sgr(chunk[0].pen()));
+
+        for cell in chunk {
+            if col >= len {
+                break;
+            }
+
+            out.push(cell.char());
+            col += cell.width();
+        }
+    }
+
+    out.push_str("\x1b[0m");
+}
+
+fn sgr(pen: &Pen) -> String {
+    let mut params = vec!["0".to_owned()];
+
+    if pen.is_bold() {
+        params.push("1".to_owned());
+    } else if pen.is_faint() {
+        params.push("2".to_owned());
+    }
+
+    if pen.is_italic() {
+        params.push("3".to_owned());
+    }
+
+    if pen.is_underline() {
+        params.push("4".to_owned());
+    }
+
+    if pen.is_blink() {
+        params.push("5".to_owned());
+    }
+
+    if pen.is_inverse() {
+        params.push("7".to_owned());
+    }
+
+    if pen.is_strikethrough() {
+        params.push("9".to_owned());
+    }
+
+    if let Some(color) = pen.foreground() {
+        params.push(color_params(color, 30));
+    }
+
+    if let Some(color) = pen.background() {
+        params.push(color_params(color, 40));
+    }
+
+    format!("\x1b[{}m", params.join(";"))
+}
+
+fn color_params(color: Color, base: u8) -> String {
+    match color {
+        Color::Indexed(c) if c < 8 => (base + c).to_string(),
+        Color::Indexed(c) if c < 16 => (base + 52 + c).to_string(),
+        Color::Indexed(c) => format!("{};5;{}", base + 8, c),
+        Color::RGB(c) => format!("{};2;{};{};{}", base + 8, c.r, c.g, c.b),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ansi, markdown, Format};
+    use crate::tracker::Tracker;
+
+    fn vt(input: &str) -> avt::Vt {
+        terminal(input).0
+    }
+
+    fn terminal(input: &str) -> (avt::Vt, Tracker) {
+        let mut vt = avt::Vt::new(20, 5);
+        let mut tracker = Tracker::new(20);
+        tracker.feed(&mut vt, input);
+
+        (vt, tracker)
+    }
+
+    #[test]
+    fn markdown_block() {
+        let vt = vt("\r\n\x1b[1m$ ls  \x1b[0m\r\nfoo bar\r\n$ ");
+
+        assert_eq!(markdown(&vt, false), "```\n$ ls\nfoo bar\n$\n```\n");
+
+        assert_eq!(
+            markdown(&vt, true),
+            "```\n$ ls\nfoo bar\n$\n```\n\nCursor at row 4, column 3\n"
+        );
+    }
+
+    #[test]
+    fn ansi_tab_stops() {
+        let (vt, tracker) = terminal("a\tb");
+        assert_eq!(Format::Ansi.render(&vt, &tracker), ansi(&vt, &tracker));
+
+        let (vt, tracker) = terminal("a\tb\x1b[3g\x1b[5G\x1bH\x1b[13G\x1bH\x1b[10G");
+        let mut copy = avt::Vt::new(20, 5);
+        copy.feed_str(&Format::Ansi.render(&vt, &tracker));
+        copy.feed_str("\tc");
+
+        assert_eq!(copy.text()[0].trim_end(), "a       b   c");
+    }
+
+    #[test]
+    fn ansi_screen_modes() {
+        let (vt, tracker) = terminal("ab");
+        let rendered = ansi(&vt, &tracker);
+        assert!(!rendered.contains("\x1b[?5h"));
+        assert!(!rendered.contains("\x1b[?7l"));
+
+        let (vt, tracker) = terminal("\x1b[?5h\x1b[?7lab");
+        let rendered = ansi(&vt, &tracker);
+        assert!(rendered.contains("\x1b[?5h"));
+
+        let mut copy = avt::Vt::new(20, 5);
+        copy.feed_str(&rendered);
+        copy.feed_str(&"x".repeat(30));
+
+        assert_eq!(copy.text()[1], "");
+    }
+
+    #[test]
+    fn markdown_fence() {
+        let vt = vt("```rust\r\n````");
+
+        assert_eq!(markdown(&vt, false), "`````\n```rust\n````\n`````\n");
+        assert_eq!(markdown(&avt::Vt::new(20, 5), false), "```\n```\n");
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+/// The most recent output, up to a capacity, addressed by absolute byte
+/// offsets counted from the start of the session's output.
+pub struct OutputRing {
+    data: VecDeque<u8>,
+    capacity: usize,
+    end: u64,
+}
+
+impl OutputRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::with_capacity(capacity),
+            capacity,
+            end: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.end += bytes.len() as u64;
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + bytes.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(bytes);
+    }
+
+    /// Offset of the oldest retained byte.
+    pub fn start(&self) -> u64 {
+        self.end - self.data.len() as u64
+    }
+
+    /// Offset just past the newest byte, i.e. the total output so far.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the retained part of the range, moved inwards so it doesn't
+    /// split UTF-8 characters, with its actual bounds.
+    pub fn get(&self, from: u64, to: u64) -> (u64, u64, String) {
+        let start = self.start();
+        let mut from = (from.clamp(start, self.end) - start) as usize;
+        let mut to = ((to.clamp(start, self.end) - start) as usize).max(from);
+
+        while from < to && is_continuation(self.data[from]) {
+            from += 1;
+        }
+
+        while to > from && to < self.data.len() && is_continuation(self.data[to]) {
+            to -= 1;
+        }
+
+        let bytes: Vec<u8> = self.data.range(from..to).copied().collect();
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+
+        (start + from as u64, start + to as u64, text)
+    }
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+#[cfg(test)]
+mod test {
+    use super::OutputRing;
+
+    #[test]
+    fn byte_ranges() {
+        let mut ring = OutputRing::new(8);
+        ring.push(b"hello");
+        assert_eq!(ring.get(1, 3), (1, 3, "el".to_owned()));
+
+        ring.push(b" world");
+        assert_eq!((ring.start(), ring.end()), (3, 11));
+        assert_eq!(ring.get(0, 100), (3, 11, "lo world".to_owned()));
+        assert_eq!(ring.get(9, 5), (9, 9, "".to_owned()));
+
+        ring.push("żółw".as_bytes());
+        assert_eq!((ring.start(), ring.end()), (10, 18));
+        assert_eq!(ring.get(12, 16), (13, 15, "ó".to_owned()));
+
+        ring.push(&[b'x'; 20]);
+        assert_eq!(ring.get(0, 100), (30, 38, "x".repeat(8)));
+    }
+}
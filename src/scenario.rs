@@ -0,0 +1,464 @@
+use crate::api::{self, stdio};
+use crate::check;
+use crate::cli::{parse_duration, ReportFormat, Size, TestArgs};
+use crate::command::{Command, Reply};
+use crate::session::{self, Event};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::Stream;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use std::fs;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::StreamExt;
+
+/// Declarative description of a test: the command to run and the steps to
+/// perform against it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: Option<String>,
+    pub command: String,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub size: Option<Size>,
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    #[serde(deserialize_with = "serde_yaml::with::singleton_map_recursive::deserialize")]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Step {
+    /// Sends text, same as the `input` command
+    Send(String),
+    /// Sends keys, same as the `sendKeys` command
+    Keys(Vec<String>),
+    /// Sends any STDIO API command
+    Command(serde_json::Value),
+    /// Waits for the given period
+    Sleep(#[serde(deserialize_with = "deserialize_duration")] Duration),
+    /// Waits until the screen contains the text
+    WaitFor(String),
+    /// Asserts that the screen contains the text
+    Expect(String),
+    /// Asserts that the screen doesn't contain the text
+    ExpectNot(String),
+    /// Asserts the contents of a screen line
+    ExpectLine { row: usize, text: String },
+}
+
+/// Outcome of a single assertion.
+#[derive(Debug)]
+struct TestCase {
+    name: String,
+    result: TestResult,
+    duration: Duration,
+}
+
+#[derive(Debug)]
+enum TestResult {
+    Passed,
+    Failed { message: String, screen: String },
+    Skipped,
+}
+
+/// Loads and validates a scenario file.
+pub fn load(path: &str) -> Result<Scenario> {
+    let text = fs::read_to_string(path).with_context(|| format!("cannot read scenario {path}"))?;
+    let scenario: Scenario = serde_yaml::from_str(&text)?;
+
+    for step in &scenario.steps {
+        step.command().transpose()?;
+    }
+
+    Ok(scenario)
+}
+
+/// Runs a scenario and prints a report of its assertions in the given format.
+///
+/// Every `waitFor`, `expect`, `expectNot` and `expectLine` step is a test.
+/// Assertions are retried on each output until they pass or the scenario's
+/// timeout elapses. When `waitFor` times out the remaining tests are skipped.
+///
+/// With virtual time, `sleep` steps advance a virtual clock and only wait
+/// until the output settles, which makes runs faster and independent of how
+/// long the sleeps happen to be.
+pub async fn run(
+    args: TestArgs,
+    command_tx: mpsc::Sender<(Command, Reply)>,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let scenario = args.scenario;
+    let mut events = pin!(session::stream(&clients_tx).await?);
+    let mut cases = Vec::new();
+    let mut bailed_out = false;
+    let mut virtual_time = Duration::ZERO;
+
+    for step in &scenario.steps {
+        if let Some(command) = step.command() {
+            let (reply_tx, _) = mpsc::unbounded_channel();
+            api::submit(&command_tx, command?, reply_tx).await?;
+            continue;
+        }
+
+        if let Step::Sleep(period) = step {
+            if args.virtual_time {
+                check::settle(&mut events, args.settle, *period).await;
+                virtual_time += *period;
+            } else {
+                time::sleep(*period).await;
+            }
+
+            continue;
+        }
+
+        let name = step.description();
+
+        if bailed_out {
+            cases.push(TestCase {
+                name,
+                result: TestResult::Skipped,
+                duration: Duration::ZERO,
+            });
+
+            continue;
+        }
+
+        let started_at = Instant::now();
+        let outcome = wait_until(&mut events, &command_tx, scenario.timeout, |screen| {
+            step.check(screen)
+        })
+        .await?;
+
+        let result = match outcome {
+            Ok(()) => TestResult::Passed,
+
+            Err(screen) => {
+                bailed_out = matches!(step, Step::WaitFor(_));
+
+                TestResult::Failed {
+                    message: format!("timed out after {:?}", scenario.timeout),
+                    screen,
+                }
+            }
+        };
+
+        cases.push(TestCase {
+            name,
+            result,
+            duration: started_at.elapsed(),
+        });
+    }
+
+    let suite = scenario.name.as_deref().unwrap_or(&scenario.command);
+
+    match args.format {
+        ReportFormat::Tap => print!("{}", tap(&cases)),
+        ReportFormat::Junit => print!("{}", junit(suite, &cases)),
+    }
+
+    if args.virtual_time {
+        eprintln!("skipped {virtual_time:?} of sleeps");
+    }
+
+    let failures = cases
+        .iter()
+        .filter(|c| matches!(c.result, TestResult::Failed { .. }))
+        .count();
+
+    if failures > 0 {
+        bail!("{failures} of {} tests failed", cases.len());
+    }
+
+    Ok(())
+}
+
+impl Step {
+    /// Returns the command to send, if the step sends one.
+    fn command(&self) -> Option<Result<Command>> {
+        let value = match self {
+            Step::Send(text) => json!({ "type": "input", "payload": text }),
+            Step::Keys(keys) => json!({ "type": "sendKeys", "keys": keys }),
+            Step::Command(value) => value.clone(),
+            _ => return None,
+        };
+
+        Some(stdio::build_command(value).map_err(|e| anyhow!("invalid step: {e}")))
+    }
+
+    fn check(&self, screen: &str) -> bool {
+        match self {
+            Step::WaitFor(text) | Step::Expect(text) => screen.contains(text.as_str()),
+            Step::ExpectNot(text) => !screen.contains(text.as_str()),
+
+            Step::ExpectLine { row, text } => {
+                screen.lines().nth(*row).unwrap_or_default() == text.trim_end()
+            }
+
+            _ => true,
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            Step::WaitFor(text) => format!("wait for {text:?}"),
+            Step::Expect(text) => format!("screen contains {text:?}"),
+            Step::ExpectNot(text) => format!("screen doesn't contain {text:?}"),
+            Step::ExpectLine { row, text } => format!("line {row} is {text:?}"),
+            step => format!("{step:?}"),
+        }
+    }
+}
+
+/// Checks the screen on every output until `check` passes, returning the last
+/// screen on timeout.
+async fn wait_until<S>(
+    events: &mut S,
+    command_tx: &mpsc::Sender<(Command, Reply)>,
+    timeout: Duration,
+    check: impl Fn(&str) -> bool,
+) -> Result<Result<(), String>>
+where
+    S: Stream<Item = Result<Event, BroadcastStreamRecvError>> + Unpin,
+{
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let screen = check::screen_text(command_tx).await?;
+
+        if check(&screen) {
+            return Ok(Ok(()));
+        }
+
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    match event {
+                        Some(Ok(Event::Output(_, _, _, _) | Event::Resize(_, _, _))) => break,
+                        Some(_) => (),
+                        None => return Ok(Err(screen)),
+                    }
+                }
+
+                _ = time::sleep_until(deadline) => return Ok(Err(screen)),
+            }
+        }
+    }
+}
+
+/// Formats the results in Test Anything Protocol.
+fn tap(cases: &[TestCase]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", cases.len());
+
+    for (i, case) in cases.iter().enumerate() {
+        let n = i + 1;
+
+        match &case.result {
+            TestResult::Passed => out.push_str(&format!("ok {n} - {}\n", case.name)),
+
+            TestResult::Skipped => out.push_str(&format!(
+                "ok {n} - {} # SKIP previous wait failed\n",
+                case.name
+            )),
+
+            TestResult::Failed { message, screen } => {
+                out.push_str(&format!("not ok {n} - {}\n", case.name));
+                out.push_str(&format!("  ---\n  message: {message:?}\n  screen: |\n"));
+
+                for line in screen.lines() {
+                    out.push_str(&format!("    {line}\n"));
+                }
+
+                out.push_str("  ...\n");
+            }
+        }
+    }
+
+    out
+}
+
+/// Formats the results as a JUnit XML report.
+fn junit(suite: &str, cases: &[TestCase]) -> String {
+    let count = |f: fn(&TestResult) -> bool| cases.iter().filter(|c| f(&c.result)).count();
+    let failures = count(|r| matches!(r, TestResult::Failed { .. }));
+    let skipped = count(|r| matches!(r, TestResult::Skipped));
+    let time: f64 = cases.iter().map(|c| c.duration.as_secs_f64()).sum();
+
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" skipped=\"{skipped}\" time=\"{time:.3}\">\n",
+        xml_escape(suite),
+        cases.len()
+    );
+
+    for case in cases {
+        out.push_str(&format!(
+            "  <testcase name=\"{}\" time=\"{:.3}\"",
+            xml_escape(&case.name),
+            case.duration.as_secs_f64()
+        ));
+
+        match &case.result {
+            TestResult::Passed => out.push_str("/>\n"),
+            TestResult::Skipped => out.push_str(">\n    <skipped/>\n  </testcase>\n"),
+
+            TestResult::Failed { message, screen } => out.push_str(&format!(
+                ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>\n",
+                xml_escape(message),
+                xml_escape(screen)
+            )),
+        }
+    }
+
+    out.push_str("</testsuite>\n");
+
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .fold(String::new(), |mut out, c| {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                c => out.push(c),
+            }
+
+            out
+        })
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Accepts a number of seconds or a string such as `500ms`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Secs(f64),
+        Text(String),
+    }
+
+    let result = match Value::deserialize(deserializer)? {
+        Value::Secs(secs) => Duration::try_from_secs_f64(secs).map_err(anyhow::Error::from),
+        Value::Text(text) => parse_duration(&text),
+    };
+
+    result.map_err(serde::de::Error::custom)
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Size>, D::Error> {
+    let size = String::deserialize(deserializer)?;
+
+    size.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{junit, tap, Scenario, Step, TestCase, TestResult};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_scenario() {
+        let yaml = r#"
+            name: login
+            command: ./my-app --verbose
+            size: 80x24
+            timeout: 500ms
+            steps:
+              - waitFor: "login:"
+              - send: "admin\r"
+              - keys: [Down, C-c]
+              - sleep: 1
+              - command: { type: resize, cols: 100, rows: 30 }
+              - expect: Welcome
+              - expectNot: Error
+              - expectLine: { row: 0, text: "$ " }
+        "#;
+
+        let scenario: Scenario = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(scenario.command, "./my-app --verbose");
+        assert_eq!(scenario.size.unwrap().cols(), 80);
+        assert_eq!(scenario.timeout, Duration::from_millis(500));
+        assert_eq!(scenario.steps.len(), 8);
+        assert!(matches!(&scenario.steps[3], Step::Sleep(d) if d.as_secs() == 1));
+        assert!(scenario
+            .steps
+            .iter()
+            .all(|s| s.command().is_none_or(|c| c.is_ok())));
+
+        let step = |yaml| {
+            let deserializer = serde_yaml::Deserializer::from_str(yaml);
+            serde_yaml::with::singleton_map::deserialize::<Step, _>(deserializer)
+        };
+
+        assert!(step("command: { type: bogus }")
+            .unwrap()
+            .command()
+            .unwrap()
+            .is_err());
+        assert!(step("frobnicate: 1").is_err());
+    }
+
+    #[test]
+    fn check_steps() {
+        let screen = "$ ls\nfoo  bar\n";
+
+        assert!(Step::Expect("foo".to_owned()).check(screen));
+        assert!(!Step::ExpectNot("foo".to_owned()).check(screen));
+
+        let step = Step::ExpectLine {
+            row: 1,
+            text: "foo  bar".to_owned(),
+        };
+
+        assert!(step.check(screen));
+    }
+
+    #[test]
+    fn reports() {
+        let cases = vec![
+            TestCase {
+                name: "wait for \"a\"".to_owned(),
+                result: TestResult::Passed,
+                duration: Duration::from_millis(10),
+            },
+            TestCase {
+                name: "screen contains \"<b>\"".to_owned(),
+                result: TestResult::Failed {
+                    message: "timed out".to_owned(),
+                    screen: "x & y\n".to_owned(),
+                },
+                duration: Duration::from_millis(20),
+            },
+            TestCase {
+                name: "wait for \"c\"".to_owned(),
+                result: TestResult::Skipped,
+                duration: Duration::ZERO,
+            },
+        ];
+
+        assert_eq!(
+            tap(&cases),
+            "TAP version 13\n1..3\nok 1 - wait for \"a\"\nnot ok 2 - screen contains \"<b>\"\n  ---\n  message: \"timed out\"\n  screen: |\n    x & y\n  ...\nok 3 - wait for \"c\" # SKIP previous wait failed\n"
+        );
+
+        let xml = junit("demo", &cases);
+        assert!(xml.contains(
+            r#"<testsuite name="demo" tests="3" failures="1" skipped="1" time="0.030">"#
+        ));
+        assert!(
+            xml.contains(r#"<testcase name="screen contains &quot;&lt;b&gt;&quot;" time="0.020">"#)
+        );
+        assert!(xml.contains(r#"<failure message="timed out">x &amp; y"#));
+        assert!(xml.contains("<skipped/>"));
+    }
+}
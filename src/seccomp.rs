@@ -0,0 +1,470 @@
+use crate::prexec::PreExec;
+use anyhow::{bail, Context, Result};
+use nix::libc;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+#[cfg(target_os = "linux")]
+mod syscalls;
+
+/// Name of the built-in profile.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// System calls denied by the built-in profile: the ones letting a process
+/// tamper with the kernel, the system's clock, mounts and namespaces, or with
+/// other processes' memory.
+const DANGEROUS: &[&str] = &[
+    "_sysctl",
+    "acct",
+    "add_key",
+    "adjtimex",
+    "bpf",
+    "chroot",
+    "clock_adjtime",
+    "clock_settime",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "init_module",
+    "io_uring_enter",
+    "io_uring_register",
+    "io_uring_setup",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mount",
+    "mount_setattr",
+    "move_mount",
+    "name_to_handle_at",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setns",
+    "settimeofday",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "umount2",
+    "unshare",
+    "userfaultfd",
+    "uselib",
+    "vhangup",
+];
+
+/// Seccomp profile, in the format used by Docker and other OCI runtimes,
+/// without conditional rules (`args`, `includes` and `excludes`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    default_action: Action,
+    default_errno_ret: Option<u16>,
+    #[serde(default)]
+    syscalls: Vec<Rule>,
+    /// Whether `clone` creating namespaces fails, with EPERM, and `clone3`
+    /// with ENOSYS (its flags can't be checked, being in memory), which makes
+    /// libc fall back to `clone`. Only set for the built-in profile.
+    #[serde(skip)]
+    deny_namespaces: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    names: Vec<String>,
+    action: Action,
+    errno_ret: Option<u16>,
+    #[serde(default)]
+    args: Vec<IgnoredAny>,
+    #[serde(default)]
+    includes: HashMap<String, IgnoredAny>,
+    #[serde(default)]
+    excludes: HashMap<String, IgnoredAny>,
+}
+
+/// What the filter does with a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW", alias = "allow")]
+    Allow,
+    /// Fail the call, with EPERM unless given another error number
+    #[serde(rename = "SCMP_ACT_ERRNO", alias = "errno")]
+    Errno,
+    /// Kill the process
+    #[serde(
+        rename = "SCMP_ACT_KILL_PROCESS",
+        alias = "SCMP_ACT_KILL",
+        alias = "SCMP_ACT_KILL_THREAD",
+        alias = "kill"
+    )]
+    Kill,
+    /// Send SIGSYS to the process
+    #[serde(rename = "SCMP_ACT_TRAP", alias = "trap")]
+    Trap,
+    /// Allow the call, logging it to the kernel's audit log
+    #[serde(rename = "SCMP_ACT_LOG", alias = "log")]
+    Log,
+}
+
+/// Loads a profile from a JSON (or YAML) file, or the built-in profile,
+/// which allows everything but `DANGEROUS` system calls, and creating
+/// namespaces with `clone`.
+pub fn load(path: &str) -> Result<Profile> {
+    if path == DEFAULT_PROFILE {
+        return Ok(Profile {
+            default_action: Action::Allow,
+            default_errno_ret: None,
+            syscalls: vec![Rule {
+                names: DANGEROUS.iter().map(|name| name.to_string()).collect(),
+                action: Action::Errno,
+                errno_ret: None,
+                args: Vec::new(),
+                includes: HashMap::new(),
+                excludes: HashMap::new(),
+            }],
+            deny_namespaces: true,
+        });
+    }
+
+    let text =
+        fs::read_to_string(path).with_context(|| format!("cannot read seccomp profile {path}"))?;
+
+    let profile: Profile =
+        serde_yaml::from_str(&text).with_context(|| format!("invalid seccomp profile {path}"))?;
+
+    for rule in &profile.syscalls {
+        if !rule.args.is_empty() || !rule.includes.is_empty() || !rule.excludes.is_empty() {
+            bail!(
+                "invalid seccomp profile {path}: conditional rules aren't supported ({})",
+                rule.names.join(", ")
+            );
+        }
+    }
+
+    Ok(profile)
+}
+
+impl Profile {
+    /// Filter's return values (action and data) for system calls handled
+    /// otherwise than by default, by number, and the default return value.
+    /// Unknown names fail the profile, unless allowed, as profiles list system
+    /// calls of many architectures (or kernel versions), and the built-in one
+    /// some of x86-64 only.
+    fn rules(
+        &self,
+        number: impl Fn(&str) -> Option<libc::c_long>,
+    ) -> Result<(BTreeMap<libc::c_long, u32>, u32)> {
+        let default = ret(self.default_action, self.default_errno_ret);
+        let mut rules = BTreeMap::new();
+
+        for rule in &self.syscalls {
+            let ret = ret(rule.action, rule.errno_ret);
+
+            for name in &rule.names {
+                match number(name) {
+                    // a later rule for the same call overrides an earlier one
+                    Some(nr) => {
+                        rules.insert(nr, ret);
+                    }
+
+                    None if rule.action == Action::Allow || DANGEROUS.contains(&name.as_str()) => {}
+
+                    None => bail!("unknown system call in seccomp profile: {name}"),
+                }
+            }
+        }
+
+        rules.retain(|_, r| *r != default);
+
+        Ok((rules, default))
+    }
+}
+
+/// Seccomp return value, as defined in linux/seccomp.h.
+fn ret(action: Action, errno: Option<u16>) -> u32 {
+    match action {
+        Action::Allow => 0x7fff_0000,
+        Action::Errno => 0x0005_0000 | errno.unwrap_or(1) as u32,
+        Action::Kill => 0x8000_0000,
+        Action::Trap => 0x0003_0000,
+        Action::Log => 0x7ffc_0000,
+    }
+}
+
+/// Attaches the profile's filter to the child, after the rest of its setup
+/// (so the setup itself isn't subject to it), right before exec. It comes
+/// with the no_new_privs flag, as required for unprivileged processes, which
+/// stops set-user-ID programs (e.g. sudo) from gaining privileges.
+#[cfg(target_os = "linux")]
+pub fn apply(pre_exec: &mut PreExec, profile: &Profile) -> Result<()> {
+    let (rules, default) = profile.rules(syscalls::number)?;
+    let program = linux::program(&rules, default, profile.deny_namespaces)?;
+    pre_exec.hook(move || linux::install(&program));
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_pre_exec: &mut PreExec, _profile: &Profile) -> Result<()> {
+    bail!("seccomp is only available on Linux")
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use anyhow::{bail, Result};
+    use nix::libc;
+    use std::collections::BTreeMap;
+    use std::io;
+
+    /// Architecture the filter is built for, as in linux/audit.h.
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    /// Bit set in the numbers of x32 ABI system calls, on x86-64.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+    const RET_EPERM: u32 = 0x0005_0000 | libc::EPERM as u32;
+    const RET_ENOSYS: u32 = 0x0005_0000 | libc::ENOSYS as u32;
+
+    /// Offsets of the fields of `struct seccomp_data`. The first argument's
+    /// lower 32 bits come first on little-endian architectures.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+    const ARG0_OFFSET: u32 = 16;
+
+    /// `clone` flags creating namespaces, as checked by Docker's profile:
+    /// CLONE_NEWNS, CLONE_NEWCGROUP, CLONE_NEWUTS, CLONE_NEWIPC,
+    /// CLONE_NEWUSER, CLONE_NEWPID and CLONE_NEWNET.
+    const CLONE_NEW_FLAGS: u32 = 0x7e02_0000;
+
+    /// Builds a BPF program returning the rule's value for the system calls
+    /// with rules, and the default for all others. Calls made with another
+    /// architecture's ABI (e.g. 32-bit ones on x86-64) kill the process, as
+    /// their numbers differ. With `deny_namespaces`, `clone` with any of
+    /// `CLONE_NEW_FLAGS` fails with EPERM, and `clone3` with ENOSYS.
+    pub fn program(
+        rules: &BTreeMap<libc::c_long, u32>,
+        default: u32,
+        deny_namespaces: bool,
+    ) -> Result<Vec<libc::sock_filter>> {
+        let Some(arch) = AUDIT_ARCH else {
+            bail!("seccomp isn't supported on this architecture");
+        };
+
+        let mut program = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+            stmt(libc::BPF_RET | libc::BPF_K, RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+        ];
+
+        if cfg!(target_arch = "x86_64") {
+            program.push(jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ));
+            program.push(stmt(libc::BPF_RET | libc::BPF_K, RET_KILL_PROCESS));
+        }
+
+        if deny_namespaces {
+            program.extend([
+                jump(
+                    libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                    libc::SYS_clone3 as u32,
+                    0,
+                    1,
+                ),
+                stmt(libc::BPF_RET | libc::BPF_K, RET_ENOSYS),
+                // skips the flags check for other calls
+                jump(
+                    libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                    libc::SYS_clone as u32,
+                    0,
+                    4,
+                ),
+                stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARG0_OFFSET),
+                jump(
+                    libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+                    CLONE_NEW_FLAGS,
+                    0,
+                    1,
+                ),
+                stmt(libc::BPF_RET | libc::BPF_K, RET_EPERM),
+                stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+            ]);
+        }
+
+        for (nr, ret) in rules {
+            program.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *nr as u32,
+                0,
+                1,
+            ));
+            program.push(stmt(libc::BPF_RET | libc::BPF_K, *ret));
+        }
+
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, default));
+
+        if program.len() > u16::MAX as usize {
+            bail!("seccomp profile has too many rules");
+        }
+
+        Ok(program)
+    }
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    pub fn install(program: &[libc::sock_filter]) -> io::Result<()> {
+        let prog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, ret, Action, Profile};
+    use nix::libc;
+    use std::collections::BTreeMap;
+
+    fn number(name: &str) -> Option<libc::c_long> {
+        ["read", "write", "ptrace"]
+            .iter()
+            .position(|n| *n == name)
+            .map(|i| i as libc::c_long)
+    }
+
+    fn parse(yaml: &str) -> Profile {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn default_profile() {
+        let (rules, default) = load("default").unwrap().rules(number).unwrap();
+
+        assert_eq!(default, ret(Action::Allow, None));
+        assert_eq!(rules, BTreeMap::from([(2, ret(Action::Errno, Some(1)))]));
+    }
+
+    #[test]
+    fn profile_rules() {
+        let profile = parse(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "defaultErrnoRet": 38,
+                "syscalls": [
+                    { "names": ["read", "write", "arch_specific"], "action": "SCMP_ACT_ALLOW" },
+                    { "names": ["write"], "action": "SCMP_ACT_KILL" },
+                    { "names": ["ptrace"], "action": "SCMP_ACT_ERRNO", "errnoRet": 38 }
+                ]
+            }"#,
+        );
+
+        let (rules, default) = profile.rules(number).unwrap();
+
+        assert_eq!(default, 0x0005_0026);
+        assert_eq!(rules, BTreeMap::from([(0, 0x7fff_0000), (1, 0x8000_0000)]));
+
+        parse(r#"{ "defaultAction": "allow", "syscalls": [{ "names": ["nope"], "action": "kill" }] }"#)
+            .rules(number)
+            .expect_err("should fail");
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn deny_namespaces() {
+        use nix::errno::Errno;
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+        use std::ptr;
+
+        let (rules, default) = load("default")
+            .unwrap()
+            .rules(super::syscalls::number)
+            .unwrap();
+        let program = super::linux::program(&rules, default, true).unwrap();
+
+        // the child exits with the number of the first check which failed
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe {
+                if super::linux::install(&program).is_err() {
+                    libc::_exit(100);
+                }
+
+                // CLONE_NEWNS with CLONE_FS is invalid, so the call fails even
+                // when allowed, with EINVAL rather than EPERM
+                let flags = libc::CLONE_NEWNS | libc::CLONE_FS | libc::SIGCHLD;
+
+                if libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0) != -1
+                    || Errno::last() != Errno::EPERM
+                {
+                    libc::_exit(1);
+                }
+
+                if libc::syscall(libc::SYS_clone3, ptr::null::<u8>(), 0) != -1
+                    || Errno::last() != Errno::ENOSYS
+                {
+                    libc::_exit(2);
+                }
+
+                // fork is clone without namespace flags, the child exits too
+                libc::_exit(if libc::fork() == -1 { 3 } else { 0 });
+            },
+
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
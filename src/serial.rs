@@ -0,0 +1,107 @@
+use crate::pty;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use nix::fcntl::{self, OFlag};
+use nix::libc;
+use nix::sys::stat::Mode;
+use nix::sys::termios::{self, BaudRate, ControlFlags, SetArg};
+use std::future::Future;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// How often the carrier detect line is checked.
+const CARRIER_POLL_PERIOD: Duration = Duration::from_millis(500);
+
+/// Opens a serial device in raw mode and moves data between it and the
+/// channels.
+///
+/// The session ends when the input channel is closed, or when the carrier is
+/// lost, i.e. DCD drops after having been raised. Devices which never raise
+/// DCD (most USB adapters without modem lines) run until closed.
+pub fn open(
+    path: &str,
+    baud: u32,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<impl Future<Output = Result<()>>> {
+    let fd = fcntl::open(
+        path,
+        OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
+        Mode::empty(),
+    )
+    .with_context(|| format!("cannot open {path}"))?;
+
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    configure(&fd, baud).with_context(|| format!("cannot configure {path}"))?;
+    let carrier_fd = fd.try_clone()?;
+
+    Ok(async move {
+        tokio::select! {
+            result = pty::do_drive_child(fd, input_rx, written_tx, output_tx) => result,
+
+            _ = watch_carrier(carrier_fd) => {
+                eprintln!("carrier lost");
+                Ok(())
+            }
+        }
+    })
+}
+
+pub fn baud_rate(baud: u32) -> Option<BaudRate> {
+    let rate = match baud {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => BaudRate::B460800,
+        #[cfg(target_os = "linux")]
+        921600 => BaudRate::B921600,
+        _ => return None,
+    };
+
+    Some(rate)
+}
+
+fn configure(fd: &OwnedFd, baud: u32) -> Result<()> {
+    let mut attrs = termios::tcgetattr(fd)?;
+    termios::cfmakeraw(&mut attrs);
+    attrs.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+    let rate = baud_rate(baud).context("unsupported baud rate")?;
+    termios::cfsetspeed(&mut attrs, rate)?;
+    termios::tcsetattr(fd, SetArg::TCSANOW, &attrs)?;
+
+    Ok(())
+}
+
+async fn watch_carrier(fd: OwnedFd) {
+    let mut seen = false;
+    let mut interval = tokio::time::interval(CARRIER_POLL_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        match (carrier(&fd), seen) {
+            (Some(true), _) => seen = true,
+            (Some(false), true) => return,
+            _ => (),
+        }
+    }
+}
+
+/// Reads the state of the DCD line, if the device has modem lines.
+fn carrier(fd: &OwnedFd) -> Option<bool> {
+    let mut bits: libc::c_int = 0;
+
+    match unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCMGET, &mut bits) } {
+        -1 => None,
+        _ => Some(bits & libc::TIOCM_CAR != 0),
+    }
+}
@@ -1,33 +1,224 @@
+use crate::api;
+use crate::api::queue::Backlog;
+use crate::command::{Region, Reply, Scroll, Selection, SelectionMode, SignalTarget, Trim};
+use crate::history::{History, Moment};
+use crate::procfs;
+use crate::pty;
+use crate::redact::Redactor;
+use crate::render;
+use crate::ring::OutputRing;
+use crate::tracker::{CursorStyle, Mark, Tracker};
+use crate::utf8;
 use anyhow::Result;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
 use futures_util::{stream, Stream, StreamExt};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
 use serde_json::json;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
 use std::future;
+use std::io::Read;
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 
+const RESET_SEQ: &str = "\x1bc";
+const CLEAR_SCROLLBACK_SEQ: &str = "\x1b[3J";
+const CLEAR_SCREEN_SEQ: &str = "\x1b[H\x1b[2J";
+const PASTE_CHUNK_SIZE: usize = 4096;
+const DRAIN_POLL_PERIOD: Duration = Duration::from_millis(10);
+const BRACKETED_PASTE_MODE: u16 = 2004;
+const FOCUS_EVENTS_MODE: u16 = 1004;
+
+/// DEC private modes enabling mouse tracking: X10, normal, button-event and
+/// any-event.
+const MOUSE_TRACKING_MODES: [u16; 4] = [9, 1000, 1002, 1003];
+
+/// DEC private modes reported with `modeChanged` events, with their names:
+/// mouse tracking, its encodings, focus events and bracketed paste.
+const REPORTED_MODES: [(u16, &str); 9] = [
+    (9, "mouseX10"),
+    (1000, "mouseNormal"),
+    (1002, "mouseButtonEvent"),
+    (1003, "mouseAnyEvent"),
+    (1005, "mouseUtf8"),
+    (1006, "mouseSgr"),
+    (1015, "mouseUrxvt"),
+    (FOCUS_EVENTS_MODE, "focusEvents"),
+    (BRACKETED_PASTE_MODE, "bracketedPaste"),
+];
+
 pub struct Session {
     vt: avt::Vt,
+    tracker: Tracker,
     broadcast_tx: broadcast::Sender<Event>,
+    /// Events waiting in the clients' queues
+    backlog: Backlog,
     stream_time: f64,
     start_time: Instant,
     last_event_time: Instant,
     pid: i32,
+    heartbeat_count: u64,
+    output_limit: Option<usize>,
+    allow_resize: bool,
+    strict_mouse: bool,
+    allow_clipboard_read: bool,
+    /// Content provided with `setClipboard`
+    clipboard: Option<String>,
+    output_bytes: usize,
+    truncated: bool,
+    utf8_policy: utf8::Policy,
+    output_decoder: utf8::Decoder,
+    stderr_decoder: utf8::Decoder,
+    redactor: Redactor,
+    warnings: Vec<Event>,
+    suspended: bool,
+    terminal: Option<OwnedFd>,
+    foreground: Option<(i32, Option<String>)>,
+    exec: Option<Exec>,
+    /// Where the user started typing a command, after the prompt
+    command_input: Option<(usize, usize)>,
+    /// Command line and start time of the command run at the prompt
+    running_command: Option<(Option<String>, Instant)>,
+    /// Sequence number of the last output event
+    output_id: u64,
+    /// Hash of the screen as last reported
+    screen_hash: Option<u64>,
+    /// When the hash last changed
+    screen_changed_at: Instant,
+    /// When the screen was last changed, if it wasn't hashed since
+    screen_touched_at: Option<Instant>,
+    stable_waits: Vec<StableWait>,
+    /// Viewports of the clients which don't follow the live screen: the
+    /// client's reply channel, and the index of the viewport's top line in
+    /// the scrollback
+    viewports: Vec<(Reply, usize)>,
+    /// Selection made with `setSelection`, cleared on resize
+    selection: Option<Selection>,
+    /// Recorded input macros, by name
+    macros: BTreeMap<String, Vec<(Duration, Vec<u8>)>>,
+    macro_recording: Option<MacroRecording>,
+    /// Steps of the macro being played, with when each one is due
+    macro_playback: VecDeque<(Instant, Vec<u8>)>,
+    /// Recent past of the screen, when enabled with `--history`
+    history: Option<History>,
+    output_ring: Option<OutputRing>,
+    paste: Option<Paste>,
 }
 
 #[derive(Clone)]
 pub enum Event {
     Init(f64, usize, usize, i32, String, String),
-    Output(f64, String),
+    Output(f64, u64, Arc<String>, Option<Bytes>),
     Resize(f64, usize, usize),
-    Snapshot(usize, usize, String, String),
+    Snapshot {
+        cols: usize,
+        rows: usize,
+        seq: String,
+        text: String,
+        cursor: Cursor,
+        cwd: Option<String>,
+        /// Screen rendered in the requested format
+        rendered: Option<(render::Format, String)>,
+        output_id: u64,
+        /// Terminal state, in full snapshots
+        state: Option<TerminalState>,
+    },
+    Heartbeat(f64, u64, Option<bool>),
+    Timeout(f64, f64),
+    Truncated(f64, usize, usize),
+    Stderr(f64, Arc<String>, Option<Bytes>),
+    InvalidUtf8(f64, &'static str, usize, Vec<u8>),
+    Warning(f64, String),
+    ProcessState(f64, bool),
+    ForegroundChanged(f64, i32, Option<String>),
+    CwdChanged(f64, String),
+    CursorStyle(f64, CursorStyle),
+    /// Time, DEC private mode number, whether it's enabled
+    ModeChanged(f64, u16, bool),
+    PromptStart(f64),
+    CommandStart(f64),
+    CommandEnd(f64, Option<i32>),
+    CommandExecuted(f64, Option<String>, f64, Option<i32>),
+    ScreenHash(f64, String),
+    PasteProgress(f64, usize, usize),
+    Annotation(f64, serde_json::Value),
+    RecordingPaused(f64, bool),
+}
+
+#[derive(Clone)]
+pub struct Cursor {
+    col: usize,
+    row: usize,
+    visible: bool,
+    style: CursorStyle,
+}
+
+/// Terminal state included in full snapshots.
+#[derive(Clone)]
+pub struct TerminalState {
+    title: Option<String>,
+    alternate_screen: bool,
+    dec_modes: BTreeMap<u16, bool>,
+    ansi_modes: BTreeMap<u16, bool>,
+    tab_stops: Vec<usize>,
+}
+
+/// Shell command run with the `exec` command, waiting for the shell to report
+/// its completion.
+struct Exec {
+    command: String,
+    reply: Reply,
+    start_time: Instant,
+    deadline: Option<Instant>,
+    /// First line of the output, after the line with the command itself
+    output_line: usize,
+}
+
+/// Input written in chunks, each one once the child accepted the previous
+/// ones.
+struct Paste {
+    data: Vec<u8>,
+    /// How much of the data has been sent to the child
+    sent: usize,
+    reply: Reply,
+    start_time: Instant,
+}
+
+/// Input macro being recorded with `macroRecord`.
+struct MacroRecording {
+    name: String,
+    /// When the first step was recorded
+    first_step: Option<Instant>,
+    /// Input of each step, with its delay since the first step
+    steps: Vec<(Duration, Vec<u8>)>,
+}
+
+/// Client waiting for the screen to stop changing.
+struct StableWait {
+    reply: Reply,
+    quiet: Duration,
+    deadline: Instant,
 }
 
 pub struct Client(oneshot::Sender<Subscription>);
 
 pub struct Subscription {
     init: Event,
+    warnings: Vec<Event>,
     broadcast_rx: broadcast::Receiver<Event>,
+    backlog: Backlog,
+}
+
+impl Subscription {
+    /// Returns the receiver of the events following the subscription.
+    pub fn into_receiver(self) -> broadcast::Receiver<Event> {
+        self.broadcast_rx
+    }
 }
 
 impl Session {
@@ -37,144 +228,3116 @@ impl Session {
 
         Self {
             vt: build_vt(cols, rows),
+            tracker: Tracker::new(cols),
             broadcast_tx,
+            backlog: Backlog::default(),
             stream_time: 0.0,
             start_time: now,
             last_event_time: now,
             pid,
+            heartbeat_count: 0,
+            output_limit: None,
+            allow_resize: false,
+            strict_mouse: false,
+            allow_clipboard_read: false,
+            clipboard: None,
+            output_bytes: 0,
+            truncated: false,
+            utf8_policy: utf8::Policy::default(),
+            output_decoder: utf8::Decoder::default(),
+            stderr_decoder: utf8::Decoder::default(),
+            redactor: Redactor::default(),
+            warnings: Vec::new(),
+            suspended: false,
+            terminal: None,
+            foreground: None,
+            exec: None,
+            command_input: None,
+            running_command: None,
+            output_id: 0,
+            screen_hash: None,
+            screen_changed_at: Instant::now(),
+            screen_touched_at: Some(Instant::now()),
+            stable_waits: Vec::new(),
+            viewports: Vec::new(),
+            selection: None,
+            macros: BTreeMap::new(),
+            macro_recording: None,
+            macro_playback: VecDeque::new(),
+            history: None,
+            output_ring: None,
+            paste: None,
+        }
+    }
+
+    pub fn set_output_limit(&mut self, limit: Option<usize>) {
+        self.output_limit = limit;
+    }
+
+    pub fn set_allow_resize(&mut self, allow: bool) {
+        self.allow_resize = allow;
+    }
+
+    pub fn set_strict_mouse(&mut self, strict: bool) {
+        self.strict_mouse = strict;
+    }
+
+    pub fn set_history(&mut self, period: Option<Duration>) {
+        self.history = period.map(|p| History::new(p, &self.vt));
+    }
+
+    pub fn set_output_buffer(&mut self, capacity: Option<usize>) {
+        self.output_ring = capacity.map(OutputRing::new);
+    }
+
+    pub fn set_allow_clipboard_read(&mut self, allow: bool) {
+        self.allow_clipboard_read = allow;
+    }
+
+    pub fn set_clipboard(&mut self, content: String) {
+        self.clipboard = Some(content);
+    }
+
+    /// Returns the answers to the application's clipboard read requests (OSC
+    /// 52), to be written to its input. The requests are ignored unless
+    /// allowed, like most terminals do by default.
+    pub fn take_clipboard_answers(&mut self) -> Vec<u8> {
+        let requests = std::mem::take(&mut self.tracker.clipboard_requests);
+
+        if !self.allow_clipboard_read {
+            return Vec::new();
+        }
+
+        let content = BASE64_STANDARD.encode(self.clipboard.as_deref().unwrap_or_default());
+
+        requests
+            .iter()
+            .flat_map(|selection| format!("\x1b]52;{selection};{content}\x1b\\").into_bytes())
+            .collect()
+    }
+
+    pub fn set_utf8_policy(&mut self, policy: utf8::Policy) {
+        self.utf8_policy = policy;
+    }
+
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Masks the echo of the text, typed as secret input, in the output to
+    /// come, and on the screen once echoed.
+    pub fn add_secrets(&mut self, secrets: Vec<String>) {
+        for secret in secrets {
+            self.redactor.add_secret(&secret);
+        }
+    }
+
+    /// Sets the PTY master, used for tracking the foreground process group.
+    pub fn set_terminal(&mut self, fd: OwnedFd) {
+        self.terminal = Some(fd);
+    }
+
+    pub fn has_terminal(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    /// Checks which process group owns the terminal, emitting
+    /// `ForegroundChanged` event when it's a different one than before.
+    ///
+    /// The leader's name is compared too, since a freshly forked process may
+    /// not have executed its program yet.
+    pub fn update_foreground(&mut self) {
+        let Some(pgid) = self
+            .terminal
+            .as_ref()
+            .and_then(|fd| unistd::tcgetpgrp(fd).ok())
+            .map(Pid::as_raw)
+        else {
+            return;
+        };
+
+        let foreground = Some((pgid, procfs::process(pgid).ok().map(|p| p.name)));
+
+        if self.foreground != foreground {
+            self.foreground = foreground;
+            let name = self.foreground.as_ref().and_then(|(_, name)| name.clone());
+            let time = self.start_time.elapsed().as_secs_f64();
+            let _ = self
+                .broadcast_tx
+                .send(Event::ForegroundChanged(time, pgid, name));
+        }
+    }
+
+    /// Hashes the rendered screen (text, colors and attributes, cursor),
+    /// emitting `ScreenHash` event when it differs from the last one.
+    /// Rendering is skipped when the screen wasn't changed since last time.
+    pub fn update_screen_hash(&mut self) {
+        let Some(touched_at) = self.screen_touched_at.take() else {
+            return;
+        };
+
+        let hash = fnv1a(render::ansi(&self.vt, &self.tracker).as_bytes());
+
+        if self.screen_hash != Some(hash) {
+            self.screen_hash = Some(hash);
+            self.screen_changed_at = touched_at;
+            let time = self.start_time.elapsed().as_secs_f64();
+            let _ = self
+                .broadcast_tx
+                .send(Event::ScreenHash(time, format!("{hash:016x}")));
         }
     }
 
-    pub fn output(&mut self, data: String) {
-        self.vt.feed_str(&data);
+    /// Emits a warning, which is also delivered to clients subscribing later.
+    pub fn warn(&mut self, message: String) {
+        let event = Event::Warning(self.start_time.elapsed().as_secs_f64(), message);
+        self.warnings.push(event.clone());
+        let _ = self.broadcast_tx.send(event);
+    }
+
+    pub fn output(&mut self, bytes: impl Into<Bytes>) {
+        let bytes = bytes.into();
+        let mut decoded = self.output_decoder.decode(&bytes);
+        self.report_invalid_utf8("stdout", std::mem::take(&mut decoded.invalid));
+        let text = std::mem::take(&mut decoded.text);
+        let cwd = self.tracker.cwd.clone();
+        let cursor_style = self.tracker.cursor_style;
+        let modes = self.reported_modes();
+        // the emulator gets the output as is, so masks can't break sequences
+        let dropped = self.tracker.feed(&mut self.vt, &text);
+        self.shift_viewports(dropped);
+        // counted as produced, whatever the redaction does to it
+        let len = text.len();
+        let (mut data, mut raw, redacted) = self.redact(text, &bytes);
+        self.record_history(Some(&data));
+        self.screen_touched_at = Some(Instant::now());
+
+        if self.tracker.cwd != cwd {
+            if let Some(cwd) = &self.tracker.cwd {
+                let time = self.start_time.elapsed().as_secs_f64();
+                let _ = self.broadcast_tx.send(Event::CwdChanged(time, cwd.clone()));
+            }
+        }
+
+        if self.tracker.cursor_style != cursor_style {
+            let time = self.start_time.elapsed().as_secs_f64();
+            let style = self.tracker.cursor_style;
+            let _ = self.broadcast_tx.send(Event::CursorStyle(time, style));
+        }
+
+        for ((mode, _), (old, new)) in REPORTED_MODES
+            .iter()
+            .zip(modes.iter().zip(self.reported_modes()))
+        {
+            if *old != new {
+                let time = self.start_time.elapsed().as_secs_f64();
+                let _ = self.broadcast_tx.send(Event::ModeChanged(time, *mode, new));
+            }
+        }
+
+        self.report_marks();
+
+        let emitted = self.output_bytes;
+        self.output_bytes += len;
+
+        if self.truncated {
+            return;
+        }
+
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Output(time, data));
+
+        match self.output_limit {
+            Some(limit) if self.output_bytes > limit => {
+                data.truncate(char_boundary(&data, limit - emitted));
+
+                if let Some(raw) = &mut raw {
+                    // the raw bytes are the text itself when it was redacted
+                    let end = if redacted {
+                        data.len()
+                    } else {
+                        decoded.byte_offset(data.len())
+                    };
+
+                    raw.truncate(end);
+                }
+
+                if !data.is_empty() {
+                    self.emit_output(time, data, raw);
+                }
+
+                let _ = self
+                    .broadcast_tx
+                    .send(Event::Truncated(time, limit, self.output_bytes));
+
+                self.truncated = true;
+            }
+
+            _ => {
+                self.emit_output(time, data, raw);
+            }
+        }
+
         self.stream_time = time;
         self.last_event_time = Instant::now();
     }
 
-    pub fn resize(&mut self, cols: usize, rows: usize) {
-        resize_vt(&mut self.vt, cols, rows);
+    /// Resizes the terminal as requested by the application, when allowed.
+    ///
+    /// The request is applied after the output it came with was emitted, so
+    /// the rest of that output has been processed at the old size.
+    pub fn apply_resize_request(&mut self) {
+        let Some((cols, rows)) = self.tracker.resize_request.take() else {
+            return;
+        };
+
+        if !self.allow_resize {
+            return;
+        }
+
+        let (old_cols, old_rows) = self.vt.size();
+        let cols = if cols == 0 { old_cols } else { cols };
+        let rows = if rows == 0 { old_rows } else { rows };
+
+        if (cols, rows) != (old_cols, old_rows) {
+            // keeps the size of a cell in pixels, when known
+            let (xpixel, ypixel) = match self.terminal.as_ref().map(pty::size) {
+                Some(Ok(size)) => (
+                    scale_pixels(size.ws_xpixel, old_cols, cols),
+                    scale_pixels(size.ws_ypixel, old_rows, rows),
+                ),
+
+                _ => (0, 0),
+            };
+
+            self.resize(cols, rows, xpixel, ypixel);
+        }
+    }
+
+    fn emit_output(&mut self, time: f64, data: String, raw: Option<Bytes>) {
+        self.output_id += 1;
+
+        if let Some(ring) = &mut self.output_ring {
+            ring.push(data.as_bytes());
+        }
+
+        let _ = self
+            .broadcast_tx
+            .send(Event::Output(time, self.output_id, Arc::new(data), raw));
+    }
+
+    fn report_marks(&mut self) {
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Resize(time, cols, rows));
-        self.stream_time = time;
-        self.last_event_time = Instant::now();
+
+        for (mark, (col, line)) in std::mem::take(&mut self.tracker.marks) {
+            let event = match mark {
+                Mark::PromptStart => Some(Event::PromptStart(time)),
+                Mark::PromptEnd => None,
+                Mark::CommandStart => Some(Event::CommandStart(time)),
+                Mark::CommandEnd(status) => Some(Event::CommandEnd(time, status)),
+            };
+
+            if let Some(event) = event {
+                let _ = self.broadcast_tx.send(event);
+            }
+
+            match mark {
+                Mark::PromptEnd => self.command_input = Some((col, line)),
+
+                Mark::CommandStart => {
+                    let command = self
+                        .command_input
+                        .take()
+                        .map(|start| self.text_between(start, (col, line)))
+                        .filter(|c| !c.is_empty());
+
+                    self.running_command = Some((command, Instant::now()));
+
+                    if let Some(exec) = &mut self.exec {
+                        exec.output_line = line;
+                    }
+                }
+
+                Mark::CommandEnd(status) => {
+                    if let Some((command, start_time)) = self.running_command.take() {
+                        let duration = start_time.elapsed().as_secs_f64();
+                        let event = Event::CommandExecuted(time, command, duration, status);
+                        let _ = self.broadcast_tx.send(event);
+                    }
+
+                    self.finish_exec(status, (col, line));
+                }
+
+                // shells which don't report the end of the command still
+                // mark the next prompt
+                Mark::PromptStart => self.finish_exec(None, (col, line)),
+            }
+        }
     }
 
-    pub fn snapshot(&self) {
-        let (cols, rows) = self.vt.size();
+    /// Starts waiting for a shell command, which the caller then types into
+    /// the terminal.
+    ///
+    /// Only one command can run at a time. Returns false, replying with an
+    /// error, when another one is still running.
+    pub fn start_exec(&mut self, command: String, timeout: Option<Duration>, reply: Reply) -> bool {
+        if let Some(exec) = &self.exec {
+            let _ = reply.send(error_reply(format!(
+                "command {:?} is still running",
+                exec.command
+            )));
 
-        let _ = self.broadcast_tx.send(Event::Snapshot(
-            cols,
-            rows,
-            self.vt.dump(),
-            self.text_view(),
-        ));
+            return false;
+        }
+
+        let now = Instant::now();
+
+        self.exec = Some(Exec {
+            command,
+            reply,
+            start_time: now,
+            deadline: timeout.map(|t| now + t),
+            output_line: self.cursor_line() + 1,
+        });
+
+        true
     }
 
-    pub fn cursor_key_app_mode(&self) -> bool {
-        self.vt.cursor_key_app_mode()
+    /// Starts pasting the text, wrapped in bracketed paste sequences when the
+    /// app enabled bracketed paste mode. The caller then sends the chunks
+    /// returned by `next_paste_chunk`.
+    pub fn start_paste(&mut self, text: String, reply: Reply) {
+        let data = if self.bracketed_paste() {
+            // as xterm does, so the text can't end the paste early and get
+            // the rest taken as keystrokes
+            let text = text
+                .replace("\x1b[200~", "")
+                .replace("\x1b[201~", "")
+                .replace('\x1b', "");
+
+            format!("\x1b[200~{text}\x1b[201~").into_bytes()
+        } else {
+            text.into_bytes()
+        };
+
+        self.paste = Some(Paste {
+            data,
+            sent: 0,
+            reply,
+            start_time: Instant::now(),
+        });
     }
 
-    pub fn size(&self) -> (usize, usize) {
-        self.vt.size()
+    pub fn is_pasting(&self) -> bool {
+        self.paste.is_some()
     }
 
-    pub fn subscribe(&self) -> Subscription {
-        let (cols, rows) = self.vt.size();
+    /// Returns the next chunk of the paste, to be called once the child
+    /// accepted all the input sent so far. Reports the progress, and replies
+    /// once the whole paste was accepted.
+    pub fn next_paste_chunk(&mut self) -> Option<Vec<u8>> {
+        let paste = self.paste.as_mut()?;
+        let total = paste.data.len();
 
-        let init = Event::Init(
-            self.elapsed_time(),
-            cols,
-            rows,
-            self.pid,
-            self.vt.dump(),
-            self.text_view(),
-        );
+        if paste.sent > 0 {
+            let time = self.start_time.elapsed().as_secs_f64();
+            let _ = self
+                .broadcast_tx
+                .send(Event::PasteProgress(time, paste.sent, total));
+        }
 
-        let broadcast_rx = self.broadcast_tx.subscribe();
+        if paste.sent == total {
+            let paste = self.paste.take().unwrap();
+            let data = json!({
+                "bytes": total,
+                "duration": paste.start_time.elapsed().as_secs_f64(),
+            });
+
+            let _ = paste.reply.send(reply("paste", data));
 
-        Subscription { init, broadcast_rx }
+            return None;
+        }
+
+        let end = (paste.sent + PASTE_CHUNK_SIZE).min(total);
+        let chunk = paste.data[paste.sent..end].to_vec();
+        paste.sent = end;
+
+        Some(chunk)
     }
 
-    fn elapsed_time(&self) -> f64 {
-        self.stream_time + self.last_event_time.elapsed().as_secs_f64()
+    /// Reply to the `sync` command, sent once the output received before it
+    /// was processed.
+    pub fn sync_reply(&self) -> serde_json::Value {
+        let time = self.start_time.elapsed().as_secs_f64();
+
+        reply("sync", json!({ "time": time }))
     }
 
-    fn text_view(&self) -> String {
-        self.vt
-            .view()
-            .iter()
-            .map(|l| l.text())
-            .collect::<Vec<_>>()
-            .join("\n")
+    pub fn exec_deadline(&self) -> Option<Instant> {
+        self.exec.as_ref().and_then(|e| e.deadline)
     }
-}
 
-impl Event {
-    pub fn to_json(&self) -> serde_json::Value {
-        match self {
-            Event::Init(_time, cols, rows, pid, seq, text) => json!({
-                "type": "init",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                    "pid": pid,
-                    "seq": seq,
-                    "text": text,
-                })
-            }),
+    /// Gives up waiting for the shell command, replying with an error.
+    pub fn exec_timeout(&mut self) {
+        if let Some(exec) = self.exec.take() {
+            let _ = exec.reply.send(error_reply(format!(
+                "command {:?} didn't finish in time",
+                exec.command
+            )));
+        }
+    }
 
-            Event::Output(_time, seq) => json!({
-                "type": "output",
-                "data": json!({
-                    "seq": seq
-                })
-            }),
+    /// Replies with the output, which ends at the position of the mark.
+    ///
+    /// Marks are handled after the whole chunk of output is fed, so the line
+    /// with the mark may already hold the following prompt.
+    fn finish_exec(&mut self, status: Option<i32>, (end_col, end_line): (usize, usize)) {
+        let Some(exec) = self.exec.take() else {
+            return;
+        };
 
-            Event::Resize(_time, cols, rows) => json!({
-                "type": "resize",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                })
-            }),
+        let output = self.text_between((0, exec.output_line), (end_col, end_line));
 
-            Event::Snapshot(cols, rows, seq, text) => json!({
-                "type": "snapshot",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                    "seq": seq,
-                    "text": text,
-                })
+        let _ = exec.reply.send(reply(
+            "exec",
+            json!({
+                "command": exec.command,
+                "output": output,
+                "exitCode": status,
+                "duration": exec.start_time.elapsed().as_secs_f64(),
             }),
+        ));
+    }
+
+    /// Returns the text between two positions (col, line counted from the top
+    /// of the scrollback), without trailing whitespace and empty lines.
+    fn text_between(
+        &self,
+        (start_col, start_line): (usize, usize),
+        (end_col, end_line): (usize, usize),
+    ) -> String {
+        let lines = self.vt.lines();
+        let end = (end_line + 1).min(lines.len());
+        let start = start_line.min(end);
+
+        let mut text: Vec<String> = lines[start..end]
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let from = if start + i == start_line {
+                    start_col
+                } else {
+                    0
+                };
+                let to = if start + i == end_line {
+                    end_col
+                } else {
+                    l.len()
+                };
+
+                slice_line(l, from, to.saturating_sub(from))
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect();
+
+        while text.last().is_some_and(|l| l.is_empty()) {
+            text.pop();
         }
+
+        self.redact_screen(text.join("\n"))
     }
-}
 
-fn build_vt(cols: usize, rows: usize) -> avt::Vt {
-    avt::Vt::builder().size(cols, rows).build()
-}
+    fn cursor_line(&self) -> usize {
+        self.vt.lines().len() - self.vt.view().len() + self.vt.cursor().row
+    }
 
-fn resize_vt(vt: &mut avt::Vt, cols: usize, rows: usize) {
-    vt.resize(cols, rows);
-}
+    /// Performs a full terminal reset (RIS).
+    ///
+    /// The reset sequence is also sent to the clients as output, so their
+    /// terminals follow.
+    pub fn reset(&mut self) {
+        let time = self.start_time.elapsed().as_secs_f64();
+        self.tracker.feed(&mut self.vt, RESET_SEQ);
+        self.record_history(Some(RESET_SEQ));
+        self.screen_touched_at = Some(Instant::now());
+        self.emit_output(time, RESET_SEQ.to_owned(), None);
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+    }
 
-impl Client {
-    pub fn accept(self, subscription: Subscription) {
-        let _ = self.0.send(subscription);
+    /// Drops the scrollback, optionally clearing the screen too.
+    ///
+    /// avt ignores `ED 3`, so the emulator is rebuilt from its dump, which
+    /// holds only the screen. Clients get the equivalent sequences as output.
+    pub fn clear_scrollback(&mut self, clear_screen: bool) {
+        let (cols, rows) = self.vt.size();
+        let mut vt = build_vt(cols, rows);
+        vt.feed_str(&self.vt.dump());
+        self.vt = vt;
+        self.viewports.clear();
+        let mut seq = CLEAR_SCROLLBACK_SEQ.to_owned();
+
+        if clear_screen {
+            self.tracker.feed(&mut self.vt, CLEAR_SCREEN_SEQ);
+            seq.push_str(CLEAR_SCREEN_SEQ);
+        }
+
+        self.record_history(None);
+        self.screen_touched_at = Some(Instant::now());
+        let time = self.start_time.elapsed().as_secs_f64();
+        self.emit_output(time, seq, None);
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
     }
-}
 
-pub async fn stream(
-    clients_tx: &mpsc::Sender<Client>,
-) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
-    let (sub_tx, sub_rx) = oneshot::channel();
-    clients_tx.send(Client(sub_tx)).await?;
-    let sub = tokio::time::timeout(Duration::from_secs(5), sub_rx).await??;
-    let init = stream::once(future::ready(Ok(sub.init)));
-    let events = BroadcastStream::new(sub.broadcast_rx);
+    pub fn annotate(&mut self, payload: serde_json::Value) {
+        let time = self.start_time.elapsed().as_secs_f64();
+        let _ = self.broadcast_tx.send(Event::Annotation(time, payload));
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+    }
+
+    /// Pauses or resumes the recording.
+    ///
+    /// Sent through the event stream, so the recorder cuts exactly between
+    /// the output that preceded and followed the command.
+    pub fn pause_recording(&mut self, paused: bool) {
+        let time = self.start_time.elapsed().as_secs_f64();
+        let _ = self.broadcast_tx.send(Event::RecordingPaused(time, paused));
+    }
+
+    pub fn stderr(&mut self, bytes: impl Into<Bytes>) {
+        let bytes = bytes.into();
+        let decoded = self.stderr_decoder.decode(&bytes);
+        self.report_invalid_utf8("stderr", decoded.invalid);
+        let (text, raw, _) = self.redact(decoded.text, &bytes);
+        let time = self.start_time.elapsed().as_secs_f64();
+        let _ = self
+            .broadcast_tx
+            .send(Event::Stderr(time, Arc::new(text), raw));
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+    }
+
+    fn report_invalid_utf8(&mut self, stream: &'static str, invalid: Vec<(usize, Vec<u8>)>) {
+        if self.utf8_policy != utf8::Policy::Strict {
+            return;
+        }
+
+        let time = self.start_time.elapsed().as_secs_f64();
+
+        for (offset, bytes) in invalid {
+            let _ = self
+                .broadcast_tx
+                .send(Event::InvalidUtf8(time, stream, offset, bytes));
+        }
+    }
+
+    /// The original bytes, when events carry them. They're shared with the
+    /// reader's buffer, not copied.
+    fn raw_bytes(&self, bytes: &Bytes) -> Option<Bytes> {
+        (self.utf8_policy == utf8::Policy::Raw).then(|| bytes.clone())
+    }
+
+    /// Applies the redaction rules to the decoded text, and masks the echo of
+    /// secret input, telling whether anything was redacted. The original
+    /// bytes can't be kept when it was, so they're replaced with the redacted
+    /// text.
+    fn redact(&mut self, text: String, bytes: &Bytes) -> (String, Option<Bytes>, bool) {
+        match self.redactor.redact_output(&text) {
+            Cow::Borrowed(_) => (text, self.raw_bytes(bytes), false),
+
+            Cow::Owned(redacted) => {
+                let raw = self.raw_bytes(&Bytes::from(redacted.clone()));
+
+                (redacted, raw, true)
+            }
+        }
+    }
+
+    /// Applies the redaction rules to text read from the screen, which holds
+    /// the output as it was.
+    fn redact_screen(&self, text: String) -> String {
+        match self.redactor.redact(&text) {
+            Cow::Borrowed(_) => text,
+            Cow::Owned(redacted) => redacted,
+        }
+    }
 
-    Ok(init.chain(events))
+    /// Starts recording the input into a macro, replacing the one with the
+    /// same name once stopped.
+    ///
+    /// Returns an error reply when a macro is being recorded already.
+    pub fn record_macro(&mut self, name: String) -> Option<serde_json::Value> {
+        if let Some(recording) = &self.macro_recording {
+            return Some(error_reply(format!(
+                "macro {:?} is being recorded already",
+                recording.name
+            )));
+        }
+
+        self.macro_recording = Some(MacroRecording {
+            name,
+            first_step: None,
+            steps: Vec::new(),
+        });
+
+        None
+    }
+
+    /// Adds the input to the macro being recorded, if any.
+    pub fn record_macro_input(&mut self, data: &[u8]) {
+        if let Some(recording) = &mut self.macro_recording {
+            let first_step = *recording.first_step.get_or_insert_with(Instant::now);
+            recording.steps.push((first_step.elapsed(), data.to_vec()));
+        }
+    }
+
+    /// Stops recording the macro, replying with its summary.
+    pub fn stop_macro(&mut self) -> serde_json::Value {
+        let Some(recording) = self.macro_recording.take() else {
+            return error_reply("no macro is being recorded".to_owned());
+        };
+
+        let reply = macro_reply(&recording.name, &recording.steps);
+        self.macros.insert(recording.name, recording.steps);
+
+        reply
+    }
+
+    /// Starts playing the macro, with the original delays between the steps
+    /// when `timing` is set, or all at once otherwise. The input due now is
+    /// taken with `take_macro_input`.
+    ///
+    /// Returns an error reply when there's no such macro.
+    pub fn play_macro(&mut self, name: &str, timing: bool) -> Option<serde_json::Value> {
+        let Some(steps) = self.macros.get(name) else {
+            return Some(error_reply(format!("there's no macro {name:?}")));
+        };
+
+        let now = Instant::now();
+
+        self.macro_playback = steps
+            .iter()
+            .map(|(delay, data)| (if timing { now + *delay } else { now }, data.clone()))
+            .collect();
+
+        None
+    }
+
+    /// Returns when the next step of the played macro is due.
+    pub fn macro_deadline(&self) -> Option<Instant> {
+        self.macro_playback.front().map(|(at, _)| *at)
+    }
+
+    /// Returns the input of the played macro's steps which are due.
+    pub fn take_macro_input(&mut self) -> Vec<u8> {
+        let now = Instant::now();
+        let mut data = Vec::new();
+
+        while self
+            .macro_playback
+            .front()
+            .is_some_and(|(at, _)| *at <= now)
+        {
+            if let Some((_, step)) = self.macro_playback.pop_front() {
+                data.extend_from_slice(&step);
+            }
+        }
+
+        data
+    }
+
+    /// Stops (SIGSTOP) or continues (SIGCONT) the child's process group.
+    ///
+    /// Returns an error reply when the signal can't be delivered.
+    pub fn suspend(&mut self, suspended: bool) -> Option<serde_json::Value> {
+        let sig = if suspended {
+            Signal::SIGSTOP
+        } else {
+            Signal::SIGCONT
+        };
+
+        self.signal(sig, SignalTarget::Group)
+    }
+
+    /// Sends a signal to the child or to its whole process group.
+    ///
+    /// Returns an error reply when the signal can't be delivered.
+    pub fn signal(&mut self, sig: Signal, target: SignalTarget) -> Option<serde_json::Value> {
+        // 0 would signal ht's own process group
+        if self.pid <= 0 {
+            return Some(error_reply("there's no child process".to_owned()));
+        }
+
+        let pid = Pid::from_raw(self.pid);
+
+        let result = match target {
+            SignalTarget::Child => queue_signal(pid, sig),
+            SignalTarget::Group => signal::killpg(pid, sig),
+        };
+
+        if let Err(e) = result {
+            return Some(error_reply(format!("cannot send {sig} to the child: {e}")));
+        }
+
+        let suspended = match sig {
+            // SIGTSTP and friends can be caught or ignored, so only SIGSTOP counts
+            Signal::SIGSTOP => true,
+            Signal::SIGCONT => false,
+            _ => return None,
+        };
+
+        if self.suspended != suspended {
+            self.suspended = suspended;
+            let time = self.start_time.elapsed().as_secs_f64();
+            let _ = self.broadcast_tx.send(Event::ProcessState(time, suspended));
+        }
+
+        None
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize, xpixel: u16, ypixel: u16) {
+        let (old_cols, _) = self.vt.size();
+        resize_vt(&mut self.vt, cols, rows);
+        self.tracker.resize(old_cols, cols);
+        self.selection = None;
+        self.record_history(None);
+        self.screen_touched_at = Some(Instant::now());
+
+        if let Some(fd) = &self.terminal {
+            if let Err(e) = pty::resize(fd, cols, rows, xpixel, ypixel) {
+                eprintln!("cannot resize the terminal: {e}");
+            }
+        }
+
+        let time = self.start_time.elapsed().as_secs_f64();
+        let _ = self.broadcast_tx.send(Event::Resize(time, cols, rows));
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+    }
+
+    pub fn snapshot(&self, format: Option<render::Format>, full: bool, trim: Trim) {
+        let _ = self
+            .broadcast_tx
+            .send(self.snapshot_event(format, full, trim));
+    }
+
+    fn snapshot_event(&self, format: Option<render::Format>, full: bool, trim: Trim) -> Event {
+        let (cols, rows) = self.vt.size();
+        let rendered = format.map(|f| (f, self.redact_screen(f.render(&self.vt, &self.tracker))));
+
+        Event::Snapshot {
+            cols,
+            rows,
+            seq: self.dump(),
+            text: trim.apply(self.view_lines()).join("\n"),
+            cursor: self.cursor(),
+            cwd: self.tracker.cwd.clone(),
+            rendered,
+            output_id: self.output_id,
+            state: full.then(|| self.terminal_state()),
+        }
+    }
+
+    fn terminal_state(&self) -> TerminalState {
+        TerminalState {
+            title: self.tracker.title.clone(),
+            alternate_screen: self.tracker.alternate_screen,
+            dec_modes: self.dec_modes(),
+            ansi_modes: self.tracker.ansi_modes.clone(),
+            tab_stops: self.tracker.tab_stops.clone(),
+        }
+    }
+
+    fn dec_modes(&self) -> BTreeMap<u16, bool> {
+        let mut dec_modes = self.tracker.dec_modes.clone();
+        dec_modes.insert(1, self.vt.cursor_key_app_mode());
+        dec_modes.insert(5, self.tracker.reverse_video());
+        dec_modes.insert(6, self.tracker.origin_mode());
+        dec_modes.insert(7, self.tracker.auto_wrap());
+        dec_modes.insert(25, self.vt.cursor().visible);
+
+        dec_modes
+    }
+
+    fn bracketed_paste(&self) -> bool {
+        self.tracker.dec_modes.get(&BRACKETED_PASTE_MODE) == Some(&true)
+    }
+
+    /// Records the output fed to the emulator in the history, or adds
+    /// a checkpoint after the emulator was changed otherwise.
+    fn record_history(&mut self, data: Option<&str>) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+
+        let time = self.start_time.elapsed().as_secs_f64();
+
+        match data {
+            // the id of the output event about to be emitted
+            Some(data) => history.output(time, self.output_id + 1, data, &self.vt),
+            None => history.checkpoint(time, self.output_id, &self.vt),
+        }
+    }
+
+    /// Returns the screen as it was at an earlier moment, as a reply to
+    /// `snapshotAt`.
+    pub fn snapshot_at(&self, moment: Moment) -> serde_json::Value {
+        let Some(history) = &self.history else {
+            return error_reply("history isn't enabled (see --history)".to_owned());
+        };
+
+        let Some(past) = history.at(moment) else {
+            return error_reply("the moment is before the retained history".to_owned());
+        };
+
+        let (cols, rows) = past.vt.size();
+        let cursor = past.vt.cursor();
+        let lines: Vec<String> = past.vt.view().iter().map(|l| l.text()).collect();
+        let text = self.redact_screen(lines.join("\n"));
+
+        reply(
+            "pastSnapshot",
+            json!({
+                "time": past.time,
+                "outputId": past.output_id,
+                "cols": cols,
+                "rows": rows,
+                "text": text,
+                "seq": self.redact_screen(past.vt.dump()),
+                "cursor": { "col": cursor.col, "row": cursor.row, "visible": cursor.visible },
+            }),
+        )
+    }
+
+    /// Returns the buffered output in the byte range, as a reply to
+    /// `getOutput`. Missing bounds default to the whole buffer.
+    pub fn get_output(&self, from: Option<u64>, to: Option<u64>) -> serde_json::Value {
+        let Some(ring) = &self.output_ring else {
+            return error_reply("output buffer isn't enabled (see --output-buffer)".to_owned());
+        };
+
+        let (from, to, seq) = ring.get(from.unwrap_or(0), to.unwrap_or(u64::MAX));
+
+        reply(
+            "outputRange",
+            json!({
+                "from": from,
+                "to": to,
+                "start": ring.start(),
+                "end": ring.end(),
+                "seq": seq,
+            }),
+        )
+    }
+
+    /// Returns the focus report (`CSI I` when gained, `CSI O` when lost) to
+    /// be written to the app, if it has enabled focus events.
+    pub fn focus_report(&self, gained: bool) -> Option<&'static [u8]> {
+        if self.tracker.dec_modes.get(&FOCUS_EVENTS_MODE) != Some(&true) {
+            return None;
+        }
+
+        Some(if gained { b"\x1b[I" } else { b"\x1b[O" })
+    }
+
+    fn mouse_tracking(&self) -> bool {
+        MOUSE_TRACKING_MODES
+            .iter()
+            .any(|mode| self.tracker.dec_modes.get(mode) == Some(&true))
+    }
+
+    /// Returns an error reply for a mouse command in strict mouse mode, when
+    /// the app doesn't track the mouse and wouldn't see the event.
+    pub fn reject_mouse(&self) -> Option<serde_json::Value> {
+        (self.strict_mouse && !self.mouse_tracking())
+            .then(|| error_reply("the application hasn't enabled mouse tracking".to_owned()))
+    }
+
+    fn reported_modes(&self) -> [bool; REPORTED_MODES.len()] {
+        REPORTED_MODES.map(|(mode, _)| self.tracker.dec_modes.get(&mode) == Some(&true))
+    }
+
+    /// Returns the emulator's dump, plus reverse video, which avt doesn't
+    /// know about. Redaction only masks the printable text of the dump, so
+    /// its escape sequences still rebuild the screen.
+    fn dump(&self) -> String {
+        let mut seq = self.vt.dump();
+
+        if self.tracker.reverse_video() {
+            seq.push_str("\x1b[?5h");
+        }
+
+        self.redact_screen(seq)
+    }
+
+    /// Replies with a snapshot once the screen hasn't changed for `quiet`, or
+    /// with an error when that doesn't happen before `timeout`.
+    pub fn wait_for_stable(&mut self, quiet: Duration, timeout: Duration, reply: Reply) {
+        self.stable_waits.push(StableWait {
+            reply,
+            quiet,
+            deadline: Instant::now() + timeout,
+        });
+    }
+
+    /// Returns when the next `waitForStable` is due, for settling or timing
+    /// out.
+    pub fn stable_deadline(&self) -> Option<Instant> {
+        self.stable_waits
+            .iter()
+            .map(|w| (self.screen_changed_at + w.quiet).min(w.deadline))
+            .min()
+    }
+
+    /// Replies to the waits which are due.
+    pub fn check_stable(&mut self) {
+        self.update_screen_hash();
+        let now = Instant::now();
+        let quiet_for = now - self.screen_changed_at;
+
+        for wait in std::mem::take(&mut self.stable_waits) {
+            if quiet_for >= wait.quiet {
+                let mut data =
+                    self.snapshot_event(None, false, Trim::default()).to_json()["data"].take();
+                data["hash"] = json!(self.screen_hash.map(|h| format!("{h:016x}")));
+                let _ = wait.reply.send(reply("stable", data));
+            } else if now >= wait.deadline {
+                let message = format!("screen didn't settle for {:?}", wait.quiet);
+                let _ = wait.reply.send(error_reply(message));
+            } else {
+                self.stable_waits.push(wait);
+            }
+        }
+    }
+
+    pub fn heartbeat(&mut self) {
+        self.heartbeat_count += 1;
+        // without a child process (e.g. on a serial device) there's nothing to
+        // check, and 0 would probe ht's own process group
+        let alive = (self.pid > 0).then(|| signal::kill(Pid::from_raw(self.pid), None).is_ok());
+
+        let _ = self.broadcast_tx.send(Event::Heartbeat(
+            self.elapsed_time(),
+            self.heartbeat_count,
+            alive,
+        ));
+    }
+
+    /// Waits until every subscriber has received the events sent so far, and
+    /// every client has handled the ones in its queue, or until `timeout`
+    /// passes, so the last events (e.g. `timeout`) get out before ht exits.
+    pub async fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        while !(self.broadcast_tx.is_empty() && self.backlog.is_empty())
+            && Instant::now() < deadline
+        {
+            tokio::time::sleep(DRAIN_POLL_PERIOD).await;
+        }
+    }
+
+    pub fn timeout(&mut self, idle: Duration) {
+        let time = self.start_time.elapsed().as_secs_f64();
+        let _ = self
+            .broadcast_tx
+            .send(Event::Timeout(time, idle.as_secs_f64()));
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+    }
+
+    /// Returns the text of a single screen line, as a reply to `getLine`.
+    pub fn get_line(&self, row: usize) -> serde_json::Value {
+        match self.vt.view().get(row) {
+            Some(line) => reply(
+                "line",
+                json!({ "row": row, "text": self.redact_screen(line.text()) }),
+            ),
+            None => error_reply(format!("row {row} is outside of the screen")),
+        }
+    }
+
+    /// Returns the layout of a screen line, as a reply to `getColumns`.
+    ///
+    /// Each grapheme (a character occupying one or two cells) has its screen
+    /// column and width, and its byte offset in the line's text as returned by
+    /// `getLine`, so clients can map between the two.
+    pub fn get_columns(&self, row: usize) -> serde_json::Value {
+        let Some(line) = self.vt.view().get(row) else {
+            return error_reply(format!("row {row} is outside of the screen"));
+        };
+
+        let mut offset = 0;
+        let mut text = String::new();
+        let mut graphemes = Vec::new();
+        // redacted characters are masked one by one, keeping the layout
+        let masked = self.redactor.ranges(&line.text());
+
+        for (col, cell) in line.cells().iter().enumerate() {
+            if cell.width() == 0 {
+                continue;
+            }
+
+            let c = if masked.iter().any(|r| r.contains(&offset)) {
+                '*'
+            } else {
+                cell.char()
+            };
+
+            graphemes.push(json!({
+                "text": c.to_string(),
+                "col": col,
+                "width": cell.width(),
+                "offset": text.len(),
+            }));
+
+            text.push(c);
+            offset += cell.char().len_utf8();
+        }
+
+        reply(
+            "columns",
+            json!({ "row": row, "text": text, "graphemes": graphemes }),
+        )
+    }
+
+    /// Returns the lines of the client's viewport, or the one at `offset`,
+    /// as a reply to `getView`.
+    pub fn get_view(&self, trim: Trim, offset: Option<usize>, client: &Reply) -> serde_json::Value {
+        let (cols, rows) = self.vt.size();
+        let offset = offset
+            .unwrap_or_else(|| self.viewport(client))
+            .min(self.scrollback_len());
+
+        let lines = self.vt.lines();
+        let end = lines.len() - offset;
+        let lines = lines[end - rows..end]
+            .iter()
+            .map(|l| self.redact_screen(l.text()))
+            .collect();
+
+        let lines = trim.apply(lines);
+
+        reply(
+            "view",
+            json!({
+                "cols": cols,
+                "rows": rows,
+                "offset": offset,
+                "follow": self.following(client),
+                "lines": lines,
+            }),
+        )
+    }
+
+    /// Moves the client's viewport over the scrollback, as a reply to
+    /// `scrollUp`, `scrollDown` and `scrollTo`.
+    ///
+    /// A viewport scrolled back stays on the same lines when new ones are
+    /// added, and one scrolled back down to the live screen follows it again.
+    pub fn scroll(&mut self, scroll: Scroll, client: &Reply) -> serde_json::Value {
+        let (_, rows) = self.vt.size();
+        let offset = self.viewport(client);
+
+        let offset = match scroll {
+            Scroll::Up(lines) => offset + lines.unwrap_or(rows),
+            Scroll::Down(lines) => offset.saturating_sub(lines.unwrap_or(rows)),
+            Scroll::To(offset) => offset,
+        }
+        .min(self.scrollback_len());
+
+        self.set_viewport(client, (offset > 0).then_some(offset))
+    }
+
+    /// Makes the client's viewport follow the live screen, or stay on the
+    /// current lines, as a reply to `follow`.
+    pub fn follow(&mut self, enabled: bool, client: &Reply) -> serde_json::Value {
+        let offset = (!enabled).then(|| self.viewport(client));
+
+        self.set_viewport(client, offset)
+    }
+
+    /// Anchors the client's viewport `offset` lines above the live screen, or
+    /// makes it follow the live screen when `None`.
+    fn set_viewport(&mut self, client: &Reply, offset: Option<usize>) -> serde_json::Value {
+        self.viewports
+            .retain(|(c, _)| !c.is_closed() && !c.same_channel(client));
+
+        if let Some(offset) = offset {
+            let top = self.scrollback_len() - offset;
+            self.viewports.push((client.clone(), top));
+        }
+
+        reply(
+            "viewport",
+            json!({
+                "offset": self.viewport(client),
+                "scrollback": self.scrollback_len(),
+                "follow": offset.is_none(),
+            }),
+        )
+    }
+
+    /// Returns the number of lines the client's viewport is above the live
+    /// screen.
+    fn viewport(&self, client: &Reply) -> usize {
+        self.viewports
+            .iter()
+            .find(|(c, _)| c.same_channel(client))
+            .map_or(0, |(_, top)| self.scrollback_len().saturating_sub(*top))
+    }
+
+    /// Keeps the viewports on the same lines when the emulator dropped old
+    /// scrollback lines, or on the oldest line left when theirs are gone.
+    fn shift_viewports(&mut self, dropped: usize) {
+        for (_, top) in &mut self.viewports {
+            *top = top.saturating_sub(dropped);
+        }
+    }
+
+    fn following(&self, client: &Reply) -> bool {
+        !self.viewports.iter().any(|(c, _)| c.same_channel(client))
+    }
+
+    fn scrollback_len(&self) -> usize {
+        self.vt.lines().len() - self.vt.view().len()
+    }
+
+    /// Returns the text of a screen region, as a reply to `getText`.
+    ///
+    /// The region is clipped to the screen. Lines are joined with `\n`.
+    pub fn get_text(&self, region: &Region) -> serde_json::Value {
+        let (cols, rows) = self.vt.size();
+
+        if region.x >= cols || region.y >= rows {
+            return error_reply(format!(
+                "position ({}, {}) is outside of the screen",
+                region.x, region.y
+            ));
+        }
+
+        let w = region.w.unwrap_or(cols).min(cols - region.x);
+        let h = region.h.unwrap_or(rows).min(rows - region.y);
+
+        let text = self.vt.view()[region.y..region.y + h]
+            .iter()
+            .map(|line| slice_line(line, region.x, w))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let text = self.redact_screen(text);
+
+        reply(
+            "text",
+            json!({ "x": region.x, "y": region.y, "w": w, "h": h, "text": text }),
+        )
+    }
+
+    /// Returns the word under a screen position, as a reply to `getWord`.
+    ///
+    /// Words are delimited by whitespace and any of the extra `separators`.
+    /// The returned extent (`start`..`end`, in columns) is empty when the
+    /// position points at a delimiter.
+    pub fn get_word(&self, row: usize, col: usize, separators: Option<&str>) -> serde_json::Value {
+        let (cols, _) = self.vt.size();
+
+        let Some(line) = self.vt.view().get(row).filter(|_| col < cols) else {
+            return error_reply(format!("position ({col}, {row}) is outside of the screen"));
+        };
+
+        let is_separator = |c: char| c.is_whitespace() || separators.is_some_and(|s| s.contains(c));
+
+        let word = word_cells(line, col, is_separator);
+
+        let (start, end) = match (word.first(), word.last()) {
+            (Some((start, _)), Some((last, cell))) => (*start, last + cell.width()),
+            _ => (col, col),
+        };
+
+        let text = self.redact_screen(word.iter().map(|(_, cell)| cell.char()).collect());
+
+        reply(
+            "word",
+            json!({ "row": row, "col": col, "start": start, "end": end, "text": text }),
+        )
+    }
+
+    /// Sets (or clears) the selection, replying with the selected text.
+    pub fn set_selection(&mut self, selection: Option<Selection>) -> serde_json::Value {
+        let (cols, rows) = self.vt.size();
+
+        if let Some(selection) = &selection {
+            for (col, row) in [selection.anchor, selection.extent] {
+                if col >= cols || row >= rows {
+                    return error_reply(format!(
+                        "position ({col}, {row}) is outside of the screen"
+                    ));
+                }
+            }
+        }
+
+        self.selection = selection;
+
+        self.get_selection()
+    }
+
+    /// Returns the selection and its text, as a reply to `getSelection`.
+    ///
+    /// Lines wrapped at the right edge of the screen are joined, and trailing
+    /// whitespace of the other lines is stripped, so that the text is what was
+    /// printed rather than how it was laid out. Wide characters are included
+    /// when any of their cells is selected.
+    pub fn get_selection(&self) -> serde_json::Value {
+        let Some(selection) = &self.selection else {
+            return reply("selection", json!(null));
+        };
+
+        let position = |(col, row): (usize, usize)| json!({ "col": col, "row": row });
+
+        reply(
+            "selection",
+            json!({
+                "anchor": position(selection.anchor),
+                "extent": position(selection.extent),
+                "mode": selection.mode.name(),
+                "text": self.redact_screen(self.selected_text(selection)),
+            }),
+        )
+    }
+
+    fn selected_text(&self, selection: &Selection) -> String {
+        let (cols, rows) = self.vt.size();
+        let wrapped = wrapped_lines(&self.vt);
+        let lines = self.vt.view().iter().zip(&wrapped[wrapped.len() - rows..]);
+        let (anchor, extent) = (selection.anchor, selection.extent);
+        let (top, bottom) = (anchor.1.min(extent.1), anchor.1.max(extent.1));
+
+        // reading order: by row, then by column
+        let (start, end) = if (anchor.1, anchor.0) <= (extent.1, extent.0) {
+            (anchor, extent)
+        } else {
+            (extent, anchor)
+        };
+
+        let mut text = String::new();
+
+        for (row, (line, wrapped)) in lines.enumerate().take(bottom + 1).skip(top) {
+            let (from, to) = match selection.mode {
+                SelectionMode::Normal if row == start.1 && row == end.1 => (start.0, end.0 + 1),
+                SelectionMode::Normal if row == start.1 => (start.0, cols),
+                SelectionMode::Normal if row == end.1 => (0, end.0 + 1),
+                SelectionMode::Normal | SelectionMode::Line => (0, cols),
+                SelectionMode::Block => (anchor.0.min(extent.0), anchor.0.max(extent.0) + 1),
+            };
+
+            let part = selected_cells(line, from, to);
+            let joined = *wrapped && row < bottom && selection.mode != SelectionMode::Block;
+
+            if joined {
+                text.push_str(&part);
+            } else {
+                text.push_str(part.trim_end());
+
+                if row < bottom {
+                    text.push('\n');
+                }
+            }
+        }
+
+        text
+    }
+
+    /// Returns the child and its descendant processes, as a reply to
+    /// `getProcesses`.
+    pub fn get_processes(&self) -> serde_json::Value {
+        match procfs::tree(self.pid) {
+            Ok(processes) => reply(
+                "processes",
+                json!({ "processes": processes.iter().map(|p| p.to_json()).collect::<Vec<_>>() }),
+            ),
+
+            Err(e) => error_reply(format!("cannot read the process list: {e}")),
+        }
+    }
+
+    /// Returns everything known about the terminal, as a reply to `dumpState`.
+    ///
+    /// Only the active screen buffer is available as text, the inactive one is
+    /// included in `seq` only.
+    pub fn dump_state(&self) -> serde_json::Value {
+        let (cols, rows) = self.vt.size();
+        let tracker = &self.tracker;
+
+        let saved_cursor = tracker.saved_cursor[tracker.alternate_screen as usize]
+            .map(|(col, row)| json!({ "col": col, "row": row }));
+
+        reply(
+            "state",
+            json!({
+                "cols": cols,
+                "rows": rows,
+                "screen": screen_name(tracker.alternate_screen),
+                "lines": self.view_lines(),
+                "scrollback": self.scrollback_len(),
+                "cursor": self.cursor().to_json(),
+                "savedCursor": saved_cursor,
+                "tabStops": tracker.tab_stops,
+                "charsets": {
+                    "g0": tracker.charsets[0].name(),
+                    "g1": tracker.charsets[1].name(),
+                    "active": tracker.active_charset().name(),
+                },
+                "decModes": modes_json(&self.dec_modes()),
+                "ansiModes": modes_json(&tracker.ansi_modes),
+                "title": tracker.title,
+                "cwd": tracker.cwd,
+                "seq": self.dump(),
+            }),
+        )
+    }
+
+    pub fn cursor_key_app_mode(&self) -> bool {
+        self.vt.cursor_key_app_mode()
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        self.vt.size()
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        let (cols, rows) = self.vt.size();
+
+        let init = Event::Init(
+            self.elapsed_time(),
+            cols,
+            rows,
+            self.pid,
+            self.dump(),
+            self.text_view(),
+        );
+
+        let broadcast_rx = self.broadcast_tx.subscribe();
+
+        Subscription {
+            init,
+            warnings: self.warnings.clone(),
+            broadcast_rx,
+            backlog: self.backlog.clone(),
+        }
+    }
+
+    fn cursor(&self) -> Cursor {
+        let cursor = self.vt.cursor();
+
+        Cursor {
+            col: cursor.col,
+            row: cursor.row,
+            visible: cursor.visible,
+            style: self.tracker.cursor_style,
+        }
+    }
+
+    fn elapsed_time(&self) -> f64 {
+        self.stream_time + self.last_event_time.elapsed().as_secs_f64()
+    }
+
+    fn text_view(&self) -> String {
+        self.view_lines().join("\n")
+    }
+
+    fn view_lines(&self) -> Vec<String> {
+        self.vt
+            .view()
+            .iter()
+            .map(|l| self.redact_screen(l.text()))
+            .collect()
+    }
+}
+
+impl Event {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Event::Init(_time, cols, rows, pid, seq, text) => json!({
+                "type": "init",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "pid": pid,
+                    "seq": seq,
+                    "text": text,
+                })
+            }),
+
+            Event::Output(_time, id, seq, raw) => {
+                let mut data = stream_data(seq, raw);
+                data["id"] = json!(id);
+
+                json!({
+                    "type": "output",
+                    "data": data
+                })
+            }
+
+            Event::Resize(_time, cols, rows) => json!({
+                "type": "resize",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                })
+            }),
+
+            Event::Snapshot {
+                cols,
+                rows,
+                seq,
+                text,
+                cursor,
+                cwd,
+                rendered,
+                output_id,
+                state,
+            } => {
+                let mut data = json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "seq": seq,
+                    "text": text,
+                    "cursor": cursor.to_json(),
+                    "cwd": cwd,
+                    "outputId": output_id,
+                });
+
+                if let Some((format, content)) = rendered {
+                    data[format.name()] = json!(content);
+                }
+
+                if let Some(state) = state {
+                    data["title"] = json!(state.title);
+                    data["screen"] = json!(state.screen_name());
+                    data["decModes"] = modes_json(&state.dec_modes);
+                    data["ansiModes"] = modes_json(&state.ansi_modes);
+                    data["tabStops"] = json!(state.tab_stops);
+                }
+
+                json!({
+                    "type": "snapshot",
+                    "data": data
+                })
+            }
+
+            Event::Heartbeat(time, count, alive) => {
+                let mut data = json!({
+                    "time": time,
+                    "count": count,
+                });
+
+                if let Some(alive) = alive {
+                    data["alive"] = json!(alive);
+                }
+
+                json!({
+                    "type": "heartbeat",
+                    "data": data
+                })
+            }
+
+            Event::Timeout(time, idle) => json!({
+                "type": "timeout",
+                "data": json!({
+                    "time": time,
+                    "idle": idle,
+                })
+            }),
+
+            Event::Truncated(time, limit, total) => json!({
+                "type": "truncated",
+                "data": json!({
+                    "time": time,
+                    "limit": limit,
+                    "total": total,
+                })
+            }),
+
+            Event::Stderr(_time, seq, raw) => json!({
+                "type": "stderr",
+                "data": stream_data(seq, raw)
+            }),
+
+            Event::ProcessState(time, suspended) => json!({
+                "type": "processState",
+                "data": json!({
+                    "time": time,
+                    "state": if *suspended { "suspended" } else { "running" },
+                })
+            }),
+
+            Event::ForegroundChanged(time, pid, name) => json!({
+                "type": "foregroundChanged",
+                "data": json!({
+                    "time": time,
+                    "pid": pid,
+                    "name": name,
+                })
+            }),
+
+            Event::CwdChanged(time, cwd) => json!({
+                "type": "cwdChanged",
+                "data": json!({
+                    "time": time,
+                    "cwd": cwd,
+                })
+            }),
+
+            Event::CursorStyle(time, style) => json!({
+                "type": "cursorStyle",
+                "data": json!({
+                    "time": time,
+                    "shape": style.shape.name(),
+                    "blink": style.blink,
+                })
+            }),
+
+            Event::ModeChanged(time, mode, enabled) => json!({
+                "type": "modeChanged",
+                "data": json!({
+                    "time": time,
+                    "mode": mode,
+                    "name": mode_name(*mode),
+                    "enabled": enabled,
+                })
+            }),
+
+            Event::ScreenHash(time, hash) => json!({
+                "type": "screenHash",
+                "data": json!({
+                    "time": time,
+                    "hash": hash,
+                })
+            }),
+
+            Event::PasteProgress(time, written, total) => json!({
+                "type": "pasteProgress",
+                "data": json!({
+                    "time": time,
+                    "written": written,
+                    "total": total,
+                })
+            }),
+
+            Event::PromptStart(time) => json!({
+                "type": "promptStart",
+                "data": json!({ "time": time })
+            }),
+
+            Event::CommandStart(time) => json!({
+                "type": "commandStart",
+                "data": json!({ "time": time })
+            }),
+
+            Event::CommandEnd(time, status) => json!({
+                "type": "commandEnd",
+                "data": json!({
+                    "time": time,
+                    "exitCode": status,
+                })
+            }),
+
+            Event::CommandExecuted(time, command, duration, status) => json!({
+                "type": "commandExecuted",
+                "data": json!({
+                    "time": time,
+                    "command": command,
+                    "duration": duration,
+                    "exitCode": status,
+                })
+            }),
+
+            Event::Warning(time, message) => json!({
+                "type": "warning",
+                "data": json!({
+                    "time": time,
+                    "message": message,
+                })
+            }),
+
+            Event::InvalidUtf8(time, stream, offset, bytes) => json!({
+                "type": "invalidUtf8",
+                "data": json!({
+                    "time": time,
+                    "stream": stream,
+                    "offset": offset,
+                    "bytes": BASE64_STANDARD.encode(bytes),
+                })
+            }),
+
+            Event::Annotation(time, payload) => json!({
+                "type": "annotation",
+                "data": json!({
+                    "time": time,
+                    "payload": payload,
+                })
+            }),
+
+            Event::RecordingPaused(time, paused) => json!({
+                "type": "recordingPaused",
+                "data": json!({
+                    "time": time,
+                    "paused": paused,
+                })
+            }),
+        }
+    }
+}
+
+impl Cursor {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "col": self.col,
+            "row": self.row,
+            "visible": self.visible,
+            "blink": self.style.blink,
+            "shape": self.style.shape.name(),
+        })
+    }
+}
+
+fn stream_data(seq: &str, raw: &Option<Bytes>) -> serde_json::Value {
+    let mut data = json!({ "seq": seq });
+
+    if let Some(raw) = raw {
+        data["raw"] = json!(BASE64_STANDARD.encode(raw));
+    }
+
+    data
+}
+
+impl TerminalState {
+    fn screen_name(&self) -> &'static str {
+        screen_name(self.alternate_screen)
+    }
+}
+
+fn mode_name(mode: u16) -> Option<&'static str> {
+    REPORTED_MODES
+        .iter()
+        .find(|(m, _)| *m == mode)
+        .map(|(_, name)| *name)
+}
+
+fn macro_reply(name: &str, steps: &[(Duration, Vec<u8>)]) -> serde_json::Value {
+    let duration = steps.last().map(|(delay, _)| delay.as_secs_f64());
+
+    reply(
+        "macro",
+        json!({
+            "name": name,
+            "steps": steps.len(),
+            "duration": duration.unwrap_or_default(),
+        }),
+    )
+}
+
+fn screen_name(alternate_screen: bool) -> &'static str {
+    if alternate_screen {
+        "alternate"
+    } else {
+        "primary"
+    }
+}
+
+fn modes_json(modes: &BTreeMap<u16, bool>) -> serde_json::Value {
+    modes
+        .iter()
+        .map(|(mode, on)| (mode.to_string(), json!(on)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Reply to the `flush` command, once `written` bytes of input were written.
+pub fn flush_reply(written: u64) -> serde_json::Value {
+    reply("flush", json!({ "written": written }))
+}
+
+fn reply(kind: &str, data: serde_json::Value) -> serde_json::Value {
+    json!({
+        "type": kind,
+        "data": data
+    })
+}
+
+fn error_reply(message: String) -> serde_json::Value {
+    reply("error", json!({ "message": message }))
+}
+
+/// Returns the text of columns `x..x + w` of a line.
+///
+/// The continuation cells of wide characters are skipped.
+fn slice_line(line: &avt::Line, x: usize, w: usize) -> String {
+    line.cells()
+        .iter()
+        .skip(x)
+        .take(w)
+        .filter(|c| c.width() > 0)
+        .map(|c| c.char())
+        .collect()
+}
+
+/// Returns the characters of the line overlapping columns `from..to`, with wide
+/// characters included in full.
+fn selected_cells(line: &avt::Line, from: usize, to: usize) -> String {
+    let mut text = String::new();
+    let mut col = 0;
+
+    for cell in line.cells().iter().filter(|c| c.width() > 0) {
+        if col < to && col + cell.width() > from {
+            text.push(cell.char());
+        }
+
+        col += cell.width();
+    }
+
+    text
+}
+
+/// Tells which lines (of the whole buffer, scrollback included) continue on
+/// the next one, having been wrapped at the right edge. avt doesn't expose
+/// this per line, but `Vt::text` joins the wrapped lines, so it's recovered
+/// by matching the lines against that text. A line wrapped right before
+/// a run of blanks reads as not wrapped.
+fn wrapped_lines(vt: &avt::Vt) -> Vec<bool> {
+    let mut texts = vt.text().into_iter().map(|t| t.chars().count());
+    let mut remaining = texts.next().unwrap_or_default();
+
+    vt.lines()
+        .iter()
+        .map(|line| {
+            let text = line.text();
+            let wrapped = remaining > text.trim_end().chars().count();
+
+            if wrapped {
+                remaining = remaining.saturating_sub(text.chars().count());
+            } else {
+                remaining = texts.next().unwrap_or_default();
+            }
+
+            wrapped
+        })
+        .collect()
+}
+
+/// Returns the cells (with their columns) of the word covering column `col`,
+/// or nothing when the column is a separator.
+fn word_cells(
+    line: &avt::Line,
+    col: usize,
+    is_separator: impl Fn(char) -> bool,
+) -> Vec<(usize, &avt::Cell)> {
+    let mut word = Vec::new();
+    let mut covered = false;
+    let mut pos = 0;
+
+    for cell in line.cells().iter().filter(|c| c.width() > 0) {
+        if !is_separator(cell.char()) {
+            word.push((pos, cell));
+            covered = covered || (pos..pos + cell.width()).contains(&col);
+        } else if covered {
+            break;
+        } else {
+            word.clear();
+        }
+
+        pos += cell.width();
+    }
+
+    if !covered {
+        word.clear();
+    }
+
+    word
+}
+
+fn char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+
+    index
+}
+
+/// Describes what this build of ht supports, as a reply to `capabilities`.
+pub fn capabilities() -> serde_json::Value {
+    reply(
+        "capabilities",
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "commands": api::stdio::COMMANDS.as_slice(),
+            "events": api::filter::EVENTS,
+            "protocols": ["json", "jsonrpc"],
+            "apis": ["stdio", "http", "grpc", "hub", "publish"],
+            "backends": ["pty", "pipes", "ssh", "serial", "tmux"],
+            "snapshotFormats": [
+                render::Format::Ansi.name(),
+                render::Format::Markdown { cursor: false }.name(),
+            ],
+            "build": {
+                "os": std::env::consts::OS,
+                "arch": std::env::consts::ARCH,
+                "debug": cfg!(debug_assertions),
+            },
+        }),
+    )
+}
+
+/// Reply to the `schema` command.
+pub fn schema() -> serde_json::Value {
+    reply("schema", api::schema::schema())
+}
+
+/// 64-bit FNV-1a hash, which (unlike std's hashers) is guaranteed to stay the
+/// same across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Returns a random session ID, 16 hex digits.
+pub fn generate_id() -> String {
+    let mut bytes = [0u8; 8];
+
+    if let Err(e) = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        eprintln!("cannot read /dev/urandom: {e}");
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        bytes = (nanos ^ std::process::id() as u64).to_be_bytes();
+    }
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Scales a size in pixels from one number of cells to another.
+fn scale_pixels(pixels: u16, old_cells: usize, cells: usize) -> u16 {
+    (pixels as usize / old_cells.max(1) * cells).min(u16::MAX as usize) as u16
+}
+
+fn build_vt(cols: usize, rows: usize) -> avt::Vt {
+    avt::Vt::builder().size(cols, rows).build()
+}
+
+fn resize_vt(vt: &mut avt::Vt, cols: usize, rows: usize) {
+    vt.resize(cols, rows);
+}
+
+/// Sends a signal as queued (SI_QUEUE), which the supervisor of a command in
+/// a new PID namespace passes on to the command, telling it apart from the
+/// signals sent to the whole process group, which reach the command anyway.
+#[cfg(target_os = "linux")]
+fn queue_signal(pid: Pid, sig: Signal) -> nix::Result<()> {
+    use nix::libc;
+
+    let value = libc::sigval {
+        sival_ptr: std::ptr::null_mut(),
+    };
+
+    let result = unsafe { libc::sigqueue(pid.as_raw(), sig as libc::c_int, value) };
+    nix::errno::Errno::result(result).map(drop)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn queue_signal(pid: Pid, sig: Signal) -> nix::Result<()> {
+    signal::kill(pid, sig)
+}
+
+impl Client {
+    pub fn accept(self, subscription: Subscription) {
+        let _ = self.0.send(subscription);
+    }
+}
+
+pub async fn stream(
+    clients_tx: &mpsc::Sender<Client>,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    Ok(queued_stream(clients_tx).await?.0)
+}
+
+/// Same as `stream`, also returning the backlog that the client's queue
+/// counts its items in, so the session waits for them when draining.
+pub async fn queued_stream(
+    clients_tx: &mpsc::Sender<Client>,
+) -> Result<(
+    impl Stream<Item = Result<Event, BroadcastStreamRecvError>>,
+    Backlog,
+)> {
+    let (sub_tx, sub_rx) = oneshot::channel();
+    clients_tx.send(Client(sub_tx)).await?;
+    let sub = tokio::time::timeout(Duration::from_secs(5), sub_rx).await??;
+    let init = stream::once(future::ready(Ok(sub.init)));
+    let warnings = stream::iter(sub.warnings.into_iter().map(Ok));
+    let events = BroadcastStream::new(sub.broadcast_rx);
+
+    Ok((init.chain(warnings).chain(events), sub.backlog))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, Moment, Redactor, Session};
+    use crate::command::{Region, Reply, Scroll, Selection, SelectionMode, Trim};
+    use crate::utf8;
+    use bytes::Bytes;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn client() -> Reply {
+        mpsc::unbounded_channel().0
+    }
+
+    #[test]
+    fn snapshot_preserves_truecolor() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b[38;2;1;2;3;48;2;250;128;0mrgb\x1b[0m");
+        session.snapshot(None, false, Trim::default());
+
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Snapshot { seq, text, .. } => {
+                assert!(seq.contains("38:2:1:2:3"), "{seq:?}");
+                assert!(seq.contains("48:2:250:128:0"), "{seq:?}");
+                assert!(text.starts_with("rgb"));
+            }
+
+            _ => panic!("expected snapshot event"),
+        }
+    }
+
+    #[test]
+    fn output_ids() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("a");
+        session.output("b");
+        session.reset();
+        session.snapshot(None, false, Trim::default());
+
+        let ids: Vec<_> = (0..3)
+            .map(|_| sub.broadcast_rx.try_recv().unwrap().to_json()["data"]["id"].clone())
+            .collect();
+
+        assert_eq!(ids, [1, 2, 3]);
+
+        let snapshot = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(snapshot["data"]["outputId"], 3);
+    }
+
+    #[test]
+    fn truncate_output() {
+        let mut session = Session::new(20, 2, 0);
+        session.set_output_limit(Some(5));
+        let mut sub = session.subscribe();
+        session.output("abc");
+        session.output("dżef");
+        session.output("gh");
+
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "abc");
+
+        // cut before the character crossing the limit
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "d");
+
+        let truncated = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(truncated["type"], "truncated");
+        assert_eq!(truncated["data"]["limit"], 5);
+        assert_eq!(truncated["data"]["total"], 8);
+
+        assert!(sub.broadcast_rx.try_recv().is_err());
+        assert!(session.is_truncated());
+    }
+
+    #[test]
+    fn truncate_redacted_output() {
+        let mut session = Session::new(20, 2, 0);
+        let rules = vec![regex::Regex::new("s3cr3t").unwrap()];
+        session.set_redactor(Redactor::new(rules, "*".to_owned()));
+        session.set_output_limit(Some(12));
+        let mut sub = session.subscribe();
+        session.output("pw s3cr3t");
+        session.output("abcd");
+
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "pw *");
+
+        // the output produced counts, not what's left of it
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "abc");
+
+        let truncated = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(truncated["data"]["total"], 13);
+    }
+
+    #[test]
+    fn truncate_raw_output() {
+        let mut session = Session::new(20, 2, 0);
+        session.set_output_limit(Some(6));
+        session.set_utf8_policy(utf8::Policy::Raw);
+        let mut sub = session.subscribe();
+        session.output(&b"\xff\xfeabc"[..]);
+
+        // each invalid byte takes 3 bytes of text, as U+FFFD
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "\u{fffd}\u{fffd}");
+        assert_eq!(output["data"]["raw"], "//4=");
+    }
+
+    #[test]
+    fn raw_output_not_copied() {
+        let mut session = Session::new(20, 2, 0);
+        session.set_utf8_policy(utf8::Policy::Raw);
+        let mut sub = session.subscribe();
+        let bytes = Bytes::from(b"a\xffb".to_vec());
+        session.output(bytes.clone());
+
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Output(_, _, _, raw) => assert_eq!(raw.unwrap().as_ptr(), bytes.as_ptr()),
+            _ => panic!("expected output event"),
+        }
+    }
+
+    #[test]
+    fn heartbeat_without_child() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.heartbeat();
+
+        let heartbeat = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(heartbeat["data"]["count"], 1);
+        assert!(heartbeat["data"].get("alive").is_none(), "{heartbeat}");
+
+        let mut session = Session::new(20, 2, std::process::id() as i32);
+        let mut sub = session.subscribe();
+        session.heartbeat();
+
+        let heartbeat = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(heartbeat["data"]["alive"], true);
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_subscribers() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.timeout(Duration::from_secs(3));
+
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sub.broadcast_rx.recv().await.unwrap().to_json()
+        });
+
+        session.drain(Duration::from_secs(5)).await;
+        assert!(session.broadcast_tx.is_empty());
+
+        let timeout = reader.await.unwrap();
+        assert_eq!(timeout["type"], "timeout");
+        assert_eq!(timeout["data"]["idle"], 3.0);
+
+        // a subscriber which never reads doesn't hold ht up past the timeout
+        let _sub = session.subscribe();
+        session.timeout(Duration::from_secs(3));
+        session.drain(Duration::from_millis(50)).await;
+        assert!(!session.broadcast_tx.is_empty());
+    }
+
+    #[test]
+    fn get_text_region() {
+        let mut session = Session::new(10, 3, 0);
+        session.output("hello\r\nworld\r\n!");
+
+        assert_eq!(session.get_line(1)["data"]["text"], "world     ");
+        assert_eq!(session.get_line(3)["type"], "error");
+
+        let region = Region {
+            x: 1,
+            y: 0,
+            w: Some(3),
+            h: Some(2),
+        };
+
+        assert_eq!(session.get_text(&region)["data"]["text"], "ell\norl");
+
+        let region = Region {
+            x: 8,
+            y: 2,
+            ..Region::default()
+        };
+
+        assert_eq!(session.get_text(&region)["data"]["w"], 2);
+        assert_eq!(session.get_text(&region)["data"]["h"], 1);
+    }
+
+    #[test]
+    fn get_view() {
+        let mut session = Session::new(5, 2, 0);
+        session.output("ab\r\ncd");
+        let view = session.get_view(Trim::default(), None, &client());
+
+        assert_eq!(view["type"], "view");
+        assert_eq!(view["data"]["lines"], serde_json::json!(["ab   ", "cd   "]));
+        assert_eq!(view["data"]["rows"], 2);
+    }
+
+    #[test]
+    fn scroll_viewport() {
+        let mut session = Session::new(5, 2, 0);
+        session.output("1\r\n2\r\n3\r\n4\r\n5\r\n6");
+        let (client, other) = (client(), client());
+        let trim = Trim {
+            trailing: true,
+            collapse_blank: false,
+        };
+
+        let lines = |session: &Session, client| {
+            session.get_view(trim, None, client)["data"]["lines"].clone()
+        };
+
+        let viewport = session.scroll(Scroll::Up(None), &client);
+        assert_eq!(viewport["type"], "viewport");
+        assert_eq!(viewport["data"]["offset"], 2);
+        assert_eq!(viewport["data"]["scrollback"], 4);
+        assert_eq!(lines(&session, &client), serde_json::json!(["3", "4"]));
+        assert_eq!(lines(&session, &other), serde_json::json!(["5", "6"]));
+
+        let viewport = session.scroll(Scroll::Up(Some(10)), &client);
+        assert_eq!(viewport["data"]["offset"], 4);
+        assert_eq!(lines(&session, &client), serde_json::json!(["1", "2"]));
+
+        session.scroll(Scroll::Down(Some(1)), &client);
+        assert_eq!(lines(&session, &client), serde_json::json!(["2", "3"]));
+
+        let view = session.get_view(trim, Some(0), &client);
+        assert_eq!(view["data"]["lines"], serde_json::json!(["5", "6"]));
+
+        session.scroll(Scroll::To(0), &client);
+        assert_eq!(lines(&session, &client), serde_json::json!(["5", "6"]));
+    }
+
+    #[test]
+    fn follow_live_screen() {
+        let mut session = Session::new(5, 2, 0);
+        session.output("1\r\n2\r\n3\r\n4");
+        let client = client();
+        let trim = Trim {
+            trailing: true,
+            collapse_blank: false,
+        };
+
+        let lines =
+            |session: &Session| session.get_view(trim, None, &client)["data"]["lines"].clone();
+
+        let viewport = session.scroll(Scroll::Up(Some(1)), &client);
+        assert_eq!(viewport["data"]["follow"], false);
+        assert_eq!(lines(&session), serde_json::json!(["2", "3"]));
+
+        // stays on the same lines
+        session.output("\r\n5\r\n6");
+        assert_eq!(lines(&session), serde_json::json!(["2", "3"]));
+        assert_eq!(session.get_view(trim, None, &client)["data"]["offset"], 3);
+
+        let viewport = session.follow(true, &client);
+        assert_eq!(viewport["data"]["follow"], true);
+        assert_eq!(viewport["data"]["offset"], 0);
+        session.output("\r\n7");
+        assert_eq!(lines(&session), serde_json::json!(["6", "7"]));
+
+        // pinned at the live screen
+        session.follow(false, &client);
+        session.output("\r\n8");
+        assert_eq!(lines(&session), serde_json::json!(["6", "7"]));
+
+        let viewport = session.scroll(Scroll::Down(None), &client);
+        assert_eq!(viewport["data"]["follow"], true);
+        assert_eq!(lines(&session), serde_json::json!(["7", "8"]));
+    }
+
+    #[test]
+    fn viewport_over_dropped_scrollback() {
+        let mut session = Session::new(5, 2, 0);
+        session.vt = avt::Vt::builder().size(5, 2).scrollback_limit(4).build();
+        session.output("1\r\n2\r\n3\r\n4\r\n5\r\n6");
+        let client = client();
+        let trim = Trim {
+            trailing: true,
+            collapse_blank: false,
+        };
+
+        let lines =
+            |session: &Session| session.get_view(trim, None, &client)["data"]["lines"].clone();
+
+        session.scroll(Scroll::Up(Some(2)), &client);
+        assert_eq!(lines(&session), serde_json::json!(["3", "4"]));
+
+        // lines 1 and 2 are dropped
+        session.output("\r\n7\r\n8");
+        assert_eq!(lines(&session), serde_json::json!(["3", "4"]));
+
+        // the viewport's lines are dropped too
+        session.output("\r\n9\r\n10\r\n11");
+        assert_eq!(lines(&session), serde_json::json!(["6", "7"]));
+        assert_eq!(session.get_view(trim, None, &client)["data"]["offset"], 4);
+    }
+
+    #[test]
+    fn trim_view() {
+        let mut session = Session::new(5, 7, 0);
+        session.output("ab  \r\n\r\n\r\n\r\ncd\r\n");
+
+        let trim = |trailing, collapse_blank| Trim {
+            trailing,
+            collapse_blank,
+        };
+
+        let view = session.get_view(trim(true, false), None, &client());
+        assert_eq!(
+            view["data"]["lines"],
+            serde_json::json!(["ab", "", "", "", "cd"])
+        );
+
+        let view = session.get_view(trim(true, true), None, &client());
+        assert_eq!(view["data"]["lines"], serde_json::json!(["ab", "", "cd"]));
+
+        let view = session.get_view(trim(false, true), None, &client());
+        assert_eq!(
+            view["data"]["lines"],
+            serde_json::json!(["ab   ", "     ", "cd   ", "     "])
+        );
+
+        let mut sub = session.subscribe();
+        session.snapshot(None, false, trim(true, true));
+
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Snapshot { rows, text, .. } => {
+                assert_eq!(rows, 7);
+                assert_eq!(text, "ab\n\ncd");
+            }
+
+            _ => panic!("expected snapshot event"),
+        }
+    }
+
+    #[test]
+    fn get_columns() {
+        let mut session = Session::new(8, 2, 0);
+        session.output("a日本é".as_bytes());
+
+        let columns = session.get_columns(0);
+        assert_eq!(columns["type"], "columns");
+        assert_eq!(columns["data"]["text"], "a日本é  ");
+
+        let graphemes = columns["data"]["graphemes"].as_array().unwrap();
+        assert_eq!(graphemes.len(), 6);
+        assert_eq!(graphemes[1]["text"], "日");
+
+        let layout: Vec<_> = graphemes
+            .iter()
+            .map(|g| [&g["col"], &g["width"], &g["offset"]].map(|v| v.as_u64().unwrap()))
+            .collect();
+
+        assert_eq!(
+            layout,
+            [
+                [0, 1, 0],
+                [1, 2, 1],
+                [3, 2, 4],
+                [5, 1, 7],
+                [6, 1, 9],
+                [7, 1, 10]
+            ]
+        );
+
+        assert_eq!(session.get_columns(2)["type"], "error");
+    }
+
+    #[test]
+    fn get_word_under_position() {
+        let mut session = Session::new(30, 2, 0);
+        session.output("ls src/main.rs  ok");
+
+        let word = session.get_word(0, 5, None);
+        assert_eq!(word["data"]["text"], "src/main.rs");
+        assert_eq!(word["data"]["start"], 3);
+        assert_eq!(word["data"]["end"], 14);
+
+        let word = session.get_word(0, 5, Some("/."));
+        assert_eq!(word["data"]["text"], "src");
+
+        let word = session.get_word(0, 14, None);
+        assert_eq!(word["data"]["text"], "");
+        assert_eq!(word["data"]["start"], word["data"]["end"]);
+
+        assert_eq!(session.get_word(2, 0, None)["type"], "error");
+    }
+
+    #[test]
+    fn past_snapshots() {
+        let mut session = Session::new(10, 2, 0);
+        assert_eq!(session.snapshot_at(Moment::OutputId(0))["type"], "error");
+
+        session.set_history(Some(Duration::from_secs(60)));
+        session.output("one");
+        session.output("\r\ntwo");
+
+        let snapshot = session.snapshot_at(Moment::OutputId(1));
+        assert_eq!(snapshot["type"], "pastSnapshot");
+        assert_eq!(snapshot["data"]["outputId"], 1);
+        assert_eq!(snapshot["data"]["text"], "one       \n          ");
+
+        let snapshot = session.snapshot_at(Moment::Time(f64::MAX));
+        assert_eq!(snapshot["data"]["outputId"], 2);
+        assert_eq!(snapshot["data"]["text"], "one       \ntwo       ");
+        assert_eq!(snapshot["data"]["cursor"]["col"], 3);
+    }
+
+    #[test]
+    fn output_ranges() {
+        let mut session = Session::new(10, 2, 0);
+        assert_eq!(session.get_output(None, None)["type"], "error");
+
+        session.set_output_buffer(Some(4));
+        session.output("abc");
+        session.output("def");
+
+        let range = session.get_output(Some(1), None);
+        assert_eq!(range["type"], "outputRange");
+        assert_eq!(
+            range["data"],
+            json!({ "from": 2, "to": 6, "start": 2, "end": 6, "seq": "cdef" })
+        );
+        assert_eq!(session.get_output(Some(3), Some(5))["data"]["seq"], "de");
+    }
+
+    #[test]
+    fn input_macros() {
+        let mut session = Session::new(80, 24, 0);
+        session.record_macro_input(b"ignored");
+        assert_eq!(session.stop_macro()["type"], "error");
+
+        assert_eq!(session.record_macro("login".to_owned()), None);
+        assert_eq!(
+            session.record_macro("other".to_owned()).unwrap()["type"],
+            "error"
+        );
+        session.record_macro_input(b"root\r");
+        std::thread::sleep(Duration::from_millis(50));
+        session.record_macro_input(b"secret\r");
+
+        let summary = session.stop_macro();
+        assert_eq!(summary["type"], "macro");
+        assert_eq!(summary["data"]["name"], "login");
+        assert_eq!(summary["data"]["steps"], 2);
+        assert!(summary["data"]["duration"].as_f64().unwrap() >= 0.05);
+
+        assert_eq!(session.play_macro("login", false), None);
+        assert_eq!(session.take_macro_input(), b"root\rsecret\r");
+        assert_eq!(session.macro_deadline(), None);
+
+        assert_eq!(session.play_macro("login", true), None);
+        assert_eq!(session.take_macro_input(), b"root\r");
+        assert!(session.macro_deadline().is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(session.take_macro_input(), b"secret\r");
+        assert_eq!(session.macro_deadline(), None);
+
+        assert_eq!(session.play_macro("nope", false).unwrap()["type"], "error");
+    }
+
+    #[test]
+    fn focus_reports() {
+        let mut session = Session::new(80, 24, 0);
+        assert_eq!(session.focus_report(true), None);
+
+        session.output("\x1b[?1004h");
+        assert_eq!(session.focus_report(true), Some(&b"\x1b[I"[..]));
+        assert_eq!(session.focus_report(false), Some(&b"\x1b[O"[..]));
+
+        session.output("\x1b[?1004l");
+        assert_eq!(session.focus_report(false), None);
+    }
+
+    #[test]
+    fn clipboard_read_requests() {
+        let mut session = Session::new(80, 24, 0);
+        session.output("\x1b]52;c;?\x07");
+        assert!(session.take_clipboard_answers().is_empty());
+
+        session.set_allow_clipboard_read(true);
+        session.output("\x1b]52;c;?\x07");
+        assert_eq!(session.take_clipboard_answers(), b"\x1b]52;c;\x1b\\");
+
+        session.set_clipboard("hello".to_owned());
+        session.output("\x1b]52;p;?\x1b\\\x1b]52;c;aGk=\x07");
+        assert_eq!(
+            session.take_clipboard_answers(),
+            b"\x1b]52;p;aGVsbG8=\x1b\\"
+        );
+        assert!(session.take_clipboard_answers().is_empty());
+    }
+
+    #[test]
+    fn selection_text() {
+        let mut session = Session::new(10, 4, 0);
+        session.output("0123456789abc\r\nx 漢字 y\r\nlast".as_bytes());
+
+        let select = |session: &mut Session, anchor, extent, mode| {
+            let selection = Selection {
+                anchor,
+                extent,
+                mode,
+            };
+            session.set_selection(Some(selection))["data"]["text"].clone()
+        };
+
+        // the wrapped line is joined
+        assert_eq!(
+            select(&mut session, (5, 0), (1, 1), SelectionMode::Normal),
+            "56789ab"
+        );
+        assert_eq!(
+            select(&mut session, (0, 2), (8, 0), SelectionMode::Normal),
+            "89abc\nx"
+        );
+        assert_eq!(
+            select(&mut session, (3, 0), (3, 2), SelectionMode::Line),
+            "0123456789abc\nx 漢字 y"
+        );
+
+        // wide characters are included in full
+        assert_eq!(
+            select(&mut session, (3, 2), (4, 1), SelectionMode::Block),
+            "\n漢字"
+        );
+        assert_eq!(
+            select(&mut session, (3, 2), (3, 2), SelectionMode::Normal),
+            "漢"
+        );
+
+        let selection = session.get_selection();
+        assert_eq!(selection["data"]["anchor"], json!({ "col": 3, "row": 2 }));
+        assert_eq!(selection["data"]["mode"], "normal");
+
+        let selection = Selection {
+            anchor: (0, 4),
+            extent: (0, 0),
+            mode: SelectionMode::Normal,
+        };
+        assert_eq!(session.set_selection(Some(selection))["type"], "error");
+
+        session.resize(20, 4, 0, 0);
+        assert_eq!(session.get_selection()["data"], json!(null));
+    }
+
+    #[test]
+    fn snapshot_cursor() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("ab\x1b[?25l\x1b[4 q");
+        session.snapshot(None, false, Trim::default());
+
+        let _cursor_style = sub.broadcast_rx.try_recv().unwrap();
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+        let cursor = &sub.broadcast_rx.try_recv().unwrap().to_json()["data"]["cursor"];
+
+        assert_eq!(cursor["col"], 2);
+        assert_eq!(cursor["visible"], false);
+        assert_eq!(cursor["blink"], false);
+        assert_eq!(cursor["shape"], "underline");
+    }
+
+    #[test]
+    fn snapshot_full() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b]2;top\x07\x1b[?1049h\x1b[?2004h\x1b[4h");
+        session.snapshot(None, false, Trim::default());
+        session.snapshot(None, true, Trim::default());
+
+        let _mode_changed = sub.broadcast_rx.try_recv().unwrap();
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+        let data = &sub.broadcast_rx.try_recv().unwrap().to_json()["data"];
+        assert!(data.get("title").is_none());
+
+        let data = &sub.broadcast_rx.try_recv().unwrap().to_json()["data"];
+        assert_eq!(data["title"], "top");
+        assert_eq!(data["screen"], "alternate");
+        assert_eq!(data["decModes"]["2004"], true);
+        assert_eq!(data["decModes"]["25"], true);
+        assert_eq!(data["ansiModes"]["4"], true);
+        assert_eq!(data["tabStops"], serde_json::json!([8, 16]));
+        assert_eq!(data["cols"], 20);
+    }
+
+    #[test]
+    fn snapshot_line_drawing() {
+        let mut session = Session::new(10, 3, 0);
+        session.output("\x1b(0lqqk\x1b(B\r\n\x1b)0\x0ex\x0fok\x0ex\x0f\r\n\x0emqqj\x0f");
+
+        let trim = Trim {
+            trailing: true,
+            collapse_blank: false,
+        };
+
+        let view = session.get_view(trim, None, &client());
+
+        assert_eq!(
+            view["data"]["lines"],
+            serde_json::json!(["┌──┐", "│ok│", "└──┘"])
+        );
+    }
+
+    #[test]
+    fn snapshot_custom_tab_stops() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b[3g\x1b[5G\x1bH\x1b[13G\x1bH\ra\tb\tc");
+        session.snapshot(Some(crate::render::Format::Ansi), true, Trim::default());
+
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+        let data = &sub.broadcast_rx.try_recv().unwrap().to_json()["data"];
+        assert_eq!(
+            data["text"].as_str().unwrap().lines().next().unwrap(),
+            "a   b       c       "
+        );
+        assert_eq!(data["tabStops"], serde_json::json!([4, 12]));
+        assert!(data["ansi"]
+            .as_str()
+            .unwrap()
+            .contains("\x1b[3g\x1b[5G\x1bH\x1b[13G\x1bH"));
+    }
+
+    #[test]
+    fn app_resize() {
+        let mut session = Session::new(20, 2, 0);
+        session.output("\x1b[8;5;40t");
+        session.apply_resize_request();
+        assert_eq!(session.size(), (20, 2));
+
+        session.set_allow_resize(true);
+        let mut sub = session.subscribe();
+        session.output("\x1b[8;5;40t");
+        session.apply_resize_request();
+        assert_eq!(session.size(), (40, 5));
+
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+        let resize = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(resize["type"], "resize");
+        assert_eq!(resize["data"]["cols"], 40);
+
+        session.output("\x1b[8;;30t");
+        session.apply_resize_request();
+        assert_eq!(session.size(), (30, 5));
+    }
+
+    #[test]
+    fn app_resize_keeps_cell_pixels() {
+        let nix::pty::OpenptyResult {
+            master,
+            slave: _slave,
+        } = nix::pty::openpty(None, None).unwrap();
+        crate::pty::resize(&master, 20, 2, 200, 40).unwrap();
+
+        let mut session = Session::new(20, 2, 0);
+        session.set_terminal(master.try_clone().unwrap());
+        session.set_allow_resize(true);
+        session.output("\x1b[8;5;40t");
+        session.apply_resize_request();
+
+        let size = crate::pty::size(&master).unwrap();
+        assert_eq!((size.ws_col, size.ws_row), (40, 5));
+        assert_eq!((size.ws_xpixel, size.ws_ypixel), (400, 100));
+    }
+
+    #[test]
+    fn dump_state() {
+        let mut session = Session::new(20, 3, 0);
+        session.output("\x1b]2;top\x07\x1b[?2004h\x1b[4h\x1b[?1h");
+        let state = &session.dump_state()["data"];
+
+        assert_eq!(state["screen"], "primary");
+        assert_eq!(state["title"], "top");
+        assert_eq!(state["decModes"]["1"], true);
+        assert_eq!(state["decModes"]["25"], true);
+        assert_eq!(state["decModes"]["2004"], true);
+        assert_eq!(state["ansiModes"]["4"], true);
+        assert_eq!(state["tabStops"], serde_json::json!([8, 16]));
+        assert_eq!(state["savedCursor"], serde_json::Value::Null);
+        assert_eq!(state["decModes"]["5"], false);
+        assert_eq!(state["decModes"]["7"], true);
+    }
+
+    #[test]
+    fn screen_modes() {
+        let mut session = Session::new(20, 3, 0);
+        session.output("\x1b[?5h\x1b[?6h\x1b[?7l");
+        let state = &session.dump_state()["data"];
+
+        assert_eq!(state["decModes"]["5"], true);
+        assert_eq!(state["decModes"]["6"], true);
+        assert_eq!(state["decModes"]["7"], false);
+        assert!(state["seq"].as_str().unwrap().ends_with("\x1b[?5h"));
+
+        // DECSTR resets origin mode only
+        session.output("\x1b[!p");
+        let state = &session.dump_state()["data"];
+
+        assert_eq!(state["decModes"]["5"], true);
+        assert_eq!(state["decModes"]["6"], false);
+        assert_eq!(state["decModes"]["7"], false);
+    }
+
+    #[test]
+    fn reset() {
+        let mut session = Session::new(20, 2, 0);
+        session.output("abc\x1b[?25l\x1b]2;x\x07");
+        session.reset();
+        let state = &session.dump_state()["data"];
+
+        assert_eq!(state["lines"][0].as_str().unwrap().trim_end(), "");
+        assert_eq!(state["cursor"]["visible"], true);
+        assert_eq!(state["title"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn clear_scrollback() {
+        let mut session = Session::new(20, 2, 0);
+        session.output("a\r\nb\r\nc\r\nd\x1b[?1h");
+        assert_eq!(session.dump_state()["data"]["scrollback"], 2);
+
+        session.clear_scrollback(false);
+        let state = &session.dump_state()["data"];
+        assert_eq!(state["scrollback"], 0);
+        assert_eq!(state["lines"][1].as_str().unwrap().trim_end(), "d");
+        assert_eq!(state["cursor"]["col"], 1);
+        assert!(session.cursor_key_app_mode());
+
+        session.clear_scrollback(true);
+        let state = &session.dump_state()["data"];
+        assert_eq!(state["lines"][1].as_str().unwrap().trim_end(), "");
+    }
+
+    #[test]
+    fn invalid_utf8_policy() {
+        let mut session = Session::new(20, 2, 0);
+        session.set_utf8_policy(utf8::Policy::Strict);
+        let mut sub = session.subscribe();
+        session.output(&b"a\xffb"[..]);
+
+        let error = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(error["type"], "invalidUtf8");
+        assert_eq!(error["data"]["stream"], "stdout");
+        assert_eq!(error["data"]["offset"], 1);
+        assert_eq!(error["data"]["bytes"], "/w==");
+
+        let output = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(output["data"]["seq"], "a\u{fffd}b");
+        assert!(output["data"].get("raw").is_none());
+
+        session.set_utf8_policy(utf8::Policy::Raw);
+        session.stderr(&b"\xfe"[..]);
+        let stderr = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(stderr["data"]["seq"], "\u{fffd}");
+        assert_eq!(stderr["data"]["raw"], "/g==");
+    }
+
+    #[test]
+    fn warnings_reach_late_subscribers() {
+        let mut session = Session::new(20, 2, 0);
+        session.warn("careful".to_owned());
+        let sub = session.subscribe();
+
+        assert_eq!(sub.warnings.len(), 1);
+        assert_eq!(sub.warnings[0].to_json()["data"]["message"], "careful");
+    }
+
+    #[test]
+    fn exec_captures_output() {
+        let mut session = Session::new(20, 3, 0);
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        session.output("\x1b]133;A\x07$ ");
+        assert!(session.start_exec("ls".to_owned(), None, reply_tx.clone()));
+        assert!(!session.start_exec("pwd".to_owned(), None, reply_tx));
+        assert_eq!(reply_rx.try_recv().unwrap()["type"], "error");
+
+        session.output("ls\r\n\x1b]133;C\x07a\r\nb\r\nc\r\n");
+        assert!(reply_rx.try_recv().is_err());
+        session.output("\x1b]133;D;0\x07\x1b]133;A\x07$ ");
+
+        let reply = reply_rx.try_recv().unwrap();
+        assert_eq!(reply["type"], "exec");
+        assert_eq!(reply["data"]["output"], "a\nb\nc");
+        assert_eq!(reply["data"]["exitCode"], 0);
+        assert!(reply_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn command_history() {
+        let mut session = Session::new(20, 3, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b]133;A\x07$ \x1b]133;B\x07");
+        session.output("echo hi\r\n\x1b]133;C\x07hi\r\n\x1b]133;D;0\x07");
+
+        let event = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .map(|e| e.to_json())
+            .find(|e| e["type"] == "commandExecuted")
+            .unwrap();
+
+        assert_eq!(event["data"]["command"], "echo hi");
+        assert_eq!(event["data"]["exitCode"], 0);
+
+        // a command end without a command start isn't reported
+        session.output("\x1b]133;D;1\x07");
+
+        assert!(std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .all(|e| e.to_json()["type"] != "commandExecuted"));
+    }
+
+    #[test]
+    fn cursor_style_changes() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b[6 q");
+
+        let event = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(event["type"], "cursorStyle");
+        assert_eq!(event["data"]["shape"], "bar");
+        assert_eq!(event["data"]["blink"], false);
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+
+        session.output("\x1b[6 qabc");
+        assert_eq!(
+            sub.broadcast_rx.try_recv().unwrap().to_json()["type"],
+            "output"
+        );
+
+        session.output("\x1b[?12h");
+        let event = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(event["type"], "cursorStyle");
+        assert_eq!(event["data"]["shape"], "bar");
+        assert_eq!(event["data"]["blink"], true);
+    }
+
+    #[test]
+    fn bracketed_paste_changes() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b[?2004h$ ");
+
+        let event = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(event["type"], "modeChanged");
+        assert_eq!(event["data"]["mode"], 2004);
+        assert_eq!(event["data"]["name"], "bracketedPaste");
+        assert_eq!(event["data"]["enabled"], true);
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+
+        session.output("\x1b[?2004h");
+        assert_eq!(
+            sub.broadcast_rx.try_recv().unwrap().to_json()["type"],
+            "output"
+        );
+
+        session.output("\x1b[?2004l");
+        let event = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(event["type"], "modeChanged");
+        assert_eq!(event["data"]["enabled"], false);
+    }
+
+    #[test]
+    fn strict_mouse() {
+        let mut session = Session::new(20, 2, 0);
+        assert!(session.reject_mouse().is_none());
+
+        session.set_strict_mouse(true);
+        let error = session.reject_mouse().unwrap();
+        assert_eq!(error["type"], "error");
+
+        session.output("\x1b[?1006h");
+        assert!(session.reject_mouse().is_some());
+
+        session.output("\x1b[?1000h");
+        assert!(session.reject_mouse().is_none());
+    }
+
+    #[test]
+    fn mouse_mode_changes() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b[?1002h\x1b[?1006h");
+
+        let names: Vec<_> = (0..2)
+            .map(|_| sub.broadcast_rx.try_recv().unwrap().to_json()["data"]["name"].clone())
+            .collect();
+
+        assert_eq!(names, ["mouseButtonEvent", "mouseSgr"]);
+        let _output = sub.broadcast_rx.try_recv().unwrap();
+
+        session.output("\x1bc");
+        let modes: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .map(|e| e.to_json())
+            .filter(|e| e["type"] == "modeChanged")
+            .map(|e| (e["data"]["mode"].clone(), e["data"]["enabled"].clone()))
+            .collect();
+
+        assert_eq!(
+            modes,
+            [(1002.into(), false.into()), (1006.into(), false.into())]
+        );
+    }
+
+    #[test]
+    fn cwd_changes() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        session.output("\x1b]7;file://box/tmp\x07$ ");
+
+        let event = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(event["type"], "cwdChanged");
+        assert_eq!(event["data"]["cwd"], "/tmp");
+        assert_eq!(
+            sub.broadcast_rx.try_recv().unwrap().to_json()["type"],
+            "output"
+        );
+
+        session.output("\x1b]7;file://box/tmp\x07$ ");
+        assert_eq!(
+            sub.broadcast_rx.try_recv().unwrap().to_json()["type"],
+            "output"
+        );
+
+        session.snapshot(None, false, Trim::default());
+        let snapshot = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert_eq!(snapshot["data"]["cwd"], "/tmp");
+    }
+
+    #[test]
+    fn screen_hash() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+
+        let mut hash = |session: &mut Session| {
+            session.update_screen_hash();
+
+            while let Ok(event) = sub.broadcast_rx.try_recv() {
+                if let Event::ScreenHash(_, hash) = event {
+                    return Some(hash);
+                }
+            }
+
+            None
+        };
+
+        let empty = hash(&mut session).unwrap();
+        session.output("hello");
+        let hello = hash(&mut session).unwrap();
+        assert_ne!(empty, hello);
+
+        // nothing changed
+        session.output("\x1b[m");
+        assert_eq!(hash(&mut session), None);
+
+        // not rendered again until the screen changes
+        assert!(session.screen_touched_at.is_none());
+        assert_eq!(hash(&mut session), None);
+
+        // same text, different color
+        session.output("\r\x1b[31mhello");
+        assert_ne!(hash(&mut session), None);
+
+        // stable across sessions
+        let mut other = Session::new(20, 2, 0);
+        let mut other_sub = other.subscribe();
+        other.output("hello");
+        other.update_screen_hash();
+
+        while let Ok(event) = other_sub.broadcast_rx.try_recv() {
+            if let Event::ScreenHash(_, hash) = event {
+                assert_eq!(hash, hello);
+            }
+        }
+    }
+
+    #[test]
+    fn wait_for_stable() {
+        let mut session = Session::new(20, 2, 0);
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        session.update_screen_hash();
+        session.wait_for_stable(Duration::ZERO, Duration::from_secs(5), reply_tx);
+        assert!(session.stable_deadline().is_some());
+
+        session.check_stable();
+        let reply = reply_rx.try_recv().unwrap();
+        assert_eq!(reply["type"], "stable");
+        assert_eq!(reply["data"]["cols"], 20);
+        assert!(reply["data"]["hash"].is_string());
+        assert!(session.stable_deadline().is_none());
+
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        session.output("hello");
+        session.update_screen_hash();
+        session.wait_for_stable(Duration::from_secs(60), Duration::ZERO, reply_tx);
+        session.check_stable();
+        assert_eq!(reply_rx.try_recv().unwrap()["type"], "error");
+    }
+
+    #[test]
+    fn redact_output() {
+        let mut session = Session::new(20, 2, 0);
+        let rules = vec![regex::Regex::new("s3cr3t").unwrap()];
+        session.set_redactor(Redactor::new(rules, "***".to_owned()));
+        session.set_utf8_policy(utf8::Policy::Raw);
+        let mut sub = session.subscribe();
+        session.output("pw s3cr3t");
+
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Output(_, _, data, raw) => {
+                assert_eq!(*data, "pw ***");
+                assert_eq!(raw.unwrap(), &b"pw ***"[..]);
+            }
+
+            _ => panic!("expected output event"),
+        }
+
+        assert_eq!(
+            session.text_view().lines().next().unwrap().trim_end(),
+            "pw ***"
+        );
+
+        // the screen keeps the output as is, it's masked when read
+        assert_eq!(session.vt.view()[0].text().trim_end(), "pw s3cr3t");
+        assert_eq!(
+            session.get_line(0)["data"]["text"]
+                .as_str()
+                .unwrap()
+                .trim_end(),
+            "pw ***"
+        );
+
+        let columns = session.get_columns(0);
+        assert_eq!(
+            columns["data"]["text"].as_str().unwrap().trim_end(),
+            "pw ******"
+        );
+        assert_eq!(columns["data"]["graphemes"][3]["text"], "*");
+
+        session.snapshot(Some(crate::render::Format::Ansi), false, Trim::default());
+        let snapshot = sub.broadcast_rx.try_recv().unwrap().to_json();
+        assert!(!snapshot.to_string().contains("s3cr3t"), "{snapshot}");
+    }
+
+    #[test]
+    fn redact_output_not_sequences() {
+        let mut session = Session::new(20, 2, 0);
+        let rules = vec![regex::Regex::new("31").unwrap()];
+        session.set_redactor(Redactor::new(rules, "***".to_owned()));
+        let mut sub = session.subscribe();
+        session.output("\x1b[31mred 31");
+
+        // the sequence comes through as is, only the text is masked
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Output(_, _, data, _) => assert_eq!(*data, "\x1b[31mred ***"),
+            _ => panic!("expected output event"),
+        }
+
+        // the emulator got the color, not the mask
+        let cell = session.vt.view()[0].cells()[0];
+        assert_eq!(cell.char(), 'r');
+        assert_eq!(cell.pen().foreground(), Some(avt::Color::Indexed(1)));
+    }
+
+    #[test]
+    fn mask_secret_input() {
+        let mut session = Session::new(20, 2, 0);
+        session.add_secrets(vec!["hunter2".to_owned()]);
+        let mut sub = session.subscribe();
+        session.output("hunter2\r\n");
+        session.output("\x1b[1mhunter2");
+
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Output(_, _, data, _) => assert_eq!(*data, "********\r\n"),
+            _ => panic!("expected output event"),
+        }
+
+        // a redraw of the echo is masked too
+        match sub.broadcast_rx.try_recv().unwrap() {
+            Event::Output(_, _, data, _) => assert_eq!(*data, "\x1b[1m********"),
+            _ => panic!("expected output event"),
+        }
+
+        // the screen got the echo as is, and masks it when read
+        assert_eq!(session.vt.view()[0].text().trim_end(), "hunter2");
+        assert_eq!(
+            session.get_line(0)["data"]["text"]
+                .as_str()
+                .unwrap()
+                .trim_end(),
+            "********"
+        );
+    }
+
+    #[test]
+    fn paste() {
+        let mut session = Session::new(20, 2, 0);
+        let mut sub = session.subscribe();
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        session.start_paste("a".repeat(5000), reply_tx);
+        assert!(session.is_pasting());
+
+        assert_eq!(session.next_paste_chunk().unwrap().len(), 4096);
+        assert_eq!(session.next_paste_chunk().unwrap().len(), 904);
+        assert!(session.next_paste_chunk().is_none());
+        assert!(!session.is_pasting());
+
+        let reply = reply_rx.try_recv().unwrap();
+        assert_eq!(reply["type"], "paste");
+        assert_eq!(reply["data"]["bytes"], 5000);
+
+        let progress: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .filter_map(|e| match e {
+                Event::PasteProgress(_, written, total) => Some((written, total)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(progress, vec![(4096, 5000), (5000, 5000)]);
+
+        // bracketed paste mode
+        session.output("\x1b[?2004h");
+        let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
+        session.start_paste("ls".to_owned(), reply_tx);
+        assert_eq!(session.next_paste_chunk().unwrap(), b"\x1b[200~ls\x1b[201~");
+
+        // the text can't end bracketed paste
+        let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
+        session.start_paste("ls\x1b[201~rm -rf ~\r".to_owned(), reply_tx);
+
+        assert_eq!(
+            session.next_paste_chunk().unwrap(),
+            b"\x1b[200~lsrm -rf ~\r\x1b[201~"
+        );
+
+        let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
+        session.start_paste("a\x1b[200~b\x1bc".to_owned(), reply_tx);
+        assert_eq!(
+            session.next_paste_chunk().unwrap(),
+            b"\x1b[200~abc\x1b[201~"
+        );
+    }
 }
@@ -0,0 +1,58 @@
+/// Builds the shell command connecting to a remote host, with a remote PTY
+/// forced even though ht's stdin isn't a terminal.
+///
+/// Without a remote command ssh starts the user's login shell. ssh joins the
+/// remote command's arguments with spaces for the remote shell to parse, so
+/// they're quoted twice: for the remote shell, then for the local one.
+pub fn command(destination: &str, remote_command: &[String]) -> String {
+    let mut command = format!("exec ssh -tt -- {}", quote(destination));
+
+    for arg in remote_command {
+        command.push(' ');
+        command.push_str(&quote(&quote(arg)));
+    }
+
+    command
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::command;
+    use std::process;
+
+    fn sh(script: &str) -> String {
+        let output = process::Command::new("sh")
+            .args(["-c", script])
+            .output()
+            .unwrap();
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn ssh_command() {
+        assert_eq!(command("me@box", &[]), "exec ssh -tt -- 'me@box'");
+
+        assert_eq!(
+            command("box", &["cat".to_owned(), "it's".to_owned()]),
+            r"exec ssh -tt -- 'box' ''\''cat'\''' ''\''it'\''\'\'''\''s'\'''"
+        );
+    }
+
+    #[test]
+    fn remote_arguments() {
+        let args = ["tail", "-f", "my file's.log", "$HOME", "a;b"].map(String::from);
+        let command = command("box", &args);
+
+        // the words ssh gets locally, which it joins for the remote shell
+        let local = command.replacen("exec ssh -tt -- 'box'", "printf '%s\\n'", 1);
+        let remote = sh(&local).lines().collect::<Vec<_>>().join(" ");
+
+        let words = sh(&format!("printf '%s\\n' {remote}"));
+        assert_eq!(words.lines().collect::<Vec<_>>(), args);
+    }
+}
@@ -0,0 +1,85 @@
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, MissedTickBehavior};
+
+const TICK: Duration = Duration::from_millis(100);
+
+/// Most input held back before more gets dropped.
+pub const MAX_QUEUED: usize = 16 * 1024 * 1024;
+
+/// Forwards input to the child at no more than `rate` bytes per second.
+///
+/// Input coming faster is queued, up to `MAX_QUEUED` bytes. Input which doesn't
+/// fit in the queue is dropped. The returned channel closes as soon as the
+/// input channel closes, discarding whatever is still queued.
+pub fn start(rate: usize, mut input_rx: mpsc::Receiver<Vec<u8>>) -> mpsc::Receiver<Vec<u8>> {
+    let (output_tx, output_rx) = mpsc::channel(1);
+    let budget = (rate * TICK.as_millis() as usize / 1000).max(1);
+
+    tokio::spawn(async move {
+        let mut queue: Vec<u8> = Vec::new();
+        let mut overflowing = false;
+        let mut ticks = time::interval(TICK);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                data = input_rx.recv() => {
+                    let Some(data) = data else {
+                        return;
+                    };
+
+                    if queue.len() + data.len() > MAX_QUEUED {
+                        if !overflowing {
+                            eprintln!("input rate limit exceeded, dropping input");
+                            overflowing = true;
+                        }
+                    } else {
+                        queue.extend_from_slice(&data);
+                        overflowing = false;
+                    }
+                }
+
+                _ = ticks.tick(), if !queue.is_empty() => {
+                    let chunk: Vec<u8> = queue.drain(..budget.min(queue.len())).collect();
+
+                    if output_tx.send(chunk).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    output_rx
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn limit_rate() {
+        let (input_tx, input_rx) = mpsc::channel(8);
+        let mut output_rx = super::start(1000, input_rx);
+        let start = Instant::now();
+        input_tx.send(vec![b'a'; 150]).await.unwrap();
+        input_tx.send(vec![b'b'; 100]).await.unwrap();
+        let mut chunks = Vec::new();
+
+        while chunks.iter().map(Vec::len).sum::<usize>() < 250 {
+            chunks.push(output_rx.recv().await.unwrap());
+        }
+
+        assert_eq!(
+            chunks.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![100, 100, 50]
+        );
+
+        assert_eq!(&chunks[1][..], [[b'a'; 50], [b'b'; 50]].concat());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        drop(input_tx);
+        assert!(output_rx.recv().await.is_none());
+    }
+}
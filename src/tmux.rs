@@ -0,0 +1,222 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::future::Future;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, ChildStdout, Command};
+use tokio::sync::{mpsc, watch};
+
+/// Max number of input bytes sent with a single `send-keys` command.
+const SEND_KEYS_CHUNK: usize = 256;
+
+/// Attaches to a pane of an existing tmux session, using tmux control mode.
+///
+/// The pane's current screen is delivered as the first output. Returns the
+/// PID of the process running in the pane.
+pub fn spawn(
+    target: String,
+    cols: usize,
+    rows: usize,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<(i32, impl Future<Output = Result<()>>)> {
+    let info = query(&target, "#{pane_id} #{pane_pid} #{cursor_x} #{cursor_y}")?;
+    let fields: Vec<&str> = info.split(' ').collect();
+
+    let [pane, pid, x, y] = fields[..] else {
+        bail!("unexpected tmux reply: {info}");
+    };
+
+    let pane = pane.to_owned();
+    let pid = pid.parse()?;
+    let screen = capture_pane(&pane, x.parse()?, y.parse()?)?;
+
+    let mut client = Command::new("tmux")
+        .args(["-C", "attach-session", "-t", &pane])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = client.stdin.take().unwrap();
+    let stdout = client.stdout.take().unwrap();
+
+    let fut = async move {
+        let size = format!("refresh-client -C {cols}x{rows}\n");
+        stdin.write_all(size.as_bytes()).await?;
+        output_tx.send(Bytes::from(screen)).await?;
+        let writer = tokio::spawn(write_input(stdin, pane.clone(), input_rx, written_tx));
+        let result = read_output(stdout, &pane, output_tx).await;
+
+        // closing stdin detaches the client, leaving the session running
+        writer.abort();
+        eprintln!("detaching from tmux");
+        client.wait().await?;
+
+        result
+    };
+
+    Ok((pid, fut))
+}
+
+/// Expands tmux format for the target pane.
+fn query(target: &str, format: &str) -> Result<String> {
+    let output = std::process::Command::new("tmux")
+        .args(["display-message", "-p", "-t", target, format])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "cannot find tmux pane {target}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Returns the visible contents of the pane (with colors), followed by
+/// cursor positioning.
+fn capture_pane(pane: &str, x: usize, y: usize) -> Result<Vec<u8>> {
+    let output = std::process::Command::new("tmux")
+        .args(["capture-pane", "-p", "-e", "-t", pane])
+        .output()?;
+
+    if !output.status.success() {
+        bail!("cannot capture tmux pane {pane}");
+    }
+
+    let mut screen = b"\x1b[H\x1b[2J".to_vec();
+    let text = String::from_utf8_lossy(&output.stdout);
+    screen.extend_from_slice(text.trim_end_matches('\n').replace('\n', "\r\n").as_bytes());
+    screen.extend_from_slice(format!("\x1b[0m\x1b[{};{}H", y + 1, x + 1).as_bytes());
+
+    Ok(screen)
+}
+
+async fn read_output(
+    stdout: ChildStdout,
+    pane: &str,
+    output_tx: mpsc::Sender<Bytes>,
+) -> Result<()> {
+    let mut lines = BufReader::new(stdout).split(b'\n');
+    let prefix = format!("%output {pane} ");
+
+    while let Some(line) = lines.next_segment().await? {
+        if let Some(data) = line.strip_prefix(prefix.as_bytes()) {
+            if output_tx.send(Bytes::from(unescape(data))).await.is_err() {
+                break;
+            }
+        } else if line.starts_with(b"%exit")
+            || (is_layout_change(&line) && !pane_exists(pane).await)
+        {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells whether the notification may mean the pane was closed. tmux
+/// doesn't announce closing of a single pane.
+fn is_layout_change(line: &[u8]) -> bool {
+    [
+        &b"%layout-change"[..],
+        b"%window-close",
+        b"%unlinked-window-close",
+    ]
+    .iter()
+    .any(|n| line.starts_with(n))
+}
+
+async fn pane_exists(pane: &str) -> bool {
+    // display-message falls back to the current pane when the target is gone
+    let output = Command::new("tmux")
+        .args(["list-panes", "-a", "-F", "#{pane_id}"])
+        .stderr(Stdio::null())
+        .output()
+        .await;
+
+    output.is_ok_and(|o| {
+        String::from_utf8_lossy(&o.stdout)
+            .lines()
+            .any(|p| p == pane)
+    })
+}
+
+/// Sends input to the pane, as hex-encoded keys.
+async fn write_input(
+    mut stdin: ChildStdin,
+    pane: String,
+    mut input_rx: mpsc::Receiver<Vec<u8>>,
+    written_tx: watch::Sender<u64>,
+) {
+    while let Some(data) = input_rx.recv().await {
+        for chunk in data.chunks(SEND_KEYS_CHUNK) {
+            if stdin.write_all(&send_keys(&pane, chunk)).await.is_err() {
+                return;
+            }
+
+            written_tx.send_modify(|total| *total += chunk.len() as u64);
+        }
+    }
+}
+
+fn send_keys(pane: &str, data: &[u8]) -> Vec<u8> {
+    let mut command = format!("send-keys -t {pane} -H");
+
+    for byte in data {
+        command.push_str(&format!(" {byte:02x}"));
+    }
+
+    command.push('\n');
+    command.into_bytes()
+}
+
+/// Decodes `%output` data, in which tmux escapes control characters and
+/// backslashes as octal (`\ooo`).
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        let octal = data
+            .get(i + 1..i + 4)
+            .filter(|_| data[i] == b'\\')
+            .and_then(|o| std::str::from_utf8(o).ok())
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+
+        match octal {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 4;
+            }
+
+            None => {
+                bytes.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::{send_keys, unescape};
+
+    #[test]
+    fn unescape_output() {
+        assert_eq!(unescape(br"ls\015\012\033[1mok\134"), b"ls\r\n\x1b[1mok\\");
+        assert_eq!(unescape(br"a\9b\01"), br"a\9b\01");
+        assert_eq!(unescape("zażółć".as_bytes()), "zażółć".as_bytes());
+    }
+
+    #[test]
+    fn encode_input() {
+        assert_eq!(send_keys("%3", b"ls\r"), b"send-keys -t %3 -H 6c 73 0d\n");
+    }
+}
@@ -0,0 +1,667 @@
+use std::collections::BTreeMap;
+
+/// Terminal state which avt doesn't expose, collected by scanning the output
+/// of the child for the relevant control sequences.
+#[derive(Debug)]
+pub struct Tracker {
+    scanner: Scanner,
+    pub cursor_style: CursorStyle,
+    pub dec_modes: BTreeMap<u16, bool>,
+    pub ansi_modes: BTreeMap<u16, bool>,
+    pub alternate_screen: bool,
+    pub saved_cursor: [Option<(usize, usize)>; 2],
+    pub tab_stops: Vec<usize>,
+    pub charsets: [Charset; 2],
+    pub shifted: bool,
+    pub title: Option<String>,
+    pub cwd: Option<String>,
+    /// Shell integration marks (OSC 133) seen since last taken, along with
+    /// the cursor position (col, line counted from the top of the scrollback).
+    pub marks: Vec<(Mark, (usize, usize))>,
+    /// Size (cols, rows) requested by the application with XTWINOPS, 0
+    /// keeping the current value.
+    pub resize_request: Option<(usize, usize)>,
+    /// Clipboard read requests (OSC 52 with `?` data) seen since last taken,
+    /// with the selection parameter of each.
+    pub clipboard_requests: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blink: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+/// Semantic prompt mark, sent by shells with shell integration enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mark {
+    /// `OSC 133 ; A` - the prompt is about to be printed
+    PromptStart,
+    /// `OSC 133 ; B` - the prompt ends, the user types the command next
+    PromptEnd,
+    /// `OSC 133 ; C` - the entered command starts executing
+    CommandStart,
+    /// `OSC 133 ; D [; exit code]` - the command finished
+    CommandEnd(Option<i32>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Charset {
+    Ascii,
+    Drawing,
+}
+
+impl Tracker {
+    pub fn new(cols: usize) -> Self {
+        Self {
+            scanner: Scanner::default(),
+            cursor_style: CursorStyle::default(),
+            dec_modes: BTreeMap::new(),
+            ansi_modes: BTreeMap::new(),
+            alternate_screen: false,
+            saved_cursor: [None, None],
+            tab_stops: (8..cols).step_by(8).collect(),
+            charsets: [Charset::Ascii, Charset::Ascii],
+            shifted: false,
+            title: None,
+            cwd: None,
+            marks: Vec::new(),
+            resize_request: None,
+            clipboard_requests: Vec::new(),
+        }
+    }
+
+    /// Feeds the output to the emulator, updating the tracked state. Returns
+    /// the number of old scrollback lines the emulator dropped, past its
+    /// scrollback limit.
+    ///
+    /// Sequences acting on the cursor position (DECSC, HTS, TBC) split the
+    /// data, so the emulator's cursor can be inspected right after them.
+    pub fn feed(&mut self, vt: &mut avt::Vt, data: &str) -> usize {
+        let mut fed = 0;
+        let mut dropped = 0;
+
+        for (seq, end) in self.scanner.scan(data) {
+            if seq.uses_cursor() {
+                dropped += vt.feed_str(&data[fed..end]).scrollback.count();
+                fed = end;
+            }
+
+            self.handle(seq, vt);
+        }
+
+        dropped + vt.feed_str(&data[fed..]).scrollback.count()
+    }
+
+    pub fn resize(&mut self, old_cols: usize, cols: usize) {
+        if cols > old_cols {
+            let start = old_cols + 8 - old_cols % 8;
+            self.tab_stops.extend((start..cols).step_by(8));
+        } else {
+            self.tab_stops.retain(|t| *t < cols);
+        }
+    }
+
+    pub fn active_charset(&self) -> Charset {
+        self.charsets[self.shifted as usize]
+    }
+
+    /// Whether reverse video (DECSCNM) is on. avt ignores it, so it's only
+    /// known here.
+    pub fn reverse_video(&self) -> bool {
+        self.dec_modes.get(&5) == Some(&true)
+    }
+
+    /// Whether origin mode (DECOM) is on.
+    pub fn origin_mode(&self) -> bool {
+        self.dec_modes.get(&6) == Some(&true)
+    }
+
+    /// Whether autowrap (DECAWM) is on, which it is by default.
+    pub fn auto_wrap(&self) -> bool {
+        self.dec_modes.get(&7) != Some(&false)
+    }
+
+    fn handle(&mut self, seq: Sequence, vt: &avt::Vt) {
+        match seq {
+            Sequence::Csi(params, 'h') => self.set_modes(&params, true, vt),
+            Sequence::Csi(params, 'l') => self.set_modes(&params, false, vt),
+            Sequence::Csi(params, 'q') => self.set_cursor_style(&params),
+            Sequence::Csi(params, 's') if params.is_empty() => self.save_cursor(vt),
+            Sequence::Csi(params, 'g') => self.clear_tab_stops(&params, vt),
+            Sequence::Csi(params, 'p') if params == "!" => self.soft_reset(),
+            Sequence::Csi(params, 't') => self.handle_window_op(&params),
+            Sequence::Esc(i, '7') if i.is_empty() => self.save_cursor(vt),
+            Sequence::Esc(i, 'H') if i.is_empty() => self.set_tab_stop(vt.cursor().col),
+            Sequence::Esc(i, 'c') if i.is_empty() => self.hard_reset(vt),
+            Sequence::Esc(i, c) if i == "(" => self.charsets[0] = charset(c),
+            Sequence::Esc(i, c) if i == ")" => self.charsets[1] = charset(c),
+            Sequence::Control('\x0e') => self.shifted = true,
+            Sequence::Control('\x0f') => self.shifted = false,
+            Sequence::Osc(data) => self.handle_osc(&data, vt),
+            _ => (),
+        }
+    }
+
+    fn set_modes(&mut self, params: &str, on: bool, vt: &avt::Vt) {
+        let Some(params) = params.strip_prefix('?') else {
+            for mode in mode_numbers(params) {
+                self.ansi_modes.insert(mode, on);
+            }
+
+            return;
+        };
+
+        for mode in mode_numbers(params) {
+            self.dec_modes.insert(mode, on);
+
+            match mode {
+                12 => self.cursor_style.blink = on,
+
+                47 | 1047 | 1049 => {
+                    if mode == 1049 && on {
+                        self.save_cursor(vt);
+                    }
+
+                    self.alternate_screen = on;
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    /// Handles DECSCUSR (`CSI Ps SP q`).
+    fn set_cursor_style(&mut self, params: &str) {
+        let Some(param) = params.strip_suffix(' ') else {
+            return;
+        };
+
+        let (shape, blink) = match param {
+            "" | "0" | "1" => (CursorShape::Block, true),
+            "2" => (CursorShape::Block, false),
+            "3" => (CursorShape::Underline, true),
+            "4" => (CursorShape::Underline, false),
+            "5" => (CursorShape::Bar, true),
+            "6" => (CursorShape::Bar, false),
+            _ => return,
+        };
+
+        self.cursor_style = CursorStyle { shape, blink };
+    }
+
+    /// Handles the resize request of XTWINOPS (`CSI 8 ; rows ; cols t`).
+    fn handle_window_op(&mut self, params: &str) {
+        let mut params = params.split(';');
+
+        if params.next() != Some("8") {
+            return;
+        }
+
+        let mut size = [0, 0];
+
+        for (value, param) in size.iter_mut().zip(params) {
+            match param {
+                "" => (),
+
+                _ => match param.parse::<u16>() {
+                    Ok(n) => *value = n as usize,
+                    Err(_) => return,
+                },
+            }
+        }
+
+        let [rows, cols] = size;
+        self.resize_request = Some((cols, rows));
+    }
+
+    fn save_cursor(&mut self, vt: &avt::Vt) {
+        let cursor = vt.cursor();
+        self.saved_cursor[self.alternate_screen as usize] = Some((cursor.col, cursor.row));
+    }
+
+    fn set_tab_stop(&mut self, col: usize) {
+        if let Err(index) = self.tab_stops.binary_search(&col) {
+            self.tab_stops.insert(index, col);
+        }
+    }
+
+    fn clear_tab_stops(&mut self, params: &str, vt: &avt::Vt) {
+        match params {
+            "" | "0" => {
+                let col = vt.cursor().col;
+                self.tab_stops.retain(|t| *t != col);
+            }
+
+            "3" => self.tab_stops.clear(),
+            _ => (),
+        }
+    }
+
+    /// Handles DECSTR, which resets the modes without clearing the screen.
+    /// Same as avt, autowrap is left as is.
+    fn soft_reset(&mut self) {
+        self.saved_cursor = [None, None];
+        self.charsets = [Charset::Ascii, Charset::Ascii];
+        self.shifted = false;
+        self.dec_modes.retain(|mode, _| ![1, 6, 25].contains(mode));
+        self.ansi_modes.remove(&4);
+    }
+
+    /// RIS resets the terminal, but the shell stays in the same directory.
+    fn hard_reset(&mut self, vt: &avt::Vt) {
+        let cwd = self.cwd.take();
+        let marks = std::mem::take(&mut self.marks);
+        let resize_request = self.resize_request.take();
+        let clipboard_requests = std::mem::take(&mut self.clipboard_requests);
+        *self = Self::new(vt.size().0);
+        self.cwd = cwd;
+        self.marks = marks;
+        self.resize_request = resize_request;
+        self.clipboard_requests = clipboard_requests;
+    }
+
+    fn handle_osc(&mut self, data: &str, vt: &avt::Vt) {
+        match data.split_once(';') {
+            Some(("0" | "2", title)) => self.title = Some(title.to_owned()),
+
+            Some(("7", url)) => {
+                if let Some(path) = file_url_path(url) {
+                    self.cwd = Some(path);
+                }
+            }
+
+            Some(("52", params)) => {
+                if let Some((selection, "?")) = params.split_once(';') {
+                    self.clipboard_requests.push(selection.to_owned());
+                }
+            }
+
+            Some(("133", params)) => {
+                let mut params = params.split(';');
+
+                let mark = match params.next() {
+                    Some("A") => Mark::PromptStart,
+                    Some("B") => Mark::PromptEnd,
+                    Some("C") => Mark::CommandStart,
+                    Some("D") => Mark::CommandEnd(params.next().and_then(|c| c.parse().ok())),
+                    _ => return,
+                };
+
+                let cursor = vt.cursor();
+                let line = vt.lines().len() - vt.view().len() + cursor.row;
+                self.marks.push((mark, (cursor.col, line)));
+            }
+
+            _ => (),
+        }
+    }
+}
+
+/// Extracts the path from a `file://host/path` URL, as sent with OSC 7.
+fn file_url_path(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+
+    while i < path.len() {
+        let hex = path
+            .get(i + 1..i + 3)
+            .filter(|_| path.as_bytes()[i] == b'%');
+
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+
+            None => {
+                bytes.push(path.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Returns the output with control sequences removed, keeping newlines and
+/// tabs. Line-drawing characters selected with `ESC ( 0` / `ESC ) 0` and
+/// SO/SI are translated to box-drawing glyphs, same as on the screen.
+/// Sequences split between chunks of output aren't recognized.
+pub fn plain_text(data: &str) -> String {
+    let mut scanner = Scanner::default();
+    let mut charsets = [Charset::Ascii, Charset::Ascii];
+    let mut shifted = false;
+    let mut text = String::with_capacity(data.len());
+
+    for ch in data.chars() {
+        let ground = scanner.state == State::Ground;
+
+        match scanner.advance(ch) {
+            Some(Sequence::Control('\n' | '\t')) => text.push(ch),
+            Some(Sequence::Control('\x0e')) => shifted = true,
+            Some(Sequence::Control('\x0f')) => shifted = false,
+            Some(Sequence::Esc(i, c)) if i == "(" => charsets[0] = charset(c),
+            Some(Sequence::Esc(i, c)) if i == ")" => charsets[1] = charset(c),
+
+            None if ground && scanner.state == State::Ground => {
+                text.push(charsets[shifted as usize].translate(ch))
+            }
+
+            _ => (),
+        }
+    }
+
+    text
+}
+
+fn mode_numbers(params: &str) -> impl Iterator<Item = u16> + '_ {
+    params.split(';').filter_map(|p| p.parse().ok())
+}
+
+fn charset(designator: char) -> Charset {
+    match designator {
+        '0' => Charset::Drawing,
+        _ => Charset::Ascii,
+    }
+}
+
+impl Default for CursorStyle {
+    fn default() -> Self {
+        Self {
+            shape: CursorShape::Block,
+            blink: true,
+        }
+    }
+}
+
+impl CursorShape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CursorShape::Block => "block",
+            CursorShape::Underline => "underline",
+            CursorShape::Bar => "bar",
+        }
+    }
+}
+
+impl Charset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Charset::Ascii => "ascii",
+            Charset::Drawing => "drawing",
+        }
+    }
+
+    fn translate(self, ch: char) -> char {
+        match self {
+            Charset::Ascii => ch,
+            Charset::Drawing => avt::Charset::Drawing.translate(ch),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Sequence {
+    /// Escape sequence: intermediate bytes, final byte.
+    Esc(String, char),
+    /// CSI sequence: parameter and intermediate bytes, final byte.
+    Csi(String, char),
+    /// OSC sequence: everything between `ESC ]` and the terminator.
+    Osc(String),
+    /// C0 control character other than ESC.
+    Control(char),
+}
+
+impl Sequence {
+    fn uses_cursor(&self) -> bool {
+        match self {
+            Sequence::Esc(i, '7' | 'H') => i.is_empty(),
+            Sequence::Csi(params, 's') => params.is_empty(),
+            Sequence::Csi(params, 'h') => params.split(';').any(|p| p == "?1049"),
+            Sequence::Csi(_, 'g') => true,
+            Sequence::Osc(data) => data.starts_with("133;"),
+            _ => false,
+        }
+    }
+}
+
+/// Extracts control sequences from the output, keeping incomplete ones
+/// between chunks.
+#[derive(Debug, Default)]
+struct Scanner {
+    state: State,
+    buf: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+    Str,
+    StrEscape,
+}
+
+impl Scanner {
+    /// Returns the complete sequences found in the data, with the byte
+    /// offsets of their ends.
+    fn scan(&mut self, data: &str) -> Vec<(Sequence, usize)> {
+        let mut seqs = Vec::new();
+
+        for (i, ch) in data.char_indices() {
+            let end = i + ch.len_utf8();
+
+            if let Some(seq) = self.advance(ch) {
+                seqs.push((seq, end));
+            }
+        }
+
+        seqs
+    }
+
+    fn advance(&mut self, ch: char) -> Option<Sequence> {
+        use State::*;
+
+        match (self.state, ch) {
+            (Osc, '\x1b') => self.state = OscEscape,
+            (Str, '\x1b') => self.state = StrEscape,
+            (Osc, '\x07') => return Some(Sequence::Osc(self.take())),
+            (Osc, _) => self.buf.push(ch),
+            (Str, _) => (),
+
+            (OscEscape, '\\') => return Some(Sequence::Osc(self.take())),
+            (StrEscape, '\\') => self.state = Ground,
+            (OscEscape | StrEscape, _) => {
+                self.start(Escape);
+                return self.advance(ch);
+            }
+
+            (_, '\x1b') => self.start(Escape),
+            (_, '\u{9b}') => self.start(Csi),
+            (_, '\u{9d}') => self.start(Osc),
+            (_, '\x18' | '\x1a') => self.state = Ground,
+            (_, '\x00'..='\x1f') => return Some(Sequence::Control(ch)),
+
+            (Ground, _) => (),
+
+            (Escape, '[') if self.buf.is_empty() => self.start(Csi),
+            (Escape, ']') if self.buf.is_empty() => self.start(Osc),
+            (Escape, 'P' | 'X' | '^' | '_') if self.buf.is_empty() => self.start(Str),
+            (Escape, '\x20'..='\x2f') => self.buf.push(ch),
+            (Escape, '\x30'..='\x7e') => return Some(Sequence::Esc(self.take(), ch)),
+            (Escape, _) => self.state = Ground,
+
+            (Csi, '\x40'..='\x7e') => return Some(Sequence::Csi(self.take(), ch)),
+            (Csi, '\x20'..='\x3f') => self.buf.push(ch),
+            (Csi, _) => (),
+        }
+
+        None
+    }
+
+    fn start(&mut self, state: State) {
+        self.buf.clear();
+        self.state = state;
+    }
+
+    fn take(&mut self) -> String {
+        self.state = State::Ground;
+
+        std::mem::take(&mut self.buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{plain_text, Charset, CursorShape, Mark, Scanner, Sequence, Tracker};
+
+    fn sequences(scanner: &mut Scanner, data: &str) -> Vec<Sequence> {
+        scanner.scan(data).into_iter().map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn scan_split_sequences() {
+        let mut scanner = Scanner::default();
+
+        assert_eq!(sequences(&mut scanner, "a\x1b[?25"), vec![]);
+
+        assert_eq!(
+            sequences(&mut scanner, "l\x1b]0;ti"),
+            vec![Sequence::Csi("?25".to_owned(), 'l')]
+        );
+
+        assert_eq!(
+            sequences(&mut scanner, "tle\x07\x1b]2;x\x1b\\\x1bc\x1b(0\x0e"),
+            vec![
+                Sequence::Osc("0;title".to_owned()),
+                Sequence::Osc("2;x".to_owned()),
+                Sequence::Esc("".to_owned(), 'c'),
+                Sequence::Esc("(".to_owned(), '0'),
+                Sequence::Control('\x0e'),
+            ]
+        );
+
+        assert_eq!(sequences(&mut scanner, "\x1bPq#0\x1b\\x"), vec![]);
+    }
+
+    #[test]
+    fn cursor_style() {
+        let mut vt = avt::Vt::new(20, 2);
+        let mut tracker = Tracker::new(20);
+        assert!(tracker.cursor_style.blink);
+
+        tracker.feed(&mut vt, "\x1b[6 q");
+        assert_eq!(tracker.cursor_style.shape, CursorShape::Bar);
+        assert!(!tracker.cursor_style.blink);
+
+        tracker.feed(&mut vt, "\x1b[?12h");
+        assert!(tracker.cursor_style.blink);
+
+        tracker.feed(&mut vt, "\x1b[?1;12l");
+        assert!(!tracker.cursor_style.blink);
+
+        tracker.feed(&mut vt, "\x1b[0 q");
+        assert_eq!(tracker.cursor_style.shape, CursorShape::Block);
+        assert!(tracker.cursor_style.blink);
+    }
+
+    #[test]
+    fn cursor_dependent_state() {
+        let mut vt = avt::Vt::new(20, 2);
+        let mut tracker = Tracker::new(20);
+        assert_eq!(tracker.tab_stops, vec![8, 16]);
+
+        tracker.feed(&mut vt, "abc\x1b7\x1bHdef");
+        assert_eq!(tracker.saved_cursor, [Some((3, 0)), None]);
+        assert_eq!(tracker.tab_stops, vec![3, 8, 16]);
+        assert_eq!(vt.text()[0].trim_end(), "abcdef");
+
+        tracker.feed(&mut vt, "\x1b[?1049h\x1b(0\x1b]2;vim\x07");
+        assert_eq!(tracker.saved_cursor, [Some((6, 0)), None]);
+        assert!(tracker.alternate_screen);
+        assert_eq!(tracker.active_charset(), Charset::Drawing);
+        assert_eq!(tracker.title.as_deref(), Some("vim"));
+
+        tracker.resize(20, 30);
+        assert_eq!(tracker.tab_stops, vec![3, 8, 16, 24]);
+    }
+
+    #[test]
+    fn working_directory() {
+        let mut vt = avt::Vt::new(20, 2);
+        let mut tracker = Tracker::new(20);
+
+        tracker.feed(&mut vt, "\x1b]7;file://box/home/me/My%20Docs\x07");
+        assert_eq!(tracker.cwd.as_deref(), Some("/home/me/My Docs"));
+
+        tracker.feed(&mut vt, "\x1b]7;file:///tmp/%zz%C5%BC\x1b\\");
+        assert_eq!(tracker.cwd.as_deref(), Some("/tmp/%zzż"));
+
+        tracker.feed(&mut vt, "\x1b]7;http://box/etc\x07\x1bc");
+        assert_eq!(tracker.cwd.as_deref(), Some("/tmp/%zzż"));
+    }
+
+    #[test]
+    fn prompt_marks() {
+        let mut vt = avt::Vt::new(20, 2);
+        let mut tracker = Tracker::new(20);
+
+        tracker.feed(
+            &mut vt,
+            "\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]133;C\x1b\\x\r\n\x1b]133;D;2\x07\x1b]133;D\x07",
+        );
+
+        assert_eq!(
+            tracker.marks,
+            vec![
+                (Mark::PromptStart, (0, 0)),
+                (Mark::PromptEnd, (2, 0)),
+                (Mark::CommandStart, (0, 1)),
+                (Mark::CommandEnd(Some(2)), (0, 2)),
+                (Mark::CommandEnd(None), (0, 2))
+            ]
+        );
+    }
+
+    #[test]
+    fn resize_request() {
+        let mut vt = avt::Vt::new(20, 2);
+        let mut tracker = Tracker::new(20);
+
+        tracker.feed(&mut vt, "\x1b[8;30;100t");
+        assert_eq!(tracker.resize_request, Some((100, 30)));
+
+        tracker.feed(&mut vt, "\x1b[8;;90t");
+        assert_eq!(tracker.resize_request, Some((90, 0)));
+
+        tracker.resize_request = None;
+        tracker.feed(&mut vt, "\x1b[14t\x1b[8;x;1t");
+        assert_eq!(tracker.resize_request, None);
+    }
+
+    #[test]
+    fn strip_sequences() {
+        assert_eq!(
+            plain_text("\x1b[31mERR\x1b[0mOR: \x1b]2;t\x07disk\tfull\r\n\x1b(0x"),
+            "ERROR: disk\tfull\n│"
+        );
+
+        assert_eq!(
+            plain_text("\x1b(0lqk\x1b(B x\n\x1b)0\x0emj\x0fq"),
+            "┌─┐ x\n└┘q"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use clap::ValueEnum;
+
+/// How bytes that aren't valid UTF-8 are handled in events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Policy {
+    /// Replace them with U+FFFD
+    #[default]
+    Lossy,
+    /// Replace them with U+FFFD and emit invalidUtf8 events
+    Strict,
+    /// Replace them with U+FFFD and include the original bytes in events (base64)
+    Raw,
+}
+
+/// Incremental UTF-8 decoder.
+///
+/// Multi-byte characters split between chunks are carried over to the next
+/// chunk instead of being replaced. Invalid bytes are replaced with U+FFFD and
+/// reported along with their offset in the stream.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Decoded {
+    pub text: String,
+    pub invalid: Vec<(usize, Vec<u8>)>,
+    /// Offsets in the text of the replacement characters, with the number of
+    /// bytes each of them replaced
+    replaced: Vec<(usize, usize)>,
+    /// Number of bytes at the start that came with the previous chunk
+    carried: usize,
+}
+
+impl Decoder {
+    pub fn decode(&mut self, data: &[u8]) -> Decoded {
+        let mut buf = std::mem::take(&mut self.pending);
+        let carried = buf.len();
+
+        // decoded straight from the data, unless completing a split character
+        let mut rest = if buf.is_empty() {
+            data
+        } else {
+            buf.extend_from_slice(data);
+            &buf[..]
+        };
+
+        let mut decoded = Decoded {
+            text: String::with_capacity(rest.len()),
+            carried,
+            ..Decoded::default()
+        };
+
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    decoded.text.push_str(text);
+                    self.offset += rest.len();
+                    break;
+                }
+
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    decoded
+                        .text
+                        .push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    self.offset += valid.len();
+
+                    match e.error_len() {
+                        Some(len) => {
+                            decoded.replaced.push((decoded.text.len(), len));
+                            decoded.text.push(char::REPLACEMENT_CHARACTER);
+                            decoded.invalid.push((self.offset, after[..len].to_vec()));
+                            self.offset += len;
+                            rest = &after[len..];
+                        }
+
+                        None => {
+                            // incomplete character at the end
+                            self.pending = after.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        decoded
+    }
+}
+
+impl Decoded {
+    /// Offset in the chunk's bytes matching the given offset in the text,
+    /// which differ when bytes were replaced or carried over.
+    pub fn byte_offset(&self, text_offset: usize) -> usize {
+        let mut offset = text_offset;
+
+        for (at, len) in &self.replaced {
+            if *at >= text_offset {
+                break;
+            }
+
+            offset = offset + len - char::REPLACEMENT_CHARACTER.len_utf8();
+        }
+
+        offset.saturating_sub(self.carried)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Decoder;
+
+    #[test]
+    fn decode_split_characters() {
+        let mut decoder = Decoder::default();
+        let bytes = "zażółć".as_bytes();
+
+        let first = decoder.decode(&bytes[..3]);
+        assert_eq!(first.text, "za");
+        assert!(first.invalid.is_empty());
+
+        let second = decoder.decode(&bytes[3..]);
+        assert_eq!(second.text, "żółć");
+        assert!(second.invalid.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid_bytes() {
+        let mut decoder = Decoder::default();
+        decoder.decode(b"ab");
+        let decoded = decoder.decode(b"c\xff\xfed\xc3");
+
+        assert_eq!(decoded.text, "c\u{fffd}\u{fffd}d");
+        assert_eq!(decoded.invalid, vec![(3, vec![0xff]), (4, vec![0xfe])]);
+
+        let decoded = decoder.decode(b"x");
+        assert_eq!(decoded.text, "\u{fffd}x");
+        assert_eq!(decoded.invalid, vec![(6, vec![0xc3])]);
+    }
+
+    #[test]
+    fn byte_offsets() {
+        let mut decoder = Decoder::default();
+        let decoded = decoder.decode(b"a\xff\xfeb\xc5");
+
+        assert_eq!(decoded.text, "a\u{fffd}\u{fffd}b");
+        assert_eq!(decoded.byte_offset(1), 1);
+        assert_eq!(decoded.byte_offset(4), 2);
+        assert_eq!(decoded.byte_offset(7), 3);
+        assert_eq!(decoded.byte_offset(8), 4);
+
+        // the first character started in the previous chunk
+        let decoded = decoder.decode("żc".as_bytes().split_at(1).1);
+        assert_eq!(decoded.text, "żc");
+        assert_eq!(decoded.byte_offset(0), 0);
+        assert_eq!(decoded.byte_offset(2), 1);
+        assert_eq!(decoded.byte_offset(3), 2);
+    }
+}